@@ -1,172 +1,1008 @@
+use super::diagnostics::{self, Category};
 use super::heat::Heatmap;
+use super::slippy;
 
+use std::convert::{TryFrom, TryInto};
 use std::error::Error;
 use std::ffi::OsStr;
+use std::fmt;
 use std::fs::File;
-use std::io::BufReader;
+use std::io::{BufReader, Read};
 use std::path::{Path, PathBuf};
 
+use clap::ValueEnum;
+use fitparser::de::{DecodeOption, FitObject, FitStreamProcessor};
 use fitparser::profile::field_types;
+use fitparser::FitDataRecord;
 use flate2::read::GzDecoder;
-use geo::Point;
+use geo::algorithm::haversine_distance::HaversineDistance;
+use geo::algorithm::simplify::SimplifyIdx;
+use geo::{LineString, Point};
 use geo_types::Coord;
-use gpx::{Gpx, Track};
+use gpx::{Gpx, Waypoint};
+use rayon::prelude::*;
+use regex::bytes::Regex as BytesRegex;
+use serde::{Deserialize, Serialize};
 use time::OffsetDateTime;
+use xml::reader::{EventReader, XmlEvent};
+
+/// Converts an angle in semicircles, as FIT files store coordinates, to degrees
+fn semicircles_to_degrees(semicircles: f64) -> f64 {
+    semicircles * 180.0 / u32::pow(2, 31) as f64
+}
 
 fn extract_coordinate(field: &fitparser::FitDataField) -> Option<f64> {
     if field.units() == "semicircles" {
         if let fitparser::Value::SInt32(raw) = field.value() {
-            return Some(*raw as f64 * 180.0 / u32::pow(2, 31) as f64);
+            return Some(semicircles_to_degrees(*raw as f64));
         }
     }
     None
 }
 
-fn parse_fit<T: std::io::Read>(reader: &mut BufReader<T>) -> Result<Activity, Box<dyn Error>> {
+/// A field of FIT records read into `Sensors::field` by the name fitparser decodes it with,
+/// e.g., `saturated_hemoglobin_percent`, and the factor converting its values to the unit
+/// wanted; without a factor, semicircles are converted to degrees and other units kept
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct FitField {
+    pub name: String,
+    pub factor: Option<f64>,
+}
+
+impl std::str::FromStr for FitField {
+    type Err = String;
+
+    /// Parses `NAME` or `NAME*FACTOR`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, factor) = match s.split_once('*') {
+            Some((name, factor)) => match factor.trim().parse::<f64>() {
+                Ok(factor) if factor.is_finite() && factor != 0.0 => (name, Some(factor)),
+                _ => return Err(format!("not a factor: '{}'", factor)),
+            },
+            None => (s, None),
+        };
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(format!("no field name in '{}'", s));
+        }
+        Ok(FitField {
+            name: name.to_string(),
+            factor,
+        })
+    }
+}
+
+impl fmt::Display for FitField {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.factor {
+            Some(factor) => write!(f, "{}*{}", self.name, factor),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+impl From<FitField> for String {
+    fn from(field: FitField) -> Self {
+        field.to_string()
+    }
+}
+
+impl TryFrom<String> for FitField {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl FitField {
+    /// The value of a record field in the unit wanted
+    fn extract(&self, field: &fitparser::FitDataField) -> Option<f64> {
+        match self.factor {
+            Some(factor) => extract_float(field).map(|value| value * factor),
+            None if field.units() == "semicircles" => {
+                extract_float(field).map(semicircles_to_degrees)
+            }
+            None => extract_float(field),
+        }
+    }
+}
+
+/// Highest latitude of the Web Mercator projection; points beyond are drawn at its edge
+const MAX_LATITUDE: f64 = 85.051_128_78;
+
+/// Drops track points with coordinates no device can record, e.g., NaN, beyond ±90° or ±180°,
+/// or exactly 0/0 as written without a fix, clamps latitudes to what maps show and discards
+/// readings that are not finite; returns the number of points dropped
+fn sanitize(track_points: &mut Vec<TrackPoint<Point<f64>>>) -> usize {
+    let before = track_points.len();
+    track_points.retain(|tp| {
+        let (lon, lat) = tp.point.x_y();
+        lon.is_finite()
+            && lat.is_finite()
+            && lon.abs() <= 180.0
+            && lat.abs() <= 90.0
+            && (lon, lat) != (0.0, 0.0)
+    });
+    let finite = |value: Option<f64>| value.filter(|v| v.is_finite());
+    for tp in track_points.iter_mut() {
+        let (lon, lat) = tp.point.x_y();
+        tp.point = Point::new(lon, lat.clamp(-MAX_LATITUDE, MAX_LATITUDE));
+        tp.elevation = finite(tp.elevation);
+        tp.sensors.heart_rate = finite(tp.sensors.heart_rate);
+        tp.sensors.cadence = finite(tp.sensors.cadence);
+        tp.sensors.temperature = finite(tp.sensors.temperature);
+        tp.sensors.field = finite(tp.sensors.field);
+    }
+    before - track_points.len()
+}
+
+/// Time a track point was recorded at, if known
+pub type Timestamp = Option<chrono::DateTime<chrono::Utc>>;
+
+/// Readings of sensors paired with the recording device
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Sensors {
+    /// Heart rate, in beats per minute
+    pub heart_rate: Option<f64>,
+    /// Cadence, in revolutions or steps per minute
+    pub cadence: Option<f64>,
+    /// Temperature, in degrees Celsius
+    pub temperature: Option<f64>,
+    /// Reading of the FIT record field requested by `FitField`
+    pub field: Option<f64>,
+}
+
+/// A point of a track, in lon/lat, world or screen coordinates, with what was recorded there
+#[derive(Clone, Copy, Debug)]
+pub struct TrackPoint<P> {
+    pub point: P,
+    pub time: Timestamp,
+    /// Elevation, in meters
+    pub elevation: Option<f64>,
+    pub sensors: Sensors,
+}
+
+impl<P> TrackPoint<P> {
+    /// The same recording at another coordinate
+    pub fn with_point<Q>(self, point: Q) -> TrackPoint<Q> {
+        TrackPoint {
+            point,
+            time: self.time,
+            elevation: self.elevation,
+            sensors: self.sensors,
+        }
+    }
+}
+
+fn extract_float(field: &fitparser::FitDataField) -> Option<f64> {
+    field.value().clone().try_into().ok()
+}
+
+/// Fallback for activities without any timestamp information
+fn unknown_date() -> chrono::DateTime<chrono::Utc> {
+    chrono::DateTime::from_timestamp(0, 0).unwrap()
+}
+
+/// Whether a date is the fallback of activities without any timestamp information
+pub fn is_undated(date: &chrono::DateTime<chrono::Utc>) -> bool {
+    *date == unknown_date()
+}
+
+fn convert_gpx_time(time: gpx::Time) -> Option<chrono::DateTime<chrono::Utc>> {
+    let time = OffsetDateTime::from(time);
+    chrono::DateTime::from_timestamp(time.unix_timestamp(), time.nanosecond())
+}
+
+/// A session of a FIT file, e.g., one leg of a triathlon
+struct Session {
+    start: chrono::DateTime<chrono::Utc>,
+    sport: Option<String>,
+}
+
+fn extract_timestamp(field: &fitparser::FitDataField) -> Timestamp {
+    // FIT timestamps are absolute, converting them to UTC keeps DST out of the way
+    match field.value() {
+        fitparser::Value::Timestamp(t) => Some(t.with_timezone(&chrono::Utc)),
+        _ => None,
+    }
+}
+
+/// Decodes the messages of a FIT file; leniently, those before any damage, e.g., of a file
+/// truncated by a crashed head unit, together with the error stopping the decoding
+fn read_fit_messages<T: std::io::Read>(
+    reader: &mut BufReader<T>,
+    lenient: bool,
+) -> Result<(Vec<FitDataRecord>, Option<String>), Box<dyn Error>> {
+    if !lenient {
+        return Ok((fitparser::from_reader(reader)?, None));
+    }
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data)?;
+    let mut processor = FitStreamProcessor::new();
+    // Damaged files rarely have intact checksums
+    processor.add_option(DecodeOption::SkipHeaderCrcValidation);
+    processor.add_option(DecodeOption::SkipDataCrcValidation);
+    let mut messages = Vec::new();
+    let mut buffer = &data[..];
+    while !buffer.is_empty() {
+        let (rest, object) = match processor.deserialize_next(buffer) {
+            Ok(next) => next,
+            Err(e) => return Ok((messages, Some(e.to_string()))),
+        };
+        match object {
+            FitObject::Crc(_) => processor.reset(),
+            FitObject::DataMessage(message) => match processor.decode_message(message) {
+                Ok(record) => messages.push(record),
+                Err(e) => return Ok((messages, Some(e.to_string()))),
+            },
+            FitObject::Header(_) | FitObject::DefinitionMessage(_) => {}
+        }
+        buffer = rest;
+    }
+    Ok((messages, None))
+}
+
+/// Parses the records of a FIT file, as one activity per session if there are several, named
+/// after their sport and dated by their start; courses are named after the course; the given
+/// record field, if any, is read into the sensors of each point
+fn parse_fit(
+    messages: Vec<FitDataRecord>,
+    extra: Option<&FitField>,
+) -> Result<Vec<Activity>, Box<dyn Error>> {
     let mut activity = Activity {
         name: "Untitled".to_string(),
-        date: chrono::Utc::now(),
+        kind: None,
+        date: unknown_date(),
+        path: PathBuf::new(),
         track_points: vec![],
     };
+    let mut first_timestamp = None;
+    let mut sessions = Vec::new();
 
-    for data in fitparser::from_reader(reader)? {
-        if data.kind() == field_types::MesgNum::Record {
+    for data in messages {
+        if data.kind() == field_types::MesgNum::Session {
+            let mut start = None;
+            let mut sport = None;
+            for field in data.fields() {
+                match field.name() {
+                    "start_time" => start = extract_timestamp(field),
+                    "sport" => sport = Some(field.value().to_string()),
+                    _ => {}
+                }
+            }
+            if let Some(start) = start {
+                sessions.push(Session { start, sport });
+            }
+        } else if data.kind() == field_types::MesgNum::Course {
+            for field in data.fields() {
+                if field.name() == "name" {
+                    activity.name = field.value().to_string();
+                }
+            }
+        } else if data.kind() == field_types::MesgNum::Record {
             let mut lat: Option<f64> = None;
             let mut lon: Option<f64> = None;
+            let mut timestamp: Timestamp = None;
+            let mut elevation: Option<f64> = None;
+            let mut sensors = Sensors::default();
             for field in data.fields() {
+                if let Some(extra) = extra.filter(|extra| extra.name == field.name()) {
+                    sensors.field = extra.extract(field);
+                }
                 if field.name() == "position_lat" {
                     lat = extract_coordinate(field);
                 } else if field.name() == "position_long" {
                     lon = extract_coordinate(field);
+                } else if field.name() == "enhanced_altitude" {
+                    elevation = extract_float(field).or(elevation);
+                } else if field.name() == "altitude" {
+                    elevation = elevation.or_else(|| extract_float(field));
+                } else if field.name() == "heart_rate" {
+                    sensors.heart_rate = extract_float(field);
+                } else if field.name() == "cadence" {
+                    sensors.cadence = extract_float(field);
+                } else if field.name() == "temperature" {
+                    sensors.temperature = extract_float(field);
+                } else if field.name() == "timestamp" {
+                    timestamp = extract_timestamp(field);
                 }
             }
+            first_timestamp = first_timestamp.or(timestamp);
             if let Some((x, y)) = lon.zip(lat) {
-                activity.track_points.push(Point::new(x, y));
+                activity.track_points.push(TrackPoint {
+                    point: Point::new(x, y),
+                    time: timestamp,
+                    elevation,
+                    sensors,
+                });
             }
         }
     }
+    if let Some(date) = first_timestamp {
+        activity.date = date;
+    }
 
     if activity.track_points.is_empty() {
-        Err(Box::from("No track points"))
-    } else {
-        Ok(activity)
+        return Err(Box::from("No track points"));
+    }
+    if sessions.len() < 2 {
+        return Ok(vec![activity]);
     }
+
+    // Records belong to the last session started before them, untimed ones to the one before
+    sessions.sort_by_key(|s| s.start);
+    let mut parts: Vec<Activity> = sessions
+        .iter()
+        .map(|session| Activity {
+            name: session
+                .sport
+                .clone()
+                .unwrap_or_else(|| activity.name.clone()),
+            kind: session.sport.clone(),
+            date: session.start,
+            path: PathBuf::new(),
+            track_points: vec![],
+        })
+        .collect();
+    let mut current = 0;
+    for tp in activity.track_points {
+        if let Some(time) = tp.time {
+            current = sessions.iter().rposition(|s| s.start <= time).unwrap_or(0);
+        }
+        parts[current].track_points.push(tp);
+    }
+    parts.retain(|part| !part.track_points.is_empty());
+    Ok(parts)
 }
 
-fn parse_gpx<T: std::io::Read>(reader: &mut BufReader<T>) -> Result<Activity, Box<dyn Error>> {
-    let gpx: Gpx = gpx::read(reader)?;
-    // Nothing to do if there are no tracks
-    if gpx.tracks.is_empty() {
-        return Err(Box::from("file has no tracks"));
-    } else if gpx.tracks.len() > 1 {
-        eprintln!("Warning! more than 1 track, just taking first");
+/// Sensor readings from Garmin's TrackPointExtension for each track point, which the gpx crate
+/// skips; stops at the first malformed element
+fn parse_gpx_sensors(data: &[u8]) -> Vec<Sensors> {
+    let mut sensors = Vec::new();
+    let mut current: Option<Sensors> = None;
+    let mut element = String::new();
+    for event in EventReader::new(data) {
+        match event {
+            Ok(XmlEvent::StartElement { name, .. }) => {
+                if name.local_name == "trkpt" {
+                    current = Some(Sensors::default());
+                }
+                element = name.local_name;
+            }
+            Ok(XmlEvent::Characters(text)) => {
+                if let Some(ref mut reading) = current {
+                    let value = text.trim().parse().ok();
+                    match element.as_str() {
+                        "hr" => reading.heart_rate = value,
+                        "cad" => reading.cadence = value,
+                        "atemp" | "wtemp" => reading.temperature = value,
+                        _ => {}
+                    }
+                }
+            }
+            Ok(XmlEvent::EndElement { name }) => {
+                if name.local_name == "trkpt" {
+                    sensors.extend(current.take());
+                }
+                element.clear();
+            }
+            Ok(_) => {}
+            Err(_) => break,
+        }
     }
+    sensors
+}
 
-    let track: &Track = &gpx.tracks[0];
+/// Removes track and route points whose coordinates are missing or impossible, which the gpx
+/// crate rejects the whole file for, returning the cleaned GPX and the number of points removed
+fn drop_invalid_waypoints(data: &[u8]) -> Result<(Vec<u8>, usize), Box<dyn Error>> {
+    let element = BytesRegex::new(r"(?s)<(?:trkpt|rtept)\b[^>]*?(?:/>|>.*?</(?:trkpt|rtept)>)")?;
+    let attribute = |name| BytesRegex::new(&format!(r#"\b{}\s*=\s*["']([^"']*)["']"#, name));
+    let (lat, lon) = (attribute("lat")?, attribute("lon")?);
+    let coordinate = |tag: &[u8], attribute: &BytesRegex| -> Option<f64> {
+        let value = attribute.captures(tag)?.get(1)?.as_bytes();
+        std::str::from_utf8(value).ok()?.trim().parse().ok()
+    };
+    let mut removed = 0;
+    let cleaned = element.replace_all(data, |captures: &regex::bytes::Captures| {
+        let point = &captures[0];
+        let tag = &point[..point.iter().position(|&b| b == b'>').unwrap_or(point.len())];
+        let valid = match (coordinate(tag, &lat), coordinate(tag, &lon)) {
+            (Some(lat), Some(lon)) => lat.abs() <= 90.0 && lon.abs() <= 180.0,
+            _ => false,
+        };
+        if valid {
+            point.to_vec()
+        } else {
+            removed += 1;
+            vec![]
+        }
+    });
+    Ok((cleaned.into_owned(), removed))
+}
+
+/// Parses all tracks of a GPX file into one activity, one after the other, or its routes if it
+/// has no tracks, e.g., when exported from a planner; returns the number of points removed for
+/// impossible coordinates along with it
+fn parse_gpx<T: std::io::Read>(
+    reader: &mut BufReader<T>,
+) -> Result<(Activity, usize), Box<dyn Error>> {
+    // Read twice, as the gpx crate does not keep extensions
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data)?;
+    let mut removed = 0;
+    let gpx: Gpx = match gpx::read(&data[..]) {
+        Ok(gpx) => gpx,
+        Err(e) => {
+            // A single corrupt point fails the whole file, retry without any
+            let (cleaned, count) = drop_invalid_waypoints(&data)?;
+            if count == 0 {
+                return Err(e.into());
+            }
+            removed = count;
+            data = cleaned;
+            gpx::read(&data[..])?
+        }
+    };
+    let (name, waypoints): (Option<&String>, Vec<&Waypoint>) = if !gpx.tracks.is_empty() {
+        let points = gpx
+            .tracks
+            .iter()
+            .flat_map(|track| track.segments.iter())
+            .flat_map(|seg| seg.points.iter());
+        (gpx.tracks[0].name.as_ref(), points.collect())
+    } else if !gpx.routes.is_empty() {
+        let points = gpx.routes.iter().flat_map(|route| route.points.iter());
+        (gpx.routes[0].name.as_ref(), points.collect())
+    } else {
+        return Err(Box::from("file has no tracks or routes"));
+    };
 
     let mut activity = Activity {
-        name: track
-            .name
-            .clone()
-            .unwrap_or_else(|| String::from("Untitled")),
-        date: chrono::Utc::now(),
+        name: name.cloned().unwrap_or_else(|| String::from("Untitled")),
+        kind: None,
+        date: unknown_date(),
+        path: PathBuf::new(),
         track_points: vec![],
     };
 
-    if let Some(metadata) = gpx.metadata {
-        if let Some(time) = metadata.time {
-            activity.date =
-                chrono::DateTime::from_timestamp(OffsetDateTime::from(time).unix_timestamp(), 0)
-                    .expect("Timestamp conversion failed");
-        }
+    // Prefer the metadata time, falling back to the first timestamped waypoint
+    let time = gpx
+        .metadata
+        .as_ref()
+        .and_then(|m| m.time)
+        .or_else(|| waypoints.iter().find_map(|wpt| wpt.time));
+    if let Some(date) = time.and_then(convert_gpx_time) {
+        activity.date = date;
     }
 
-    // Append all the waypoints.
-    for seg in track.segments.iter() {
-        let points = seg.points.iter().map(|wpt| wpt.point());
-        activity.track_points.extend(points);
+    activity.track_points = waypoints
+        .iter()
+        .map(|wpt| TrackPoint {
+            point: wpt.point(),
+            time: wpt.time.and_then(convert_gpx_time),
+            elevation: wpt.elevation,
+            sensors: Sensors::default(),
+        })
+        .collect();
+    // Readings only line up with the points if both parsers saw the same ones
+    let sensors = parse_gpx_sensors(&data);
+    if sensors.len() == activity.track_points.len() {
+        for (tp, reading) in activity.track_points.iter_mut().zip(sensors) {
+            tp.sensors = reading;
+        }
     }
 
     if activity.track_points.is_empty() {
         Err(Box::from("No track points"))
+    } else {
+        Ok((activity, removed))
+    }
+}
+
+/// Track points of a KML `coordinates` element, whitespace separated `lon,lat[,alt]` tuples
+fn parse_kml_coordinates(text: &str) -> Vec<TrackPoint<Point<f64>>> {
+    text.split_whitespace()
+        .filter_map(|tuple| {
+            let mut values = tuple.split(',').map(|v| v.parse::<f64>().ok());
+            let lon = values.next()??;
+            let lat = values.next()??;
+            Some(TrackPoint {
+                point: Point::new(lon, lat),
+                time: None,
+                elevation: values.next().flatten(),
+                sensors: Sensors::default(),
+            })
+        })
+        .collect()
+}
+
+/// Parses all LineStrings of a KML file into one activity, one after the other, named after the
+/// first placemark or the document
+fn parse_kml<T: std::io::Read>(reader: &mut BufReader<T>) -> Result<Activity, Box<dyn Error>> {
+    let mut activity = Activity {
+        name: String::from("Untitled"),
+        kind: None,
+        date: unknown_date(),
+        path: PathBuf::new(),
+        track_points: vec![],
+    };
+    let mut name = None;
+    let mut path = Vec::new();
+    for event in EventReader::new(reader) {
+        match event? {
+            XmlEvent::StartElement { name, .. } => path.push(name.local_name),
+            XmlEvent::Characters(text) => {
+                let parent = path.iter().rev().nth(1).map(String::as_str);
+                match (path.last().map(String::as_str), parent) {
+                    (Some("coordinates"), Some("LineString")) => {
+                        activity.track_points.extend(parse_kml_coordinates(&text))
+                    }
+                    (Some("name"), Some("Placemark")) | (Some("name"), Some("Document")) => {
+                        name.get_or_insert_with(|| text.trim().to_string());
+                    }
+                    _ => {}
+                }
+            }
+            XmlEvent::EndElement { .. } => {
+                path.pop();
+            }
+            _ => {}
+        }
+    }
+    if let Some(name) = name.filter(|n| !n.is_empty()) {
+        activity.name = name;
+    }
+
+    if activity.track_points.is_empty() {
+        Err(Box::from("file has no LineStrings"))
     } else {
         Ok(activity)
     }
 }
 
+/// Parses the main KML document of a KMZ archive, `doc.kml` or else the first KML file in it
+fn parse_kmz<T: std::io::Read>(reader: &mut BufReader<T>) -> Result<Activity, Box<dyn Error>> {
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data)?;
+    let mut archive = zip::ZipArchive::new(std::io::Cursor::new(data))?;
+    let document = archive
+        .file_names()
+        .filter(|name| name.to_lowercase().ends_with(".kml"))
+        .min_by_key(|name| *name != "doc.kml")
+        .map(String::from)
+        .ok_or("archive contains no KML file")?;
+    let mut reader = BufReader::new(archive.by_name(&document)?);
+    parse_kml(&mut reader)
+}
+
+/// Parses the activities of a file, several only for FIT files with multiple sessions; FIT
+/// files are read leniently if requested, keeping what precedes any damage; points with
+/// impossible coordinates are dropped with a warning
 fn parse<T: std::io::Read>(
     reader: &mut BufReader<T>,
     path: &Path,
-) -> Result<Activity, Box<dyn Error>> {
+    lenient: bool,
+    fit_field: Option<&FitField>,
+) -> Result<Vec<Activity>, Box<dyn Error>> {
+    let (mut activities, removed) = parse_format(reader, path, lenient, fit_field)?;
+    let dropped: usize = removed
+        + activities
+            .iter_mut()
+            .map(|activity| sanitize(&mut activity.track_points))
+            .sum::<usize>();
+    if dropped > 0 {
+        diagnostics::warn(
+            Category::InvalidCoordinates,
+            format!("{}: {} points", path.display(), dropped),
+        );
+    }
+    activities.retain(|activity| !activity.track_points.is_empty());
+    if activities.is_empty() {
+        return Err(Box::from("No valid track points"));
+    }
+    Ok(activities)
+}
+
+/// Parses the activities of a file by its format, along with the number of points the parser
+/// already removed for impossible coordinates
+fn parse_format<T: std::io::Read>(
+    reader: &mut BufReader<T>,
+    path: &Path,
+    lenient: bool,
+    fit_field: Option<&FitField>,
+) -> Result<(Vec<Activity>, usize), Box<dyn Error>> {
     if path.extension() == Some(OsStr::new("gpx")) {
-        parse_gpx(reader)
+        parse_gpx(reader).map(|(a, removed)| (vec![a], removed))
     } else if path.extension() == Some(OsStr::new("fit")) {
-        parse_fit(reader)
+        let (messages, damage) = read_fit_messages(reader, lenient)?;
+        if let Some(e) = damage {
+            diagnostics::warn(Category::DamagedFile, format!("{}: {}", path.display(), e));
+        }
+        parse_fit(messages, fit_field).map(|a| (a, 0))
+    } else if path.extension() == Some(OsStr::new("kml")) {
+        parse_kml(reader).map(|a| (vec![a], 0))
+    } else if path.extension() == Some(OsStr::new("kmz")) {
+        parse_kmz(reader).map(|a| (vec![a], 0))
     } else {
         Err(Box::from("Unknown file type"))
     }
 }
 
 pub struct RawActivity {
-    name: String,
-    date: chrono::DateTime<chrono::Utc>,
+    name: Option<String>,
+    kind: Option<String>,
+    date: Option<chrono::DateTime<chrono::Utc>>,
     path: PathBuf,
+    /// Keep the records of a damaged FIT file read before the damage
+    lenient_fit: bool,
+    /// Additional field to read from the records of a FIT file
+    fit_field: Option<FitField>,
 }
 
 #[derive(Debug)]
 pub struct Activity {
     name: String,
+    kind: Option<String>,
     date: chrono::DateTime<chrono::Utc>,
-    track_points: Vec<Point<f64>>,
+    path: PathBuf,
+    track_points: Vec<TrackPoint<Point<f64>>>,
+}
+
+/// A stretch of a track within the view
+#[derive(Clone, Copy, Debug)]
+pub struct VisibleRun {
+    /// Index of the first track point of the stretch
+    pub start: usize,
+    /// Where the track entered the view, in fractional screen coordinates, unless it started
+    /// within it
+    pub entry: Option<Point<f64>>,
 }
 
 #[derive(Debug)]
 pub struct ScreenActivity {
     pub name: String,
     pub date: chrono::DateTime<chrono::Utc>,
-    pub track_points: Vec<Coord<u32>>,
+    pub path: PathBuf,
+    /// Length of the full track, in meters
+    pub distance: f64,
+    /// Visible track points, keeping the recording of the first of each run of equal points
+    pub track_points: Vec<TrackPoint<Coord<u32>>>,
+    /// Stretches of the visible track points, split wherever the track leaves the view so that
+    /// its ends are not connected across
+    pub runs: Vec<VisibleRun>,
+}
+
+/// An activity with its track in world coordinates, see `slippy::to_world`, independent of any
+/// view port and projected onto heatmaps only when rendering
+#[derive(Debug)]
+pub struct WorldActivity {
+    pub name: String,
+    pub date: chrono::DateTime<chrono::Utc>,
+    pub path: PathBuf,
+    /// Length of the full track, in meters
+    pub distance: f64,
+    pub track_points: Vec<TrackPoint<Point<f64>>>,
+}
+
+/// Orders in which activities are accumulated
+#[derive(Clone, Copy, Debug, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Order {
+    Date,
+    Distance,
+    Name,
+    File,
+}
+
+impl Order {
+    /// Sorts activities by this order, optionally reversed
+    pub fn sort(self, activities: &mut [ScreenActivity], reverse: bool) {
+        match self {
+            Order::Date => activities.sort_by_key(|a| a.date),
+            Order::Distance => activities.sort_by(|a, b| a.distance.total_cmp(&b.distance)),
+            Order::Name => activities.sort_by(|a, b| a.name.cmp(&b.name)),
+            Order::File => activities.sort_by(|a, b| a.path.cmp(&b.path)),
+        }
+        if reverse {
+            activities.reverse();
+        }
+    }
 }
 
 impl RawActivity {
-    pub fn new(name: String, date: chrono::DateTime<chrono::Utc>, path: PathBuf) -> Self {
-        RawActivity { name, date, path }
+    pub fn new(
+        name: String,
+        kind: String,
+        date: chrono::DateTime<chrono::Utc>,
+        path: PathBuf,
+    ) -> Self {
+        RawActivity {
+            name: Some(name),
+            kind: Some(kind),
+            date: Some(date),
+            path,
+            lenient_fit: false,
+            fit_field: None,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// An activity known only by its file; name and date are taken from the file contents
+    pub fn from_path(path: PathBuf) -> Self {
+        RawActivity {
+            name: None,
+            kind: None,
+            date: None,
+            path,
+            lenient_fit: false,
+            fit_field: None,
+        }
     }
 
-    pub fn parse(self) -> Result<Activity, Box<dyn Error>> {
+    /// Salvage the records of truncated or corrupt FIT files read before the damage instead of
+    /// failing on them
+    pub fn with_lenient_fit(mut self, lenient: bool) -> Self {
+        self.lenient_fit = lenient;
+        self
+    }
+
+    /// Read an additional field of FIT records into the sensors of each point
+    pub fn with_fit_field(mut self, field: Option<FitField>) -> Self {
+        self.fit_field = field;
+        self
+    }
+
+    pub fn parse(self) -> Result<Vec<Activity>, Box<dyn Error>> {
         let file = File::open(&self.path)?;
-        let mut activity = if self.path.extension() == Some(OsStr::new("gz")) {
-            let decoder = GzDecoder::new(file);
+        self.parse_from(file)
+    }
+
+    /// Parses the contents of the activity file, e.g., streamed out of an archive; sessions of
+    /// a multi-session file keep their dates and add their sport to the name
+    pub fn parse_from<R: Read>(self, source: R) -> Result<Vec<Activity>, Box<dyn Error>> {
+        let gzipped = self
+            .path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("gz"));
+        let mut activities = if gzipped {
+            let decoder = GzDecoder::new(source);
             let mut reader = BufReader::new(decoder);
-            parse(&mut reader, &self.path.with_extension(""))
+            parse(
+                &mut reader,
+                &self.path.with_extension(""),
+                self.lenient_fit,
+                self.fit_field.as_ref(),
+            )
         } else {
-            let mut reader = BufReader::new(file);
-            parse(&mut reader, &self.path)
+            let mut reader = BufReader::new(source);
+            parse(
+                &mut reader,
+                &self.path,
+                self.lenient_fit,
+                self.fit_field.as_ref(),
+            )
         }?;
-        activity.name = self.name;
-        activity.date = self.date;
-        Ok(activity)
+        let sessions = activities.len() > 1;
+        for activity in activities.iter_mut() {
+            match (&self.name, sessions) {
+                (Some(name), true) => activity.name = format!("{} ({})", name, activity.name),
+                (Some(name), false) => activity.name = name.clone(),
+                (None, _) => {}
+            }
+            if let (Some(date), false) = (self.date, sessions) {
+                activity.date = date;
+            }
+            if !sessions || activity.kind.is_none() {
+                activity.kind = self.kind.clone();
+            }
+            activity.path = self.path.clone();
+        }
+        Ok(activities)
     }
 }
 
 impl Activity {
+    /// An activity of track points read from elsewhere, e.g., a location history, dated by
+    /// its first timestamp
+    pub fn new(
+        name: String,
+        kind: Option<String>,
+        path: PathBuf,
+        track_points: Vec<TrackPoint<Point<f64>>>,
+    ) -> Self {
+        let date = track_points
+            .iter()
+            .find_map(|tp| tp.time)
+            .unwrap_or_else(unknown_date);
+        Activity {
+            name,
+            kind,
+            date,
+            path,
+            track_points,
+        }
+    }
+
+    /// Activity type, e.g., `Ride`, if known
+    pub fn kind(&self) -> Option<&str> {
+        self.kind.as_deref()
+    }
+
+    pub fn date(&self) -> chrono::DateTime<chrono::Utc> {
+        self.date
+    }
+
+    /// Track points in lon/lat
+    pub fn track_points<'a>(&'a self) -> impl Iterator<Item = &'a Point<f64>> + 'a {
+        self.track_points.iter().map(|tp| &tp.point)
+    }
+
+    /// Track points in lon/lat with what was recorded there
+    pub fn recorded_track_points(&self) -> &[TrackPoint<Point<f64>>] {
+        &self.track_points
+    }
+
+    /// Drop track points with the Douglas-Peucker algorithm, so that the track deviates at most
+    /// by the given distance in meters from the recorded one
+    pub fn simplified(mut self, epsilon: f64) -> Self {
+        let latitude = match self.track_points.first() {
+            Some(tp) => tp.point.y().to_radians(),
+            None => return self,
+        };
+        // Locally, degrees are close enough to a constant length
+        let meters_per_degree = slippy::EARTH_CIRCUMFERENCE / 360.0;
+        let line: LineString<f64> = self
+            .track_points
+            .iter()
+            .map(|tp| {
+                (
+                    tp.point.x() * meters_per_degree * latitude.cos(),
+                    tp.point.y() * meters_per_degree,
+                )
+            })
+            .collect();
+        let kept = line.simplify_idx(&epsilon);
+        self.track_points = kept.into_iter().map(|i| self.track_points[i]).collect();
+        self
+    }
+
+    /// Length of the track, in meters
+    pub fn distance(&self) -> f64 {
+        self.track_points
+            .windows(2)
+            .map(|w| w[0].point.haversine_distance(&w[1].point))
+            .sum()
+    }
+
     pub fn project_to_screen(
         self,
         heatmap: &dyn Heatmap,
     ) -> Result<ScreenActivity, Box<dyn Error>> {
-        let mut track_points: Vec<Coord<u32>> = self
-            .track_points
-            .iter()
-            .filter_map(|pt| heatmap.project_to_screen(pt))
-            .collect();
-        track_points.dedup();
+        self.to_world().project_to_screen(heatmap)
+    }
+}
+
+impl Activity {
+    /// Converts the track to world coordinates, to be projected onto any heatmap later
+    pub fn to_world(self) -> WorldActivity {
+        WorldActivity {
+            distance: self.distance(),
+            track_points: self
+                .track_points
+                .iter()
+                .map(|tp| tp.with_point(slippy::to_world(tp.point)))
+                .collect(),
+            name: self.name,
+            date: self.date,
+            path: self.path,
+        }
+    }
+}
+
+impl WorldActivity {
+    pub fn project_to_screen(
+        &self,
+        heatmap: &dyn Heatmap,
+    ) -> Result<ScreenActivity, Box<dyn Error>> {
+        let (min, max) = heatmap.screen_bounds();
+        let mut track_points: Vec<TrackPoint<Coord<u32>>> = Vec::new();
+        let mut runs = Vec::new();
+        // The previous point and whether it was visible
+        let mut previous: Option<(Point<f64>, bool)> = None;
+        for tp in self.track_points.iter() {
+            let visible = heatmap.project_world_to_screen(&tp.point);
+            if let Some(point) = visible {
+                let entering = !matches!(previous, Some((_, true)));
+                // Points on the pixel of the one before count once, unless the track left the
+                // view in between, e.g., into the next strip of a poster, where it counts anyway
+                if entering || track_points.last().map(|last| last.point) != Some(point) {
+                    if entering {
+                        let entry = previous.and_then(|(outside, _)| {
+                            let from = heatmap.project_world(&outside);
+                            let to = heatmap.project_world(&tp.point);
+                            slippy::clip_segment(from, to, min, max).map(|(entry, _)| entry)
+                        });
+                        runs.push(VisibleRun {
+                            start: track_points.len(),
+                            entry,
+                        });
+                    }
+                    track_points.push(tp.with_point(point));
+                }
+            }
+            previous = Some((tp.point, visible.is_some()));
+        }
         if track_points.is_empty() {
             Err(Box::from("No visible track points"))
         } else {
             Ok(ScreenActivity {
-                name: self.name,
+                distance: self.distance,
+                name: self.name.clone(),
                 date: self.date,
+                path: self.path.clone(),
                 track_points,
+                runs,
             })
         }
     }
 }
+
+impl ScreenActivity {
+    /// Distance traveled to reach each visible point from the previous one, or from the edge of
+    /// the view when entering it, in meters, given the ground resolution of the screen; steps
+    /// longer than the maximum, e.g., over gaps in the recording, count as the maximum
+    pub fn step_lengths(&self, meters_per_pixel: f64, max_step: f64) -> Vec<f64> {
+        let mut runs = self.runs.iter().peekable();
+        let mut previous: Option<Point<f64>> = None;
+        self.track_points
+            .iter()
+            .enumerate()
+            .map(|(index, tp)| {
+                let point = Point::new(tp.point.x as f64, tp.point.y as f64);
+                if let Some(run) = runs.next_if(|run| run.start == index) {
+                    previous = run.entry;
+                }
+                let step = previous.map_or(0.0, |p| {
+                    (point.x() - p.x()).hypot(point.y() - p.y()) * meters_per_pixel
+                });
+                previous = Some(point);
+                step.min(max_step)
+            })
+            .collect()
+    }
+
+    /// Track points of each stretch within the view, e.g., to be drawn as separate lines
+    pub fn visible_runs<'a>(&'a self) -> impl Iterator<Item = &'a [TrackPoint<Coord<u32>>]> + 'a {
+        let ends = self
+            .runs
+            .iter()
+            .skip(1)
+            .map(|run| run.start)
+            .chain(std::iter::once(self.track_points.len()));
+        self.runs
+            .iter()
+            .zip(ends)
+            .map(move |(run, end)| &self.track_points[run.start..end])
+    }
+}
+
+/// Projects activities onto the heatmap, dropping those not visible, sorted by the given order
+pub fn project_to_screen(
+    activities: &[WorldActivity],
+    heatmap: &dyn Heatmap,
+    order: Order,
+    reverse: bool,
+) -> Vec<ScreenActivity> {
+    let mut projected: Vec<ScreenActivity> = activities
+        .par_iter()
+        .filter_map(|a| a.project_to_screen(heatmap).ok())
+        .collect();
+    order.sort(&mut projected, reverse);
+    projected
+}
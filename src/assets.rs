@@ -0,0 +1,88 @@
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+
+use super::osmbase;
+
+/// An optional file too heavy to bundle, downloaded on demand into the assets directory
+#[derive(Debug)]
+pub struct Asset {
+    /// Name to fetch the asset by and to refer to it in options, e.g., `--borders=countries`
+    pub name: &'static str,
+    pub file: &'static str,
+    pub url: &'static str,
+    pub description: &'static str,
+}
+
+/// All assets that can be fetched
+pub const ASSETS: [Asset; 4] = [
+    Asset {
+        name: "roboto-light",
+        file: "Roboto-Light.ttf",
+        url: "https://github.com/googlefonts/roboto/raw/main/src/hinted/Roboto-Light.ttf",
+        description: "Font of attributions and labels where Roboto Light is not installed",
+    },
+    Asset {
+        name: "countries",
+        file: "ne_50m_admin_0_countries.geojson",
+        url: "https://raw.githubusercontent.com/nvkelso/natural-earth-vector/master/geojson/\
+              ne_50m_admin_0_countries.geojson",
+        description: "Country borders from Natural Earth, at 1:50m",
+    },
+    Asset {
+        name: "states",
+        file: "ne_10m_admin_1_states_provinces.geojson",
+        url: "https://raw.githubusercontent.com/nvkelso/natural-earth-vector/master/geojson/\
+              ne_10m_admin_1_states_provinces.geojson",
+        description: "State and province borders from Natural Earth, at 1:10m",
+    },
+    Asset {
+        name: "coastlines",
+        file: "ne_10m_coastline.geojson",
+        url: "https://raw.githubusercontent.com/nvkelso/natural-earth-vector/master/geojson/\
+              ne_10m_coastline.geojson",
+        description: "Coastlines from Natural Earth, at 1:10m",
+    },
+];
+
+/// Directory where fetched assets are kept
+pub fn directory() -> PathBuf {
+    directories::BaseDirs::new()
+        .unwrap()
+        .data_dir()
+        .join("derive.rs")
+        .join("assets")
+}
+
+/// The asset of the given name
+pub fn find(name: &str) -> Option<&'static Asset> {
+    ASSETS.iter().find(|asset| asset.name == name)
+}
+
+/// The file of the asset of the given name, if it was fetched
+pub fn fetched(name: &str) -> Option<PathBuf> {
+    find(name).map(Asset::path).filter(|path| path.is_file())
+}
+
+impl Asset {
+    /// Where the asset is kept once fetched
+    pub fn path(&self) -> PathBuf {
+        directory().join(self.file)
+    }
+
+    /// Size of the fetched file in bytes, None if it was not fetched
+    pub fn size(&self) -> Option<u64> {
+        fs::metadata(self.path()).ok().map(|m| m.len())
+    }
+
+    /// Downloads the asset, replacing an earlier copy only once the download completed, and
+    /// returns its size in bytes
+    pub fn fetch(&self) -> Result<u64, Box<dyn Error>> {
+        let data = osmbase::download(self.url)?;
+        fs::create_dir_all(directory())?;
+        let partial = self.path().with_extension("part");
+        fs::write(&partial, &data)?;
+        fs::rename(&partial, self.path())?;
+        Ok(data.len() as u64)
+    }
+}
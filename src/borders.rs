@@ -0,0 +1,106 @@
+use geo::Point;
+use image::{DynamicImage, Rgba};
+use imageproc::drawing::draw_antialiased_line_segment_mut;
+use imageproc::pixelops::interpolate;
+use serde_json::Value;
+
+use std::error::Error;
+use std::path::Path;
+
+use super::slippy;
+
+/// Lines of coastlines, country borders or other outlines, in lon/lat, e.g., from the Natural
+/// Earth GeoJSON files
+#[derive(Debug, Default)]
+pub struct Borders {
+    lines: Vec<Vec<Point<f64>>>,
+}
+
+/// Points of a GeoJSON position array
+fn positions(value: &Value) -> Vec<Point<f64>> {
+    value
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|p| Some(Point::new(p.get(0)?.as_f64()?, p.get(1)?.as_f64()?)))
+        .collect()
+}
+
+/// Collect the lines of a GeoJSON object, taking polygons by their rings and ignoring points
+fn collect_lines(value: &Value, lines: &mut Vec<Vec<Point<f64>>>) {
+    let coordinates = &value["coordinates"];
+    let rings = || coordinates.as_array().into_iter().flatten();
+    match value["type"].as_str() {
+        Some("FeatureCollection") => {
+            for feature in value["features"].as_array().into_iter().flatten() {
+                collect_lines(feature, lines);
+            }
+        }
+        Some("Feature") => collect_lines(&value["geometry"], lines),
+        Some("GeometryCollection") => {
+            for geometry in value["geometries"].as_array().into_iter().flatten() {
+                collect_lines(geometry, lines);
+            }
+        }
+        Some("LineString") => lines.push(positions(coordinates)),
+        Some("MultiLineString") | Some("Polygon") => lines.extend(rings().map(positions)),
+        Some("MultiPolygon") => {
+            for polygon in rings() {
+                lines.extend(polygon.as_array().into_iter().flatten().map(positions));
+            }
+        }
+        _ => {}
+    }
+}
+
+impl Borders {
+    /// Read all lines and polygon outlines of a GeoJSON file
+    pub fn from_file(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let value: Value = serde_json::from_str(&std::fs::read_to_string(path)?)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        let mut borders = Self::default();
+        collect_lines(&value, &mut borders.lines);
+        borders.lines.retain(|line| line.len() > 1);
+        if borders.lines.is_empty() {
+            return Err(format!("{}: no lines or polygons", path.display()).into());
+        }
+        Ok(borders)
+    }
+
+    /// Add the lines of another file
+    pub fn extend(&mut self, other: Borders) {
+        self.lines.extend(other.lines);
+    }
+
+    /// Draw the lines antialiased onto an image of the map in the given color, blended by its
+    /// alpha
+    pub fn draw(&self, image: &mut DynamicImage, map: &slippy::Map, color: Rgba<u8>) {
+        let (width, height) = map.pixel_size();
+        let opacity = color[3] as f32 / 255.0;
+        let color = Rgba([color[0], color[1], color[2], 255]);
+        let blend = |line: Rgba<u8>, original: Rgba<u8>, weight: f32| {
+            interpolate(line, original, weight * opacity)
+        };
+        for line in &self.lines {
+            let pixels: Vec<(i32, i32)> = line
+                .iter()
+                .map(|p| {
+                    let pixel = map.project(p);
+                    (pixel.x().round() as i32, pixel.y().round() as i32)
+                })
+                .collect();
+            for segment in pixels.windows(2) {
+                let ((x0, y0), (x1, y1)) = (segment[0], segment[1]);
+                // Skip segments entirely beside the image
+                if x0.max(x1) < 0
+                    || y0.max(y1) < 0
+                    || x0.min(x1) >= width as i32
+                    || y0.min(y1) >= height as i32
+                {
+                    continue;
+                }
+                draw_antialiased_line_segment_mut(image, segment[0], segment[1], color, blend);
+            }
+        }
+    }
+}
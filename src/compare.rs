@@ -0,0 +1,144 @@
+use image::{Rgba, RgbaImage};
+use serde::Serialize;
+
+use std::error::Error;
+use std::fmt;
+
+/// Side length of the windows SSIM is computed over
+const SSIM_WINDOW: u32 = 8;
+
+/// How much two images of the same size differ
+#[derive(Debug, Serialize)]
+pub struct Comparison {
+    pub width: u32,
+    pub height: u32,
+    /// Number of pixels differing in any channel
+    pub differing_pixels: usize,
+    /// Largest difference of any channel
+    pub max_difference: u8,
+    /// Mean absolute difference over all channels
+    pub mean_difference: f64,
+    /// Mean structural similarity of the brightness, 1.0 for identical images
+    pub ssim: f64,
+}
+
+/// Brightness of a pixel, darkened by its transparency
+fn luma(pixel: &Rgba<u8>) -> f64 {
+    let [r, g, b, a] = pixel.0;
+    (0.299 * r as f64 + 0.587 * g as f64 + 0.114 * b as f64) * a as f64 / 255.0
+}
+
+/// Structural similarity of one window of both images
+fn window_ssim(a: &[f64], b: &[f64]) -> f64 {
+    const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+    const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+    let n = a.len() as f64;
+    let mean_a = a.iter().sum::<f64>() / n;
+    let mean_b = b.iter().sum::<f64>() / n;
+    let (mut var_a, mut var_b, mut covariance) = (0.0, 0.0, 0.0);
+    for (x, y) in a.iter().zip(b) {
+        var_a += (x - mean_a) * (x - mean_a);
+        var_b += (y - mean_b) * (y - mean_b);
+        covariance += (x - mean_a) * (y - mean_b);
+    }
+    let (var_a, var_b, covariance) = (var_a / n, var_b / n, covariance / n);
+    ((2.0 * mean_a * mean_b + C1) * (2.0 * covariance + C2))
+        / ((mean_a * mean_a + mean_b * mean_b + C1) * (var_a + var_b + C2))
+}
+
+/// Mean SSIM over non-overlapping windows, smaller ones at the right and bottom edges
+fn ssim(a: &RgbaImage, b: &RgbaImage) -> f64 {
+    let (width, height) = a.dimensions();
+    let mut total = 0.0;
+    let mut windows = 0;
+    for y0 in (0..height).step_by(SSIM_WINDOW as usize) {
+        for x0 in (0..width).step_by(SSIM_WINDOW as usize) {
+            let (mut wa, mut wb) = (Vec::new(), Vec::new());
+            for y in y0..(y0 + SSIM_WINDOW).min(height) {
+                for x in x0..(x0 + SSIM_WINDOW).min(width) {
+                    wa.push(luma(a.get_pixel(x, y)));
+                    wb.push(luma(b.get_pixel(x, y)));
+                }
+            }
+            total += window_ssim(&wa, &wb);
+            windows += 1;
+        }
+    }
+    if windows == 0 {
+        1.0
+    } else {
+        total / windows as f64
+    }
+}
+
+impl Comparison {
+    /// Compare two images, which need to have the same size
+    pub fn from(a: &RgbaImage, b: &RgbaImage) -> Result<Self, Box<dyn Error>> {
+        if a.dimensions() != b.dimensions() {
+            return Err(format!(
+                "images differ in size: {}x{} and {}x{}",
+                a.width(),
+                a.height(),
+                b.width(),
+                b.height()
+            )
+            .into());
+        }
+        let (mut differing_pixels, mut max_difference, mut sum) = (0, 0, 0u64);
+        for (pa, pb) in a.pixels().zip(b.pixels()) {
+            let differences = pa.0.iter().zip(pb.0.iter()).map(|(x, y)| x.abs_diff(*y));
+            let largest = differences.clone().max().unwrap_or(0);
+            if largest > 0 {
+                differing_pixels += 1;
+            }
+            max_difference = max_difference.max(largest);
+            sum += differences.map(u64::from).sum::<u64>();
+        }
+        let channels = (a.width() as u64 * a.height() as u64 * 4).max(1);
+        Ok(Comparison {
+            width: a.width(),
+            height: a.height(),
+            differing_pixels,
+            max_difference,
+            mean_difference: sum as f64 / channels as f64,
+            ssim: ssim(a, b),
+        })
+    }
+}
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let total = (self.width as usize * self.height as usize).max(1);
+        writeln!(f, "Size:             {}x{}", self.width, self.height)?;
+        writeln!(
+            f,
+            "Differing pixels: {} ({:.2}%)",
+            self.differing_pixels,
+            100.0 * self.differing_pixels as f64 / total as f64
+        )?;
+        writeln!(f, "Max difference:   {}", self.max_difference)?;
+        writeln!(f, "Mean difference:  {:.3}", self.mean_difference)?;
+        writeln!(f, "SSIM:             {:.5}", self.ssim)?;
+        Ok(())
+    }
+}
+
+/// Visualize the differences of two images of the same size: the first one in faded gray, with
+/// differing pixels in red, brighter the larger the difference
+pub fn diff_image(a: &RgbaImage, b: &RgbaImage) -> RgbaImage {
+    RgbaImage::from_fn(a.width(), a.height(), |x, y| {
+        let (pa, pb) = (a.get_pixel(x, y), b.get_pixel(x, y));
+        let difference =
+            pa.0.iter()
+                .zip(pb.0.iter())
+                .map(|(x, y)| x.abs_diff(*y))
+                .max()
+                .unwrap_or(0);
+        if difference > 0 {
+            Rgba([128 + difference / 2, 0, 0, 255])
+        } else {
+            let gray = (luma(pa) / 4.0) as u8;
+            Rgba([gray, gray, gray, 255])
+        }
+    })
+}
@@ -0,0 +1,205 @@
+use chrono::{DateTime, Datelike, Duration, Timelike, Utc};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use geo_types::Point;
+use gpx::{Gpx, GpxVersion, Track, TrackSegment, Waypoint};
+
+use std::error::Error;
+use std::f64::consts::PI;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+/// Meters per degree of latitude
+const METERS_PER_DEGREE: f64 = 111_320.0;
+
+/// Seconds between recorded points
+const RECORDING_INTERVAL: f64 = 5.0;
+
+/// Activity types with the radius of their loops and their speed, in meters and meters per
+/// second
+const KINDS: [(&str, f64, f64); 3] = [
+    ("Ride", 9000.0, 7.0),
+    ("Run", 3000.0, 3.0),
+    ("Walk", 1500.0, 1.4),
+];
+
+/// A xorshift generator, so that the same seed always yields the same routes
+struct Random(u64);
+
+impl Random {
+    fn new(seed: u64) -> Self {
+        // Xorshift never leaves zero
+        Random(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+    }
+
+    /// A number in [0, 1)
+    fn next(&mut self) -> f64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// A number in [min, max)
+    fn between(&mut self, min: f64, max: f64) -> f64 {
+        min + (max - min) * self.next()
+    }
+}
+
+/// A loop starting and ending near home, as offsets in meters east and north
+struct Route {
+    kind: usize,
+    points: Vec<(f64, f64)>,
+}
+
+impl Route {
+    /// A wobbly loop of the given kind in a random direction from home
+    fn random(kind: usize, random: &mut Random) -> Self {
+        let (_, radius, speed) = KINDS[kind];
+        let radius = radius * random.between(0.5, 1.5);
+        let direction = random.between(0.0, 2.0 * PI);
+        let (a2, p2) = (random.between(0.0, 0.2), random.between(0.0, 2.0 * PI));
+        let (a3, p3) = (random.between(0.0, 0.1), random.between(0.0, 2.0 * PI));
+        let center = (radius * direction.cos(), radius * direction.sin());
+        // One point per recording interval along the perimeter
+        let steps = (2.0 * PI * radius / (speed * RECORDING_INTERVAL)).ceil() as usize;
+        let points = (0..=steps)
+            .map(|step| {
+                let angle = 2.0 * PI * step as f64 / steps as f64;
+                let wobble = 1.0
+                    + a2 * ((2.0 * angle + p2).sin() - p2.sin())
+                    + a3 * ((3.0 * angle + p3).sin() - p3.sin());
+                let heading = direction + PI + angle;
+                (
+                    center.0 + radius * wobble * heading.cos(),
+                    center.1 + radius * wobble * heading.sin(),
+                )
+            })
+            .collect();
+        Route { kind, points }
+    }
+}
+
+/// Converts an offset in meters from the center to lon/lat
+fn offset(center: Point<f64>, (east, north): (f64, f64)) -> Point<f64> {
+    let lat = center.y() + north / METERS_PER_DEGREE;
+    let lon = center.x() + east / (METERS_PER_DEGREE * center.y().to_radians().cos());
+    Point::new(lon, lat)
+}
+
+/// Records a route as a GPX track starting at the given time, with GPS noise of a few meters
+fn record(
+    route: &Route,
+    name: &str,
+    center: Point<f64>,
+    start: DateTime<Utc>,
+    random: &mut Random,
+) -> Gpx {
+    let (kind, _, speed) = KINDS[route.kind];
+    let mut segment = TrackSegment::new();
+    let mut time = start;
+    let mut previous = route.points[0];
+    for (i, &(east, north)) in route.points.iter().enumerate() {
+        let step = (east - previous.0).hypot(north - previous.1);
+        time += Duration::milliseconds((step / speed * 1000.0) as i64);
+        previous = (east, north);
+        let noisy = (
+            east + random.between(-4.0, 4.0),
+            north + random.between(-4.0, 4.0),
+        );
+        let mut point = Waypoint::new(offset(center, noisy));
+        point.elevation = Some(400.0 + 0.02 * north + 15.0 * (i as f64 / 50.0).sin());
+        point.time = time::OffsetDateTime::from_unix_timestamp(time.timestamp())
+            .ok()
+            .map(gpx::Time::from);
+        segment.points.push(point);
+    }
+    let mut track = Track::new();
+    track.name = Some(name.to_string());
+    track.type_ = Some(kind.to_string());
+    track.segments.push(segment);
+    Gpx {
+        version: GpxVersion::Gpx11,
+        creator: Some("derive.rs demo".to_string()),
+        tracks: vec![track],
+        ..Default::default()
+    }
+}
+
+/// Name like Strava gives activities, by time of day
+fn activity_name(kind: &str, start: DateTime<Utc>) -> String {
+    let time_of_day = match start.hour() {
+        5..=11 => "Morning",
+        12..=16 => "Afternoon",
+        17..=20 => "Evening",
+        _ => "Night",
+    };
+    format!("{} {}", time_of_day, kind)
+}
+
+/// Writes a fake export of activities looping around the center into the directory: an
+/// `activities.csv` like Strava's, and a GPX file per activity, some compressed, over the year
+/// before the given end; routes repeat, as real ones do
+pub fn generate(
+    dir: &Path,
+    center: Point<f64>,
+    count: usize,
+    seed: u64,
+    end: DateTime<Utc>,
+) -> Result<(), Box<dyn Error>> {
+    if dir.join("activities.csv").exists() {
+        return Err(format!("{} already contains an export", dir.display()).into());
+    }
+    std::fs::create_dir_all(dir.join("activities"))?;
+    let mut random = Random::new(seed);
+    let routes: Vec<Route> = (0..(count / 3).max(1))
+        .map(|i| Route::random(i % KINDS.len(), &mut random))
+        .collect();
+
+    let mut csv = BufWriter::new(File::create(dir.join("activities.csv"))?);
+    writeln!(
+        csv,
+        "Activity ID,Activity Date,Activity Name,Activity Type,Commute,Filename"
+    )?;
+    let first_day = end - Duration::days(365);
+    for id in 0..count {
+        let route = &routes[(random.next() * routes.len() as f64) as usize];
+        let day = first_day + Duration::days((365 * id / count.max(1)) as i64);
+        let hour = random.between(6.0, 20.0);
+        let start = day.date_naive().and_hms_opt(0, 0, 0).unwrap().and_utc()
+            + Duration::seconds((hour * 3600.0) as i64);
+        let kind = KINDS[route.kind].0;
+        let name = activity_name(kind, start);
+        let weekday = start.weekday().num_days_from_monday() < 5;
+        let commute = kind == "Ride" && weekday && hour < 9.0;
+
+        let gpx = record(route, &name, center, start, &mut random);
+        let filename = if id % 3 == 0 {
+            let filename = format!("activities/{}.gpx.gz", 1000 + id);
+            let file = File::create(dir.join(&filename))?;
+            let mut encoder = GzEncoder::new(BufWriter::new(file), Compression::default());
+            gpx::write(&gpx, &mut encoder)?;
+            encoder.finish()?.flush()?;
+            filename
+        } else {
+            let filename = format!("activities/{}.gpx", 1000 + id);
+            let mut file = BufWriter::new(File::create(dir.join(&filename))?);
+            gpx::write(&gpx, &mut file)?;
+            file.flush()?;
+            filename
+        };
+        writeln!(
+            csv,
+            "{},\"{}\",{},{},{},{}",
+            1000 + id,
+            start.format("%b %-d, %Y, %-I:%M:%S %p"),
+            name,
+            kind,
+            commute,
+            filename
+        )?;
+    }
+    csv.flush()?;
+    Ok(())
+}
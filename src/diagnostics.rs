@@ -0,0 +1,89 @@
+use serde::Serialize;
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use super::i18n;
+
+/// Number of messages listed per category in the summary
+const LISTED_MESSAGES: usize = 5;
+
+/// Kinds of problems encountered while reading an export
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Category {
+    /// A record of `activities.csv` could not be read
+    UnreadableRecord,
+    /// An activity timestamp could not be parsed
+    InvalidDate,
+    /// An activity file referenced by `activities.csv` does not exist
+    MissingFile,
+    /// An activity file could not be parsed
+    InvalidFile,
+    /// An activity file is damaged, only the part before the damage was read
+    DamagedFile,
+    /// An activity file contains points with impossible coordinates, which were dropped
+    InvalidCoordinates,
+}
+
+impl Category {
+    fn describe(self) -> &'static str {
+        match self {
+            Category::UnreadableRecord => "activity records could not be read",
+            Category::InvalidDate => "timestamps could not be parsed",
+            Category::MissingFile => "activity files are missing from the export",
+            Category::InvalidFile => "activity files could not be parsed",
+            Category::DamagedFile => "activity files are damaged, keeping what was read before",
+            Category::InvalidCoordinates => {
+                "activity files contain impossible coordinates, which were dropped"
+            }
+        }
+    }
+}
+
+lazy_static! {
+    static ref WARNINGS: Mutex<BTreeMap<Category, Vec<String>>> = Mutex::new(BTreeMap::new());
+}
+
+/// Records a warning; safe to call from parallel workers
+pub fn warn<S: Into<String>>(category: Category, message: S) {
+    WARNINGS
+        .lock()
+        .unwrap()
+        .entry(category)
+        .or_default()
+        .push(message.into());
+}
+
+/// Forgets the warnings recorded so far, e.g., before reading another export
+pub fn clear() {
+    WARNINGS.lock().unwrap().clear();
+}
+
+/// Number of warnings recorded per category
+pub fn counts() -> BTreeMap<Category, usize> {
+    WARNINGS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(category, messages)| (*category, messages.len()))
+        .collect()
+}
+
+/// Logs all recorded warnings, grouped by category
+pub fn print_summary() {
+    let warnings = WARNINGS.lock().unwrap();
+    for (category, messages) in warnings.iter() {
+        warn!("{} {}", messages.len(), i18n::tr(category.describe()));
+        for message in messages.iter().take(LISTED_MESSAGES) {
+            warn!("    {}", message);
+        }
+        if messages.len() > LISTED_MESSAGES {
+            let more = messages.len() - LISTED_MESSAGES;
+            warn!(
+                "{}",
+                i18n::fill(i18n::tr("    ... and {} more"), &[more.to_string()])
+            );
+        }
+    }
+}
@@ -0,0 +1,166 @@
+use geo_types::Point;
+use serde_json::{json, Value};
+
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use super::slippy;
+
+/// A tile index at a fixed zoom level
+pub type Tile = (u32, u32);
+
+/// Visits of a single tile
+#[derive(Clone, Copy, Debug)]
+pub struct Visit {
+    /// Number of points within the tile
+    pub count: u32,
+    /// Time of the first and last point within the tile, if known
+    pub first: Option<chrono::DateTime<chrono::Utc>>,
+    pub last: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// All visited tiles of a zoom level
+#[derive(Debug)]
+pub struct VisitedTiles {
+    pub zoom: u8,
+    pub tiles: HashMap<Tile, Visit>,
+}
+
+impl VisitedTiles {
+    /// Explorer statistics of the visited tiles
+    pub fn explorer(&self) -> Explorer {
+        Explorer::from(&self.tiles.keys().cloned().collect())
+    }
+
+    /// Visited tiles as a GeoJSON feature collection of polygons
+    pub fn to_geojson(&self) -> Value {
+        let mut tiles: Vec<_> = self.tiles.iter().collect();
+        tiles.sort_by_key(|&(&(x, y), _)| (y, x));
+
+        let features: Vec<Value> = tiles
+            .into_iter()
+            .map(|(&(x, y), visit)| {
+                let corner = |dx: u32, dy: u32| {
+                    let p =
+                        slippy::from_tile(Point::new((x + dx) as f64, (y + dy) as f64), self.zoom);
+                    json!([p.x(), p.y()])
+                };
+                json!({
+                    "type": "Feature",
+                    "geometry": {
+                        "type": "Polygon",
+                        "coordinates": [[
+                            corner(0, 0), corner(0, 1), corner(1, 1), corner(1, 0), corner(0, 0)
+                        ]],
+                    },
+                    "properties": {
+                        "zoom": self.zoom,
+                        "x": x,
+                        "y": y,
+                        "count": visit.count,
+                        "first_visit": visit.first.map(|d| d.to_rfc3339()),
+                        "last_visit": visit.last.map(|d| d.to_rfc3339()),
+                    },
+                })
+            })
+            .collect();
+
+        json!({
+            "type": "FeatureCollection",
+            "features": features,
+        })
+    }
+}
+
+/// A square of visited tiles
+#[derive(Clone, Copy, Debug)]
+pub struct Square {
+    /// Top left tile
+    pub x: u32,
+    pub y: u32,
+    /// Edge length, in tiles
+    pub size: u32,
+}
+
+/// Explorer statistics of visited tiles, as popularized by Squadrats and VeloViewer
+#[derive(Debug, Default)]
+pub struct Explorer {
+    /// Largest square of visited tiles
+    pub max_square: Option<Square>,
+    /// Largest connected group of tiles whose four neighbors are all visited
+    pub max_cluster: HashSet<Tile>,
+}
+
+fn neighbors((x, y): Tile) -> [Tile; 4] {
+    [
+        (x.wrapping_sub(1), y),
+        (x + 1, y),
+        (x, y.wrapping_sub(1)),
+        (x, y + 1),
+    ]
+}
+
+/// Finds the largest square by growing squares towards their bottom right corner
+fn max_square(visited: &HashSet<Tile>) -> Option<Square> {
+    let mut tiles: Vec<Tile> = visited.iter().cloned().collect();
+    tiles.sort_by_key(|&(x, y)| (y, x));
+
+    // Edge length of the largest square with its bottom right corner at each tile
+    let mut sizes: HashMap<Tile, u32> = HashMap::with_capacity(tiles.len());
+    let mut best: Option<Square> = None;
+    for &(x, y) in tiles.iter() {
+        let size_at = |tile: Tile| sizes.get(&tile).cloned().unwrap_or(0);
+        let size = if x == 0 || y == 0 {
+            1
+        } else {
+            1 + size_at((x - 1, y))
+                .min(size_at((x, y - 1)))
+                .min(size_at((x - 1, y - 1)))
+        };
+        sizes.insert((x, y), size);
+        if best.is_none_or(|b| size > b.size) {
+            best = Some(Square {
+                x: x + 1 - size,
+                y: y + 1 - size,
+                size,
+            });
+        }
+    }
+    best
+}
+
+/// Finds the largest group of connected cluster tiles
+fn max_cluster(visited: &HashSet<Tile>) -> HashSet<Tile> {
+    let mut remaining: HashSet<Tile> = visited
+        .iter()
+        .filter(|&&t| neighbors(t).iter().all(|n| visited.contains(n)))
+        .cloned()
+        .collect();
+
+    let mut best = HashSet::new();
+    while let Some(&start) = remaining.iter().next() {
+        remaining.remove(&start);
+        let mut cluster = HashSet::new();
+        let mut queue = VecDeque::from(vec![start]);
+        while let Some(tile) = queue.pop_front() {
+            cluster.insert(tile);
+            for n in neighbors(tile).iter() {
+                if remaining.remove(n) {
+                    queue.push_back(*n);
+                }
+            }
+        }
+        if cluster.len() > best.len() {
+            best = cluster;
+        }
+    }
+    best
+}
+
+impl Explorer {
+    pub fn from(visited: &HashSet<Tile>) -> Self {
+        Explorer {
+            max_square: max_square(visited),
+            max_cluster: max_cluster(visited),
+        }
+    }
+}
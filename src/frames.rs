@@ -0,0 +1,406 @@
+use clap::ValueEnum;
+use image::{ColorType, DynamicImage, GenericImageView};
+use serde::{Deserialize, Serialize};
+
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver};
+use std::time::{Duration, Instant};
+
+use super::interrupt;
+use super::metadata::{self, Metadata};
+
+/// Time between saves of the manifest while frames are written
+const MANIFEST_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Frames per second announced in the header of Y4M streams; ffmpeg's `-r` before `-i`
+/// overrides it
+const Y4M_RATE: u32 = 30;
+
+/// Encodings of streamed frames
+#[derive(Clone, Copy, Debug, Default, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FrameFormat {
+    /// Compressed PNG images with the heat metadata
+    #[default]
+    Png,
+    /// Raw RGBA pixels without any header, sized as announced on stderr
+    Rgba,
+    /// A YUV4MPEG2 stream of full resolution 4:4:4 frames, transparency blended onto black
+    Y4m,
+    /// Uncompressed BMP images
+    Bmp,
+}
+
+/// A frame queued for encoding, with the file it goes to when writing to a directory
+type Encoding = (Option<PathBuf>, Receiver<Result<Vec<u8>, String>>);
+
+/// Writes frames of equal size one after another, e.g., to stdout for ffmpeg, or into
+/// numbered files of a directory. Frames are encoded by the thread pool while the caller goes
+/// on accumulating heat, and written in the order they were given
+pub struct FrameWriter<W: Write> {
+    out: W,
+    format: FrameFormat,
+    /// Directory to write the frames to as files instead
+    dir: Option<PathBuf>,
+    /// Number of frames written
+    written: u64,
+    /// Size of the first frame, all others need to match for raw formats
+    size: Option<(u32, u32)>,
+    /// Drop frames identical to the one written before
+    skip_duplicates: bool,
+    /// Frame written last, kept when skipping duplicates
+    previous: Option<DynamicImage>,
+    /// Frames being encoded, oldest first
+    encoding: VecDeque<Encoding>,
+    /// Memory the frames being encoded may take, if limited
+    memory_limit: Option<u64>,
+}
+
+impl<W: Write> FrameWriter<W> {
+    pub fn new(out: W, format: FrameFormat) -> Self {
+        FrameWriter {
+            out,
+            format,
+            dir: None,
+            written: 0,
+            size: None,
+            skip_duplicates: false,
+            previous: None,
+            encoding: VecDeque::new(),
+            memory_limit: None,
+        }
+    }
+
+    pub fn with_duplicates_skipped(mut self, skip: bool) -> Self {
+        self.skip_duplicates = skip;
+        self
+    }
+
+    /// Writes each frame to its own file in the directory, e.g., `frame_000001.png`, instead
+    /// of the stream; PNG and BMP frames only
+    pub fn with_directory(mut self, dir: Option<&Path>) -> Self {
+        self.dir = dir.map(Path::to_path_buf);
+        self
+    }
+
+    /// Holds fewer frames than threads for encoding if they would take more memory than this,
+    /// counting each frame and its encoding at four bytes per pixel
+    pub fn with_memory_limit(mut self, bytes: Option<u64>) -> Self {
+        self.memory_limit = bytes;
+        self
+    }
+
+    /// Number of frames encoded at the same time before waiting for the oldest one
+    fn queue_length(&self) -> usize {
+        let threads = rayon::current_num_threads();
+        match (self.memory_limit, self.size) {
+            (Some(limit), Some((width, height))) => {
+                let frame = 2 * 4 * width as u64 * height as u64;
+                threads.min((limit / frame) as usize).max(1)
+            }
+            _ => threads,
+        }
+    }
+
+    /// Number of frames written out, leaving those still being encoded
+    pub fn stored(&self) -> u64 {
+        self.written - self.encoding.len() as u64
+    }
+
+    /// Name of the file of a frame, counting from 1, when writing to a directory
+    pub fn frame_file(&self, frame: u64) -> Option<String> {
+        let extension = match self.format {
+            FrameFormat::Png => "png",
+            FrameFormat::Bmp => "bmp",
+            FrameFormat::Rgba | FrameFormat::Y4m => return None,
+        };
+        self.dir
+            .as_ref()
+            .map(|_| format!("frame_{:06}.{}", frame, extension))
+    }
+
+    /// Queues a frame, preceded by the stream header if it is the first one; returns whether it
+    /// was queued rather than skipped as a duplicate. Waits for the oldest frames once all
+    /// threads are busy encoding or the memory limit is reached, so only a few frames are held
+    /// in memory
+    pub fn write(
+        &mut self,
+        image: DynamicImage,
+        metadata: &Metadata,
+    ) -> Result<bool, Box<dyn Error>> {
+        if self.skip_duplicates {
+            if self.previous.as_ref() == Some(&image) {
+                return Ok(false);
+            }
+            self.previous = Some(image.clone());
+        }
+        let (width, height) = image.dimensions();
+        match self.size {
+            None => {
+                self.size = Some((width, height));
+                self.write_header(width, height)?;
+            }
+            Some(size) if size != (width, height) => {
+                return Err(format!(
+                    "frame of {}x{} pixels in a stream of {}x{}",
+                    width, height, size.0, size.1
+                )
+                .into())
+            }
+            Some(_) => {}
+        }
+        self.written += 1;
+        let file = match (&self.dir, self.frame_file(self.written)) {
+            (Some(dir), Some(file)) => Some(dir.join(file)),
+            (Some(_), None) => return Err("only PNG and BMP frames are written to files".into()),
+            (None, _) => None,
+        };
+        let (sender, receiver) = mpsc::channel();
+        let (format, metadata) = (self.format, metadata.clone());
+        rayon::spawn(move || {
+            let mut encoded = Vec::new();
+            let result = encode(format, &mut encoded, &image, &metadata)
+                .map(|_| encoded)
+                .map_err(|e| e.to_string());
+            // Nothing waits for the frame anymore if writing an earlier one failed
+            let _ = sender.send(result);
+        });
+        self.encoding.push_back((file, receiver));
+        while self.encoding.len() > self.queue_length() {
+            self.write_next()?;
+        }
+        Ok(true)
+    }
+
+    /// Waits for all queued frames and writes them
+    pub fn finish(&mut self) -> Result<(), Box<dyn Error>> {
+        while !self.encoding.is_empty() {
+            self.write_next()?;
+        }
+        Ok(())
+    }
+
+    /// Waits for the oldest queued frame to be encoded and writes it
+    fn write_next(&mut self) -> Result<(), Box<dyn Error>> {
+        let (file, receiver) = match self.encoding.pop_front() {
+            Some(encoding) => encoding,
+            None => return Ok(()),
+        };
+        let encoded = receiver
+            .recv()
+            .map_err(|_| "encoding a frame failed unexpectedly")??;
+        let _hold = interrupt::hold();
+        match file {
+            Some(file) => std::fs::write(file, encoded)?,
+            None => {
+                self.out.write_all(&encoded)?;
+                self.out.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_header(&mut self, width: u32, height: u32) -> Result<(), Box<dyn Error>> {
+        if self.dir.is_some() {
+            return Ok(());
+        }
+        match self.format {
+            FrameFormat::Rgba => info!(
+                "Writing raw frames, read them with `ffmpeg -f rawvideo -pix_fmt rgba -s {}x{} \
+                 -i -`",
+                width, height
+            ),
+            FrameFormat::Y4m => writeln!(
+                self.out,
+                "YUV4MPEG2 W{} H{} F{}:1 Ip A1:1 C444",
+                width, height, Y4M_RATE
+            )?,
+            FrameFormat::Png | FrameFormat::Bmp => {}
+        }
+        Ok(())
+    }
+}
+
+/// Encodes a single frame in the format
+fn encode<W: Write>(
+    format: FrameFormat,
+    out: &mut W,
+    image: &DynamicImage,
+    metadata: &Metadata,
+) -> Result<(), Box<dyn Error>> {
+    let rgba = image.to_rgba8();
+    let (width, height) = rgba.dimensions();
+    match format {
+        FrameFormat::Png => metadata::write_png(image, &mut *out, metadata)?,
+        FrameFormat::Rgba => out.write_all(&rgba)?,
+        FrameFormat::Y4m => {
+            let pixels = rgba.pixels().map(|p| {
+                let alpha = p[3] as f64 / 255.0;
+                let [r, g, b] = [p[0], p[1], p[2]].map(|c| c as f64 / 255.0 * alpha);
+                // BT.601 in the limited range most players expect
+                [
+                    16.0 + 65.481 * r + 128.553 * g + 24.966 * b,
+                    128.0 - 37.797 * r - 74.203 * g + 112.0 * b,
+                    128.0 + 112.0 * r - 93.786 * g - 18.214 * b,
+                ]
+            });
+            let yuv: Vec<[f64; 3]> = pixels.collect();
+            let mut frame = Vec::with_capacity(6 + 3 * yuv.len());
+            frame.extend_from_slice(b"FRAME\n");
+            for plane in 0..3 {
+                frame.extend(yuv.iter().map(|p| p[plane].round() as u8));
+            }
+            out.write_all(&frame)?;
+        }
+        FrameFormat::Bmp => {
+            image::codecs::bmp::BmpEncoder::new(out).encode(
+                &rgba,
+                width,
+                height,
+                ColorType::Rgba8,
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// A line of the frame log
+#[derive(Debug, Serialize)]
+struct LoggedFrame {
+    /// Number of the frame in the stream, starting at 1
+    frame: u64,
+    /// Date of the activity being added
+    date: chrono::DateTime<chrono::Utc>,
+    activity: String,
+    /// Number of identical frames skipped after this one, to be repeated when encoding
+    repeats: u64,
+}
+
+/// Writes a CSV line per frame of a video with what it shows, e.g., to time or label it when
+/// encoding
+pub struct FrameLog {
+    writer: csv::Writer<File>,
+    /// Frame logged last, written once the number of its repeats is known
+    pending: Option<LoggedFrame>,
+}
+
+impl FrameLog {
+    pub fn create(path: &Path) -> Result<Self, Box<dyn Error>> {
+        Ok(FrameLog {
+            writer: csv::Writer::from_path(path)?,
+            pending: None,
+        })
+    }
+
+    /// Records a frame written to the stream while the given activity was added
+    pub fn frame(
+        &mut self,
+        frame: u64,
+        activity: &str,
+        date: chrono::DateTime<chrono::Utc>,
+    ) -> Result<(), Box<dyn Error>> {
+        if let Some(pending) = self.pending.take() {
+            self.writer.serialize(pending)?;
+        }
+        self.pending = Some(LoggedFrame {
+            frame,
+            date,
+            activity: activity.to_string(),
+            repeats: 0,
+        });
+        Ok(())
+    }
+
+    /// Counts a repeat of the frame recorded last, skipped in the stream
+    pub fn repeat(&mut self) {
+        if let Some(pending) = self.pending.as_mut() {
+            pending.repeats += 1;
+        }
+    }
+
+    pub fn finish(mut self) -> Result<(), Box<dyn Error>> {
+        if let Some(pending) = self.pending.take() {
+            self.writer.serialize(pending)?;
+        }
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// An entry of the manifest of a frames directory
+#[derive(Debug, Serialize)]
+struct ManifestFrame {
+    /// Number of the frame, starting at 1
+    frame: u64,
+    file: String,
+    /// Name of the activity being added
+    activity: String,
+    date: chrono::DateTime<chrono::Utc>,
+}
+
+/// Lists the files of frames written to a directory with what they show, saved as
+/// `manifest.json` next to them
+pub struct Manifest {
+    path: PathBuf,
+    frames: Vec<ManifestFrame>,
+    /// When the manifest was last saved while frames were written
+    saved: Option<Instant>,
+}
+
+impl Manifest {
+    pub fn new(dir: &Path) -> Self {
+        Manifest {
+            path: dir.join("manifest.json"),
+            frames: vec![],
+            saved: None,
+        }
+    }
+
+    /// Records the file of a frame written while the given activity was added
+    pub fn frame(
+        &mut self,
+        frame: u64,
+        file: String,
+        activity: &str,
+        date: chrono::DateTime<chrono::Utc>,
+    ) {
+        self.frames.push(ManifestFrame {
+            frame,
+            file,
+            activity: activity.to_string(),
+            date,
+        });
+    }
+
+    /// Saves the frames up to the given one, e.g., those stored so far, at most once per
+    /// `MANIFEST_INTERVAL`, so that an interrupted run keeps the manifest of its files
+    pub fn checkpoint(&mut self, stored: u64) -> Result<(), Box<dyn Error>> {
+        if self
+            .saved
+            .is_some_and(|saved| saved.elapsed() < MANIFEST_INTERVAL)
+        {
+            return Ok(());
+        }
+        self.saved = Some(Instant::now());
+        let count = self.frames.partition_point(|frame| frame.frame <= stored);
+        self.write(&self.frames[..count])
+    }
+
+    /// Saves all frames, once they are stored
+    pub fn save(&self) -> Result<(), Box<dyn Error>> {
+        self.write(&self.frames)
+    }
+
+    /// Replaces the manifest only once written completely, so that an interruption leaves the
+    /// one before
+    fn write(&self, frames: &[ManifestFrame]) -> Result<(), Box<dyn Error>> {
+        let partial = self.path.with_extension("json.part");
+        std::fs::write(&partial, serde_json::to_string_pretty(frames)?)?;
+        std::fs::rename(&partial, &self.path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,83 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::path::PathBuf;
+
+use super::osmbase;
+
+/// Search endpoint of the Nominatim geocoder
+const NOMINATIM: &str = "https://nominatim.openstreetmap.org/search";
+
+/// A place name resolved to coordinates
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Place {
+    /// Full name of the place found
+    pub name: String,
+    pub lat: f64,
+    pub lon: f64,
+}
+
+/// File caching resolved places, next to the cached tiles
+fn places_file() -> PathBuf {
+    osmbase::cache_dir().with_file_name("places.json")
+}
+
+/// Percent-encode everything but unreserved characters, for use in a query string
+fn encode(text: &str) -> String {
+    text.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Resolve a place name, e.g., "Innsbruck, Austria", to the coordinates of its best match on
+/// Nominatim; answers are cached so that every name is only looked up once
+pub fn geocode(query: &str) -> Result<Place, Box<dyn Error>> {
+    let file = places_file();
+    let mut cache: BTreeMap<String, Place> = std::fs::read_to_string(&file)
+        .ok()
+        .and_then(|text| serde_json::from_str(&text).ok())
+        .unwrap_or_default();
+    let key = query.trim().to_lowercase();
+    if let Some(place) = cache.get(&key) {
+        return Ok(place.clone());
+    }
+
+    let url = format!(
+        "{}?q={}&format=json&limit=1",
+        NOMINATIM,
+        encode(query.trim())
+    );
+    let results: Value = serde_json::from_slice(&osmbase::download(&url)?)?;
+    let best = results
+        .get(0)
+        .ok_or_else(|| format!("no place found for '{}'", query))?;
+    let coordinate = |name: &str| {
+        best.get(name)
+            .and_then(Value::as_str)
+            .and_then(|v| v.parse::<f64>().ok())
+            .ok_or_else(|| format!("no {} for '{}' in the answer", name, query))
+    };
+    let place = Place {
+        name: best
+            .get("display_name")
+            .and_then(Value::as_str)
+            .unwrap_or(query)
+            .to_string(),
+        lat: coordinate("lat")?,
+        lon: coordinate("lon")?,
+    };
+
+    cache.insert(key, place.clone());
+    if let Some(dir) = file.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(&file, serde_json::to_string_pretty(&cache)?)?;
+    Ok(place)
+}
@@ -0,0 +1,567 @@
+use geo_types::{Coord, Point};
+use image::{ImageBuffer, Luma};
+use pollster::block_on;
+use wgpu::util::DeviceExt;
+
+use std::error::Error;
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use super::heat::{Heatmap, Measurement, PixelHeatmap};
+use super::slippy;
+
+/// Points collected before they are sent to the GPU together
+const BATCH: usize = 1 << 20;
+
+/// Invocations per workgroup, as declared by the shaders
+const WORKGROUP: u32 = 256;
+
+/// Workgroups per row of a dispatch, below the limit of 65535 per dimension
+const GROUPS_PER_ROW: u32 = 1 << 15;
+
+/// Steps per unit of heat, which GPUs can only add up as integers; the heat of a pixel is kept
+/// in two words, the low one carrying into the high one, so that weights down to a
+/// sixteen-millionth add up and counts stay exact up to 2^40
+const SCALE: f64 = (1u64 << 24) as f64;
+
+/// Splats points into the heat and colors it like `heat::heat_color`, in single precision as
+/// the CPU heatmap
+const SHADER: &str = r#"
+struct Params {
+    // Items to process, invocations per dispatched row and width of the canvas
+    count: u32,
+    stride: u32,
+    width: u32,
+    // Heat colors are normalized against
+    max_value: f32,
+}
+
+// Low and high word of the heat of each pixel, in steps of 1/2^24
+@group(0) @binding(0) var<storage, read_write> heat: array<atomic<u32>>;
+@group(0) @binding(1) var<storage, read_write> peak: atomic<u32>;
+@group(0) @binding(2) var<storage, read> points: array<u32>;
+@group(0) @binding(3) var<uniform> params: Params;
+@group(0) @binding(4) var<storage, read_write> pixels: array<u32>;
+
+fn heat_at(i: u32) -> f32 {
+    let low = atomicLoad(&heat[2u * i]);
+    let high = atomicLoad(&heat[2u * i + 1u]);
+    return (f32(high) * 4294967296.0 + f32(low)) / 16777216.0;
+}
+
+@compute @workgroup_size(256)
+fn splat(@builtin(global_invocation_id) id: vec3<u32>) {
+    let i = id.y * params.stride + id.x;
+    if i >= params.count {
+        return;
+    }
+    let x = points[4u * i];
+    let y = points[4u * i + 1u];
+    let low = points[4u * i + 2u];
+    let high = points[4u * i + 3u];
+    let pixel = 2u * (y * params.width + x);
+    let old = atomicAdd(&heat[pixel], low);
+    // The low word wrapped around
+    let carry = select(0u, 1u, old + low < old);
+    if high + carry > 0u {
+        atomicAdd(&heat[pixel + 1u], high + carry);
+    }
+}
+
+@compute @workgroup_size(256)
+fn find_peak(@builtin(global_invocation_id) id: vec3<u32>) {
+    let i = id.y * params.stride + id.x;
+    if i >= params.count {
+        return;
+    }
+    // Bits of floats that are not negative order like the floats
+    atomicMax(&peak, bitcast<u32>(heat_at(i)));
+}
+
+@compute @workgroup_size(256)
+fn colorize(@builtin(global_invocation_id) id: vec3<u32>) {
+    let i = id.y * params.stride + id.x;
+    if i >= params.count {
+        return;
+    }
+    let count = heat_at(i);
+    var level = 0u;
+    if count > 0.0 {
+        let clamped = min(count, params.max_value);
+        let scaled = log2(clamped + 1.0) / log2(params.max_value + 1.0) * 250.0 + 6.0;
+        level = min(u32(scaled), 255u);
+    }
+    // Red and opacity, in the byte order of RGBA pixels
+    pixels[i] = level | (level << 24u);
+}
+"#;
+
+/// Workgroups covering the given number of invocations, in rows of at most `GROUPS_PER_ROW`
+fn workgroups(count: u32) -> (u32, u32) {
+    let groups = count.div_ceil(WORKGROUP);
+    (
+        groups.min(GROUPS_PER_ROW),
+        groups.div_ceil(GROUPS_PER_ROW).max(1),
+    )
+}
+
+/// A GPU with the pipelines adding to and coloring heat, set up once and shared by the
+/// heatmaps on it, e.g., those of the strips of a poster
+pub struct Gpu {
+    device: wgpu::Device,
+    queue: wgpu::Queue,
+    splat: wgpu::ComputePipeline,
+    find_peak: wgpu::ComputePipeline,
+    colorize: wgpu::ComputePipeline,
+    name: String,
+}
+
+impl Gpu {
+    /// Sets up the preferred GPU; fails without one
+    pub fn new() -> Result<Self, Box<dyn Error>> {
+        let instance = wgpu::Instance::default();
+        let adapter = block_on(instance.request_adapter(&wgpu::RequestAdapterOptions {
+            power_preference: wgpu::PowerPreference::HighPerformance,
+            ..Default::default()
+        }))
+        .ok_or("no GPU found")?;
+        let limits = adapter.limits();
+        let (device, queue) = block_on(adapter.request_device(
+            &wgpu::DeviceDescriptor {
+                label: None,
+                required_features: wgpu::Features::empty(),
+                required_limits: limits,
+            },
+            None,
+        ))?;
+
+        let module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("heat"),
+            source: wgpu::ShaderSource::Wgsl(SHADER.into()),
+        });
+        let pipeline = |entry_point| {
+            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some(entry_point),
+                layout: None,
+                module: &module,
+                entry_point,
+            })
+        };
+        Ok(Gpu {
+            splat: pipeline("splat"),
+            find_peak: pipeline("find_peak"),
+            colorize: pipeline("colorize"),
+            name: adapter.get_info().name,
+            device,
+            queue,
+        })
+    }
+
+    /// Fails if the GPU cannot hold the heat of a canvas of the given size
+    pub fn holds(&self, width: u32, height: u32) -> Result<(), Box<dyn Error>> {
+        let limits = self.device.limits();
+        let size = 8 * width as u64 * height as u64;
+        if size > limits.max_storage_buffer_binding_size as u64 || size > limits.max_buffer_size {
+            return Err(format!("{} cannot hold {}×{} pixels", self.name, width, height).into());
+        }
+        Ok(())
+    }
+}
+
+/// Heat of a canvas held by a GPU
+struct Canvas {
+    gpu: Arc<Gpu>,
+    /// Heat per pixel as low and high word of its steps, in row-major order
+    heat: wgpu::Buffer,
+    /// Highest heat of any pixel as the bits of a float, once found
+    peak: wgpu::Buffer,
+    width: u32,
+    height: u32,
+}
+
+impl Canvas {
+    /// Empty heat of a canvas of the given size, which the GPU needs to hold
+    fn new(gpu: Arc<Gpu>, width: u32, height: u32) -> Self {
+        let size = 8 * width as u64 * height as u64;
+        let buffer = |size| {
+            gpu.device.create_buffer(&wgpu::BufferDescriptor {
+                label: None,
+                size,
+                usage: wgpu::BufferUsages::STORAGE
+                    | wgpu::BufferUsages::COPY_SRC
+                    | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            })
+        };
+        Canvas {
+            heat: buffer(size),
+            peak: buffer(4),
+            width,
+            height,
+            gpu,
+        }
+    }
+
+    fn pixels(&self) -> u32 {
+        self.width * self.height
+    }
+
+    /// Runs a pipeline over the given number of items, with buffers bound at the given indices
+    fn dispatch(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        pipeline: &wgpu::ComputePipeline,
+        count: u32,
+        max_value: f32,
+        buffers: &[(u32, &wgpu::Buffer)],
+    ) {
+        let (columns, rows) = workgroups(count);
+        let params = [count, columns * WORKGROUP, self.width, max_value.to_bits()];
+        let params = self
+            .gpu
+            .device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: None,
+                contents: &to_bytes(&params),
+                usage: wgpu::BufferUsages::UNIFORM,
+            });
+        let entries: Vec<wgpu::BindGroupEntry> = buffers
+            .iter()
+            .chain(&[(3, &params)])
+            .map(|&(binding, buffer)| wgpu::BindGroupEntry {
+                binding,
+                resource: buffer.as_entire_binding(),
+            })
+            .collect();
+        let bind_group = self
+            .gpu
+            .device
+            .create_bind_group(&wgpu::BindGroupDescriptor {
+                label: None,
+                layout: &pipeline.get_bind_group_layout(0),
+                entries: &entries,
+            });
+        let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor::default());
+        pass.set_pipeline(pipeline);
+        pass.set_bind_group(0, &bind_group, &[]);
+        pass.dispatch_workgroups(columns, rows, 1);
+    }
+
+    fn encoder(&self) -> wgpu::CommandEncoder {
+        self.gpu
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor::default())
+    }
+
+    /// Adds points given as x, y and low and high word of their steps to the heat
+    fn splat(&self, points: &[u32]) {
+        let count = (points.len() / 4) as u32;
+        let points = self
+            .gpu
+            .device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: None,
+                contents: &to_bytes(points),
+                usage: wgpu::BufferUsages::STORAGE,
+            });
+        let mut encoder = self.encoder();
+        let buffers = [(0, &self.heat), (2, &points)];
+        self.dispatch(&mut encoder, &self.gpu.splat, count, 0.0, &buffers);
+        self.gpu.queue.submit(Some(encoder.finish()));
+    }
+
+    /// Copies a buffer back from the GPU, after the given commands ran
+    fn read(
+        &self,
+        mut encoder: wgpu::CommandEncoder,
+        buffer: &wgpu::Buffer,
+    ) -> Result<Vec<u8>, Box<dyn Error>> {
+        let staging = self.gpu.device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            size: buffer.size(),
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        encoder.copy_buffer_to_buffer(buffer, 0, &staging, 0, buffer.size());
+        self.gpu.queue.submit(Some(encoder.finish()));
+
+        let slice = staging.slice(..);
+        let (sender, receiver) = mpsc::channel();
+        slice.map_async(wgpu::MapMode::Read, move |result| {
+            let _ = sender.send(result);
+        });
+        self.gpu.device.poll(wgpu::Maintain::Wait);
+        receiver.recv()??;
+        let data = slice.get_mapped_range().to_vec();
+        staging.unmap();
+        Ok(data)
+    }
+
+    /// Highest heat of any pixel
+    fn peak(&self) -> Result<f64, Box<dyn Error>> {
+        let mut encoder = self.encoder();
+        encoder.clear_buffer(&self.peak, 0, None);
+        let buffers = [(0, &self.heat), (1, &self.peak)];
+        let pipeline = &self.gpu.find_peak;
+        self.dispatch(&mut encoder, pipeline, self.pixels(), 0.0, &buffers);
+        let data = self.read(encoder, &self.peak)?;
+        Ok(f32::from_bits(from_bytes(&data)[0]) as f64)
+    }
+
+    /// Heat per pixel in row-major order, leaving none on the GPU
+    fn take_heat(&self) -> Result<Vec<f32>, Box<dyn Error>> {
+        let data = self.read(self.encoder(), &self.heat)?;
+        let mut encoder = self.encoder();
+        encoder.clear_buffer(&self.heat, 0, None);
+        self.gpu.queue.submit(Some(encoder.finish()));
+        let words = from_bytes(&data);
+        let heat = words.chunks_exact(2).map(|word| {
+            let steps = (word[1] as u64) << 32 | word[0] as u64;
+            (steps as f64 / SCALE) as f32
+        });
+        Ok(heat.collect())
+    }
+
+    /// Colors the heat against the given value like `heat::heat_color`
+    fn colorize(&self, max_value: f64) -> Result<image::RgbaImage, Box<dyn Error>> {
+        let pixels = self.gpu.device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            size: 4 * self.pixels() as u64,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+        let mut encoder = self.encoder();
+        let buffers = [(0, &self.heat), (4, &pixels)];
+        let max_value = max_value as f32;
+        self.dispatch(
+            &mut encoder,
+            &self.gpu.colorize,
+            self.pixels(),
+            max_value,
+            &buffers,
+        );
+        let data = self.read(encoder, &pixels)?;
+        Ok(ImageBuffer::from_raw(self.width, self.height, data).unwrap())
+    }
+}
+
+/// Values as laid out in GPU buffers
+fn to_bytes(values: &[u32]) -> Vec<u8> {
+    values.iter().flat_map(|v| v.to_le_bytes()).collect()
+}
+
+fn from_bytes(data: &[u8]) -> Vec<u32> {
+    data.chunks_exact(4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect()
+}
+
+/// A pixel heatmap adding points and coloring heat on the GPU; measurements, saved states and
+/// colorings the GPU does not support, e.g., blurred ones, are left to a wrapped CPU heatmap,
+/// which the heat is moved to when needed
+pub struct GpuHeatmap {
+    gpu: Canvas,
+    map: slippy::Map,
+    /// Points not yet sent to the GPU, as x, y and low and high word of their steps
+    pending: Mutex<Vec<u32>>,
+    /// Whether the GPU holds heat not yet moved to the CPU heatmap
+    splatted: AtomicBool,
+    /// Whether heat was moved to or loaded into the CPU heatmap, which colors it from then on
+    moved: AtomicBool,
+    cpu: Mutex<PixelHeatmap>,
+}
+
+impl GpuHeatmap {
+    /// Accumulates the heat of the given heatmap on the GPU, which needs to hold its canvas,
+    /// see `Gpu::holds`
+    pub fn from(heatmap: PixelHeatmap, gpu: Arc<Gpu>) -> Self {
+        let (width, height) = heatmap.map().pixel_size();
+        Self {
+            map: *heatmap.map(),
+            gpu: Canvas::new(gpu, width, height),
+            pending: Mutex::new(vec![]),
+            splatted: AtomicBool::new(false),
+            moved: AtomicBool::new(false),
+            cpu: Mutex::new(heatmap),
+        }
+    }
+
+    /// Sends the collected points to the GPU
+    fn flush(&self) {
+        let mut pending = self.pending.lock().unwrap();
+        if !pending.is_empty() {
+            self.gpu.splat(&pending);
+            pending.clear();
+            self.splatted.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// The CPU heatmap, with all heat moved to it
+    fn synced(&self) -> MutexGuard<'_, PixelHeatmap> {
+        self.flush();
+        let mut cpu = self.cpu.lock().unwrap();
+        if self.splatted.swap(false, Ordering::Relaxed) {
+            let heat = self.gpu.take_heat().expect("Cannot read heat from the GPU");
+            cpu.add_heat(&heat);
+            self.moved.store(true, Ordering::Relaxed);
+        }
+        cpu
+    }
+
+    /// The value colors are normalized against if the GPU can color the heat, i.e., all heat
+    /// is still there and colors depend on nothing else
+    fn gpu_normalization(&self) -> Option<f64> {
+        let cpu = self.cpu.lock().unwrap();
+        if self.moved.load(Ordering::Relaxed) || !cpu.plain_colors() {
+            return None;
+        }
+        cpu.fixed_max().or_else(|| {
+            self.flush();
+            Some(self.gpu.peak().expect("Cannot read heat from the GPU"))
+        })
+    }
+}
+
+impl Heatmap for GpuHeatmap {
+    fn as_image(&self) -> image::DynamicImage {
+        match self.gpu_normalization() {
+            Some(max_value) => {
+                self.flush();
+                let image = self
+                    .gpu
+                    .colorize(max_value)
+                    .expect("Cannot color heat on the GPU");
+                image::DynamicImage::ImageRgba8(image)
+            }
+            None => self.synced().as_image(),
+        }
+    }
+
+    fn as_image_at_phase(&self, phase: f64) -> image::DynamicImage {
+        self.synced().as_image_at_phase(phase)
+    }
+
+    fn as_mask(&self) -> ImageBuffer<Luma<u16>, Vec<u16>> {
+        self.synced().as_mask()
+    }
+
+    fn as_image_with_overlay(
+        &self,
+        name: &str,
+        date: &chrono::DateTime<chrono::Utc>,
+    ) -> image::DynamicImage {
+        self.synced().as_image_with_overlay(name, date)
+    }
+
+    #[inline]
+    fn add_point(&mut self, point: &Coord<u32>) {
+        self.add_weighted_point(point, 1.0);
+    }
+
+    fn add_weighted_point(&mut self, point: &Coord<u32>, weight: f64) {
+        if point.x >= self.gpu.width || point.y >= self.gpu.height {
+            return;
+        }
+        let pending = self.pending.get_mut().unwrap();
+        let steps = (weight * SCALE).round() as u64;
+        pending.extend_from_slice(&[point.x, point.y, steps as u32, (steps >> 32) as u32]);
+        if pending.len() >= 4 * BATCH {
+            self.flush();
+        }
+    }
+
+    fn add_measurement(&mut self, point: &Coord<u32>, measurement: Measurement, value: f64) {
+        let cpu = self.cpu.get_mut().unwrap();
+        cpu.add_measurement(point, measurement, value);
+    }
+
+    fn decay(&mut self, amount: u32) {
+        self.synced().decay(amount);
+    }
+
+    fn project_to_screen(&self, coord: &Point<f64>) -> Option<Coord<u32>> {
+        self.map.to_pixels(coord)
+    }
+
+    fn project_world_to_screen(&self, world: &Point<f64>) -> Option<Coord<u32>> {
+        self.map.world_to_pixels(world)
+    }
+
+    fn project_world(&self, world: &Point<f64>) -> Point<f64> {
+        self.map.project_world(world)
+    }
+
+    fn screen_bounds(&self) -> (Point<f64>, Point<f64>) {
+        (
+            Point::new(0.0, 0.0),
+            Point::new(self.gpu.width as f64, self.gpu.height as f64),
+        )
+    }
+
+    fn max_value(&self) -> f64 {
+        match self.gpu_normalization() {
+            Some(max_value) => max_value,
+            None => self.synced().max_value(),
+        }
+    }
+
+    fn unit(&self) -> &'static str {
+        self.cpu.lock().unwrap().unit()
+    }
+
+    fn heat_percentile(&self, fraction: f64) -> f64 {
+        self.synced().heat_percentile(fraction)
+    }
+
+    fn measurement_range(&self) -> Option<(f64, f64)> {
+        self.cpu.lock().unwrap().measurement_range()
+    }
+
+    fn save_state(&self, writer: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+        self.synced().save_state(writer)
+    }
+
+    fn load_state(&mut self, reader: &mut dyn Read) -> Result<(), Box<dyn Error>> {
+        self.cpu.get_mut().unwrap().load_state(reader)?;
+        self.moved.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn weighted_heat_matches_the_cpu() {
+        let gpu = match Gpu::new() {
+            Ok(gpu) => Arc::new(gpu),
+            // Nothing to compare against without a GPU
+            Err(_) => return,
+        };
+        let map = slippy::Map::from(6.1432, 46.2044, 64, 48, 14);
+        let mut cpu = PixelHeatmap::from(map, false, false);
+        let mut on_gpu = GpuHeatmap::from(PixelHeatmap::from(map, false, false), gpu);
+        // Weights of activities fading with their age, down to far below a hundredth
+        for i in 0..5000u32 {
+            let point = Coord {
+                x: i * 7 % 64,
+                y: i * 13 % 48,
+            };
+            let weight = 0.5f64.powi((i % 16) as i32);
+            cpu.add_weighted_point(&point, weight);
+            on_gpu.add_weighted_point(&point, weight);
+        }
+        let max_value = cpu.max_value();
+        assert!((on_gpu.max_value() - max_value).abs() < 1e-4 * max_value);
+        // Added up in another order, heat may differ in its last bits, and colors by a level
+        let (expected, actual) = (cpu.as_image().to_rgba8(), on_gpu.as_image().to_rgba8());
+        for (expected, actual) in expected.pixels().zip(actual.pixels()) {
+            for channel in 0..4 {
+                assert!(expected[channel].abs_diff(actual[channel]) <= 1);
+            }
+        }
+    }
+}
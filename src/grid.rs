@@ -0,0 +1,151 @@
+use rayon::prelude::*;
+
+/// Side length of the square chunks of chunked grids, in pixels
+const CHUNK: u32 = 64;
+
+/// Grids with more pixels than this are stored in chunks, e.g., posters or high zoom levels
+/// where tracks touch only a small part of the canvas
+pub const CHUNKED_PIXELS: u64 = 1 << 24;
+
+/// Values per pixel of a canvas, in row-major order
+enum Storage<T> {
+    Dense(Vec<T>),
+    /// Chunks in row-major order, allocated when a value in them is first changed
+    Chunked(Vec<Option<Box<[T]>>>),
+}
+
+/// Values per pixel that start out at their default, stored densely for small canvases and in
+/// chunks allocated on first write for large ones, as most pixels of those are never touched
+pub struct Grid<T> {
+    width: u32,
+    height: u32,
+    storage: Storage<T>,
+}
+
+impl<T: Copy + Default + Send + Sync> Default for Grid<T> {
+    fn default() -> Self {
+        Self::new(0, 0)
+    }
+}
+
+impl<T: Copy + Default + Send + Sync> Grid<T> {
+    /// A grid of default values, chunked if larger than `CHUNKED_PIXELS`
+    pub fn new(width: u32, height: u32) -> Self {
+        let size = width as u64 * height as u64;
+        let storage = if size > CHUNKED_PIXELS {
+            let chunks = (width.div_ceil(CHUNK) * height.div_ceil(CHUNK)) as usize;
+            Storage::Chunked(vec![None; chunks])
+        } else {
+            Storage::Dense(vec![T::default(); size as usize])
+        };
+        Grid {
+            width,
+            height,
+            storage,
+        }
+    }
+
+    /// Whether the grid has no pixels, e.g., of measurements not recorded
+    pub fn is_empty(&self) -> bool {
+        self.width == 0 || self.height == 0
+    }
+
+    /// Index of the chunk holding a pixel and of the pixel within the chunk
+    fn chunk_index(&self, x: u32, y: u32) -> (usize, usize) {
+        let chunk = (y / CHUNK) * self.width.div_ceil(CHUNK) + x / CHUNK;
+        let within = (y % CHUNK) * CHUNK + x % CHUNK;
+        (chunk as usize, within as usize)
+    }
+
+    /// The value of a pixel within the grid
+    pub fn get(&self, x: u32, y: u32) -> T {
+        match &self.storage {
+            Storage::Dense(values) => values[(y * self.width + x) as usize],
+            Storage::Chunked(chunks) => {
+                let (chunk, within) = self.chunk_index(x, y);
+                chunks[chunk]
+                    .as_ref()
+                    .map_or_else(T::default, |c| c[within])
+            }
+        }
+    }
+
+    /// The value of a pixel to change, None outside of the grid
+    pub fn get_mut(&mut self, x: u32, y: u32) -> Option<&mut T> {
+        if x >= self.width || y >= self.height {
+            return None;
+        }
+        let (chunk, within) = self.chunk_index(x, y);
+        match &mut self.storage {
+            Storage::Dense(values) => values.get_mut((y * self.width + x) as usize),
+            Storage::Chunked(chunks) => {
+                let chunk = chunks[chunk]
+                    .get_or_insert_with(|| vec![T::default(); (CHUNK * CHUNK) as usize].into());
+                Some(&mut chunk[within])
+            }
+        }
+    }
+
+    /// All values that may differ from the default, in no particular order
+    pub fn stored(&self) -> Box<dyn Iterator<Item = T> + '_> {
+        match &self.storage {
+            Storage::Dense(values) => Box::new(values.iter().copied()),
+            Storage::Chunked(chunks) => {
+                Box::new(chunks.iter().flatten().flat_map(|c| c.iter().copied()))
+            }
+        }
+    }
+
+    /// Changes every stored value
+    pub fn update(&mut self, f: impl Fn(&mut T) + Send + Sync) {
+        match &mut self.storage {
+            Storage::Dense(values) => values.par_iter_mut().for_each(f),
+            Storage::Chunked(chunks) => chunks
+                .par_iter_mut()
+                .flatten()
+                .for_each(|chunk| chunk.iter_mut().for_each(&f)),
+        }
+    }
+
+    /// All values in row-major order, e.g., to blur or save them
+    pub fn to_vec(&self) -> Vec<T> {
+        match &self.storage {
+            Storage::Dense(values) => values.clone(),
+            Storage::Chunked(_) => (0..self.height)
+                .flat_map(|y| (0..self.width).map(move |x| (x, y)))
+                .map(|(x, y)| self.get(x, y))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunks_hold_what_a_dense_grid_does() {
+        // Partial chunks along the right and bottom edge
+        let (width, height) = (150, 100);
+        let mut dense: Grid<f32> = Grid::new(width, height);
+        let chunks = (width.div_ceil(CHUNK) * height.div_ceil(CHUNK)) as usize;
+        let mut chunked: Grid<f32> = Grid {
+            width,
+            height,
+            storage: Storage::Chunked(vec![None; chunks]),
+        };
+        for i in 0..500u32 {
+            let (x, y) = (i * 37 % width, i * 11 % height);
+            for grid in [&mut dense, &mut chunked] {
+                *grid.get_mut(x, y).unwrap() += i as f32;
+            }
+        }
+        for grid in [&mut dense, &mut chunked] {
+            assert!(grid.get_mut(width, 0).is_none());
+            grid.update(|value| *value /= 2.0);
+        }
+        assert_eq!(chunked.to_vec(), dense.to_vec());
+        let sum = |grid: &Grid<f32>| grid.stored().sum::<f32>();
+        assert_eq!(sum(&chunked), sum(&dense));
+    }
+}
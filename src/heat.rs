@@ -1,27 +1,344 @@
+use chrono::Datelike;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
 use fonts::system_fonts;
-use geo_types::{coord, Coord, Point};
-use image::ImageBuffer;
+use geo_types::{coord, Coord, LineString, Point};
+use image::{GenericImageView, ImageBuffer, Luma};
 use imageproc::drawing::{draw_filled_rect_mut, draw_text_mut};
 use imageproc::rect::Rect;
-use palette::{Gradient, Hsv};
+use palette::{FromColor, Gradient, Hsv, Srgb};
 use rayon::prelude::*;
-use rusttype::{Font, Scale};
+use rusttype::{point, Font, Scale};
+use serde::{Deserialize, Serialize};
 
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::io::{Read, Write};
+use std::sync::{Mutex, Once};
+
+use super::assets;
+use super::explorer::{Tile, Visit, VisitedTiles};
+use super::grid::Grid;
 use super::slippy;
 
 lazy_static! {
     static ref GRADIENT: Gradient<Hsv> =
         Gradient::new(vec![Hsv::new(0.0, 0.75, 0.45), Hsv::new(0.0, 0.75, 1.00),]);
-    static ref FONT: Font<'static> = {
+    static ref ELEVATION_GRADIENT: Gradient<Hsv> = Gradient::new(vec![
+        Hsv::new(240.0, 0.85, 1.0),
+        Hsv::new(160.0, 0.85, 1.0),
+        Hsv::new(80.0, 0.85, 1.0),
+        Hsv::new(0.0, 0.85, 1.0),
+    ]);
+    static ref THERMAL_GRADIENT: Gradient<Hsv> = Gradient::new(vec![
+        Hsv::new(270.0, 0.85, 1.0),
+        Hsv::new(220.0, 0.85, 1.0),
+        Hsv::new(180.0, 0.85, 1.0),
+        Hsv::new(60.0, 0.85, 1.0),
+        Hsv::new(0.0, 0.85, 1.0),
+    ]);
+    static ref SENSOR_GRADIENT: Gradient<Hsv> = Gradient::new(vec![
+        Hsv::new(280.0, 0.85, 0.6),
+        Hsv::new(200.0, 0.85, 0.9),
+        Hsv::new(120.0, 0.85, 1.0),
+        Hsv::new(60.0, 0.85, 1.0),
+    ]);
+    static ref TRAFFIC_LIGHT_GRADIENT: Gradient<Hsv> = Gradient::new(vec![
+        Hsv::new(0.0, 0.85, 1.0),
+        Hsv::new(60.0, 0.85, 1.0),
+        Hsv::new(120.0, 0.85, 1.0),
+    ]);
+    static ref FONT: Option<Font<'static>> = {
         let property = system_fonts::FontPropertyBuilder::new()
             .family("Roboto Light")
             .build();
-        if let Some((font_data, _)) = system_fonts::get(&property) {
-            Font::try_from_vec(font_data).unwrap()
-        } else {
-            panic!("Cannot load font");
+        // Without the font installed, one fetched into the assets directory may do
+        system_fonts::get(&property)
+            .map(|(font_data, _)| font_data)
+            .or_else(|| std::fs::read(assets::fetched("roboto-light")?).ok())
+            .and_then(Font::try_from_vec)
+    };
+}
+
+static MISSING_FONT: Once = Once::new();
+
+/// Writes the attribution of the basemap into the bottom right corner, on a translucent
+/// backdrop; without a font, it is skipped with a warning
+pub fn draw_attribution(image: &mut image::DynamicImage, text: &str) {
+    let height = image.height();
+    draw_attribution_sized(image, text, height);
+}
+
+/// Writes the attribution into the bottom strip of a picture rendered in strips, sized for the
+/// height of the whole picture; the strip needs to be at least `label_height` rows high
+pub fn draw_attribution_sized(image: &mut image::DynamicImage, text: &str, picture_height: u32) {
+    if FONT.is_none() {
+        MISSING_FONT.call_once(|| {
+            warn!(
+                "Cannot load font, skipping attribution (see `derivers assets fetch \
+                 roboto-light`)"
+            )
+        });
+        return;
+    }
+    draw_sized_label(image, text, picture_height);
+}
+
+/// Writes a line of text into the bottom right corner, on a translucent backdrop, if a font
+/// could be loaded
+pub fn draw_label(image: &mut image::DynamicImage, text: &str) {
+    let height = image.height();
+    draw_sized_label(image, text, height);
+}
+
+/// Size of the text of labels on a picture of the given height
+fn label_scale(picture_height: u32) -> Scale {
+    Scale::uniform((picture_height as f32 / 50.0).max(11.0))
+}
+
+/// Rows at the bottom of a picture of the given height that a label covers with its backdrop
+pub fn label_height(picture_height: u32) -> u32 {
+    let scale = label_scale(picture_height);
+    let padding = (scale.y / 4.0).ceil() as u32;
+    scale.y.ceil() as u32 + 2 * padding
+}
+
+/// Writes a label with text scaled to a picture of the given height
+fn draw_sized_label(image: &mut image::DynamicImage, text: &str, picture_height: u32) {
+    let font = match FONT.as_ref() {
+        Some(font) => font,
+        None => return,
+    };
+    let (width, height) = image.dimensions();
+    let scale = label_scale(picture_height);
+    let ascent = font.v_metrics(scale).ascent;
+    let text_width = font
+        .layout(text, scale, point(0.0, ascent))
+        .filter_map(|glyph| glyph.pixel_bounding_box())
+        .map(|bounds| bounds.max.x)
+        .max()
+        .unwrap_or(0)
+        .max(0) as u32;
+
+    let padding = (scale.y / 4.0).ceil() as u32;
+    let box_width = (text_width + 2 * padding).min(width);
+    let box_height = label_height(picture_height).min(height);
+    let (x, y) = (width - box_width, height - box_height);
+    let backdrop = ImageBuffer::from_pixel(box_width, box_height, image::Rgba([0, 0, 0, 128]));
+    image::imageops::overlay(image, &backdrop, x, y);
+
+    let white = image::Rgba([255; 4]);
+    draw_text_mut(image, white, x + padding, y + padding, scale, font, text);
+}
+
+/// Maps a visit count onto a heat level between 0 and 256, scaled logarithmically against the
+/// maximum count
+fn heat_level(count: f64, max_value: f64) -> f64 {
+    if count <= 0.0 {
+        return 0.0;
+    }
+    // Heat beyond a fixed maximum saturates
+    let count = count.min(max_value);
+
+    (count + 1.0).log10() / (max_value + 1.0).log10() * 250.0 + 6.0
+}
+
+/// Maps a visit count onto an RGBA color, scaled logarithmically against the maximum count
+pub fn heat_color(count: f64, max_value: f64) -> [u8; 4] {
+    let heat = heat_level(count, max_value) as u8;
+
+    [heat, 0, 0, heat]
+}
+
+/// Maps the change from one visit count to another onto a diverging color: orange where heat
+/// grew, e.g., on newly ridden roads, and blue where it shrank, more opaque the larger the
+/// change on the logarithmic scale of `heat_color`
+pub fn diff_color(before: f64, after: f64, max_value: f64) -> [u8; 4] {
+    let change = heat_level(after, max_value) - heat_level(before, max_value);
+    let alpha = change.abs().min(255.0) as u8;
+    if change > 0.0 {
+        [255, 140, 0, alpha]
+    } else {
+        [0, 140, 255, alpha]
+    }
+}
+
+/// Maps a visit count onto a color of the full hue wheel, starting at the given phase as a
+/// fraction of a turn, with the same opacity as `heat_color`
+pub fn cycled_heat_color(count: f64, max_value: f64, phase: f64) -> [u8; 4] {
+    let alpha = heat_color(count, max_value)[3];
+    if alpha == 0 {
+        return [0; 4];
+    }
+    let turn = (alpha as f64 / 255.0 + phase).rem_euclid(1.0);
+    let hsv = Hsv::new(turn as f32 * 360.0, 0.85, 1.0);
+    let rgb: Srgb<u8> = Srgb::from_color(hsv).into_format();
+    [rgb.red, rgb.green, rgb.blue, alpha]
+}
+
+/// Maps a fraction onto an RGB color of the gradient
+fn gradient_color(gradient: &Gradient<Hsv>, fraction: f64) -> [u8; 3] {
+    let hsv = gradient.get(fraction.clamp(0.0, 1.0) as f32);
+    let rgb: Srgb<u8> = Srgb::from_color(hsv).into_format();
+    [rgb.red, rgb.green, rgb.blue]
+}
+
+/// Quantities recorded along tracks that heatmaps can be colored by
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Measurement {
+    /// In meters
+    Elevation,
+    /// Air temperature, in degrees Celsius
+    Temperature,
+    /// A field of FIT records requested by name, in its own unit
+    FitField,
+}
+
+impl Measurement {
+    /// Maps a fraction between the lowest and highest value onto an RGB color: from blue to
+    /// red for elevation, from violet over cyan and yellow to red for temperature, and from
+    /// dark violet over green to yellow for FIT fields
+    pub fn color(self, fraction: f64) -> [u8; 3] {
+        match self {
+            Measurement::Elevation => gradient_color(&ELEVATION_GRADIENT, fraction),
+            Measurement::Temperature => gradient_color(&THERMAL_GRADIENT, fraction),
+            Measurement::FitField => gradient_color(&SENSOR_GRADIENT, fraction),
         }
+    }
+}
+
+/// Periods of time in which visits are counted
+#[derive(Clone, Copy, Debug)]
+pub enum Period {
+    Month,
+    Week,
+}
+
+impl Period {
+    /// A number identifying the period containing the date
+    fn index(self, date: &chrono::DateTime<chrono::Utc>) -> i32 {
+        match self {
+            Period::Month => date.year() * 12 + date.month0() as i32,
+            Period::Week => {
+                let week = date.iso_week();
+                week.year() * 53 + week.week0() as i32
+            }
+        }
+    }
+}
+
+/// Start of saved heatmap states, followed by the compressed state
+const STATE_MAGIC: &[u8; 8] = b"derivHS1";
+
+type Visits = Option<(chrono::DateTime<chrono::Utc>, chrono::DateTime<chrono::Utc>)>;
+
+/// What the heat of a pixel heatmap covers: the view, given by its top left corner in tile
+/// coordinates, size and zoom, and the unit of the heat
+#[derive(Serialize, Deserialize)]
+struct PixelView {
+    origin: (f64, f64),
+    width: u32,
+    height: u32,
+    zoom: u8,
+    unit: String,
+}
+
+impl PixelView {
+    /// Fails unless heat of the other view can be added to this one
+    fn check(&self, other: &PixelView) -> Result<(), Box<dyn Error>> {
+        // Allow for rounding when the view was given differently, e.g., by a bounding box
+        let shifted = (self.origin.0 - other.origin.0)
+            .abs()
+            .max((self.origin.1 - other.origin.1).abs())
+            * slippy::TILE_SIZE as f64;
+        if self.zoom != other.zoom
+            || (self.width, self.height) != (other.width, other.height)
+            || shifted > 0.01
+        {
+            return Err(Box::from("state shows a different view"));
+        }
+        if self.unit != other.unit {
+            return Err(format!("state counts {}, not {}", other.unit, self.unit).into());
+        }
+        Ok(())
+    }
+}
+
+/// Accumulated heat, saved to be added to later renders or to heat from other machines
+#[derive(Serialize, Deserialize)]
+enum HeatState {
+    /// Heat per pixel
+    Pixel {
+        view: PixelView,
+        heat: Vec<f32>,
+        measurement_sums: Vec<f64>,
+        measurement_counts: Vec<u32>,
+    },
+    /// Visits of tiles at a zoom level, in rows starting at the top left tile
+    Tiles {
+        zoom: u8,
+        min: (u32, u32),
+        width: u32,
+        counts: Vec<u32>,
+        visits: Vec<Visits>,
+        visited_periods: Vec<HashSet<i32>>,
+    },
+}
+
+impl HeatState {
+    fn write(&self, writer: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+        writer.write_all(STATE_MAGIC)?;
+        let mut encoder = GzEncoder::new(writer, Compression::default());
+        bincode::serialize_into(&mut encoder, self)?;
+        encoder.finish()?;
+        Ok(())
+    }
+
+    fn read(reader: &mut dyn Read) -> Result<Self, Box<dyn Error>> {
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != STATE_MAGIC {
+            return Err(Box::from("not a saved heatmap state"));
+        }
+        Ok(bincode::deserialize_from(GzDecoder::new(reader))?)
+    }
+}
+
+/// Renders the heat of two heatmaps of the same view into their own color channels, the first
+/// red and the second blue, so that places both visited turn purple; each is scaled against its
+/// own maximum like `heat_color`
+pub fn compare_heat(first: &dyn Heatmap, second: &dyn Heatmap) -> image::DynamicImage {
+    let (red, blue) = (first.as_mask(), second.as_mask());
+    let image = image::RgbaImage::from_fn(red.width(), red.height(), |x, y| {
+        let r = (red.get_pixel(x, y)[0] / 257) as u8;
+        let b = (blue.get_pixel(x, y)[0] / 257) as u8;
+        image::Rgba([r, 0, b, r.max(b)])
+    });
+    image::DynamicImage::ImageRgba8(image)
+}
+
+/// Renders the change of heat between two states saved by pixel heatmaps of the same view, see
+/// `diff_color`, normalized against the highest heat of either
+pub fn diff_states(
+    before: &mut dyn Read,
+    after: &mut dyn Read,
+) -> Result<image::RgbaImage, Box<dyn Error>> {
+    let pixels = |state| match state {
+        HeatState::Pixel { view, heat, .. } => Ok((view, heat)),
+        HeatState::Tiles { .. } => Err(Box::<dyn Error>::from(
+            "differences of tile heatmaps are not supported",
+        )),
     };
+    let (view, before) = pixels(HeatState::read(before)?)?;
+    let (other, after) = pixels(HeatState::read(after)?)?;
+    view.check(&other)?;
+    let max_value = before.iter().chain(&after).cloned().fold(0.0, f32::max) as f64;
+    let mut image = image::RgbaImage::new(view.width, view.height);
+    for (pixel, (&b, &a)) in image.pixels_mut().zip(before.iter().zip(&after)) {
+        *pixel = image::Rgba(diff_color(b as f64, a as f64, max_value));
+    }
+    Ok(image)
 }
 
 /// A representation of a heatmap
@@ -29,6 +346,28 @@ pub trait Heatmap: Send + Sync {
     /// Renders the heatmap
     fn as_image(&self) -> image::DynamicImage;
 
+    /// Renders the heatmap with heat colored around the hue wheel, shifted by the phase as a
+    /// fraction of a turn; colorings other than by heat ignore the phase
+    fn as_image_at_phase(&self, _phase: f64) -> image::DynamicImage {
+        self.as_image()
+    }
+
+    /// Brings an image rendered by `as_image` up to date, recoloring only the pixels that
+    /// changed since the last update where possible, e.g., between frames of a video; returns
+    /// the region that changed as x, y, width and height, None if nothing did
+    fn update_image(&mut self, image: &mut image::RgbaImage) -> Option<(u32, u32, u32, u32)> {
+        *image = self.as_image().to_rgba8();
+        Some((0, 0, image.width(), image.height()))
+    }
+
+    /// Renders the opacity of the heat as a 16 bit grayscale mask
+    fn as_mask(&self) -> ImageBuffer<Luma<u16>, Vec<u16>> {
+        let image = self.as_image().to_rgba8();
+        ImageBuffer::from_fn(image.width(), image.height(), |x, y| {
+            Luma([image.get_pixel(x, y)[3] as u16 * 257])
+        })
+    }
+
     /// Renders the heatmap with additional information
     /// TODO should be moved outside this trait
     fn as_image_with_overlay(
@@ -40,23 +379,189 @@ pub trait Heatmap: Send + Sync {
     /// Adds a point to the heatmap
     fn add_point(&mut self, point: &Coord<u32>);
 
+    /// Adds a point contributing the given weight, e.g., the distance traveled to reach it;
+    /// heatmaps counting points ignore the weight
+    fn add_weighted_point(&mut self, point: &Coord<u32>, _weight: f64) {
+        self.add_point(point);
+    }
+
+    /// Adds a point recorded at the given time to the heatmap
+    fn add_point_at(&mut self, point: &Coord<u32>, _date: &chrono::DateTime<chrono::Utc>) {
+        self.add_point(point);
+    }
+
+    /// Records a measurement at a point, for heatmaps colored by it
+    fn add_measurement(&mut self, _point: &Coord<u32>, _measurement: Measurement, _value: f64) {}
+
     /// Reduces the heatmap by the given amount
     fn decay(&mut self, amount: u32);
 
     /// Takes a coordinate and converts it into the heatmap's internal representation
     fn project_to_screen(&self, coord: &Point<f64>) -> Option<Coord<u32>>;
+
+    /// Takes world coordinates, see `slippy::to_world`, and converts them into the heatmap's
+    /// internal representation
+    fn project_world_to_screen(&self, world: &Point<f64>) -> Option<Coord<u32>>;
+
+    /// Takes world coordinates to fractional screen coordinates, even if outside of the view
+    fn project_world(&self, world: &Point<f64>) -> Point<f64>;
+
+    /// Top left and bottom right corner of the view in screen coordinates, the latter exclusive
+    fn screen_bounds(&self) -> (Point<f64>, Point<f64>);
+
+    /// Adds a line in lon/lat, e.g., a track, with a point wherever it crosses the screen, so
+    /// that segments leave no gaps however far apart their ends are
+    fn add_linestring(&mut self, line: &LineString<f64>) {
+        let (min, max) = self.screen_bounds();
+        let screen: Vec<Point<f64>> = line
+            .points()
+            .map(|p| self.project_world(&slippy::to_world(p)))
+            .collect();
+        let mut previous: Option<Coord<u32>> = None;
+        let mut add = |heatmap: &mut Self, p: Point<f64>| {
+            let x = p.x().floor().clamp(min.x(), max.x() - 1.0) as u32;
+            let y = p.y().floor().clamp(min.y(), max.y() - 1.0) as u32;
+            let point = coord! { x: x, y: y };
+            if previous != Some(point) {
+                heatmap.add_point(&point);
+                previous = Some(point);
+            }
+        };
+        if let [only] = screen[..] {
+            if let Some((a, _)) = slippy::clip_segment(only, only, min, max) {
+                add(self, a);
+            }
+        }
+        for segment in screen.windows(2) {
+            let (a, b) = match slippy::clip_segment(segment[0], segment[1], min, max) {
+                Some(clipped) => clipped,
+                None => continue,
+            };
+            // One step per screen unit along the longer axis
+            let delta = b - a;
+            let steps = delta.x().abs().max(delta.y().abs()).ceil().max(1.0) as u32;
+            for step in 0..=steps {
+                add(self, a + delta * (step as f64 / steps as f64));
+            }
+        }
+    }
+
+    /// Highest heat value colors are normalized against, in units of `unit`
+    fn max_value(&self) -> f64;
+
+    /// Heat value below which the given fraction of visited places stays, e.g., to normalize
+    /// colors without letting a few hot spots darken everything else; defaults to the maximum
+    fn heat_percentile(&self, _fraction: f64) -> f64 {
+        self.max_value()
+    }
+
+    /// What heat values count
+    fn unit(&self) -> &'static str;
+
+    /// Lowest and highest value of the measurement colors stand for, if colored by one
+    fn measurement_range(&self) -> Option<(f64, f64)> {
+        None
+    }
+
+    /// All visited tiles, for heatmaps based on tiles
+    fn visited_tiles(&self) -> Option<VisitedTiles> {
+        None
+    }
+
+    /// Writes the accumulated heat, compressed, to be added to a heatmap with `load_state`
+    fn save_state(&self, writer: &mut dyn Write) -> Result<(), Box<dyn Error>>;
+
+    /// Adds heat written by `save_state` of a heatmap of the same kind; pixel heatmaps need to
+    /// show the same view, tile heatmaps add the tiles they have in common
+    fn load_state(&mut self, reader: &mut dyn Read) -> Result<(), Box<dyn Error>>;
 }
 
 /// Heatmap based on OSM tiles
 pub struct TileHeatmap {
     map: slippy::Map,
     heatmap: Vec<u32>,
+    /// Time of the first and last point per tile
+    visits: Vec<Option<(chrono::DateTime<chrono::Utc>, chrono::DateTime<chrono::Utc>)>>,
     height: u32,
     width: u32,
     min: Coord<u32>,
     max: Coord<u32>,
     max_value: u32,
+    /// Normalize colors to this value instead of the highest count
+    fixed_max: Option<f64>,
+    /// Normalize colors to the count below which this fraction of visited tiles stays
+    clip_percentile: Option<f64>,
+    /// Color no tile by more than this count
+    cap: Option<u32>,
+    /// Pass the count of a tile beyond the cap on to its visited neighbors, and from those
+    /// filled to theirs, instead of dropping it
+    spread_overflow: bool,
     zoom: u8,
+    /// Outline the explorer max square and cluster when rendering
+    outlines: bool,
+    /// Render tiles as softly shaded rounded squares instead of solid blocks
+    rounded: bool,
+    /// Color tiles by the number of distinct periods they were visited in instead of by heat
+    period: Option<Period>,
+    /// Periods each tile was visited in
+    visited_periods: Vec<HashSet<i32>>,
+}
+
+/// Pixel bounds as left, top, right and bottom edges, right and bottom exclusive
+type Bounds = (i32, i32, i32, i32);
+
+/// Draws a softly shaded, anti-aliased rounded rectangle within the given bounds
+fn draw_rounded_tile(buffer: &mut image::RgbaImage, bounds: Bounds, color: [u8; 4]) {
+    let (left, top, right, bottom) = bounds;
+    let (w, h) = ((right - left) as f64, (bottom - top) as f64);
+    let gap = (w.min(h) * 0.08).max(0.5);
+    let radius = w.min(h) * 0.25;
+    let (half_w, half_h) = (w / 2.0 - gap, h / 2.0 - gap);
+    let (cx, cy) = (left as f64 + w / 2.0, top as f64 + h / 2.0);
+
+    let (width, height) = buffer.dimensions();
+    let xs = left.max(0) as u32..right.clamp(0, width as i32) as u32;
+    let ys = top.max(0) as u32..bottom.clamp(0, height as i32) as u32;
+    for py in ys {
+        for px in xs.clone() {
+            // Signed distance from the pixel center to the rounded rectangle's border
+            let qx = (px as f64 + 0.5 - cx).abs() - (half_w - radius);
+            let qy = (py as f64 + 0.5 - cy).abs() - (half_h - radius);
+            let outside = qx.max(0.0).hypot(qy.max(0.0));
+            let distance = outside + qx.max(qy).min(0.0) - radius;
+            let coverage = (0.5 - distance).clamp(0.0, 1.0);
+            if coverage <= 0.0 {
+                continue;
+            }
+            // Darken slightly towards the bottom for a soft, raised look
+            let shade = 1.0 - 0.15 * (py as f64 + 0.5 - top as f64) / h;
+            buffer.put_pixel(
+                px,
+                py,
+                image::Rgba([
+                    (color[0] as f64 * shade) as u8,
+                    (color[1] as f64 * shade) as u8,
+                    (color[2] as f64 * shade) as u8,
+                    (color[3] as f64 * coverage) as u8,
+                ]),
+            );
+        }
+    }
+}
+
+/// Draws the outline of the given bounds with lines of the given width, on the inside
+fn draw_outline(buffer: &mut image::RgbaImage, bounds: Bounds, width: u32, color: image::Rgba<u8>) {
+    let (left, top, right, bottom) = bounds;
+    let (w, h) = ((right - left) as u32, (bottom - top) as u32);
+    let edges = [
+        Rect::at(left, top).of_size(w, width),
+        Rect::at(left, bottom - width as i32).of_size(w, width),
+        Rect::at(left, top).of_size(width, h),
+        Rect::at(right - width as i32, top).of_size(width, h),
+    ];
+    for edge in edges.iter() {
+        draw_filled_rect_mut(buffer, *edge, color);
+    }
 }
 
 impl TileHeatmap {
@@ -76,81 +581,248 @@ impl TileHeatmap {
         Self {
             map,
             heatmap: vec![0; size],
+            visits: vec![None; size],
             height,
             width,
             min: coord! { x: min.x.floor() as u32, y: min.y.floor() as u32 },
             max: coord! { x: max.x.ceil() as u32, y: max.y.ceil() as u32 },
             max_value: 0,
+            fixed_max: None,
+            clip_percentile: None,
+            cap: None,
+            spread_overflow: false,
             zoom,
+            outlines: false,
+            rounded: false,
+            period: None,
+            visited_periods: vec![],
         }
     }
 
+    /// Normalize colors to the given heat value instead of the highest one, so that renders
+    /// can be compared
+    pub fn with_fixed_max(mut self, max: Option<f64>) -> Self {
+        self.fixed_max = max;
+        self
+    }
+
+    /// Normalize colors to the count below which the given fraction of visited tiles stays
+    /// instead of the highest one, so that a few hot tiles do not fade all others
+    pub fn with_clip_percentile(mut self, fraction: Option<f64>) -> Self {
+        self.clip_percentile = fraction;
+        self
+    }
+
+    /// Color tiles by at most the given count, so that a single tile ridden over and over, e.g.,
+    /// a training loop, does not fade all others; the count beyond it is spread over the
+    /// visited tiles around it if requested, as far as needed to place it, and dropped otherwise
+    pub fn with_cap(mut self, cap: Option<u32>, spread_overflow: bool) -> Self {
+        self.cap = cap;
+        self.spread_overflow = spread_overflow;
+        self
+    }
+
+    /// Color tiles from red to green by the number of distinct periods they were visited in,
+    /// telling habitual from one-off territory, and use heat only for opacity
+    pub fn with_period_colors(mut self, period: Period) -> Self {
+        self.period = Some(period);
+        self.visited_periods = vec![HashSet::new(); self.heatmap.len()];
+        self
+    }
+
+    /// Render tiles as softly shaded rounded squares with anti-aliased borders
+    pub fn with_rounded_tiles(mut self, rounded: bool) -> Self {
+        self.rounded = rounded;
+        self
+    }
+
+    /// Outline the explorer max square and max cluster when rendering
+    pub fn with_outlines(mut self, outlines: bool) -> Self {
+        self.outlines = outlines;
+        self
+    }
+
     #[inline]
-    fn get_tile_mut(&mut self, point: &Coord<u32>) -> Option<&mut u32> {
+    fn get_index(&self, point: &Coord<u32>) -> Option<usize> {
         if self.min.x <= point.x
             && point.x < self.max.x
             && self.min.y <= point.y
             && point.y < self.max.y
         {
-            let index = ((point.x - self.min.x) + ((point.y - self.min.y) * self.width)) as usize;
-            return Some(&mut self.heatmap[index]);
+            return Some(((point.x - self.min.x) + ((point.y - self.min.y) * self.width)) as usize);
         }
         None
     }
 
-    /// Tile size on the projected map, in pixels
-    fn get_tile_size(&self) -> u32 {
-        let c1 = slippy::from_tile(
-            Point::new(self.min.x as f64 + 1.0, self.min.y as f64 + 1.0),
-            self.zoom,
-        );
-        let c2 = slippy::from_tile(
-            Point::new(self.min.x as f64 + 2.0, self.min.y as f64 + 2.0),
-            self.zoom,
-        );
-        let t1 = self.map.to_pixels(&c1).unwrap();
-        let t2 = self.map.to_pixels(&c2).unwrap();
-        t2.x - t1.x
+    /// Draws outlines of the explorer max square and max cluster
+    fn draw_outlines(&self, buffer: &mut image::RgbaImage) {
+        let explorer = self.visited_tiles().unwrap().explorer();
+        let width = 2;
+
+        let cluster_color = image::Rgba([64u8, 160, 255, 255]);
+        for &tile in explorer.max_cluster.iter() {
+            let (left, top, right, bottom) = self.tile_bounds(tile);
+            let (tx, ty) = tile;
+            // Only draw the edges facing tiles outside of the cluster
+            let edges = [
+                ((tx, ty.wrapping_sub(1)), (left, top, right, top + width)),
+                ((tx, ty + 1), (left, bottom - width, right, bottom)),
+                ((tx.wrapping_sub(1), ty), (left, top, left + width, bottom)),
+                ((tx + 1, ty), (right - width, top, right, bottom)),
+            ];
+            for (neighbor, edge) in edges.iter() {
+                if !explorer.max_cluster.contains(neighbor) {
+                    let (l, t, r, b) = *edge;
+                    let rect = Rect::at(l, t).of_size((r - l) as u32, (b - t) as u32);
+                    draw_filled_rect_mut(buffer, rect, cluster_color);
+                }
+            }
+        }
+
+        if let Some(square) = explorer.max_square {
+            let square_color = image::Rgba([255u8, 220, 0, 255]);
+            let (left, top, _, _) = self.tile_bounds((square.x, square.y));
+            let last = square.size - 1;
+            let (_, _, right, bottom) = self.tile_bounds((square.x + last, square.y + last));
+            draw_outline(
+                buffer,
+                (left, top, right, bottom),
+                width as u32,
+                square_color,
+            );
+        }
+    }
+
+    /// Pixel bounds of a tile on the projected map, computed from its own corners and possibly
+    /// extending beyond the canvas
+    fn tile_bounds(&self, (x, y): Tile) -> Bounds {
+        let corner = |dx: u32, dy: u32| {
+            let coord = slippy::from_tile(Point::new((x + dx) as f64, (y + dy) as f64), self.zoom);
+            self.map.project(&coord)
+        };
+        let (top_left, bottom_right) = (corner(0, 0), corner(1, 1));
+        (
+            top_left.x().round() as i32,
+            top_left.y().round() as i32,
+            bottom_right.x().round() as i32,
+            bottom_right.y().round() as i32,
+        )
     }
 
-    /// Offset for the origin tile on the projected map, in pixels
-    fn get_tile_offset(&self) -> (i32, i32) {
-        let c1 = slippy::from_tile(
-            Point::new(self.min.x as f64 + 1.0, self.min.y as f64 + 1.0),
-            self.zoom,
-        );
-        let t1 = self.map.to_pixels(&c1).unwrap();
-        let size = self.get_tile_size() as i32;
-        (t1.x as i32 - size, t1.y as i32 - size)
+    /// Pixel rect of a tile clipped to the canvas, or None if no part of it is visible
+    fn tile_rect(&self, tile: Tile) -> Option<Rect> {
+        let (width, height) = self.map.pixel_size();
+        let (left, top, right, bottom) = self.tile_bounds(tile);
+        let (left, top) = (left.max(0), top.max(0));
+        let (right, bottom) = (right.min(width as i32), bottom.min(height as i32));
+        if left < right && top < bottom {
+            Some(Rect::at(left, top).of_size((right - left) as u32, (bottom - top) as u32))
+        } else {
+            None
+        }
     }
-}
 
-impl Heatmap for TileHeatmap {
-    fn as_image(&self) -> image::DynamicImage {
+    #[inline]
+    fn get_tile_mut(&mut self, point: &Coord<u32>) -> Option<&mut u32> {
+        let index = self.get_index(point)?;
+        Some(&mut self.heatmap[index])
+    }
+
+    /// Counts of all tiles as colored: capped if requested, with what exceeds the cap shared
+    /// equally by the visited tiles left, right, above and below that are not full yet, and
+    /// passed on again by those it fills, until all of it is placed or no such tile is left
+    fn colored_counts(&self) -> Vec<f64> {
+        let mut counts: Vec<f64> = self.heatmap.iter().map(|&count| count as f64).collect();
+        let cap = match self.cap {
+            Some(cap) => cap as f64,
+            None => return counts,
+        };
+        if !self.spread_overflow {
+            for count in counts.iter_mut() {
+                *count = count.min(cap);
+            }
+            return counts;
+        }
+        let (width, height) = (self.width as usize, self.height as usize);
+        // Tiles fill up once and never take more, so every tile overflows at most once
+        let mut overflowing: Vec<usize> = (0..counts.len()).filter(|&i| counts[i] > cap).collect();
+        while let Some(index) = overflowing.pop() {
+            let excess = counts[index] - cap;
+            counts[index] = cap;
+            let (x, y) = (index % width, index / width);
+            let open: Vec<usize> = [
+                (x > 0).then(|| index - 1),
+                (x + 1 < width).then(|| index + 1),
+                (y > 0).then(|| index - width),
+                (y + 1 < height).then(|| index + width),
+            ]
+            .iter()
+            .flatten()
+            .copied()
+            .filter(|&neighbor| self.heatmap[neighbor] > 0 && counts[neighbor] < cap)
+            .collect();
+            // Without such neighbors, the excess is dropped
+            for &neighbor in &open {
+                counts[neighbor] += excess / open.len() as f64;
+                if counts[neighbor] > cap {
+                    overflowing.push(neighbor);
+                }
+            }
+        }
+        counts
+    }
+
+    /// Colors heat, around the hue wheel if a phase is given
+    fn colorize(&self, phase: Option<f64>) -> image::DynamicImage {
         let (width, height) = self.map.pixel_size();
         let mut buffer = ImageBuffer::new(width, height);
 
-        let (x0, y0) = self.get_tile_offset();
-        let tile_size = self.get_tile_size();
-
+        let max_periods = self.visited_periods.iter().map(|p| p.len()).max();
+        let max_value = self.max_value();
+        let counts = self.colored_counts();
         for x in 0..self.width {
             for y in 0..self.height {
-                let count = self.heatmap[(x + y * self.width) as usize];
-                let heat = if count > 0 {
-                    (count as f64 + 1.0).log10() / (self.max_value as f64 + 1.0).log10() * 250.0
-                        + 6.0
-                } else {
-                    0.0
+                let index = (x + y * self.width) as usize;
+                let count = counts[index];
+                if count <= 0.0 {
+                    continue;
+                }
+                let mut color = match phase {
+                    Some(phase) => cycled_heat_color(count, max_value, phase),
+                    None => heat_color(count, max_value),
                 };
-                let color = image::Rgba([heat as u8, 0, 0, heat as u8]);
-                let pos = Rect::at(x0 + (x * tile_size) as i32, y0 + (y * tile_size) as i32)
-                    .of_size(tile_size, tile_size);
-                draw_filled_rect_mut(&mut buffer, pos, color);
+                if let Some(max_periods) = max_periods {
+                    // A single period is red, the most periods of any tile green
+                    let periods = self.visited_periods[index].len().max(1);
+                    let fraction = (periods - 1) as f64 / (max_periods.max(2) - 1) as f64;
+                    let [r, g, b] = gradient_color(&TRAFFIC_LIGHT_GRADIENT, fraction);
+                    color = [r, g, b, color[3]];
+                }
+                let tile = (x + self.min.x, y + self.min.y);
+                if self.rounded {
+                    draw_rounded_tile(&mut buffer, self.tile_bounds(tile), color);
+                } else if let Some(rect) = self.tile_rect(tile) {
+                    draw_filled_rect_mut(&mut buffer, rect, image::Rgba(color));
+                }
             }
         }
 
+        if self.outlines {
+            self.draw_outlines(&mut buffer);
+        }
+
         image::DynamicImage::ImageRgba8(buffer)
     }
+}
+
+impl Heatmap for TileHeatmap {
+    fn as_image(&self) -> image::DynamicImage {
+        self.colorize(None)
+    }
+
+    fn as_image_at_phase(&self, phase: f64) -> image::DynamicImage {
+        self.colorize(Some(phase))
+    }
 
     /// Not supported
     fn as_image_with_overlay(
@@ -172,6 +844,18 @@ impl Heatmap for TileHeatmap {
         self.max_value = self.max_value.max(px);
     }
 
+    fn add_point_at(&mut self, point: &Coord<u32>, date: &chrono::DateTime<chrono::Utc>) {
+        self.add_point(point);
+        if let Some(index) = self.get_index(point) {
+            let visit = self.visits[index].get_or_insert((*date, *date));
+            visit.0 = visit.0.min(*date);
+            visit.1 = visit.1.max(*date);
+            if let Some(period) = self.period {
+                self.visited_periods[index].insert(period.index(date));
+            }
+        }
+    }
+
     #[allow(dead_code)]
     fn decay(&mut self, amount: u32) {
         self.max_value -= 1;
@@ -185,81 +869,599 @@ impl Heatmap for TileHeatmap {
 
     // Returns None if point is off screen.
     fn project_to_screen(&self, coord: &Point<f64>) -> Option<Coord<u32>> {
-        let raw_mapped = slippy::to_tile(*coord, self.zoom);
-        let x = raw_mapped.x().floor() as u32;
-        let y = raw_mapped.y().floor() as u32;
+        self.project_world_to_screen(&slippy::to_world(*coord))
+    }
+
+    // Returns None if point is off screen.
+    fn project_world_to_screen(&self, world: &Point<f64>) -> Option<Coord<u32>> {
+        let scale = 2u32.pow(self.zoom as u32) as f64;
+        let x = (world.x() * scale).floor() as u32;
+        let y = (world.y() * scale).floor() as u32;
         if self.min.x <= x && x < self.max.x && self.min.y <= y && y < self.max.y {
             return Some(coord! { x: x, y: y });
         }
         None
     }
+
+    fn project_world(&self, world: &Point<f64>) -> Point<f64> {
+        *world * 2u32.pow(self.zoom as u32) as f64
+    }
+
+    fn screen_bounds(&self) -> (Point<f64>, Point<f64>) {
+        (
+            Point::new(self.min.x as f64, self.min.y as f64),
+            Point::new(self.max.x as f64, self.max.y as f64),
+        )
+    }
+
+    fn max_value(&self) -> f64 {
+        match (self.fixed_max, self.clip_percentile) {
+            (Some(max), _) => max,
+            (None, Some(fraction)) => self.heat_percentile(fraction),
+            (None, None) if self.cap.is_some() => {
+                self.colored_counts().into_iter().fold(0.0, f64::max)
+            }
+            (None, None) => self.max_value as f64,
+        }
+    }
+
+    fn unit(&self) -> &'static str {
+        "points per tile"
+    }
+
+    fn heat_percentile(&self, fraction: f64) -> f64 {
+        let visited: Vec<f64> = self
+            .colored_counts()
+            .into_iter()
+            .filter(|&count| count > 0.0)
+            .collect();
+        percentile(visited, fraction)
+    }
+
+    fn visited_tiles(&self) -> Option<VisitedTiles> {
+        let mut tiles = HashMap::new();
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let index = (x + y * self.width) as usize;
+                let count = self.heatmap[index];
+                if count > 0 {
+                    let visit = Visit {
+                        count,
+                        first: self.visits[index].map(|(first, _)| first),
+                        last: self.visits[index].map(|(_, last)| last),
+                    };
+                    tiles.insert((x + self.min.x, y + self.min.y), visit);
+                }
+            }
+        }
+        Some(VisitedTiles {
+            zoom: self.zoom,
+            tiles,
+        })
+    }
+
+    fn save_state(&self, writer: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+        HeatState::Tiles {
+            zoom: self.zoom,
+            min: (self.min.x, self.min.y),
+            width: self.width,
+            counts: self.heatmap.clone(),
+            visits: self.visits.clone(),
+            visited_periods: self.visited_periods.clone(),
+        }
+        .write(writer)
+    }
+
+    fn load_state(&mut self, reader: &mut dyn Read) -> Result<(), Box<dyn Error>> {
+        let (min, width, counts, visits, visited_periods) = match HeatState::read(reader)? {
+            HeatState::Tiles {
+                zoom,
+                min,
+                width,
+                counts,
+                visits,
+                visited_periods,
+            } if zoom == self.zoom => (min, width, counts, visits, visited_periods),
+            HeatState::Tiles { zoom, .. } => {
+                return Err(format!("state has tiles of zoom {}, not {}", zoom, self.zoom).into())
+            }
+            HeatState::Pixel { .. } => return Err(Box::from("state is of a pixel heatmap")),
+        };
+        for (i, count) in counts.into_iter().enumerate() {
+            let i = i as u32;
+            let tile = coord! { x: min.0 + i % width, y: min.1 + i / width };
+            let index = match self.get_index(&tile) {
+                Some(index) if count > 0 => index,
+                _ => continue,
+            };
+            self.heatmap[index] += count;
+            self.max_value = self.max_value.max(self.heatmap[index]);
+            if let Some((first, last)) = visits.get(i as usize).copied().flatten() {
+                let visit = self.visits[index].get_or_insert((first, last));
+                visit.0 = visit.0.min(first);
+                visit.1 = visit.1.max(last);
+            }
+            if let (Some(periods), Some(visited)) = (
+                self.visited_periods.get_mut(index),
+                visited_periods.get(i as usize),
+            ) {
+                periods.extend(visited);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The value below which the given fraction of values stays, or 0 if there are none
+fn percentile(mut values: Vec<f64>, fraction: f64) -> f64 {
+    if values.is_empty() {
+        return 0.0;
+    }
+    let index = ((values.len() - 1) as f64 * fraction.clamp(0.0, 1.0)).round() as usize;
+    *values
+        .select_nth_unstable_by(index, |a, b| a.total_cmp(b))
+        .1
+}
+
+/// Gaussian weights with unit peak, cut off at three standard deviations
+fn gaussian_kernel(sigma: f64) -> Vec<f64> {
+    let radius = (3.0 * sigma).ceil() as i64;
+    (-radius..=radius)
+        .map(|x| (-(x * x) as f64 / (2.0 * sigma * sigma)).exp())
+        .collect()
+}
+
+/// Rows and columns of heat beyond a view that a blur of the given standard deviation carries
+/// into it, in pixels
+pub fn blur_reach(sigma: f64) -> u32 {
+    if sigma > 0.0 {
+        (gaussian_kernel(sigma).len() / 2) as u32
+    } else {
+        0
+    }
+}
+
+/// Blurs a row-major grid, first along rows, then along columns
+fn blur(values: &[f64], width: usize, height: usize, sigma: f64) -> Vec<f64> {
+    let kernel = gaussian_kernel(sigma);
+    let radius = (kernel.len() / 2) as i64;
+    let convolve = |get: &dyn Fn(i64) -> f64, len: i64, i: i64| -> f64 {
+        kernel
+            .iter()
+            .enumerate()
+            .map(|(k, w)| (i + k as i64 - radius, w))
+            .filter(|&(j, _)| 0 <= j && j < len)
+            .map(|(j, w)| w * get(j))
+            .sum()
+    };
+
+    let mut rows = vec![0.0; values.len()];
+    rows.par_chunks_mut(width).enumerate().for_each(|(y, row)| {
+        let src = &values[y * width..(y + 1) * width];
+        for (x, px) in row.iter_mut().enumerate() {
+            *px = convolve(&|j| src[j as usize], width as i64, x as i64);
+        }
+    });
+
+    let mut result = vec![0.0; values.len()];
+    result
+        .par_chunks_mut(width)
+        .enumerate()
+        .for_each(|(y, row)| {
+            for (x, px) in row.iter_mut().enumerate() {
+                *px = convolve(&|j| rows[j as usize * width + x], height as i64, y as i64);
+            }
+        });
+    result
 }
 
 pub struct PixelHeatmap {
     map: slippy::Map,
-    heatmap: Vec<u32>,
+    /// Heat per pixel, in single precision to keep the memory use of counting points
+    heatmap: Grid<f32>,
     height: u32,
     width: u32,
-    max_value: u32,
+    max_value: f32,
+    /// Normalize colors to this value instead of the highest count
+    fixed_max: Option<f64>,
+    /// Normalize colors to the heat below which this fraction of visited pixels stays
+    clip_percentile: Option<f64>,
+    /// Heat is the distance traveled to reach points rather than their number
+    distance_weighted: bool,
+    /// Heat of older activities counts less
+    age_weighted: bool,
     render_date: bool,
     render_title: bool,
+    /// Standard deviation of the Gaussian spreading heat around tracks, in pixels
+    blur: f64,
+    /// Color by the mean of a measurement between these bounds instead of by heat; missing
+    /// bounds are taken from the data
+    measurement_bounds: Option<(Measurement, Option<f64>, Option<f64>)>,
+    /// Sum and number of measurements recorded per pixel, empty unless colored by one; both
+    /// are always written together, so that their stored values line up
+    measurement_sums: Grid<f64>,
+    measurement_counts: Grid<u32>,
+    /// Top left and bottom right pixel, inclusive, of the heat added since the last update of
+    /// an image
+    dirty: Option<(Coord<u32>, Coord<u32>)>,
+    /// Heat value the image of the last update was normalized against, None to recolor all of
+    /// it
+    updated_max: Option<f64>,
+    /// Heat value colors are normalized against, kept until heat changes, so that asking for it
+    /// again does not blur and sort all pixels anew
+    normalized_max: Mutex<Option<f64>>,
+}
+
+/// Heat per pixel as colored
+enum Heat<'a> {
+    /// Spread by a blur, in row-major order
+    Blurred { values: Vec<f64>, width: u32 },
+    /// As accumulated, read without copying the heatmap
+    Raw(&'a Grid<f32>),
+}
+
+impl Heat<'_> {
+    fn get(&self, x: u32, y: u32) -> f64 {
+        match self {
+            Heat::Blurred { values, width } => values[y as usize * *width as usize + x as usize],
+            Heat::Raw(grid) => grid.get(x, y) as f64,
+        }
+    }
+
+    /// The percentile of the heat of visited places, i.e., those with any heat
+    fn visited_percentile(&self, fraction: f64) -> f64 {
+        let visited = match self {
+            Heat::Blurred { values, .. } => values.iter().cloned().filter(|&h| h > 0.0).collect(),
+            Heat::Raw(grid) => grid.stored().filter(|&h| h > 0.0).map(f64::from).collect(),
+        };
+        percentile(visited, fraction)
+    }
+}
+
+/// Mean measurement per pixel as colored
+enum Means<'a> {
+    /// Spread by a blur, in row-major order
+    Blurred { means: Vec<Option<f64>>, width: u32 },
+    /// Divided out of the recorded sums and counts when read
+    Raw {
+        sums: &'a Grid<f64>,
+        counts: &'a Grid<u32>,
+    },
+}
+
+impl Means<'_> {
+    /// The mean at a pixel, None where none was recorded
+    fn get(&self, x: u32, y: u32) -> Option<f64> {
+        match self {
+            Means::Blurred { means, width } => means[y as usize * *width as usize + x as usize],
+            Means::Raw { sums, counts } => {
+                let count = counts.get(x, y);
+                (count > 0).then(|| sums.get(x, y) / count as f64)
+            }
+        }
+    }
+
+    /// All means recorded, in no particular order
+    fn known(&self) -> Box<dyn Iterator<Item = f64> + '_> {
+        match self {
+            Means::Blurred { means, .. } => Box::new(means.iter().filter_map(|&e| e)),
+            Means::Raw { sums, counts } => Box::new(
+                sums.stored()
+                    .zip(counts.stored())
+                    .filter(|&(_, count)| count > 0)
+                    .map(|(sum, count)| sum / count as f64),
+            ),
+        }
+    }
+}
+
+/// Bounds of measurement colors, those not given being the lowest and highest mean seen
+fn resolve_bounds(means: &Means, min: Option<f64>, max: Option<f64>) -> (f64, f64) {
+    let min = min.unwrap_or_else(|| means.known().fold(f64::INFINITY, f64::min));
+    let max = max.unwrap_or_else(|| means.known().fold(f64::NEG_INFINITY, f64::max));
+    (min, max)
 }
 
 impl PixelHeatmap {
     pub fn from(map: slippy::Map, render_date: bool, render_title: bool) -> Self {
         let (width, height) = map.pixel_size();
-        let size = (width * height) as usize;
 
         Self {
             map,
-            heatmap: vec![0; size],
+            heatmap: Grid::new(width, height),
             height,
             width,
-            max_value: 0,
+            max_value: 0.0,
+            fixed_max: None,
+            clip_percentile: None,
+            distance_weighted: false,
+            age_weighted: false,
             render_date,
             render_title,
+            blur: 0.0,
+            measurement_bounds: None,
+            measurement_sums: Grid::default(),
+            measurement_counts: Grid::default(),
+            dirty: None,
+            updated_max: None,
+            normalized_max: Mutex::new(None),
         }
     }
 
-    #[inline]
-    fn get_pixel_mut(&mut self, point: &Coord<u32>) -> Option<&mut u32> {
-        if point.x >= self.width || point.y >= self.height {
-            return None;
-        }
+    /// Spread heat smoothly around tracks with a Gaussian of the given standard deviation, in
+    /// pixels
+    pub fn with_blur(mut self, sigma: f64) -> Self {
+        self.blur = sigma;
+        self.forget_normalization();
+        self
+    }
 
-        let index = (point.x + (point.y * self.width)) as usize;
-        Some(&mut self.heatmap[index])
+    /// Normalize colors to the given heat value instead of the highest one, so that renders
+    /// can be compared
+    pub fn with_fixed_max(mut self, max: Option<f64>) -> Self {
+        self.fixed_max = max;
+        self
     }
-}
 
-impl Heatmap for PixelHeatmap {
-    fn as_image(&self) -> image::DynamicImage {
-        let color_map = self
-            .heatmap
-            .clone()
+    /// Normalize colors to the heat below which the given fraction of visited pixels stays
+    /// instead of the highest one, so that a few hot spots do not fade everything else
+    pub fn with_clip_percentile(mut self, fraction: Option<f64>) -> Self {
+        self.clip_percentile = fraction;
+        self.forget_normalization();
+        self
+    }
+
+    /// Weigh points by the distance traveled to reach them, so that stops do not pile up heat;
+    /// points are then added with `add_weighted_point`
+    pub fn with_distance_weights(mut self, weighted: bool) -> Self {
+        self.distance_weighted = weighted;
+        self
+    }
+
+    /// Note that points are weighted by the age of their activity, which the unit of the heat
+    /// tells
+    pub fn with_age_weights(mut self, weighted: bool) -> Self {
+        self.age_weighted = weighted;
+        self
+    }
+
+    /// Color tracks by the mean of a measurement between the given bounds and use heat only for
+    /// opacity; bounds not given are the lowest and highest value seen
+    pub fn with_measurement_colors(
+        mut self,
+        measurement: Measurement,
+        min: Option<f64>,
+        max: Option<f64>,
+    ) -> Self {
+        self.measurement_bounds = Some((measurement, min, max));
+        self.measurement_sums = Grid::new(self.width, self.height);
+        self.measurement_counts = Grid::new(self.width, self.height);
+        self
+    }
+
+    /// The view of the heatmap
+    pub fn map(&self) -> &slippy::Map {
+        &self.map
+    }
+
+    /// Whether colors depend on nothing but the heat and the value it is normalized against,
+    /// i.e., there is no blur, percentile clipping or measurement
+    pub fn plain_colors(&self) -> bool {
+        self.blur <= 0.0 && self.clip_percentile.is_none() && self.measurement_bounds.is_none()
+    }
+
+    /// The heat value colors are normalized against, if fixed
+    pub fn fixed_max(&self) -> Option<f64> {
+        self.fixed_max
+    }
+
+    /// Adds heat per pixel in row-major order, e.g., accumulated elsewhere; only pixels with
+    /// heat are touched, so that chunked heatmaps stay sparse
+    pub fn add_heat(&mut self, heat: &[f32]) {
+        self.updated_max = None;
+        self.forget_normalization();
+        let width = self.width as usize;
+        for (index, &h) in heat.iter().enumerate().filter(|&(_, &h)| h != 0.0) {
+            let (x, y) = ((index % width) as u32, (index / width) as u32);
+            if let Some(px) = self.heatmap.get_mut(x, y) {
+                *px += h;
+                self.max_value = self.max_value.max(*px);
+            }
+        }
+    }
+
+    /// Mean measurement per pixel, blurred if requested
+    fn mean_measurements(&self) -> Means<'_> {
+        if self.blur <= 0.0 {
+            return Means::Raw {
+                sums: &self.measurement_sums,
+                counts: &self.measurement_counts,
+            };
+        }
+        let (width, height) = (self.width as usize, self.height as usize);
+        let counts: Vec<f64> = self
+            .measurement_counts
+            .to_vec()
+            .into_par_iter()
+            .map(|c| c as f64)
+            .collect();
+        let sums = blur(&self.measurement_sums.to_vec(), width, height, self.blur);
+        let counts = blur(&counts, width, height, self.blur);
+        let means = sums
             .into_par_iter()
-            .map(|count| {
-                if count == 0 {
-                    return [0u8, 0, 0, 0];
+            .zip(counts)
+            .map(|(sum, count)| {
+                if count > 1e-9 {
+                    Some(sum / count)
+                } else {
+                    None
                 }
+            })
+            .collect();
+        Means::Blurred {
+            means,
+            width: self.width,
+        }
+    }
 
-                let heat = ((count as f64 + 1.0).log10() / (self.max_value as f64 + 1.0).log10()
-                    * 250.0
-                    + 6.0) as u8;
+    /// Heat per pixel, blurred if requested, and its highest value
+    fn heat(&self) -> (Heat<'_>, f64) {
+        if self.blur > 0.0 {
+            let counts: Vec<f64> = self
+                .heatmap
+                .to_vec()
+                .into_par_iter()
+                .map(|c| c as f64)
+                .collect();
+            let blurred = blur(
+                &counts,
+                self.width as usize,
+                self.height as usize,
+                self.blur,
+            );
+            let max_value = blurred.par_iter().cloned().reduce(|| 0.0, f64::max);
+            let values = Heat::Blurred {
+                values: blurred,
+                width: self.width,
+            };
+            (values, max_value)
+        } else {
+            (Heat::Raw(&self.heatmap), self.max_value as f64)
+        }
+    }
 
-                [heat, 0, 0, heat]
-            })
-            .collect::<Vec<_>>();
+    /// Heat value colors are normalized against, given the heat and its highest value
+    fn normalization(&self, heat: &Heat, max_value: f64) -> f64 {
+        match (self.fixed_max, self.clip_percentile) {
+            (Some(max), _) => max,
+            (None, Some(fraction)) => heat.visited_percentile(fraction),
+            (None, None) => max_value,
+        }
+    }
 
-        let size = (self.width * self.height * 4) as usize;
-        let mut pixels = Vec::with_capacity(size);
+    /// The view and unit of the heat, to check saved states against
+    fn view(&self) -> PixelView {
+        let origin = self.map.tile_origin();
+        PixelView {
+            origin: (origin.x(), origin.y()),
+            width: self.width,
+            height: self.height,
+            zoom: self.map.zoom(),
+            unit: self.unit().to_string(),
+        }
+    }
 
-        for pxls in color_map.iter() {
-            pixels.extend_from_slice(&pxls[..]);
+    #[inline]
+    fn get_pixel_mut(&mut self, point: &Coord<u32>) -> Option<&mut f32> {
+        self.heatmap.get_mut(point.x, point.y)
+    }
+
+    /// Forgets the cached normalization, e.g., once heat changed
+    #[inline]
+    fn forget_normalization(&mut self) {
+        *self.normalized_max.get_mut().unwrap() = None;
+    }
+
+    /// Colors heat, around the hue wheel if a phase is given
+    fn colorize(&self, phase: Option<f64>) -> image::DynamicImage {
+        let (heat, max_value) = self.heat();
+        // Normalized against the heat colored here, so that `max_value` need not blur it again
+        let max_value = *self
+            .normalized_max
+            .lock()
+            .unwrap()
+            .get_or_insert_with(|| self.normalization(&heat, max_value));
+        let measured = self.measurement_bounds.map(|(measurement, min, max)| {
+            let means = self.mean_measurements();
+            let (min, max) = resolve_bounds(&means, min, max);
+            (measurement, means, min, (max - min).max(f64::EPSILON))
+        });
+        let color = |x: u32, y: u32| {
+            let count = heat.get(x, y);
+            match (&measured, phase) {
+                (Some((measurement, means, min, range)), _) => {
+                    let alpha = heat_color(count, max_value)[3];
+                    if alpha == 0 {
+                        return [0; 4];
+                    }
+                    // Points without the measurement stay neutral
+                    let [r, g, b] = means
+                        .get(x, y)
+                        .map_or([255; 3], |v| measurement.color((v - min) / range));
+                    [r, g, b, alpha]
+                }
+                (None, Some(phase)) => cycled_heat_color(count, max_value, phase),
+                (None, None) => heat_color(count, max_value),
+            }
+        };
+
+        // Colored row by row, without an intermediate copy of the whole canvas
+        let mut image = image::RgbaImage::new(self.width, self.height);
+        image
+            .par_chunks_mut(4 * self.width as usize)
+            .enumerate()
+            .for_each(|(y, row)| {
+                for (x, px) in row.chunks_exact_mut(4).enumerate() {
+                    px.copy_from_slice(&color(x as u32, y as u32));
+                }
+            });
+        image::DynamicImage::ImageRgba8(image)
+    }
+}
+
+impl Heatmap for PixelHeatmap {
+    fn as_image(&self) -> image::DynamicImage {
+        self.colorize(None)
+    }
+
+    fn as_image_at_phase(&self, phase: f64) -> image::DynamicImage {
+        self.colorize(Some(phase))
+    }
+
+    /// Recolors only the heat added since the last update as long as colors depend on nothing
+    /// else and the normalization stays the same
+    fn update_image(&mut self, image: &mut image::RgbaImage) -> Option<(u32, u32, u32, u32)> {
+        let recolor = |heatmap: &mut Self, image: &mut image::RgbaImage| {
+            heatmap.dirty = None;
+            *image = heatmap.as_image().to_rgba8();
+            Some((0, 0, heatmap.width, heatmap.height))
+        };
+        // Blurred or clipped heat is recolored as a whole, only colored once
+        if !self.plain_colors() {
+            self.updated_max = None;
+            return recolor(self, image);
+        }
+        let max_value = self.max_value();
+        let current =
+            self.updated_max == Some(max_value) && image.dimensions() == (self.width, self.height);
+        self.updated_max = Some(max_value);
+        if !current {
+            return recolor(self, image);
         }
+        let (min, max) = self.dirty.take()?;
+        for y in min.y..=max.y {
+            for x in min.x..=max.x {
+                let count = self.heatmap.get(x, y) as f64;
+                image.put_pixel(x, y, image::Rgba(heat_color(count, max_value)));
+            }
+        }
+        Some((min.x, min.y, max.x - min.x + 1, max.y - min.y + 1))
+    }
 
-        let buffer = ImageBuffer::from_raw(self.width, self.height, pixels).unwrap();
-        image::DynamicImage::ImageRgba8(buffer)
+    /// Without the quantization of colors, so that no levels are lost
+    fn as_mask(&self) -> ImageBuffer<Luma<u16>, Vec<u16>> {
+        let (heat, max_value) = self.heat();
+        let max_value = self.normalization(&heat, max_value);
+        let mut mask = ImageBuffer::new(self.width, self.height);
+        mask.par_chunks_mut(self.width as usize)
+            .enumerate()
+            .for_each(|(y, row)| {
+                for (x, level) in row.iter_mut().enumerate() {
+                    let count = heat.get(x as u32, y as u32);
+                    *level = (heat_level(count, max_value).min(255.0) * 257.0).round() as u16;
+                }
+            });
+        mask
     }
 
     fn as_image_with_overlay(
@@ -268,6 +1470,7 @@ impl Heatmap for PixelHeatmap {
         date: &chrono::DateTime<chrono::Utc>,
     ) -> image::DynamicImage {
         let mut image = self.as_image();
+        let font = FONT.as_ref().expect("Cannot load font");
 
         let white = image::Rgba([255; 4]);
         let scale = Scale::uniform(self.height as f32 / 15.0);
@@ -277,12 +1480,12 @@ impl Heatmap for PixelHeatmap {
 
         if self.render_date {
             let date_string = date.format("%B %d, %Y").to_string();
-            draw_text_mut(&mut image, white, x, y, scale, &FONT, date_string.as_str());
+            draw_text_mut(&mut image, white, x, y, scale, font, date_string.as_str());
             y -= scale.y as u32;
         }
 
         if self.render_title {
-            draw_text_mut(&mut image, white, x, y, scale, &FONT, name);
+            draw_text_mut(&mut image, white, x, y, scale, font, name);
         }
 
         image
@@ -290,20 +1493,47 @@ impl Heatmap for PixelHeatmap {
 
     #[inline]
     fn add_point(&mut self, point: &Coord<u32>) {
+        self.add_weighted_point(point, 1.0);
+    }
+
+    #[inline]
+    fn add_weighted_point(&mut self, point: &Coord<u32>, weight: f64) {
         let px = {
             let px = self.get_pixel_mut(point).unwrap();
-            *px += 1;
+            *px += weight as f32;
             *px
         };
 
         self.max_value = self.max_value.max(px);
+        self.forget_normalization();
+        self.dirty = Some(match self.dirty {
+            Some((min, max)) => (
+                coord! { x: min.x.min(point.x), y: min.y.min(point.y) },
+                coord! { x: max.x.max(point.x), y: max.y.max(point.y) },
+            ),
+            None => (*point, *point),
+        });
+    }
+
+    fn add_measurement(&mut self, point: &Coord<u32>, measurement: Measurement, value: f64) {
+        let colored = self
+            .measurement_bounds
+            .is_some_and(|(m, _, _)| m == measurement);
+        if !colored || point.x >= self.width || point.y >= self.height {
+            return;
+        }
+        *self.measurement_sums.get_mut(point.x, point.y).unwrap() += value;
+        *self.measurement_counts.get_mut(point.x, point.y).unwrap() += 1;
     }
 
     #[allow(dead_code)]
     fn decay(&mut self, amount: u32) {
-        self.max_value -= 1;
+        self.max_value -= 1.0;
+        self.updated_max = None;
+        self.forget_normalization();
 
-        self.heatmap.par_iter_mut().for_each(|px| {
+        let amount = amount as f32;
+        self.heatmap.update(|px| {
             if *px > amount {
                 *px -= amount;
             }
@@ -317,4 +1547,114 @@ impl Heatmap for PixelHeatmap {
         }
         None
     }
+
+    // Returns None if point is off screen.
+    fn project_world_to_screen(&self, world: &Point<f64>) -> Option<Coord<u32>> {
+        self.map.world_to_pixels(world)
+    }
+
+    fn project_world(&self, world: &Point<f64>) -> Point<f64> {
+        self.map.project_world(world)
+    }
+
+    fn screen_bounds(&self) -> (Point<f64>, Point<f64>) {
+        let (width, height) = self.map.pixel_size();
+        (
+            Point::new(0.0, 0.0),
+            Point::new(width as f64, height as f64),
+        )
+    }
+
+    fn max_value(&self) -> f64 {
+        if let Some(max) = self.fixed_max {
+            return max;
+        }
+        let mut normalized = self.normalized_max.lock().unwrap();
+        *normalized.get_or_insert_with(|| {
+            let (heat, max_value) = self.heat();
+            self.normalization(&heat, max_value)
+        })
+    }
+
+    fn unit(&self) -> &'static str {
+        match (self.distance_weighted, self.age_weighted) {
+            (true, false) => "meters traveled per pixel",
+            (true, true) => "meters traveled per pixel, weighted by age",
+            (false, false) => "points per pixel",
+            (false, true) => "points per pixel, weighted by age",
+        }
+    }
+
+    fn heat_percentile(&self, fraction: f64) -> f64 {
+        self.heat().0.visited_percentile(fraction)
+    }
+
+    fn measurement_range(&self) -> Option<(f64, f64)> {
+        let (_, min, max) = self.measurement_bounds?;
+        match (min, max) {
+            (Some(min), Some(max)) => Some((min, max)),
+            _ => Some(resolve_bounds(&self.mean_measurements(), min, max)),
+        }
+    }
+
+    fn save_state(&self, writer: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+        HeatState::Pixel {
+            view: self.view(),
+            heat: self.heatmap.to_vec(),
+            measurement_sums: self.measurement_sums.to_vec(),
+            measurement_counts: self.measurement_counts.to_vec(),
+        }
+        .write(writer)
+    }
+
+    fn load_state(&mut self, reader: &mut dyn Read) -> Result<(), Box<dyn Error>> {
+        let HeatState::Pixel {
+            view,
+            heat,
+            measurement_sums,
+            measurement_counts,
+        } = HeatState::read(reader)?
+        else {
+            return Err(Box::from("state is of a tile heatmap"));
+        };
+        self.view().check(&view)?;
+        self.add_heat(&heat);
+        // Only heatmaps colored by a measurement keep them
+        if !self.measurement_sums.is_empty() {
+            let width = self.width as usize;
+            let pixel = |index: usize| ((index % width) as u32, (index / width) as u32);
+            let recorded = measurement_sums.into_iter().zip(measurement_counts);
+            for (index, (s, c)) in recorded.enumerate().filter(|&(_, (_, c))| c > 0) {
+                let (x, y) = pixel(index);
+                if let (Some(sum), Some(count)) = (
+                    self.measurement_sums.get_mut(x, y),
+                    self.measurement_counts.get_mut(x, y),
+                ) {
+                    *sum += s;
+                    *count += c;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overflow_spreads_until_placed_or_dropped() {
+        let map = slippy::Map::from(6.1432, 46.2044, 1024, 256, 14);
+        let row = |counts: &[u32]| {
+            let mut heatmap = TileHeatmap::from(map, 14).with_cap(Some(4), true);
+            assert!(heatmap.width as usize >= counts.len());
+            heatmap.heatmap[..counts.len()].copy_from_slice(counts);
+            heatmap.colored_counts()[..counts.len()].to_vec()
+        };
+        // The full neighbor passes the excess on to the next one
+        assert_eq!(row(&[10, 1, 1]), [4.0, 4.0, 4.0]);
+        // Unvisited tiles take nothing, so what does not fit is dropped
+        assert_eq!(row(&[10, 1, 0, 1]), [4.0, 4.0, 0.0, 1.0]);
+    }
 }
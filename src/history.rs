@@ -0,0 +1,127 @@
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use sha2::{Digest, Sha256};
+
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+use super::metadata::Metadata;
+
+/// Location of the history database
+pub fn history_file() -> PathBuf {
+    directories::BaseDirs::new()
+        .unwrap()
+        .data_dir()
+        .join("derive.rs")
+        .join("history.sqlite")
+}
+
+/// A previous invocation producing an image or frames
+#[derive(Debug)]
+pub struct Run {
+    pub id: i64,
+    pub time: DateTime<Utc>,
+    /// Name of the subcommand
+    pub command: String,
+    /// Options of the subcommand as TOML
+    pub config: String,
+    /// Hex SHA-256 of the options, to spot identical runs
+    pub config_hash: String,
+    /// Image written, if not streamed to stdout
+    pub output: Option<String>,
+    /// Metadata of the final image, e.g., the heat maximum
+    pub stats: Metadata,
+}
+
+impl Run {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        let time: String = row.get(1)?;
+        let stats: String = row.get(6)?;
+        Ok(Run {
+            id: row.get(0)?,
+            time: DateTime::parse_from_rfc3339(&time)
+                .map(|t| t.with_timezone(&Utc))
+                .unwrap_or_default(),
+            command: row.get(2)?,
+            config: row.get(3)?,
+            config_hash: row.get(4)?,
+            output: row.get(5)?,
+            stats: serde_json::from_str(&stats).unwrap_or_default(),
+        })
+    }
+}
+
+/// Local database of previous runs
+pub struct History {
+    db: Connection,
+}
+
+impl History {
+    /// Open the history database at the given path, creating it if needed
+    pub fn open(path: &Path) -> Result<Self, Box<dyn Error>> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let db = Connection::open(path)?;
+        db.execute_batch(
+            "CREATE TABLE IF NOT EXISTS runs (id INTEGER PRIMARY KEY, time TEXT, command TEXT,
+                                              config TEXT, config_hash TEXT, output TEXT,
+                                              stats TEXT);",
+        )?;
+        Ok(Self { db })
+    }
+
+    /// Remember a run, returning its id
+    pub fn record(
+        &self,
+        command: &str,
+        config: &str,
+        output: Option<&str>,
+        stats: &Metadata,
+    ) -> Result<i64, Box<dyn Error>> {
+        let hash = format!("{:x}", Sha256::digest(config.as_bytes()));
+        self.db.execute(
+            "INSERT INTO runs (time, command, config, config_hash, output, stats)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                Utc::now().to_rfc3339(),
+                command,
+                config,
+                hash,
+                output,
+                serde_json::to_string(stats)?
+            ],
+        )?;
+        Ok(self.db.last_insert_rowid())
+    }
+
+    /// The most recent runs, newest first
+    pub fn runs(&self, limit: usize) -> Result<Vec<Run>, Box<dyn Error>> {
+        let mut statement = self.db.prepare(
+            "SELECT id, time, command, config, config_hash, output, stats FROM runs
+             ORDER BY id DESC LIMIT ?1",
+        )?;
+        let runs = statement
+            .query_map(params![limit as i64], Run::from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(runs)
+    }
+
+    /// The run with the given id, if recorded
+    pub fn get(&self, id: i64) -> Result<Option<Run>, Box<dyn Error>> {
+        Ok(self
+            .db
+            .query_row(
+                "SELECT id, time, command, config, config_hash, output, stats FROM runs
+                 WHERE id = ?1",
+                params![id],
+                Run::from_row,
+            )
+            .optional()?)
+    }
+
+    /// Forget all runs, returning how many there were
+    pub fn clear(&self) -> Result<usize, Box<dyn Error>> {
+        Ok(self.db.execute("DELETE FROM runs", [])?)
+    }
+}
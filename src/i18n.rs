@@ -0,0 +1,291 @@
+use std::error::Error;
+use std::sync::RwLock;
+
+/// Pairs of what is translated and its translation
+type Catalog = &'static [(&'static str, &'static str)];
+
+/// Translations of a language
+struct Language {
+    /// Messages by their English original
+    messages: Catalog,
+    /// Command line help by the command it describes, as the path of command names, e.g.,
+    /// `derivers import`, or by the id of the option after it, e.g., `derivers import.watch`
+    help: Catalog,
+}
+
+const ENGLISH: Language = Language {
+    messages: &[],
+    help: &[],
+};
+
+/// Languages messages are translated to, besides English, by their ISO 639-1 code
+const LANGUAGES: &[(&str, Language)] = &[(
+    "de",
+    Language {
+        messages: GERMAN,
+        help: GERMAN_HELP,
+    },
+)];
+
+const GERMAN_HELP: Catalog = &[
+    (
+        "derivers",
+        "Heatmaps und Statistiken aus Aktivitäten erzeugen",
+    ),
+    (
+        "derivers render",
+        "Eine Heatmap der Aktivitäten als Bild zeichnen",
+    ),
+    (
+        "derivers tui",
+        "Ausschnitt, Zoom, Aktivitätstypen und Farben in einer groben Vorschau im Terminal \
+         wählen, dann die Heatmap damit zeichnen",
+    ),
+    (
+        "derivers video",
+        "Beim Hinzufügen der Aktivitäten Einzelbilder der Heatmap auf stdout ausgeben, z. B. \
+         für ffmpeg",
+    ),
+    (
+        "derivers cycle",
+        "Einzelbilder der fertigen Heatmap mit rotierender Palette auf stdout ausgeben, z. B. \
+         für animierte Hintergründe",
+    ),
+    (
+        "derivers stats",
+        "Zusammenfassende Statistiken der Aktivitäten ausgeben",
+    ),
+    (
+        "derivers demo",
+        "Einen erfundenen Export mit Aktivitäten um einen Ort schreiben, um alles ohne echte \
+         Daten auszuprobieren",
+    ),
+    (
+        "derivers tiles",
+        "Die Heatmap als Kartenkacheln exportieren",
+    ),
+    (
+        "derivers import",
+        "Aktivitätsdateien aus einem Eingangsordner in einen Ordner mit Aktivitäten verschieben \
+         und dabei Zip-Archive und gzip-Dateien entpacken",
+    ),
+    (
+        "derivers import.inbox",
+        "Ordner, in den Aktivitätsdateien abgelegt werden, z. B. von einem Sync-Programm; \
+         importierte Dateien werden daraus entfernt",
+    ),
+    (
+        "derivers import.data",
+        "Ordner mit Aktivitätsdateien, in den importiert und aus dem gezeichnet wird",
+    ),
+    (
+        "derivers import.watch",
+        "Neue Dateien bis zur Unterbrechung laufend importieren",
+    ),
+    (
+        "derivers import.interval",
+        "Sekunden zwischen der Suche nach neuen Dateien beim laufenden Import",
+    ),
+    (
+        "derivers cache",
+        "Den Zwischenspeicher heruntergeladener Kartenkacheln anzeigen oder leeren",
+    ),
+    (
+        "derivers assets",
+        "Optionale, zum Mitliefern zu große Dateien auflisten, herunterladen oder entfernen, \
+         z. B. Schriften und Grenzen von Natural Earth",
+    ),
+    (
+        "derivers history",
+        "Frühere Heatmaps auflisten, anzeigen oder erneut zeichnen",
+    ),
+    (
+        "derivers diff-images",
+        "Zwei Bilder vergleichen, z. B. Heatmaps vor und nach geänderten Optionen",
+    ),
+    (
+        "derivers diff",
+        "Zeichnen, wo die Heatmap zwischen zwei mit `--save-state` gespeicherten Ständen wuchs \
+         oder schrumpfte, z. B. um neu befahrene und verlassene Wege zu finden",
+    ),
+    (
+        "derivers.config",
+        "Optionen aus dieser TOML-Datei lesen; Optionen auf der Kommandozeile haben Vorrang",
+    ),
+    (
+        "derivers.write_config",
+        "Die wirksamen Optionen als TOML in diese Datei schreiben und beenden",
+    ),
+    (
+        "derivers.threads",
+        "Anzahl der Threads; standardmäßig einer pro verfügbarer CPU, weniger bei knappem \
+         Speicher, z. B. in kleinen Containern",
+    ),
+    (
+        "derivers.progress_json",
+        "Den Fortschritt als ein JSON-Objekt pro Zeile in diese Datei schreiben, oder in diesen \
+         offenen Dateideskriptor, wenn eine Zahl, z. B. `3`, für Programme, die derivers \
+         aufrufen",
+    ),
+    (
+        "derivers.quiet",
+        "Nur Fehler im Terminal ausgeben, weder Fortschrittsbalken noch Hinweise, z. B. in \
+         Skripten",
+    ),
+    (
+        "derivers.verbose",
+        "Mehr Details ausgeben, z. B. jede übersprungene Kartenkachel; für noch mehr \
+         wiederholen",
+    ),
+];
+
+const GERMAN: Catalog = &[
+    ("Error: {}", "Fehler: {}"),
+    (
+        "Interrupted, finishing with {} of {} activities; press Ctrl-C again to quit",
+        "Unterbrochen, fertig mit {} von {} Aktivitäten; zum Beenden erneut Strg-C drücken",
+    ),
+    ("    ... and {} more", "    ... und {} weitere"),
+    // Warnings about exports
+    (
+        "activity records could not be read",
+        "Aktivitätseinträge konnten nicht gelesen werden",
+    ),
+    (
+        "timestamps could not be parsed",
+        "Zeitangaben konnten nicht verstanden werden",
+    ),
+    (
+        "activity files are missing from the export",
+        "Aktivitätsdateien fehlen im Export",
+    ),
+    (
+        "activity files could not be parsed",
+        "Aktivitätsdateien konnten nicht gelesen werden",
+    ),
+    (
+        "activity files are damaged, keeping what was read before",
+        "Aktivitätsdateien sind beschädigt, das zuvor Gelesene wird behalten",
+    ),
+    (
+        "activity files contain impossible coordinates, which were dropped",
+        "Aktivitätsdateien enthalten unmögliche Koordinaten, die verworfen wurden",
+    ),
+    // Statistics
+    ("Activities", "Aktivitäten"),
+    ("Total distance", "Gesamtstrecke"),
+    ("Eddington number", "Eddington-Zahl"),
+    ("Squadrats", "Squadrats"),
+    ("Squadratinhos", "Squadratinhos"),
+    ("Mean heart rate", "Mittlere Herzfrequenz"),
+    ("Mean cadence", "Mittlere Trittfrequenz"),
+    ("Mean temperature", "Mittlere Temperatur"),
+    ("Per type:", "Pro Typ:"),
+    ("Per year:", "Pro Jahr:"),
+];
+
+lazy_static! {
+    static ref LANGUAGE: RwLock<&'static Language> = RwLock::new(detect());
+}
+
+/// The language asked for by `DERIVERS_LANG`, or else the locale as by gettext, e.g.,
+/// `LANG=de_CH.UTF-8`; English for languages without translations
+fn detect() -> &'static Language {
+    let variables = ["DERIVERS_LANG", "LANGUAGE", "LC_ALL", "LC_MESSAGES", "LANG"];
+    let requested = variables
+        .iter()
+        .filter_map(|name| std::env::var(name).ok())
+        .find(|value| !value.is_empty());
+    // LANGUAGE lists fallbacks separated by colons
+    requested
+        .and_then(|value| value.split(':').find_map(|language| find(language).ok()))
+        .unwrap_or(&ENGLISH)
+}
+
+/// Translations of a language given by code or locale, e.g., `de` or `de_CH.UTF-8`
+fn find(language: &str) -> Result<&'static Language, Box<dyn Error>> {
+    let code = language.split(['_', '.', '@', '-']).next().unwrap_or("");
+    match code {
+        "en" | "C" | "POSIX" => Ok(&ENGLISH),
+        _ => LANGUAGES
+            .iter()
+            .find(|(known, _)| *known == code)
+            .map(|(_, language)| language)
+            .ok_or_else(|| format!("no translations to '{}'", language).into()),
+    }
+}
+
+/// Translates messages to the language given by code or locale, e.g., `de`, instead of the one
+/// of the environment
+pub fn set_language(language: &str) -> Result<(), Box<dyn Error>> {
+    *LANGUAGE.write().unwrap() = find(language)?;
+    Ok(())
+}
+
+/// Translation of an English message, or the message itself if it is not translated
+pub fn tr(message: &str) -> &str {
+    let language = *LANGUAGE.read().unwrap();
+    lookup(language.messages, message).unwrap_or(message)
+}
+
+/// Translation of the help of a command or option, given by the path of command names and
+/// the id of the option, e.g., `derivers import` or `derivers import.watch`
+pub fn help(key: &str) -> Option<&'static str> {
+    let language = *LANGUAGE.read().unwrap();
+    lookup(language.help, key)
+}
+
+fn lookup(catalog: Catalog, key: &str) -> Option<&'static str> {
+    catalog
+        .iter()
+        .find(|(original, _)| *original == key)
+        .map(|(_, translation)| *translation)
+}
+
+/// Replaces the placeholders of a message with the arguments, `{}` in order or `{0}` by index,
+/// so that translations may reorder them
+pub fn fill(message: &str, args: &[String]) -> String {
+    let mut filled = String::with_capacity(message.len());
+    let mut next = 0;
+    let mut rest = message;
+    while let Some(start) = rest.find('{') {
+        filled.push_str(&rest[..start]);
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => {
+                rest = &rest[start..];
+                break;
+            }
+        };
+        let index = match rest[start + 1..end].parse() {
+            Ok(index) => index,
+            Err(_) => {
+                next += 1;
+                next - 1
+            }
+        };
+        match args.get(index) {
+            Some(arg) => filled.push_str(arg),
+            None => filled.push_str(&rest[start..=end]),
+        }
+        rest = &rest[end + 1..];
+    }
+    filled.push_str(rest);
+    filled
+}
+
+/// Translates a message with `i18n::tr`, filling in arguments as `format!` does, e.g.,
+/// `tr!("{} of {} activities", done, total)`; arguments are formatted plainly, so format them
+/// beforehand to round numbers
+#[macro_export]
+macro_rules! tr {
+    ($message:expr) => {
+        $crate::i18n::tr($message)
+    };
+    ($message:expr, $($arg:expr),+ $(,)?) => {
+        $crate::i18n::fill(
+            $crate::i18n::tr($message),
+            &[$($arg.to_string()),+],
+        )
+    };
+}
@@ -0,0 +1,154 @@
+use flate2::read::GzDecoder;
+use zip::ZipArchive;
+
+use std::collections::HashSet;
+use std::error::Error;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use super::strava;
+
+/// Time since files were last modified before they are imported, so that files still being
+/// written by a syncing tool are picked up on a later pass
+const SETTLE_TIME: Duration = Duration::from_secs(2);
+
+/// Moves activity files dropped into an inbox, e.g., by a syncing tool like garmin-backup, into
+/// a plain directory of activity files as read by `DataExport`, taking activities out of zip
+/// archives and decompressing gzipped files on the way
+pub struct Importer {
+    data: PathBuf,
+    /// Files of the inbox that could not be imported, left there and reported only once
+    failed: HashSet<PathBuf>,
+}
+
+impl Importer {
+    pub fn new(data: &Path) -> Result<Self, Box<dyn Error>> {
+        if data.join("activities.csv").exists() {
+            return Err(format!(
+                "{} is an export with an activities.csv, which would ignore imported files",
+                data.display()
+            )
+            .into());
+        }
+        std::fs::create_dir_all(data)?;
+        Ok(Importer {
+            data: data.to_path_buf(),
+            failed: HashSet::new(),
+        })
+    }
+
+    /// Imports the files of the inbox that are written completely, removing them from there;
+    /// returns the activity files written to the data directory
+    pub fn import(&mut self, inbox: &Path) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+        let mut files = Vec::new();
+        for entry in std::fs::read_dir(inbox)? {
+            let path = entry?.path();
+            if path.is_file() && !self.failed.contains(&path) && settled(&path) {
+                files.push(path);
+            }
+        }
+        files.sort();
+        let mut imported = Vec::new();
+        for path in files {
+            match self.import_file(&path) {
+                Ok(stored) if stored.is_empty() => {
+                    warn!("No activities in {}, leaving it", path.display());
+                    self.failed.insert(path);
+                }
+                Ok(stored) => {
+                    std::fs::remove_file(&path)?;
+                    info!(
+                        "Imported {} activities from {}",
+                        stored.len(),
+                        path.display()
+                    );
+                    imported.extend(stored);
+                }
+                Err(e) => {
+                    warn!("Cannot import {}: {}", path.display(), e);
+                    self.failed.insert(path);
+                }
+            }
+        }
+        Ok(imported)
+    }
+
+    /// Stores the activities of a file, of all entries if it is a zip archive
+    fn import_file(&self, path: &Path) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+        let name = match path.file_name().and_then(OsStr::to_str) {
+            Some(name) => name,
+            None => return Ok(Vec::new()),
+        };
+        let is_zip = path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("zip"));
+        if !is_zip {
+            let stored = self.store(name, &mut File::open(path)?)?;
+            return Ok(stored.into_iter().collect());
+        }
+        let mut archive = ZipArchive::new(File::open(path)?)?;
+        let mut stored = Vec::new();
+        for index in 0..archive.len() {
+            let mut entry = archive.by_index(index)?;
+            // Only the name, so that entries cannot be written outside of the data directory
+            let name = match Path::new(entry.name()).file_name().and_then(OsStr::to_str) {
+                Some(name) if entry.is_file() => name.to_string(),
+                _ => continue,
+            };
+            stored.extend(self.store(&name, &mut entry)?);
+        }
+        Ok(stored)
+    }
+
+    /// Writes an activity to the data directory, decompressed if gzipped, under its name or a
+    /// numbered variant if another activity has it; files other than activities are skipped
+    fn store(&self, name: &str, source: &mut dyn Read) -> Result<Option<PathBuf>, Box<dyn Error>> {
+        if !strava::is_activity_file(Path::new(name)) {
+            return Ok(None);
+        }
+        let mut contents = Vec::new();
+        let path = Path::new(name);
+        let gzipped = path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("gz"));
+        let name = if gzipped {
+            GzDecoder::new(source).read_to_end(&mut contents)?;
+            path.file_stem().and_then(OsStr::to_str).unwrap_or(name)
+        } else {
+            source.read_to_end(&mut contents)?;
+            name
+        };
+        let original = Path::new(name);
+        let stem = original.file_stem().and_then(OsStr::to_str).unwrap_or(name);
+        let extension = original.extension().and_then(OsStr::to_str).unwrap_or("");
+        for number in 1.. {
+            let file = match number {
+                1 => self.data.join(name),
+                _ => self.data.join(format!("{}-{}.{}", stem, number, extension)),
+            };
+            if !file.exists() {
+                // Renamed once complete, so that renders running meanwhile skip it
+                let partial = self.data.join(format!(".{}.part", name));
+                std::fs::write(&partial, &contents)?;
+                std::fs::rename(&partial, &file)?;
+                return Ok(Some(file));
+            }
+            if std::fs::read(&file)? == contents {
+                info!("{} was imported before", name);
+                return Ok(Some(file));
+            }
+        }
+        unreachable!("ran out of numbers for {}", name)
+    }
+}
+
+/// Whether a file was last modified long enough ago to be written completely
+fn settled(path: &Path) -> bool {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .map(|modified| modified.elapsed().unwrap_or_default() >= SETTLE_TIME)
+        .unwrap_or(false)
+}
@@ -0,0 +1,64 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// Exit code of runs stopped early by Ctrl-C, the one shells report for processes ended by it
+pub const EXIT_CODE: i32 = 130;
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Whether Ctrl-C was pressed a second time, to quit once nothing holds it off
+static QUIT: AtomicBool = AtomicBool::new(false);
+
+/// Number of `Hold`s alive
+static HOLDS: AtomicUsize = AtomicUsize::new(0);
+
+#[cfg(unix)]
+extern "C" fn handle(_signal: libc::c_int) {
+    // A second Ctrl-C gives up on the partial output, e.g., while a slow basemap is fetched,
+    // but lets files being written be completed
+    if INTERRUPTED.swap(true, Ordering::SeqCst) {
+        QUIT.store(true, Ordering::SeqCst);
+        if HOLDS.load(Ordering::SeqCst) == 0 {
+            unsafe { libc::_exit(EXIT_CODE) };
+        }
+    }
+}
+
+/// Makes Ctrl-C ask the run to stop, see `interrupted`, instead of ending the process right away
+pub fn catch() {
+    #[cfg(unix)]
+    unsafe {
+        libc::signal(
+            libc::SIGINT,
+            handle as extern "C" fn(libc::c_int) as libc::sighandler_t,
+        );
+    }
+}
+
+/// Whether Ctrl-C was pressed since `catch`
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Forgets that Ctrl-C was pressed, e.g., where it is the usual way to stop, so that the run
+/// does not exit with `EXIT_CODE`
+pub fn clear() {
+    INTERRUPTED.store(false, Ordering::SeqCst);
+}
+
+/// Holds off quitting on a second Ctrl-C until dropped, see `hold`
+pub struct Hold(());
+
+/// Lets a second Ctrl-C quit only once the returned `Hold` is dropped, e.g., after a file is
+/// written, so that it is not left truncated
+pub fn hold() -> Hold {
+    HOLDS.fetch_add(1, Ordering::SeqCst);
+    Hold(())
+}
+
+impl Drop for Hold {
+    fn drop(&mut self) {
+        if HOLDS.fetch_sub(1, Ordering::SeqCst) == 1 && QUIT.load(Ordering::SeqCst) {
+            std::process::exit(EXIT_CODE);
+        }
+    }
+}
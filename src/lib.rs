@@ -1,4 +1,8 @@
+extern crate bincode;
+extern crate chardetng;
 extern crate chrono;
+extern crate clap;
+extern crate crossterm;
 extern crate fitparser;
 extern crate flate2;
 extern crate font_loader as fonts;
@@ -10,17 +14,59 @@ extern crate imageproc;
 extern crate indicatif;
 #[macro_use]
 extern crate lazy_static;
+#[macro_use]
+extern crate log;
 extern crate directories;
+extern crate encoding_rs;
 extern crate http_req;
+extern crate libc;
 extern crate palette;
+extern crate png;
+#[cfg(feature = "gpu")]
+extern crate pollster;
 extern crate rayon;
 extern crate regex;
+extern crate rusqlite;
 extern crate rusttype;
+extern crate serde;
+extern crate serde_json;
 extern crate sha2;
 extern crate time;
+#[cfg(feature = "gpu")]
+extern crate wgpu;
+extern crate xml;
+extern crate zip;
 
 pub mod activity;
+pub mod assets;
+pub mod borders;
+pub mod compare;
+pub mod demo;
+pub mod diagnostics;
+pub mod explorer;
+pub mod frames;
+pub mod geocode;
+#[cfg(feature = "gpu")]
+pub mod gpu;
+pub mod grid;
 pub mod heat;
+pub mod history;
+pub mod i18n;
+pub mod import;
+pub mod interrupt;
+pub mod metadata;
 pub mod osmbase;
+pub mod preview;
+pub mod progress;
+pub mod provider;
+pub mod pyramid;
+pub mod render;
+pub mod report;
+pub mod resources;
+pub mod sink;
 pub mod slippy;
+pub mod stats;
 pub mod strava;
+pub mod style;
+pub mod takeout;
+pub mod timings;
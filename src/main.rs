@@ -1,20 +1,67 @@
 extern crate chrono;
 extern crate clap;
+extern crate crossterm;
+extern crate csv;
+#[macro_use]
 extern crate derivers;
 extern crate geo;
+extern crate indicatif;
 extern crate libc;
+#[macro_use]
+extern crate log;
+extern crate rayon;
 extern crate serde;
+extern crate serde_json;
+extern crate toml;
 
-use derivers::heat::{Heatmap, PixelHeatmap, TileHeatmap};
-use derivers::osmbase::Basemap;
+use derivers::activity::{
+    self, Activity, FitField, Order, ScreenActivity, TrackPoint, WorldActivity,
+};
+use derivers::assets;
+use derivers::borders::Borders;
+use derivers::compare::{self, Comparison};
+use derivers::demo;
+use derivers::diagnostics;
+use derivers::frames::{FrameFormat, FrameLog, FrameWriter, Manifest};
+use derivers::geocode;
+#[cfg(feature = "gpu")]
+use derivers::gpu::{self, GpuHeatmap};
+use derivers::heat::{self, Heatmap, Measurement, Period, PixelHeatmap, TileHeatmap};
+use derivers::history::{self, History};
+use derivers::i18n;
+use derivers::import::Importer;
+use derivers::interrupt;
+use derivers::metadata::{self, Metadata};
+use derivers::osmbase::{self, Basemap};
+use derivers::preview;
+use derivers::progress::{self, Event as Progress};
+use derivers::provider::{self, Provider};
+use derivers::pyramid::Pyramid;
+use derivers::render::{Background, Canvas, RenderBuilder};
+use derivers::report::{self, Report};
+use derivers::resources::Resources;
+use derivers::sink::{ImageFile, OutputSink};
 use derivers::slippy;
-use derivers::strava;
+use derivers::stats::{self, Contribution, Summary, SQUADRATINHO_ZOOM, SQUADRAT_ZOOM};
+use derivers::strava::{self, Photos};
+use derivers::style::{BasemapStyle, Resampling, Tint, Vignette};
+use derivers::timings::Timings;
 
+use std::convert::TryFrom;
 use std::error::Error;
-use std::io::stdout;
+use std::fmt;
+use std::io::{stdout, Write};
 use std::path;
+use std::time::{Duration, Instant};
 
-use clap::{Parser, ValueEnum};
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use crossterm::terminal::{self, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen};
+use crossterm::{cursor, ExecutableCommand, QueueableCommand};
+use rayon::prelude::*;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
 
 /// Ensure that a number represents a fraction within [0.0, 1.0]
 fn fraction(s: &str) -> Result<f32, String> {
@@ -29,120 +76,2772 @@ fn fraction(s: &str) -> Result<f32, String> {
     }
 }
 
-/// Different heatmap representations: pixel-precise, or based on OSM tiles level 14 or 17
-#[derive(Clone, Debug, ValueEnum)]
+/// Parse a heat value greater than zero
+fn positive(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(num) if num > 0.0 => Ok(num),
+        Ok(num) => Err(format!("value not positive: {}", num)),
+        Err(_) => Err(format!("cannot parse '{}'", s)),
+    }
+}
+
+/// Parse a percentage greater than zero and at most 100
+fn percent(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(num) if num > 0.0 && num <= 100.0 => Ok(num),
+        Ok(num) => Err(format!("value not in (0, 100]: {}", num)),
+        Err(_) => Err(format!("cannot parse '{}'", s)),
+    }
+}
+
+/// A length given either in pixels (`5`, `5px`) or in meters on the ground (`20m`)
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+enum Length {
+    Pixels(f64),
+    Meters(f64),
+}
+
+impl Length {
+    fn to_pixels(self, map: &slippy::Map) -> f64 {
+        match self {
+            Length::Pixels(px) => px,
+            Length::Meters(m) => m / map.meters_per_pixel(),
+        }
+    }
+}
+
+impl fmt::Display for Length {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Length::Pixels(px) => write!(f, "{}px", px),
+            Length::Meters(m) => write!(f, "{}m", m),
+        }
+    }
+}
+
+impl From<Length> for String {
+    fn from(length: Length) -> Self {
+        length.to_string()
+    }
+}
+
+impl TryFrom<String> for Length {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        length(&s)
+    }
+}
+
+/// Parse a non-negative length in pixels or meters
+fn length(s: &str) -> Result<Length, String> {
+    let (value, unit): (&str, fn(f64) -> Length) = if let Some(v) = s.strip_suffix("px") {
+        (v, Length::Pixels)
+    } else if let Some(v) = s.strip_suffix('m') {
+        (v, Length::Meters)
+    } else {
+        (s, Length::Pixels)
+    };
+    match value.trim().parse::<f64>() {
+        Ok(num) if num >= 0.0 => Ok(unit(num)),
+        Ok(num) => Err(format!("value is negative: {}", num)),
+        Err(_) => Err(format!("cannot parse '{}'", s)),
+    }
+}
+
+/// An RGB color given in hex, e.g., `#101010`
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+struct Color([u8; 3]);
+
+impl fmt::Display for Color {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [r, g, b] = self.0;
+        write!(f, "#{:02x}{:02x}{:02x}", r, g, b)
+    }
+}
+
+impl From<Color> for String {
+    fn from(color: Color) -> Self {
+        color.to_string()
+    }
+}
+
+impl TryFrom<String> for Color {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        color(&s)
+    }
+}
+
+/// Parse a color as `#rrggbb` or `#rgb`, the `#` being optional
+fn color(s: &str) -> Result<Color, String> {
+    let hex = s.trim().trim_start_matches('#');
+    let digits: Option<Vec<u8>> = hex
+        .chars()
+        .map(|c| c.to_digit(16).map(|d| d as u8))
+        .collect();
+    match digits.as_deref() {
+        Some(&[r, g, b]) => Ok(Color([r * 17, g * 17, b * 17])),
+        Some(&[r1, r2, g1, g2, b1, b2]) => Ok(Color([r1 * 16 + r2, g1 * 16 + g2, b1 * 16 + b2])),
+        _ => Err(format!("not a color like #101010: '{}'", s)),
+    }
+}
+
+/// Parse the bits per pixel of a grayscale image
+fn alpha_depth(s: &str) -> Result<u8, String> {
+    match s {
+        "8" => Ok(8),
+        "16" => Ok(16),
+        _ => Err(format!("bits per pixel not 8 or 16: {}", s)),
+    }
+}
+
+/// Different heatmap representations: pixel-precise, or based on OSM tiles level 14 or 17,
+/// drawn as blocks or rounded squares
+#[derive(Clone, Debug, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
 enum HeatmapKind {
     Pixel,
     Squadrat,
     Squadratinho,
+    SquadratRounded,
+    SquadratinhoRounded,
 }
 
-/// Generate a heatmap from activities
-#[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
-struct Args {
-    /// Directory containing the activities
-    directory: String,
+/// Shapes of the tint over the basemap
+#[derive(Clone, Debug, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum VignetteKind {
+    None,
+    Radial,
+    Linear,
+}
 
-    // general options
+/// What the color of the heatmap shows: heat, the mean elevation, air temperature or value of
+/// the `--fit-field` for pixel heatmaps, or the number of distinct months or weeks a tile was
+/// visited in for tile heatmaps
+#[derive(Clone, Debug, PartialEq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum ColorBy {
+    Heat,
+    Elevation,
+    Temperature,
+    FitField,
+    Months,
+    Weeks,
+}
+
+/// What each track point contributes to the heat
+#[derive(Clone, Debug, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum Weight {
+    /// One count per point
+    Points,
+    /// The distance traveled to reach the point, so that stops do not pile up heat
+    Distance,
+}
+
+/// How the weight of activities falls off with their age, relative to the newest one
+#[derive(Clone, Debug, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum AgeFalloff {
+    /// All activities weigh the same
+    None,
+    /// Weight drops linearly, reaching zero after the age span
+    Linear,
+    /// Weight halves with every age span
+    Exponential,
+}
+
+impl AgeFalloff {
+    /// Weight of an activity of the given age, both in days
+    fn weight(&self, age: f64, span: f64) -> f64 {
+        match self {
+            AgeFalloff::None => 1.0,
+            AgeFalloff::Linear => (1.0 - age / span).max(0.0),
+            AgeFalloff::Exponential => 0.5f64.powf(age / span),
+        }
+    }
+}
+
+/// Options selecting which activities are used
+#[derive(clap::Args, Debug, Serialize, Deserialize)]
+struct FilterArgs {
+    /// Only use activities of this type, e.g., `Ride` or `Run` (may be repeated)
+    #[arg(long = "type", value_name = "TYPE")]
+    types: Vec<String>,
+    /// Skip activities flagged as commutes
+    #[arg(long)]
+    skip_commutes: bool,
+    /// List all activity files referenced by `activities.csv` but missing from the export
+    #[arg(long)]
+    list_missing: bool,
+    /// Stop if any activity file referenced by `activities.csv` is missing from the export
+    #[arg(long)]
+    fail_on_missing: bool,
+    /// Simplify tracks to deviate at most this many meters from the recorded ones, saving
+    /// memory and time on large exports
+    #[arg(long, value_name = "EPSILON_M", value_parser = positive)]
+    simplify: Option<f64>,
+    /// Keep the records of truncated or corrupt FIT files, e.g., from crashed head units, read
+    /// before the damage instead of skipping the files
+    #[arg(long)]
+    lenient_fit: bool,
+    /// Read this field of FIT records by its name, e.g., `saturated_hemoglobin_percent`, to
+    /// color by; `NAME*FACTOR` converts its values by a factor, semicircles become degrees
+    #[arg(long, value_name = "NAME[*FACTOR]")]
+    fit_field: Option<FitField>,
+}
+
+impl FilterArgs {
+    fn filter(&self) -> strava::Filter {
+        strava::Filter {
+            types: self.types.clone(),
+            skip_commutes: self.skip_commutes,
+        }
+    }
+
+    /// Read the export in a directory, handling missing activity files as requested
+    fn export(&self, directory: &str) -> Result<strava::DataExport, Box<dyn Error>> {
+        let export = strava::DataExport::new(&path::PathBuf::from(directory), &self.filter())?
+            .with_simplification(self.simplify)
+            .with_lenient_fit(self.lenient_fit)
+            .with_fit_field(self.fit_field.clone())
+            .with_parsers(Resources::detect().parsers());
+        let missing = export.missing_files();
+        if self.list_missing {
+            for file in missing {
+                warn!("Missing {}", file.display());
+            }
+        }
+        if self.fail_on_missing && !missing.is_empty() {
+            return Err(format!(
+                "{} activity files are missing from the export",
+                missing.len()
+            )
+            .into());
+        }
+        Ok(export)
+    }
+}
+
+/// Options defining the view port
+#[derive(clap::Args, Debug, Serialize, Deserialize)]
+struct ViewArgs {
     /// Latitude of the view port center
     #[arg(long)]
-    lat: f64,
+    lat: Option<f64>,
     /// Longitude of the view port center
     #[arg(long)]
-    lon: f64,
-    /// Output a PNG of cumulative heatmap data to file.
-    #[arg(short, long, default_value = "heatmap.png")]
-    output: String,
+    lon: Option<f64>,
+    /// Center the view port on this place instead, e.g., "Innsbruck, Austria", looked up on
+    /// Nominatim
+    #[arg(long, conflicts_with_all = ["lat", "lon"])]
+    place: Option<String>,
     /// Width of output, in pixels
     #[arg(short, long, default_value_t = 1920)]
     width: u32,
-    /// Height of output to pixel size
-    #[arg(short, long, default_value_t = 1080)]
+    /// Height of output, in pixels
+    #[arg(short = 'H', long, default_value_t = 1080)]
     height: u32,
     /// Zoom level
     #[arg(short, long, default_value_t = 10)]
     zoom: u8,
-    /// URL pattern for background tiles (standard OSM: https://a.tile.osm.org/{z}/{x}/{y}.png)
-    #[arg(long, default_value = "https://tile.openstreetmap.org/{z}/{x}/{y}.png")]
-    url: String,
+}
+
+impl ViewArgs {
+    /// Combinations of options that contradict each other
+    fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.place.is_some() && (self.lat.is_some() || self.lon.is_some()) {
+            problems.push("--place conflicts with --lat and --lon, center on either".to_string());
+        }
+        problems
+    }
+
+    fn map(&self) -> Result<slippy::Map, Box<dyn Error>> {
+        let (lat, lon) = match self.place {
+            Some(ref query) => {
+                let place = geocode::geocode(query)?;
+                info!("Centering on {}", place.name);
+                (place.lat, place.lon)
+            }
+            None => (
+                self.lat.ok_or_else(|| missing("--lat or --place"))?,
+                self.lon.ok_or_else(|| missing("--lon or --place"))?,
+            ),
+        };
+        Ok(slippy::Map::from(
+            lon,
+            lat,
+            self.width,
+            self.height,
+            self.zoom,
+        ))
+    }
+}
+
+/// Options shared by everything drawing a heatmap over a basemap
+#[derive(clap::Args, Debug, Serialize, Deserialize)]
+struct HeatmapArgs {
+    /// Directory or zip archive of the export containing the activities
+    directory: Option<String>,
+
+    // general options
+    #[command(flatten)]
+    #[serde(flatten)]
+    view: ViewArgs,
+    /// Download background tiles from this preset tile server
+    #[arg(long, value_enum, default_value_t = provider::OSM)]
+    provider: Provider,
+    /// URL pattern for background tiles instead of a `--provider`, e.g.,
+    /// https://{s}.tile.openstreetmap.org/{z}/{x}/{y}.png; `{-y}` counts rows from the south
+    /// and `{quadkey}` is replaced by a Bing style tile key
+    #[arg(long)]
+    url: Option<String>,
+    /// Subdomains taking turns for `{s}` in the `--url`
+    #[arg(long, value_delimiter = ',', default_value = "a,b,c")]
+    subdomains: Vec<String>,
+    /// Read background tiles from a local MBTiles file or `z/x/y` directory instead of
+    /// downloading them
+    #[arg(long)]
+    basemap_file: Option<String>,
+    /// Retry downloading a tile this many times after server or network errors
+    #[arg(long, default_value_t = 3)]
+    retries: u32,
+    /// Wait this long before the first retry, doubling for each further one, in milliseconds
+    #[arg(long, default_value_t = 500)]
+    retry_delay: u64,
+    /// Download at most this many basemap tiles per second
+    #[arg(long, value_parser = positive)]
+    rate_limit: Option<f64>,
+    /// Credit the basemap with this text instead of the attribution of the `--provider`
+    #[arg(long)]
+    attribution: Option<String>,
+    /// Do not write the attribution into the image, only into its metadata
+    #[arg(long)]
+    no_attribution: bool,
+    /// Leave the background transparent instead of drawing a basemap, e.g., to put the heat
+    /// over other maps; no tiles are downloaded
+    #[arg(long)]
+    no_basemap: bool,
+    /// Draw the heat over this flat color instead of a basemap, e.g., `#101010`; no tiles are
+    /// downloaded
+    #[arg(long, value_parser = color)]
+    background: Option<Color>,
 
+    /// Recolor the basemap before tinting it, so that colorful styles do not compete with the
+    /// heat
+    #[arg(long, value_enum, default_value_t = BasemapStyle::Normal)]
+    basemap_style: BasemapStyle,
+    /// Filter scaling basemap tiles that are not 256 pixels wide, e.g., retina tiles
+    #[arg(long, value_enum, default_value_t = Resampling::Lanczos)]
+    resampling: Resampling,
     /// Tint overlay over the basemap
     #[arg(long, value_parser = fraction, default_value_t = 0.8)]
     tint: f32,
+    /// Fade the tint towards the corners or the bottom of the image
+    #[arg(long, value_enum, default_value_t = VignetteKind::None)]
+    vignette: VignetteKind,
+    /// Tint reached in the corners or at the bottom with `--vignette`
+    #[arg(long, value_parser = fraction, default_value_t = 1.0)]
+    vignette_tint: f32,
+    /// Draw the lines and polygon outlines of this GeoJSON file over the basemap, e.g.,
+    /// coastlines and country borders from Natural Earth, or of a fetched asset like
+    /// `countries` (may be repeated)
+    #[arg(long, value_name = "GEOJSON")]
+    borders: Vec<String>,
+    /// Opacity of the `--borders` lines
+    #[arg(long, value_parser = fraction, default_value_t = 0.5)]
+    border_opacity: f32,
 
     /// What kind of heatmap to generate
     #[arg(long, value_enum, default_value_t = HeatmapKind::Pixel)]
     heatmap: HeatmapKind,
+    /// Spread heat around tracks with a Gaussian of this standard deviation, in pixels (`5`)
+    /// or meters (`20m`); pixel heatmaps only
+    #[arg(long, value_parser = length)]
+    blur: Option<Length>,
+    /// Add up and color heat on the GPU, e.g., for full histories with tens of millions of
+    /// points, falling back to the CPU without a usable one; pixel heatmaps only
+    #[arg(long)]
+    gpu: bool,
+    /// What each track point contributes to the heat; pixel heatmaps only
+    #[arg(long, value_enum, default_value_t = Weight::Points)]
+    weight: Weight,
+    /// Longest step between points counted when weighing by distance, in meters, so that gaps
+    /// in a recording do not add heat to a single pixel
+    #[arg(long, value_parser = positive, default_value_t = 100.0)]
+    max_step: f64,
+    /// Let older activities contribute less heat, by the age of their date relative to the
+    /// newest activity, while those without a date count fully; pixel heatmaps only
+    #[arg(long, value_enum, default_value_t = AgeFalloff::None)]
+    age_falloff: AgeFalloff,
+    /// Days over which the weight of older activities fades: to nothing when linear, by half
+    /// when exponential
+    #[arg(long, value_parser = positive, default_value_t = 365.0)]
+    age_span: f64,
+    /// What the color of the heatmap shows; heat always determines opacity
+    #[arg(long, value_enum, default_value_t = ColorBy::Heat)]
+    color_by: ColorBy,
+    /// Elevation shown in the lowest color, in meters; defaults to the lowest elevation seen
+    #[arg(long, allow_hyphen_values = true)]
+    elevation_min: Option<f64>,
+    /// Elevation shown in the highest color, in meters; defaults to the highest elevation seen
+    #[arg(long, allow_hyphen_values = true)]
+    elevation_max: Option<f64>,
+    /// Temperature shown in the coldest color, in °C; defaults to the lowest temperature seen
+    #[arg(long, allow_hyphen_values = true)]
+    temperature_min: Option<f64>,
+    /// Temperature shown in the warmest color, in °C; defaults to the highest temperature seen
+    #[arg(long, allow_hyphen_values = true)]
+    temperature_max: Option<f64>,
+    /// Value of the `--fit-field` shown in the lowest color; defaults to the lowest seen
+    #[arg(long, allow_hyphen_values = true)]
+    fit_field_min: Option<f64>,
+    /// Value of the `--fit-field` shown in the highest color; defaults to the highest seen
+    #[arg(long, allow_hyphen_values = true)]
+    fit_field_max: Option<f64>,
+    /// Normalize colors to this heat value instead of the highest one, so that renders are
+    /// comparable
+    #[arg(long, value_parser = positive)]
+    fixed_max: Option<f64>,
+    /// Normalize colors to this percentile of the heat of visited places instead of the
+    /// highest one, e.g., `99.5`, so that a single over-visited spot does not fade the rest
+    #[arg(long, value_parser = percent)]
+    clip_percentile: Option<f64>,
+    /// Color no tile by more than this many points, e.g., so that a training loop ridden daily
+    /// does not fade all other tiles; tile heatmaps only
+    #[arg(long, value_name = "POINTS", value_parser = clap::value_parser!(u32).range(1..))]
+    tile_cap: Option<u32>,
+    /// Share the points of a tile beyond the `--tile-cap` among its visited neighbors, passing
+    /// on what fills them in turn, instead of dropping them
+    #[arg(long)]
+    spread_overflow: bool,
+    /// Print the size of the largest square and cluster of visited tiles; tile heatmaps only
+    #[arg(long)]
+    explorer: bool,
+    /// Print how long each phase took, its throughput, and the peak memory use
+    #[arg(long)]
+    timings: bool,
+    /// Change the zoom level so that consecutive GPS points are about a pixel apart, keeping
+    /// the area shown, so that the image grows or shrinks with it
+    #[arg(long)]
+    auto_zoom: bool,
+    /// Outline the largest square and cluster of visited tiles; tile heatmaps only
+    #[arg(long)]
+    explorer_outlines: bool,
+    /// Write all visited tiles with visit counts and dates as GeoJSON; tile heatmaps only
+    #[arg(long)]
+    tiles_geojson: Option<String>,
+    /// Write the activities ranked by distance within the view port to this file, as CSV if
+    /// it ends in `.csv` and as JSON otherwise
+    #[arg(long)]
+    top_activities: Option<String>,
+    /// Add the heat saved by `--save-state` of an earlier render of the same view and heatmap
+    /// kind, e.g., of older activities or from another machine (may be repeated)
+    #[arg(long, value_name = "FILE")]
+    load_state: Vec<String>,
+    /// Save the accumulated heat to this file, to be added to later renders with
+    /// `--load-state`
+    #[arg(long, value_name = "FILE")]
+    save_state: Option<String>,
+
+    // filter options
+    #[command(flatten)]
+    #[serde(flatten)]
+    filter: FilterArgs,
+    /// Order in which activities are added to the heatmap
+    #[arg(long, value_enum, default_value_t = Order::Date)]
+    order: Order,
+    /// Reverse the order of activities
+    #[arg(long)]
+    reverse: bool,
+    /// Only list the activities in view with their visible points, to check filters and the
+    /// view before a long render, without downloading tiles or writing anything
+    #[arg(long)]
+    dry_run: bool,
+}
+
+impl HeatmapArgs {
+    /// Whether the heat is drawn over basemap tiles rather than a flat or no background
+    fn tiled_basemap(&self) -> bool {
+        !self.no_basemap && self.background.is_none()
+    }
+
+    /// Highest zoom level the basemap offers
+    fn max_zoom(&self) -> u8 {
+        match (&self.basemap_file, &self.url) {
+            (None, None) => self.provider.max_zoom,
+            _ => MAX_ZOOM,
+        }
+    }
+
+    /// Combinations of options that contradict each other or would be ignored
+    fn problems(&self) -> Vec<String> {
+        let mut problems = self.view.problems();
+        let mut problem = |text: &str| problems.push(text.to_string());
+        if self.no_basemap && (self.basemap_file.is_some() || self.url.is_some()) {
+            problem("--no-basemap conflicts with --basemap-file and --url");
+        }
+        if self.background.is_some() {
+            if self.no_basemap {
+                problem("--background conflicts with --no-basemap, fill the background or not");
+            }
+            if self.basemap_file.is_some() || self.url.is_some() {
+                problem("--background conflicts with --basemap-file and --url");
+            }
+        }
+        if self.basemap_file.is_some() {
+            if self.url.is_some() {
+                problem("--basemap-file and --url both give the basemap, use only one");
+            }
+            if self.rate_limit.is_some() {
+                problem("--rate-limit only applies to downloaded tiles, not to --basemap-file");
+            }
+        }
+        let pixel = matches!(self.heatmap, HeatmapKind::Pixel);
+        match self.color_by {
+            ColorBy::Elevation | ColorBy::Temperature | ColorBy::FitField if !pixel => {
+                problem("--color-by=elevation, temperature and fit-field require --heatmap=pixel")
+            }
+            ColorBy::Months | ColorBy::Weeks if pixel => {
+                problem("--color-by=months and weeks require a tile --heatmap, e.g., squadrat")
+            }
+            _ => {}
+        }
+        let elevation = matches!(self.color_by, ColorBy::Elevation);
+        if !elevation && (self.elevation_min.is_some() || self.elevation_max.is_some()) {
+            problem("--elevation-min and --elevation-max require --color-by=elevation");
+        }
+        if let (Some(min), Some(max)) = (self.elevation_min, self.elevation_max) {
+            if min >= max {
+                problem("--elevation-min needs to be below --elevation-max");
+            }
+        }
+        let temperature = matches!(self.color_by, ColorBy::Temperature);
+        if !temperature && (self.temperature_min.is_some() || self.temperature_max.is_some()) {
+            problem("--temperature-min and --temperature-max require --color-by=temperature");
+        }
+        if let (Some(min), Some(max)) = (self.temperature_min, self.temperature_max) {
+            if min >= max {
+                problem("--temperature-min needs to be below --temperature-max");
+            }
+        }
+        let fit_field = matches!(self.color_by, ColorBy::FitField);
+        if fit_field && self.filter.fit_field.is_none() {
+            problem("--color-by=fit-field requires --fit-field");
+        }
+        if !fit_field && (self.fit_field_min.is_some() || self.fit_field_max.is_some()) {
+            problem("--fit-field-min and --fit-field-max require --color-by=fit-field");
+        }
+        if let (Some(min), Some(max)) = (self.fit_field_min, self.fit_field_max) {
+            if min >= max {
+                problem("--fit-field-min needs to be below --fit-field-max");
+            }
+        }
+        if self.fixed_max.is_some() && self.clip_percentile.is_some() {
+            problem("--fixed-max conflicts with --clip-percentile, normalize by either");
+        }
+        if self.spread_overflow && self.tile_cap.is_none() {
+            problem("--spread-overflow requires --tile-cap");
+        }
+        if self.gpu && !cfg!(feature = "gpu") {
+            problem("--gpu requires derivers built with `--features gpu`");
+        }
+        if !pixel {
+            if self.gpu {
+                problem("--gpu requires --heatmap=pixel");
+            }
+            if self.blur.is_some() {
+                problem("--blur requires --heatmap=pixel");
+            }
+            if matches!(self.weight, Weight::Distance) {
+                problem("--weight=distance requires --heatmap=pixel");
+            }
+            if !matches!(self.age_falloff, AgeFalloff::None) {
+                problem("--age-falloff requires --heatmap=pixel");
+            }
+        } else if self.explorer || self.explorer_outlines || self.tiles_geojson.is_some() {
+            problem(
+                "--explorer, --explorer-outlines and --tiles-geojson require a tile --heatmap, \
+                 e.g., squadrat",
+            );
+        }
+        if pixel && self.tile_cap.is_some() {
+            problem("--tile-cap requires a tile --heatmap, e.g., squadrat");
+        }
+        problems
+    }
+}
+
+#[derive(clap::Args, Debug, Serialize, Deserialize)]
+struct RenderArgs {
+    #[command(flatten)]
+    #[serde(flatten)]
+    heatmap: HeatmapArgs,
+    /// Output a PNG of cumulative heatmap data to file.
+    #[arg(short, long, default_value = "heatmap.png")]
+    output: String,
+    /// Trim the output to all heat plus this padding, in pixels (`20`) or meters (`500m`)
+    #[arg(long, value_parser = length)]
+    crop_to_content: Option<Length>,
+    /// Also write the opacity of the heat as a grayscale PNG aligned with the output, e.g., to
+    /// be used as a matte
+    #[arg(long)]
+    export_alpha: Option<String>,
+    /// Bits per pixel of the `--export-alpha` mask
+    #[arg(long, default_value_t = 8, value_parser = alpha_depth)]
+    alpha_depth: u8,
+    /// Also write the basemap, heat and attribution as separate layers of one OpenRaster
+    /// file, e.g., `heatmap.ora`, to keep editing them in GIMP or Krita
+    #[arg(long)]
+    layers: Option<String>,
+    /// Only draw which basemap tiles of the view are cached and which would be downloaded into
+    /// this image, and print their counts, without rendering or downloading anything
+    #[arg(long)]
+    plan_image: Option<String>,
+    /// Also render the activities of this second export, e.g., of a partner, with the first
+    /// export in red, this one in blue and places both visited in purple
+    #[arg(long, value_name = "DIRECTORY")]
+    compare: Option<String>,
+    /// Also write a single HTML file, e.g., `report.html`, with the image, statistics, a
+    /// calendar of the last year and the activities covering the most distance in view
+    #[arg(long)]
+    report_html: Option<String>,
+    /// Lay out the `--report-html` by this HTML file instead, in which `{{title}}`, `{{map}}`,
+    /// `{{summary}}`, `{{calendar}}` and `{{top}}` are replaced
+    #[arg(long)]
+    report_template: Option<String>,
+    /// Render and write the PNG output in strips of at least this many rows, e.g., `1024`,
+    /// keeping memory bounded for print sized posters; the heat is accumulated twice to share
+    /// its normalization across strips
+    #[arg(long, value_name = "ROWS", value_parser = clap::value_parser!(u32).range(64..))]
+    strip_height: Option<u32>,
+}
+
+impl RenderArgs {
+    /// Combinations of options that contradict each other or would be ignored
+    fn problems(&self) -> Vec<String> {
+        let mut problems = self.heatmap.problems();
+        if self.plan_image.is_some() && !self.heatmap.tiled_basemap() {
+            problems.push(
+                "--plan-image draws basemap tiles, which --no-basemap and --background skip".into(),
+            );
+        }
+        if self.report_template.is_some() && self.report_html.is_none() {
+            problems.push("--report-template requires --report-html".into());
+        }
+        if self.strip_height.is_some() {
+            let heatmap = &self.heatmap;
+            let mut problem = |text: &str| problems.push(text.to_string());
+            if !self.output.to_lowercase().ends_with(".png") {
+                problem("--strip-height writes PNG files only, end --output in .png");
+            }
+            if heatmap.clip_percentile.is_some()
+                || matches!(heatmap.color_by, ColorBy::Months | ColorBy::Weeks)
+            {
+                problem(
+                    "--strip-height cannot share --clip-percentile or --color-by=months and \
+                     weeks between strips",
+                );
+            }
+            let whole = [
+                (self.crop_to_content.is_some(), "--crop-to-content"),
+                (self.export_alpha.is_some(), "--export-alpha"),
+                (self.layers.is_some(), "--layers"),
+                (self.plan_image.is_some(), "--plan-image"),
+                (self.compare.is_some(), "--compare"),
+                (self.report_html.is_some(), "--report-html"),
+                (heatmap.explorer, "--explorer"),
+                (heatmap.explorer_outlines, "--explorer-outlines"),
+                (heatmap.tiles_geojson.is_some(), "--tiles-geojson"),
+                (!heatmap.load_state.is_empty(), "--load-state"),
+                (heatmap.save_state.is_some(), "--save-state"),
+            ];
+            for (_, option) in whole.iter().filter(|(given, _)| *given) {
+                problem(&format!(
+                    "{} needs the whole image at once, which --strip-height avoids",
+                    option
+                ));
+            }
+        }
+        problems
+    }
+}
 
-    // video options
+#[derive(clap::Args, Debug, Serialize, Deserialize)]
+struct VideoArgs {
+    #[command(flatten)]
+    #[serde(flatten)]
+    heatmap: HeatmapArgs,
     /// Output a frame every `RATE` GPS points
     #[arg(short = 'r', long, default_value_t = 1500)]
     frame_rate: u32,
-    /// Output a stream to stdout to be processed with, e.g., ffmpeg.
-    #[arg(short, long)]
-    stream: bool,
+    /// Normalize all frames to the heat of the last one instead of each to its own, or to its
+    /// `--clip-percentile`
+    #[arg(long)]
+    shared_max: bool,
     /// Render activity title into each frame.
     #[arg(short, long)]
     title: bool,
     /// Render activity date into each frame.
     #[arg(short, long)]
     date: bool,
+    /// Show the first photo of each activity, as listed in `activities.csv`, in the bottom left
+    /// corner when the activity starts
+    #[arg(long)]
+    photos: bool,
+    /// Number of frames each photo is shown for
+    #[arg(long, default_value_t = 20, value_parser = clap::value_parser!(u32).range(1..))]
+    photo_frames: u32,
+    /// Encoding of the frames: PNG with metadata, or faster to write raw RGBA pixels, a Y4M
+    /// stream or uncompressed BMP images
+    #[arg(long, value_enum, default_value_t = FrameFormat::Png)]
+    stream_format: FrameFormat,
+    /// Skip frames identical to the one before, e.g., while activities are outside the view;
+    /// the `--frame-log` counts how often to repeat each frame to keep the pace
+    #[arg(long)]
+    skip_duplicate_frames: bool,
+    /// Write a CSV line per frame with its number, the activity being added, its date and the
+    /// number of identical frames skipped after it
+    #[arg(long, value_name = "FILE")]
+    frame_log: Option<String>,
+    /// Write the frames as numbered files into this directory instead of stdout, e.g.,
+    /// `frame_000001.png`, with a `manifest.json` listing the activity and date of each
+    #[arg(long, value_name = "DIRECTORY")]
+    frames_dir: Option<String>,
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
-    let args = Args::parse();
-
-    #[cfg(unix)]
-    {
-        let is_tty = unsafe { libc::isatty(libc::STDOUT_FILENO) } != 0;
-        if args.stream && is_tty {
-            eprintln!(
-                "Refusing to write frame data to TTY.\n
-    Please pipe output to a file or program."
+impl VideoArgs {
+    /// Combinations of options that contradict each other or would be ignored
+    fn problems(&self) -> Vec<String> {
+        let mut problems = self.heatmap.problems();
+        if self.shared_max && self.heatmap.fixed_max.is_some() {
+            problems.push("--shared-max conflicts with --fixed-max, normalize by either".into());
+        }
+        let files = matches!(self.stream_format, FrameFormat::Png | FrameFormat::Bmp);
+        if self.frames_dir.is_some() && !files {
+            problems.push(
+                "--frames-dir writes PNG or BMP files, not --stream-format=rgba or y4m".into(),
             );
-            std::process::exit(1);
         }
+        problems
     }
+}
 
-    let reference_map = slippy::Map::from(args.lon, args.lat, args.width, args.height, args.zoom);
-    let basemap = Basemap::from(reference_map, &args.url)?;
-    let mut map: Box<dyn Heatmap + Send> = match args.heatmap {
-        HeatmapKind::Pixel => Box::new(PixelHeatmap::from(reference_map, args.date, args.title)),
-        HeatmapKind::Squadrat => Box::new(TileHeatmap::from(reference_map, 14)),
-        HeatmapKind::Squadratinho => Box::new(TileHeatmap::from(reference_map, 17)),
-    };
+#[derive(clap::Args, Debug, Serialize, Deserialize)]
+struct CycleArgs {
+    #[command(flatten)]
+    #[serde(flatten)]
+    heatmap: HeatmapArgs,
+    /// Number of frames for one full turn of the palette
+    #[arg(
+        short = 'n',
+        long,
+        default_value_t = 30,
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    frames: u32,
+    /// Trim the frames to all heat plus this padding, in pixels (`20`) or meters (`500m`)
+    #[arg(long, value_parser = length)]
+    crop_to_content: Option<Length>,
+    /// Encoding of the frames: PNG with metadata, or faster to write raw RGBA pixels, a Y4M
+    /// stream or uncompressed BMP images
+    #[arg(long, value_enum, default_value_t = FrameFormat::Png)]
+    stream_format: FrameFormat,
+}
 
-    let export = strava::DataExport::new(&path::PathBuf::from(&args.directory))?;
-    let activities = export.parse(&*map);
-    let mut stdout = stdout();
-    let mut counter = 0;
-    let rendered_basemap = basemap.as_image(args.tint)?;
-    for act in activities {
-        for ref point in act.track_points.into_iter() {
-            map.add_point(point);
+impl CycleArgs {
+    /// Combinations of options that contradict each other or would be ignored
+    fn problems(&self) -> Vec<String> {
+        let mut problems = self.heatmap.problems();
+        if !matches!(self.heatmap.color_by, ColorBy::Heat) {
+            problems.push("cycling the palette requires --color-by=heat".into());
+        }
+        problems
+    }
+}
 
-            counter += 1;
+#[derive(clap::Args, Debug, Serialize, Deserialize)]
+struct DemoArgs {
+    /// Directory to write the export to
+    directory: String,
+    /// Latitude of the home the activities loop around
+    #[arg(long, allow_hyphen_values = true, default_value_t = 46.2044)]
+    lat: f64,
+    /// Longitude of the home the activities loop around
+    #[arg(long, allow_hyphen_values = true, default_value_t = 6.1432)]
+    lon: f64,
+    /// Loop around this place instead, e.g., "Innsbruck, Austria", looked up on Nominatim
+    #[arg(long, conflicts_with_all = ["lat", "lon"])]
+    place: Option<String>,
+    /// Number of activities
+    #[arg(long, default_value_t = 150)]
+    count: usize,
+    /// Seed of the generated routes, the same seed giving the same routes
+    #[arg(long, default_value_t = 1)]
+    seed: u64,
+}
 
-            if args.stream && counter % args.frame_rate == 0 {
-                let mut pixmap = rendered_basemap.clone();
-                let heat_pixmap = map.as_image().to_rgba8();
-                image::imageops::overlay(&mut pixmap, &heat_pixmap, 0, 0);
-                pixmap.write_to(&mut stdout, image::ImageFormat::Png)?;
-            }
+#[derive(clap::Args, Debug, Serialize, Deserialize)]
+struct StatsArgs {
+    /// Directory or zip archive of the export containing the activities
+    directory: Option<String>,
+    #[command(flatten)]
+    #[serde(flatten)]
+    filter: FilterArgs,
+    /// Print the statistics as JSON
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(clap::Args, Debug, Serialize, Deserialize)]
+struct TilesArgs {
+    /// Directory or zip archive of the export containing the activities
+    directory: Option<String>,
+    #[command(flatten)]
+    #[serde(flatten)]
+    view: ViewArgs,
+    #[command(flatten)]
+    #[serde(flatten)]
+    filter: FilterArgs,
+    /// Export the heat overlay as `z/x/y.png` tiles of the view port into this directory
+    #[arg(long)]
+    tiles_dir: Option<String>,
+    /// Export the heat overlay tiles of the view port into this MBTiles file
+    #[arg(long)]
+    mbtiles: Option<String>,
+    /// Lowest zoom level of the tile export
+    #[arg(
+        long,
+        default_value_t = 0,
+        value_parser = clap::value_parser!(u8).range(..=MAX_ZOOM as i64)
+    )]
+    min_zoom: u8,
+    /// Highest zoom level of the tile export, up to 22
+    #[arg(
+        long,
+        default_value_t = 16,
+        value_parser = clap::value_parser!(u8).range(..=MAX_ZOOM as i64)
+    )]
+    max_zoom: u8,
+    /// Write indexed PNG tiles with this many colors, including transparency, for smaller
+    /// files to publish
+    #[arg(long, value_parser = clap::value_parser!(u16).range(2..=256))]
+    palette_colors: Option<u16>,
+    /// Dither between the colors of `--palette-colors` instead of rounding
+    #[arg(long)]
+    dither: bool,
+}
+
+impl TilesArgs {
+    /// Combinations of options that contradict each other or would be ignored
+    fn problems(&self) -> Vec<String> {
+        let mut problems = self.view.problems();
+        if self.min_zoom > self.max_zoom {
+            problems.push("--min-zoom needs to be at most --max-zoom".into());
         }
+        if self.dither && self.palette_colors.is_none() {
+            problems.push("--dither requires --palette-colors".into());
+        }
+        problems
+    }
+}
 
-        // FIXME: this is pretty ugly.
-        // map.decay(1);
+#[derive(clap::Args, Debug, Serialize, Deserialize)]
+struct DiffImagesArgs {
+    /// Reference image
+    first: String,
+    /// Image compared to the reference
+    second: String,
+    /// Write an image highlighting the differences in red to this file
+    #[arg(short, long)]
+    output: Option<String>,
+    /// Print the comparison as JSON
+    #[arg(long)]
+    json: bool,
+    /// Exit with an error if the SSIM is below this value
+    #[arg(long, value_parser = fraction)]
+    min_ssim: Option<f32>,
+}
+
+#[derive(clap::Args, Debug, Serialize, Deserialize)]
+struct DiffArgs {
+    /// Heat saved by `--save-state` of a pixel heatmap, e.g., of last year
+    before: String,
+    /// Heat saved of the same view and weight, e.g., of this year
+    after: String,
+    /// Write the difference to this file, orange where heat grew and blue where it shrank
+    #[arg(short, long, default_value = "diff.png")]
+    output: String,
+    /// Draw the difference over this image of the same size, e.g., a render of the view
+    #[arg(long)]
+    over: Option<String>,
+}
+
+#[derive(clap::Args, Debug, Serialize, Deserialize)]
+struct HistoryArgs {
+    /// Number of runs to list
+    #[arg(short = 'n', long, default_value_t = 20)]
+    limit: usize,
+    /// Print the options of the run with this id as TOML, e.g., for a `--config` file
+    #[arg(long)]
+    show: Option<i64>,
+    /// Run the render with this id again
+    #[arg(long)]
+    rerun: Option<i64>,
+    /// Forget all runs
+    #[arg(long)]
+    clear: bool,
+}
+
+impl HistoryArgs {
+    /// Combinations of options that contradict each other
+    fn problems(&self) -> Vec<String> {
+        let actions = [self.show.is_some(), self.rerun.is_some(), self.clear];
+        if actions.iter().filter(|&&a| a).count() > 1 {
+            vec!["--show, --rerun and --clear exclude each other, use only one".into()]
+        } else {
+            vec![]
+        }
     }
+}
 
-    let mut pixmap = rendered_basemap;
-    let heat_pixmap = map.as_image().to_rgba8();
-    image::imageops::overlay(&mut pixmap, &heat_pixmap, 0, 0);
-    if args.stream {
-        pixmap.write_to(&mut stdout, image::ImageFormat::Png)?;
-    } else {
-        pixmap.save(args.output)?;
+#[derive(clap::Args, Debug, Serialize, Deserialize)]
+struct ImportArgs {
+    /// Directory activity files are dropped into, e.g., by a syncing tool; imported files are
+    /// removed from it
+    inbox: String,
+    /// Directory of activity files to import into, to be rendered from
+    data: String,
+    /// Keep importing files as they appear until interrupted
+    #[arg(long)]
+    watch: bool,
+    /// Seconds between looking for new files when watching
+    #[arg(long, default_value_t = 10)]
+    interval: u64,
+}
+
+#[derive(clap::Args, Debug, Serialize, Deserialize)]
+struct CacheArgs {
+    /// Remove all cached tiles
+    #[arg(long)]
+    clear: bool,
+}
+
+/// What to do with the optional assets
+#[derive(Clone, Debug, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum AssetAction {
+    /// Show which assets are available and which were fetched
+    List,
+    /// Download assets not fetched yet, or all again with `--force`
+    Fetch,
+    /// Delete fetched assets
+    Remove,
+}
+
+#[derive(clap::Args, Debug, Serialize, Deserialize)]
+struct AssetsArgs {
+    #[arg(value_enum, default_value_t = AssetAction::List)]
+    action: AssetAction,
+    /// Assets to fetch or remove, e.g., `countries`; all if none are given
+    names: Vec<String>,
+    /// Download assets again even if they were fetched, e.g., to update them
+    #[arg(long)]
+    force: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Render a heatmap of the activities into an image
+    Render(RenderArgs),
+    /// Move and zoom the view port, pick activity types and colors in a coarse preview in the
+    /// terminal, then render the heatmap with the options chosen
+    Tui(RenderArgs),
+    /// Stream heatmap frames to stdout while adding activities, to be processed with, e.g.,
+    /// ffmpeg
+    Video(VideoArgs),
+    /// Stream frames of the final heatmap with its palette cycling to stdout, e.g., for
+    /// animated wallpapers
+    Cycle(CycleArgs),
+    /// Print summary statistics of the activities
+    Stats(StatsArgs),
+    /// Write a fake export of activities around a place, to try everything without real data
+    Demo(DemoArgs),
+    /// Export the heat overlay as slippy map tiles
+    Tiles(TilesArgs),
+    /// Move activity files from an inbox into a directory of activities, unpacking zip archives
+    /// and gzipped files
+    Import(ImportArgs),
+    /// Show or clear the cache of downloaded basemap tiles
+    Cache(CacheArgs),
+    /// List, fetch or remove optional assets too heavy to bundle, e.g., fonts and Natural
+    /// Earth borders
+    Assets(AssetsArgs),
+    /// List, show or re-run previous renders
+    History(HistoryArgs),
+    /// Compare two images, e.g., renders before and after changing options
+    DiffImages(DiffImagesArgs),
+    /// Render where heat grew or shrank between two states saved with `--save-state`, e.g., to
+    /// find newly ridden and abandoned roads
+    Diff(DiffArgs),
+}
+
+/// Generate heatmaps and statistics from activities
+///
+/// Options not given on the command line are taken from the `--config` file, using the long
+/// option names with underscores as keys, e.g., `skip_commutes = true`.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    #[command(subcommand)]
+    command: Command,
+
+    /// Read options from this TOML file; options on the command line take precedence
+    #[arg(long, global = true)]
+    config: Option<String>,
+    /// Write the effective options as TOML to this file and exit
+    #[arg(long, global = true)]
+    write_config: Option<String>,
+    /// Number of worker threads; defaults to one per CPU available, fewer when memory is
+    /// short, e.g., in small containers
+    #[arg(long, global = true)]
+    threads: Option<usize>,
+    /// Write progress as one JSON object per line to this file, or to this open file
+    /// descriptor if a number, e.g., `3`, for programs wrapping derivers
+    #[arg(long, global = true, value_name = "FD|FILE")]
+    progress_json: Option<String>,
+    /// Print nothing but errors to the terminal, neither progress bars nor notes, e.g., when
+    /// scripted
+    #[arg(short, long, global = true)]
+    quiet: bool,
+    /// Print more details, e.g., each basemap tile skipped; repeat for even more
+    #[arg(short, long, global = true, action = clap::ArgAction::Count, conflicts_with = "quiet")]
+    verbose: u8,
+}
+
+/// Highest zoom level considered for tiles not from a `--provider`, and of the tile export
+const MAX_ZOOM: u8 = 22;
+
+fn missing(name: &str) -> String {
+    format!("missing {}, on the command line or in the config", name)
+}
+
+/// Overlay options of a subcommand with those from the config file not given on the command
+/// line
+fn merge<T: Serialize + DeserializeOwned>(
+    args: &T,
+    matches: &ArgMatches,
+    known: &[String],
+    file: &toml::Table,
+) -> Result<T, Box<dyn Error>> {
+    let mut merged = match toml::Value::try_from(args)? {
+        toml::Value::Table(table) => table,
+        _ => unreachable!("options serialize to a table"),
+    };
+    for (key, value) in file {
+        // Options of other subcommands are ignored
+        if known.contains(key) && matches.value_source(key) != Some(ValueSource::CommandLine) {
+            merged.insert(key.clone(), value.clone());
+        }
+    }
+    Ok(toml::Value::Table(merged).try_into()?)
+}
+
+impl Command {
+    fn name(&self) -> &'static str {
+        match self {
+            Command::Render(_) => "render",
+            Command::Tui(_) => "tui",
+            Command::Video(_) => "video",
+            Command::Cycle(_) => "cycle",
+            Command::Stats(_) => "stats",
+            Command::Demo(_) => "demo",
+            Command::Tiles(_) => "tiles",
+            Command::Import(_) => "import",
+            Command::Cache(_) => "cache",
+            Command::Assets(_) => "assets",
+            Command::History(_) => "history",
+            Command::DiffImages(_) => "diff-images",
+            Command::Diff(_) => "diff",
+        }
+    }
+
+    /// Subcommand of a recorded run, with its options read from TOML
+    fn from_toml(name: &str, config: &str) -> Result<Self, Box<dyn Error>> {
+        Ok(match name {
+            "render" => Command::Render(toml::from_str(config)?),
+            "video" => Command::Video(toml::from_str(config)?),
+            "cycle" => Command::Cycle(toml::from_str(config)?),
+            _ => return Err(format!("cannot re-run {}", name).into()),
+        })
+    }
+
+    /// Options of the subcommand as TOML
+    fn to_toml(&self) -> Result<String, toml::ser::Error> {
+        match self {
+            Command::Render(args) => toml::to_string_pretty(args),
+            Command::Tui(args) => toml::to_string_pretty(args),
+            Command::Video(args) => toml::to_string_pretty(args),
+            Command::Cycle(args) => toml::to_string_pretty(args),
+            Command::Stats(args) => toml::to_string_pretty(args),
+            Command::Demo(args) => toml::to_string_pretty(args),
+            Command::Tiles(args) => toml::to_string_pretty(args),
+            Command::Import(args) => toml::to_string_pretty(args),
+            Command::Cache(args) => toml::to_string_pretty(args),
+            Command::Assets(args) => toml::to_string_pretty(args),
+            Command::History(args) => toml::to_string_pretty(args),
+            Command::DiffImages(args) => toml::to_string_pretty(args),
+            Command::Diff(args) => toml::to_string_pretty(args),
+        }
+    }
+
+    /// Reject combinations of options that contradict each other or would be silently ignored,
+    /// whether given on the command line, in a config file or by a recorded run
+    fn validate(&self) -> Result<(), Box<dyn Error>> {
+        let problems = match self {
+            Command::Render(args) | Command::Tui(args) => args.problems(),
+            Command::Video(args) => args.problems(),
+            Command::Cycle(args) => args.problems(),
+            Command::Tiles(args) => args.problems(),
+            Command::History(args) => args.problems(),
+            Command::Stats(_)
+            | Command::Demo(_)
+            | Command::Import(_)
+            | Command::Cache(_)
+            | Command::Assets(_)
+            | Command::DiffImages(_)
+            | Command::Diff(_) => vec![],
+        };
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(format!("conflicting options:\n  {}", problems.join("\n  ")).into())
+        }
+    }
+
+    fn merge(
+        self,
+        matches: &ArgMatches,
+        known: &[String],
+        file: &toml::Table,
+    ) -> Result<Self, Box<dyn Error>> {
+        Ok(match self {
+            Command::Render(args) => Command::Render(merge(&args, matches, known, file)?),
+            Command::Tui(args) => Command::Tui(merge(&args, matches, known, file)?),
+            Command::Video(args) => Command::Video(merge(&args, matches, known, file)?),
+            Command::Cycle(args) => Command::Cycle(merge(&args, matches, known, file)?),
+            Command::Stats(args) => Command::Stats(merge(&args, matches, known, file)?),
+            Command::Demo(args) => Command::Demo(merge(&args, matches, known, file)?),
+            Command::Tiles(args) => Command::Tiles(merge(&args, matches, known, file)?),
+            Command::Import(args) => Command::Import(merge(&args, matches, known, file)?),
+            Command::Cache(args) => Command::Cache(merge(&args, matches, known, file)?),
+            Command::Assets(args) => Command::Assets(merge(&args, matches, known, file)?),
+            Command::History(args) => Command::History(merge(&args, matches, known, file)?),
+            Command::DiffImages(args) => Command::DiffImages(merge(&args, matches, known, file)?),
+            Command::Diff(args) => Command::Diff(merge(&args, matches, known, file)?),
+        })
+    }
+}
+
+/// Translates the descriptions of a command, its options and subcommands where a translation
+/// to the language of the user exists, looking them up by the path of command names given
+/// by the parent commands
+fn localize(command: clap::Command, parent: &str) -> clap::Command {
+    let path = match parent {
+        "" => command.get_name().to_string(),
+        _ => format!("{} {}", parent, command.get_name()),
+    };
+    let command = match i18n::help(&path) {
+        Some(about) => command.about(about),
+        None => command,
+    };
+    command
+        .mut_args(|arg| {
+            let key = format!("{}.{}", path, arg.get_id());
+            match i18n::help(&key) {
+                Some(help) => arg.help(help),
+                None => arg,
+            }
+        })
+        .mut_subcommands(|subcommand| localize(subcommand, &path))
+}
+
+/// Parse the command line, taking options not given there from the config file
+fn parse_args() -> Result<Args, Box<dyn Error>> {
+    let matches = localize(Args::command(), "").get_matches();
+    let mut args = Args::from_arg_matches(&matches)?;
+    let config = match args.config.take() {
+        Some(config) => config,
+        None => return Ok(args),
+    };
+
+    let file: toml::Table = toml::from_str(&std::fs::read_to_string(&config)?)?;
+    // Options that only make sense on the command line
+    let excluded = [
+        "config",
+        "write_config",
+        "threads",
+        "progress_json",
+        "quiet",
+        "verbose",
+        "help",
+        "version",
+    ];
+    let option_ids = |command: &clap::Command| -> Vec<String> {
+        command
+            .get_arguments()
+            .map(|a| a.get_id().to_string())
+            .filter(|id| !excluded.contains(&id.as_str()))
+            .collect()
+    };
+    let (name, sub_matches) = matches.subcommand().expect("subcommand is required");
+    let command = Args::command();
+    let known = option_ids(command.find_subcommand(name).unwrap());
+    let all: Vec<String> = command.get_subcommands().flat_map(option_ids).collect();
+    if let Some(key) = file.keys().find(|key| !all.contains(key)) {
+        return Err(format!("unknown option '{}' in {}", key, config).into());
+    }
+
+    args.command = args
+        .command
+        .merge(sub_matches, &known, &file)
+        .map_err(|e| format!("invalid option in {}: {}", config, e))?;
+    Ok(args)
+}
+
+/// Write synthetic activities around a place into a directory
+fn demo(args: DemoArgs) -> Result<(), Box<dyn Error>> {
+    let (lat, lon) = match args.place {
+        Some(ref query) => {
+            let place = geocode::geocode(query)?;
+            (place.lat, place.lon)
+        }
+        None => (args.lat, args.lon),
+    };
+    let center = geo_types::Point::new(lon, lat);
+    demo::generate(
+        path::Path::new(&args.directory),
+        center,
+        args.count,
+        args.seed,
+        chrono::Utc::now(),
+    )?;
+    println!("Wrote {} activities to {}, try", args.count, args.directory);
+    println!(
+        "    derivers render --lat {} --lon {} --zoom 12 {}",
+        lat, lon, args.directory
+    );
+    Ok(())
+}
+
+/// Print summary statistics of all activities in a directory
+fn stats(args: StatsArgs) -> Result<(), Box<dyn Error>> {
+    let directory = args.directory.ok_or_else(|| missing("directory"))?;
+    let export = args.filter.export(&directory)?;
+    let mut summary = Summary::from(&export.activities());
+    summary.warnings = diagnostics::counts();
+    diagnostics::print_summary();
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&summary)?);
+    } else {
+        print!("{}", summary);
+    }
+    Ok(())
+}
+
+/// Export the heat overlay of the view port as slippy map tiles
+fn tiles(args: TilesArgs) -> Result<(), Box<dyn Error>> {
+    let directory = args.directory.ok_or_else(|| missing("directory"))?;
+    if args.tiles_dir.is_none() && args.mbtiles.is_none() {
+        return Err(missing("--tiles-dir or --mbtiles").into());
+    }
+    let reference_map = args.view.map()?;
+    let export = args.filter.export(&directory)?;
+
+    let mut pyramid = Pyramid::from(reference_map.extends(), args.min_zoom, args.max_zoom)
+        .with_palette(args.palette_colors, args.dither);
+    let activities = export.activities();
+    diagnostics::print_summary();
+    let total = activities.len() as u64;
+    for (index, act) in activities.into_iter().enumerate() {
+        progress::emit(&Progress::new("accumulation", index as u64, total));
+        for point in act.track_points() {
+            pyramid.add_point(point);
+        }
+    }
+    pyramid.downsample();
+    if let Some(dir) = args.tiles_dir {
+        let count = pyramid.write_dir(&path::PathBuf::from(&dir))?;
+        info!("Wrote {} tiles to {}", count, dir);
+    }
+    if let Some(file) = args.mbtiles {
+        let count = pyramid.write_mbtiles(&path::PathBuf::from(&file), "heatmap")?;
+        info!("Wrote {} tiles to {}", count, file);
+    }
+    Ok(())
+}
+
+/// Import the activity files of the inbox, again and again if watching
+fn import(args: ImportArgs) -> Result<(), Box<dyn Error>> {
+    let inbox = path::Path::new(&args.inbox);
+    let mut importer = Importer::new(path::Path::new(&args.data))?;
+    if args.watch {
+        interrupt::catch();
+        info!("Watching {}, press Ctrl-C to stop", args.inbox);
+    }
+    loop {
+        let imported = {
+            let _hold = interrupt::hold();
+            importer.import(inbox)?
+        };
+        if !args.watch {
+            info!("Imported {} activities into {}", imported.len(), args.data);
+            return Ok(());
+        }
+        for _ in 0..args.interval.max(1) {
+            // Ctrl-C is how watching ends, so it is no failure
+            if interrupt::interrupted() {
+                interrupt::clear();
+                return Ok(());
+            }
+            std::thread::sleep(Duration::from_secs(1));
+        }
+    }
+}
+
+/// Show the location and size of the tile cache, clearing it if requested
+fn cache(args: CacheArgs) -> Result<(), Box<dyn Error>> {
+    let dir = osmbase::cache_dir();
+    if !dir.exists() {
+        println!("No tiles cached in {}", dir.display());
+        return Ok(());
+    }
+    let (mut count, mut size) = (0, 0);
+    for entry in std::fs::read_dir(&dir)? {
+        let metadata = entry?.metadata()?;
+        if metadata.is_file() {
+            count += 1;
+            size += metadata.len();
+        }
+    }
+    if args.clear {
+        std::fs::remove_dir_all(&dir)?;
+        println!(
+            "Removed {} tiles ({:.1} MB) from {}",
+            count,
+            size as f64 / 1e6,
+            dir.display()
+        );
+    } else {
+        println!(
+            "{} tiles ({:.1} MB) cached in {}",
+            count,
+            size as f64 / 1e6,
+            dir.display()
+        );
+    }
+    Ok(())
+}
+
+/// Lists, fetches or removes the assets named, or all of them
+fn assets(args: AssetsArgs) -> Result<(), Box<dyn Error>> {
+    let selected: Vec<&assets::Asset> = if args.names.is_empty() {
+        assets::ASSETS.iter().collect()
+    } else {
+        args.names
+            .iter()
+            .map(|name| assets::find(name).ok_or_else(|| format!("no asset named {}", name)))
+            .collect::<Result<_, _>>()?
+    };
+    match args.action {
+        AssetAction::List => {
+            for asset in selected {
+                let state = match asset.size() {
+                    Some(size) => format!("{:.1} MB", size as f64 / 1e6),
+                    None => "not fetched".to_string(),
+                };
+                println!("{:<14}{:<14}{}", asset.name, state, asset.description);
+            }
+            println!(
+                "Fetched assets are kept in {}",
+                assets::directory().display()
+            );
+        }
+        AssetAction::Fetch => {
+            let mut failed = 0;
+            for asset in selected {
+                if asset.size().is_some() && !args.force {
+                    println!("{} was fetched already", asset.name);
+                    continue;
+                }
+                match asset.fetch() {
+                    Ok(size) => println!("Fetched {} ({:.1} MB)", asset.name, size as f64 / 1e6),
+                    Err(e) => {
+                        warn!("Cannot fetch {}: {}", asset.name, e);
+                        failed += 1;
+                    }
+                }
+            }
+            if failed > 0 {
+                return Err(format!("{} assets could not be fetched", failed).into());
+            }
+        }
+        AssetAction::Remove => {
+            for asset in selected.iter().filter(|asset| asset.size().is_some()) {
+                std::fs::remove_file(asset.path())?;
+                println!("Removed {}", asset.name);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// A file given by path, or by the name of a fetched asset if no such file exists, e.g.,
+/// `countries`
+fn asset_path(file: &str) -> Result<path::PathBuf, Box<dyn Error>> {
+    let path = path::PathBuf::from(file);
+    if path.exists() {
+        return Ok(path);
+    }
+    match (assets::find(file), assets::fetched(file)) {
+        (_, Some(fetched)) => Ok(fetched),
+        (Some(_), None) => Err(format!(
+            "{} is not fetched yet, see `derivers assets fetch {}`",
+            file, file
+        )
+        .into()),
+        (None, None) => Err(format!("{}: no such file or asset", file).into()),
+    }
+}
+
+/// Write activity contributions as CSV or JSON, depending on the file extension
+fn write_contributions(
+    file: &path::Path,
+    contributions: &[Contribution],
+) -> Result<(), Box<dyn Error>> {
+    let is_csv = file
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("csv"));
+    let _hold = interrupt::hold();
+    if is_csv {
+        let mut writer = csv::Writer::from_path(file)?;
+        for contribution in contributions {
+            writer.serialize(contribution)?;
+        }
+        writer.flush()?;
+    } else {
+        std::fs::write(file, serde_json::to_string_pretty(contributions)?)?;
+    }
+    Ok(())
+}
+
+/// Draw which basemap tiles of the view are cached and which would be downloaded
+fn plan(args: &HeatmapArgs, file: &str) -> Result<(), Box<dyn Error>> {
+    let view = args.view.map()?;
+    let basemap = new_builder(args, view)
+        .basemap(view)?
+        .ok_or("--no-basemap and --background leave no tiles to plan")?;
+    basemap.plan_image().save(file)?;
+    println!("{}", basemap.plan_summary());
+    Ok(())
+}
+
+/// Renders at the zoom level resolving the GPS points with `--auto-zoom`, or else suggests it
+fn fit_zoom(args: &HeatmapArgs, activities: &[WorldActivity], view: slippy::Map) -> slippy::Map {
+    let spacing = match stats::point_spacing(activities, &view) {
+        Some(spacing) => spacing,
+        None => return view,
+    };
+    // Each zoom level doubles the distance in pixels
+    let zoom = view.zoom();
+    let best = (zoom as f64 - spacing.log2())
+        .round()
+        .clamp(0.0, args.max_zoom() as f64) as u8;
+    if args.auto_zoom && best != zoom {
+        let fitted = view.scaled_to(best);
+        let (width, height) = fitted.pixel_size();
+        info!(
+            "GPS points are {:.2} pixels apart at zoom {}, rendering the same area at zoom {} in \
+             {}x{} pixels instead",
+            spacing, zoom, best, width, height
+        );
+        return fitted;
+    } else if spacing < 0.5 && best > zoom {
+        warn!(
+            "GPS points are only {:.2} pixels apart, so most pixels collect several of them; \
+             zoom {} would resolve them (see --auto-zoom)",
+            spacing, best
+        );
+    } else if spacing > 4.0 && best < zoom {
+        warn!(
+            "GPS points are {:.1} pixels apart, so tracks look dotted; consider --blur or zoom \
+             {} (see --auto-zoom)",
+            spacing, best
+        );
+    }
+    view
+}
+
+/// List the activities that would be rendered, in the order they would be added, with the
+/// number of their points in view
+fn dry_run(args: &HeatmapArgs) -> Result<(), Box<dyn Error>> {
+    let directory = args
+        .directory
+        .as_ref()
+        .ok_or_else(|| missing("directory"))?;
+    let activities = args.filter.export(directory)?.world_activities();
+    let view = fit_zoom(args, &activities, args.view.map()?);
+    diagnostics::print_summary();
+    let probe = PixelHeatmap::from(view, false, false);
+    let visible = new_builder(args, view).project(&activities, &probe);
+    for act in visible.iter() {
+        println!(
+            "{}  {:>8}  {}",
+            act.date.format("%Y-%m-%d %H:%M"),
+            act.track_points.len(),
+            act.name
+        );
+    }
+    let (width, height) = view.pixel_size();
+    println!(
+        "{} of {} activities in view with {} points, at zoom {} in {}x{} pixels",
+        visible.len(),
+        activities.len(),
+        visible.iter().map(|a| a.track_points.len()).sum::<usize>(),
+        view.zoom(),
+        width,
+        height
+    );
+    Ok(())
+}
+
+/// Draw where the heat of two saved states differs
+fn diff(args: DiffArgs) -> Result<(), Box<dyn Error>> {
+    let open = |file: &str| -> Result<_, Box<dyn Error>> {
+        Ok(std::io::BufReader::new(std::fs::File::open(file)?))
+    };
+    let difference = heat::diff_states(&mut open(&args.before)?, &mut open(&args.after)?)?;
+    let output = match args.over {
+        Some(ref file) => {
+            let mut image = image::open(file)?.to_rgba8();
+            if image.dimensions() != difference.dimensions() {
+                return Err(format!("{} differs in size from the saved heat", file).into());
+            }
+            image::imageops::overlay(&mut image, &difference, 0, 0);
+            image
+        }
+        None => difference,
+    };
+    output.save(&args.output)?;
+    Ok(())
+}
+
+/// Compare two images, optionally failing below a similarity
+fn diff_images(args: DiffImagesArgs) -> Result<(), Box<dyn Error>> {
+    let first = image::open(&args.first)?.to_rgba8();
+    let second = image::open(&args.second)?.to_rgba8();
+    let comparison = Comparison::from(&first, &second)?;
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&comparison)?);
+    } else {
+        print!("{}", comparison);
+    }
+    if let Some(ref file) = args.output {
+        compare::diff_image(&first, &second).save(file)?;
+    }
+    match args.min_ssim {
+        Some(min) if comparison.ssim < min as f64 => {
+            Err(format!("SSIM {:.5} is below {}", comparison.ssim, min).into())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// List, show, re-run or forget previous renders
+fn history(args: HistoryArgs) -> Result<(), Box<dyn Error>> {
+    let file = history::history_file();
+    let history = History::open(&file)?;
+    if args.clear {
+        let count = history.clear()?;
+        println!("Removed {} runs from {}", count, file.display());
+        return Ok(());
+    }
+    if let Some(id) = args.show.or(args.rerun) {
+        let past = history
+            .get(id)?
+            .ok_or_else(|| format!("no run {} in the history", id))?;
+        if args.show.is_some() {
+            print!("{}", past.config);
+        } else {
+            info!("Re-running {} {} of {}", past.command, past.id, past.time);
+            run(Command::from_toml(&past.command, &past.config)?)?;
+        }
+        return Ok(());
+    }
+    for past in history.runs(args.limit)?.iter().rev() {
+        let stat = |key: &str| {
+            past.stats
+                .iter()
+                .find(|(k, _)| k == key)
+                .map_or("?", |(_, v)| v.as_str())
+        };
+        println!(
+            "{:>4}  {}  {:<6}  {}  max {} {}  {}",
+            past.id,
+            past.time.format("%Y-%m-%d %H:%M"),
+            past.command,
+            &past.config_hash[..8],
+            stat("Heat Maximum"),
+            stat("Heat Unit"),
+            past.output.as_deref().unwrap_or("stdout"),
+        );
+    }
+    Ok(())
+}
+
+/// Set up drawing a view over the requested background or tile source
+fn new_builder(args: &HeatmapArgs, view: slippy::Map) -> RenderBuilder {
+    let background = match (&args.basemap_file, &args.url) {
+        _ if !args.tiled_basemap() => match args.background {
+            Some(Color([r, g, b])) => Background::Color(image::Rgba([r, g, b, 255])),
+            None => Background::Transparent,
+        },
+        (Some(file), _) => Background::File(path::PathBuf::from(file)),
+        (None, Some(url)) => Background::Url(url.clone()),
+        (None, None) => Background::Provider(args.provider),
+    };
+    RenderBuilder::from_view(view)
+        .with_background(background)
+        .with_subdomains(&args.subdomains)
+        .with_retries(args.retries + 1, Duration::from_millis(args.retry_delay))
+        .with_rate_limit(args.rate_limit)
+        .with_style(args.basemap_style)
+        .with_resampling(args.resampling)
+        .with_order(args.order, args.reverse)
+        .with_attribution(!args.no_attribution)
+        .with_attribution_text(args.attribution.clone())
+}
+
+/// The GPU shared by the heatmaps of `--gpu`, e.g., those of the strips of a poster, set up
+/// once when first needed; None if there is none
+#[cfg(feature = "gpu")]
+static GPU: std::sync::OnceLock<Option<std::sync::Arc<gpu::Gpu>>> = std::sync::OnceLock::new();
+
+/// Reports once that `--gpu` found no usable GPU, as heatmaps are created per strip or frame
+#[cfg(feature = "gpu")]
+static NO_GPU: std::sync::Once = std::sync::Once::new();
+
+/// Create an empty heatmap of the requested kind
+fn new_heatmap(
+    args: &HeatmapArgs,
+    video: Option<&VideoArgs>,
+    map: slippy::Map,
+    fixed_max: Option<f64>,
+    bounds: Option<(f64, f64)>,
+) -> Box<dyn Heatmap + Send> {
+    let (date, title) = video.map_or((false, false), |v| (v.date, v.title));
+    let clip = args.clip_percentile.map(|percent| percent / 100.0);
+    let tiles = |zoom, rounded| {
+        let heatmap = TileHeatmap::from(map, zoom)
+            .with_rounded_tiles(rounded)
+            .with_outlines(args.explorer_outlines)
+            .with_fixed_max(fixed_max)
+            .with_clip_percentile(clip)
+            .with_cap(args.tile_cap, args.spread_overflow);
+        Box::new(match args.color_by {
+            ColorBy::Months => heatmap.with_period_colors(Period::Month),
+            ColorBy::Weeks => heatmap.with_period_colors(Period::Week),
+            ColorBy::Heat | ColorBy::Elevation | ColorBy::Temperature | ColorBy::FitField => {
+                heatmap
+            }
+        })
+    };
+    match args.heatmap {
+        HeatmapKind::Pixel => {
+            let sigma = args.blur.map_or(0.0, |b| b.to_pixels(&map));
+            let heatmap = PixelHeatmap::from(map, date, title)
+                .with_blur(sigma)
+                .with_fixed_max(fixed_max)
+                .with_clip_percentile(clip)
+                .with_distance_weights(matches!(args.weight, Weight::Distance))
+                .with_age_weights(!matches!(args.age_falloff, AgeFalloff::None));
+            let measured = match args.color_by {
+                ColorBy::Heat | ColorBy::Months | ColorBy::Weeks => None,
+                ColorBy::Elevation => Some((
+                    Measurement::Elevation,
+                    args.elevation_min,
+                    args.elevation_max,
+                )),
+                ColorBy::Temperature => Some((
+                    Measurement::Temperature,
+                    args.temperature_min,
+                    args.temperature_max,
+                )),
+                ColorBy::FitField => Some((
+                    Measurement::FitField,
+                    args.fit_field_min,
+                    args.fit_field_max,
+                )),
+            };
+            let heatmap = match measured {
+                // Bounds given by the caller, e.g., shared by the strips of a poster, come first
+                Some((measurement, min, max)) => heatmap.with_measurement_colors(
+                    measurement,
+                    bounds.map_or(min, |(min, _)| Some(min)),
+                    bounds.map_or(max, |(_, max)| Some(max)),
+                ),
+                None => heatmap,
+            };
+            #[cfg(feature = "gpu")]
+            if args.gpu {
+                let shared = GPU.get_or_init(|| match gpu::Gpu::new() {
+                    Ok(gpu) => Some(std::sync::Arc::new(gpu)),
+                    Err(e) => {
+                        NO_GPU.call_once(|| warn!("Adding up heat on the CPU: {}", e));
+                        None
+                    }
+                });
+                if let Some(gpu) = shared {
+                    let (width, height) = map.pixel_size();
+                    match gpu.holds(width, height) {
+                        Ok(()) => return Box::new(GpuHeatmap::from(heatmap, gpu.clone())),
+                        Err(e) => NO_GPU.call_once(|| warn!("Adding up heat on the CPU: {}", e)),
+                    }
+                }
+            }
+            Box::new(heatmap)
+        }
+        HeatmapKind::Squadrat => tiles(SQUADRAT_ZOOM, false),
+        HeatmapKind::Squadratinho => tiles(SQUADRATINHO_ZOOM, false),
+        HeatmapKind::SquadratRounded => tiles(SQUADRAT_ZOOM, true),
+        HeatmapKind::SquadratinhoRounded => tiles(SQUADRATINHO_ZOOM, true),
+    }
+}
+
+/// Pixel bounds of all visible content as `(x, y, width, height)`, grown by the padding but
+/// staying within the image
+fn content_bounds(image: &image::RgbaImage, padding: f64) -> Option<(u32, u32, u32, u32)> {
+    let (mut min_x, mut min_y, mut max_x, mut max_y) = (u32::MAX, u32::MAX, 0, 0);
+    for (x, y, pixel) in image.enumerate_pixels() {
+        if pixel[3] > 0 {
+            min_x = min_x.min(x);
+            min_y = min_y.min(y);
+            max_x = max_x.max(x);
+            max_y = max_y.max(y);
+        }
+    }
+    if min_x > max_x {
+        return None;
+    }
+    let padding = padding.round() as u32;
+    let (left, top) = (min_x.saturating_sub(padding), min_y.saturating_sub(padding));
+    let right = (max_x + 1 + padding).min(image.width());
+    let bottom = (max_y + 1 + padding).min(image.height());
+    Some((left, top, right - left, bottom - top))
+}
+
+/// Extra steps for single images
+#[derive(Default)]
+struct Finish<'a> {
+    /// Trim to all heat plus this padding
+    crop: Option<Length>,
+    /// Write this many frames cycling the palette instead of the image
+    cycle: Option<u32>,
+    /// Write the opacity of the heat to this file, with the given bits per pixel
+    alpha: Option<(&'a str, u8)>,
+    /// Write the layers of the image to this OpenRaster file
+    layers: Option<&'a str>,
+    /// Render the activities of this second export in their own color
+    compare: Option<&'a str>,
+    /// Write an HTML report to this file, laid out by the given template
+    report: Option<(&'a str, Option<&'a str>)>,
+    /// Write the image to this PNG file in strips of at least the given number of rows
+    strips: Option<(&'a str, u32)>,
+}
+
+/// Scale a photo to fit into a quarter of the view, with a white frame
+fn photo_insert(photo: &image::DynamicImage, view: &slippy::Map) -> image::RgbaImage {
+    let (width, height) = view.pixel_size();
+    let scaled = photo
+        .resize(width / 4, height / 4, image::imageops::FilterType::Triangle)
+        .to_rgba8();
+    let border = (height / 200).max(1);
+    let mut framed = image::RgbaImage::from_pixel(
+        scaled.width() + 2 * border,
+        scaled.height() + 2 * border,
+        image::Rgba([255; 4]),
+    );
+    image::imageops::overlay(&mut framed, &scaled, border, border);
+    framed
+}
+
+/// Write a frame of a video showing the given activity and date, counting it and listing it
+/// in the frame log and manifest, which is saved now and then; a frame skipped as a duplicate
+/// is logged as a repeat of the one before
+fn write_video_frame(
+    sink: &mut dyn OutputSink,
+    (log, manifest): (Option<&mut FrameLog>, Option<&mut Manifest>),
+    written: &mut u64,
+    pixmap: image::DynamicImage,
+    metadata: &Metadata,
+    (activity, date): (&str, chrono::DateTime<chrono::Utc>),
+) -> Result<(), Box<dyn Error>> {
+    let is_new = sink.write(pixmap, metadata)?;
+    if is_new {
+        *written += 1;
+    }
+    match log {
+        Some(log) if is_new => log.frame(*written, activity, date)?,
+        Some(log) => log.repeat(),
+        None => {}
+    }
+    if let Some(manifest) = manifest {
+        if let (Some(file), true) = (sink.frame_file(*written), is_new) {
+            manifest.frame(*written, file, activity, date);
+        }
+        manifest.checkpoint(sink.stored())?;
+    }
+    Ok(())
+}
+
+/// Draw a heatmap over the basemap into the sink, either as a single image or, given a video or
+/// palette cycle, as frames; images written in strips bypass the sink
+fn render(
+    args: &HeatmapArgs,
+    video: Option<&VideoArgs>,
+    finish: Finish,
+    sink: &mut dyn OutputSink,
+) -> Result<Metadata, Box<dyn Error>> {
+    let directory = args
+        .directory
+        .as_ref()
+        .ok_or_else(|| missing("directory"))?;
+    fraction(&args.tint.to_string())?;
+    fraction(&args.vignette_tint.to_string())?;
+    let tint = Tint::from(args.tint).with_vignette(match args.vignette {
+        VignetteKind::None => Vignette::None,
+        VignetteKind::Radial => Vignette::Radial(args.vignette_tint),
+        VignetteKind::Linear => Vignette::Linear(args.vignette_tint),
+    });
+
+    let mut reference_map = args.view.map()?;
+    let export = args.filter.export(directory)?;
+    let photos = match video {
+        Some(video) if video.photos => export.photos(),
+        _ => Photos::default(),
+    };
+    if video.is_some_and(|v| v.photos) && photos.is_empty() {
+        warn!("No photos listed in activities.csv");
+    }
+
+    // Download the basemap while parsing, unless the view still depends on the activities
+    let prefetch = std::cell::Cell::new(None);
+    if !args.auto_zoom && finish.crop.is_none() {
+        let basemap = new_builder(args, reference_map).basemap(reference_map)?;
+        prefetch.set(basemap.and_then(|basemap| basemap.prefetch()));
+    }
+
+    let mut timings = Timings::default();
+    let start = Instant::now();
+    let parsed = export.activities();
+    let summary = finish.report.map(|_| Summary::from(&parsed));
+    let world_activities: Vec<WorldActivity> =
+        parsed.into_par_iter().map(Activity::to_world).collect();
+    timings.record(
+        "Parsing",
+        start,
+        world_activities.len() as u64,
+        "activities",
+    );
+
+    reference_map = fit_zoom(args, &world_activities, reference_map);
+    let builder = new_builder(args, reference_map).with_tint(tint);
+    let mut borders = Borders::default();
+    for file in &args.borders {
+        borders.extend(Borders::from_file(&asset_path(file)?)?);
+    }
+    let border_color = image::Rgba([255, 255, 255, (args.border_opacity * 255.0) as u8]);
+    let basemap = builder.basemap(reference_map)?;
+    // The tinted basemap with borders, or only the borders on the background color or a
+    // transparent background
+    let draw_basemap = |basemap: Option<&Basemap>,
+                        view: &slippy::Map,
+                        tint: Tint,
+                        timings: &mut Timings|
+     -> Result<image::DynamicImage, Box<dyn Error>> {
+        let start = Instant::now();
+        // Tiles being downloaded in the background are not to be read half written
+        if let Some(prefetch) = prefetch.take() {
+            let _ = prefetch.join();
+        }
+        let mut pixmap = builder.draw_background(basemap, view, &tint)?;
+        if let Some(basemap) = basemap {
+            timings.record("Basemap", start, basemap.tile_count(), "tiles");
+        }
+        borders.draw(&mut pixmap, view, border_color);
+        Ok(pixmap)
+    };
+    let load_states = |map: &mut Box<dyn Heatmap + Send>| -> Result<(), Box<dyn Error>> {
+        for file in &args.load_state {
+            let mut reader = std::io::BufReader::new(std::fs::File::open(file)?);
+            map.load_state(&mut reader)
+                .map_err(|e| format!("{}: {}", file, e))?;
+        }
+        Ok(())
+    };
+    let distance_weighted = matches!(args.weight, Weight::Distance);
+    let weighted = distance_weighted || !matches!(args.age_falloff, AgeFalloff::None);
+    // Activities without a date weigh as much as the newest one
+    let newest = world_activities
+        .iter()
+        .map(|a| a.date)
+        .filter(|date| !activity::is_undated(date))
+        .max();
+    let meters_per_pixel = reference_map.meters_per_pixel();
+    let steps = |act: &ScreenActivity| {
+        let age = match newest {
+            Some(newest) if !activity::is_undated(&act.date) => {
+                (newest - act.date).num_seconds() as f64 / 86400.0
+            }
+            _ => 0.0,
+        };
+        let factor = args.age_falloff.weight(age, args.age_span);
+        if distance_weighted {
+            let mut steps = act.step_lengths(meters_per_pixel, args.max_step);
+            steps.iter_mut().for_each(|step| *step *= factor);
+            steps
+        } else {
+            vec![factor; act.track_points.len()]
+        }
+    };
+
+    let add_point = |map: &mut Box<dyn Heatmap + Send>,
+                     tp: &TrackPoint<geo_types::Coord<u32>>,
+                     weight: f64,
+                     date: &chrono::DateTime<chrono::Utc>| {
+        if weighted {
+            map.add_weighted_point(&tp.point, weight);
+        } else {
+            map.add_point_at(&tp.point, &tp.time.unwrap_or(*date));
+        }
+        if let Some(elevation) = tp.elevation {
+            map.add_measurement(&tp.point, Measurement::Elevation, elevation);
+        }
+        if let Some(temperature) = tp.sensors.temperature {
+            map.add_measurement(&tp.point, Measurement::Temperature, temperature);
+        }
+        if let Some(value) = tp.sensors.field {
+            map.add_measurement(&tp.point, Measurement::FitField, value);
+        }
+    };
+    diagnostics::print_summary();
+
+    if let Some(ref file) = args.top_activities {
+        let ranked = Contribution::ranked(&world_activities, &reference_map);
+        write_contributions(&path::PathBuf::from(file), &ranked)?;
+        info!("{} activities in view, ranked in {}", ranked.len(), file);
+    }
+
+    if let Some((file, rows)) = finish.strips {
+        let (width, height) = reference_map.pixel_size();
+        let reach = match args.heatmap {
+            HeatmapKind::Pixel => {
+                heat::blur_reach(args.blur.map_or(0.0, |b| b.to_pixels(&reference_map)))
+            }
+            _ => 0,
+        };
+        // Strips of equal height, at least the rows asked for and those the credit covers, so
+        // that it fits into the last one
+        let count = (height / rows.max(builder.credit_height(height))).max(1);
+        let strips: Vec<(u32, u32)> = (0..count)
+            .map(|i| (height * i / count, height * (i + 1) / count))
+            .collect();
+        // The heat of a strip, together with the rows around it that its blur reaches, and the
+        // number of those above it
+        let accumulate = |top: u32, bottom: u32, max, bounds, timings: &mut Timings| {
+            let start = Instant::now();
+            let above = top.min(reach);
+            let below = (height - bottom).min(reach);
+            let view = reference_map.crop(0, top - above, width, bottom - top + above + below);
+            let mut map = new_heatmap(args, None, view, max, bounds);
+            let mut points = 0;
+            for act in builder.project(&world_activities, &*map) {
+                for (tp, weight) in act.track_points.iter().zip(steps(&act)) {
+                    add_point(&mut map, tp, weight, &act.date);
+                    points += 1;
+                }
+            }
+            timings.record("Accumulation", start, points, "points");
+            (map, above)
+        };
+
+        // Strips share the normalization of heat and measurements, so that they match up;
+        // without a fixed maximum, a first pass finds the highest heat of any strip
+        let colored = matches!(
+            args.color_by,
+            ColorBy::Elevation | ColorBy::Temperature | ColorBy::FitField
+        );
+        let (mut max, mut bounds) = (args.fixed_max, None);
+        if max.is_none() || colored {
+            let mut highest: f64 = 0.0;
+            for (index, &(top, bottom)) in strips.iter().enumerate() {
+                progress::emit(&Progress::new("normalization", index as u64, count as u64));
+                let (map, _) = accumulate(top, bottom, args.fixed_max, None, &mut timings);
+                highest = highest.max(map.max_value());
+                if let Some((min, max)) = map.measurement_range() {
+                    bounds = Some(bounds.map_or((min, max), |(low, high): (f64, f64)| {
+                        (low.min(min), high.max(max))
+                    }));
+                }
+            }
+            max = max.or(Some(highest));
+        }
+        let normalization = match args.fixed_max {
+            Some(_) => "fixed",
+            None => "own",
+        };
+        // A single row stands in for the whole view, as all strips share the normalization
+        let probe = new_heatmap(args, None, reference_map.crop(0, 0, width, 1), max, bounds);
+        let metadata = builder.metadata(&*probe, normalization);
+        let mut png = metadata::PngStrips::create(path::Path::new(file), width, height, &metadata)?;
+        for (index, &(top, bottom)) in strips.iter().enumerate() {
+            progress::emit(&Progress::new("strips", index as u64, count as u64));
+            let (map, above) = accumulate(top, bottom, max, bounds, &mut timings);
+            let heat = map.as_image().to_rgba8();
+            let heat = image::imageops::crop_imm(&heat, 0, above, width, bottom - top).to_image();
+            let view = reference_map.crop(0, top, width, bottom - top);
+            let basemap = builder.basemap(view)?;
+            let strip_tint = tint.within(0, top, width, height);
+            let mut pixmap = draw_basemap(basemap.as_ref(), &view, strip_tint, &mut timings)?;
+            image::imageops::overlay(&mut pixmap, &heat, 0, 0);
+            if bottom == height {
+                builder.credit_sized(&mut pixmap, height);
+            }
+            png.write(&pixmap.to_rgba8())?;
+        }
+        png.finish()?;
+        if args.timings {
+            info!("{}", timings.to_string().trim_end());
+        }
+        return Ok(metadata);
+    }
+
+    let mut map = new_heatmap(args, video, reference_map, args.fixed_max, None);
+    load_states(&mut map)?;
+    let activities = builder.project(&world_activities, &*map);
+
+    let shared_max = video.is_some_and(|v| v.shared_max) && args.fixed_max.is_none();
+    let normalization = if args.fixed_max.is_some() {
+        "fixed"
+    } else if shared_max {
+        // Accumulate everything once to know the final heat before rendering any frame
+        let mut full = new_heatmap(args, video, reference_map, None, None);
+        load_states(&mut full)?;
+        for act in activities.iter() {
+            for (tp, weight) in act.track_points.iter().zip(steps(act)) {
+                full.add_weighted_point(&tp.point, weight);
+            }
+        }
+        let max = match args.clip_percentile {
+            Some(percent) => full.heat_percentile(percent / 100.0),
+            None => full.max_value(),
+        };
+        map = new_heatmap(args, video, reference_map, Some(max), None);
+        load_states(&mut map)?;
+        "shared"
+    } else if args.clip_percentile.is_some() {
+        "percentile"
+    } else {
+        "own"
+    };
+
+    let mut manifest = video
+        .and_then(|v| v.frames_dir.as_deref())
+        .map(|dir| Manifest::new(path::Path::new(dir)));
+    let mut frame_log = match video.and_then(|v| v.frame_log.as_ref()) {
+        Some(file) => Some(FrameLog::create(path::Path::new(file))?),
+        None => None,
+    };
+    // Activity shown in the last frame of a video
+    let mut last_activity = None;
+    let mut counter = 0;
+    // Single images may be cropped, only fetch their basemap once the heat is known
+    let rendered_basemap = match video {
+        Some(_) => Some(draw_basemap(
+            basemap.as_ref(),
+            &reference_map,
+            tint,
+            &mut timings,
+        )?),
+        None => None,
+    };
+    let mut canvas = rendered_basemap.clone().map(Canvas::new);
+    let start = Instant::now();
+    let mut frame_time = Duration::ZERO;
+    // Photo in the corner and the number of frames it is still shown in
+    let mut shown_photo: Option<(image::RgbaImage, u32)> = None;
+    let total = activities.len() as u64;
+    let mut written = 0;
+    // Drawn only if stderr is a terminal and notes are printed, i.e., without `--quiet`
+    let points = activities.iter().map(|a| a.track_points.len() as u64).sum();
+    let bar =
+        indicatif::ProgressBar::new(points).with_style(indicatif::ProgressStyle::with_template(
+            "{wide_bar} {human_pos}/{human_len} points{msg}, {eta} left",
+        )?);
+    if !log_enabled!(log::Level::Info) {
+        bar.set_draw_target(indicatif::ProgressDrawTarget::hidden());
+    }
+    // Ctrl-C stops adding activities, what was added so far is still written
+    interrupt::catch();
+    for (index, act) in activities.into_iter().enumerate() {
+        last_activity = Some((act.name.clone(), act.date));
+        let step = Progress::new("accumulation", index as u64, total).with_activity(&act.name);
+        progress::emit(&match video {
+            Some(_) => step.with_frames(written),
+            None => step,
+        });
+        match (video, photos.first(&act.path)) {
+            (Some(video), Some(Ok(photo))) => {
+                shown_photo = Some((photo_insert(&photo, &reference_map), video.photo_frames))
+            }
+            (_, Some(Err(e))) => bar.suspend(|| warn!("Cannot read photo of {}: {}", act.name, e)),
+            _ => {}
+        }
+        let weights = steps(&act);
+        for (tp, weight) in act.track_points.iter().zip(weights) {
+            add_point(&mut map, tp, weight, &act.date);
+
+            counter += 1;
+
+            if let (Some(video), Some(canvas)) = (video, canvas.as_mut()) {
+                if counter % video.frame_rate == 0 {
+                    let frame_start = Instant::now();
+                    let mut pixmap = canvas.update(&mut *map).clone();
+                    if let Some((ref photo, ref mut frames)) = shown_photo {
+                        let height = reference_map.pixel_size().1;
+                        let margin = height / 40;
+                        let y = height.saturating_sub(photo.height() + margin);
+                        image::imageops::overlay(&mut pixmap, photo, margin, y);
+                        *frames = frames.saturating_sub(1);
+                    }
+                    if shown_photo.as_ref().is_some_and(|(_, frames)| *frames == 0) {
+                        shown_photo = None;
+                    }
+                    builder.credit(&mut pixmap);
+                    let metadata = builder.metadata(&*map, normalization);
+                    let shows = (act.name.as_str(), act.date);
+                    let logs = (frame_log.as_mut(), manifest.as_mut());
+                    write_video_frame(sink, logs, &mut written, pixmap, &metadata, shows)?;
+                    frame_time += frame_start.elapsed();
+                    bar.set_message(format!(", {} frames", written));
+                    progress::emit(
+                        &Progress::new("accumulation", index as u64, total)
+                            .with_activity(&act.name)
+                            .with_frames(written),
+                    );
+                }
+            }
+        }
+
+        // FIXME: this is pretty ugly.
+        // map.decay(1);
+
+        bar.inc(act.track_points.len() as u64);
+        if interrupt::interrupted() {
+            bar.suspend(|| {
+                warn!(
+                    "{}",
+                    tr!(
+                        "Interrupted, finishing with {} of {} activities; press Ctrl-C again to \
+                         quit",
+                        index + 1,
+                        total
+                    )
+                )
+            });
+            break;
+        }
+    }
+    bar.finish_and_clear();
+    timings.add(
+        "Accumulation",
+        start.elapsed() - frame_time,
+        counter as u64,
+        "points",
+    );
+    if video.is_some() {
+        timings.add("Frames", frame_time, written, "frames");
+    }
+
+    if let Some(ref file) = args.save_state {
+        let _hold = interrupt::hold();
+        let mut writer = std::io::BufWriter::new(std::fs::File::create(file)?);
+        map.save_state(&mut writer)?;
+    }
+
+    if args.explorer {
+        match map.visited_tiles() {
+            Some(visited) => {
+                let explorer = visited.explorer();
+                let square = explorer.max_square.map_or(0, |s| s.size);
+                info!("Max square: {}x{} tiles", square, square);
+                info!("Max cluster: {} tiles", explorer.max_cluster.len());
+            }
+            None => warn!("Explorer statistics require a tile heatmap"),
+        }
+    }
+
+    if let Some(ref file) = args.tiles_geojson {
+        match map.visited_tiles() {
+            Some(visited) => {
+                let _hold = interrupt::hold();
+                std::fs::write(file, visited.to_geojson().to_string())?
+            }
+            None => warn!("Exporting visited tiles requires a tile heatmap"),
+        }
+    }
+
+    let heat_pixmap = match finish.compare {
+        Some(directory) => {
+            let start = Instant::now();
+            let others = args.filter.export(directory)?.world_activities();
+            timings.record("Parsing", start, others.len() as u64, "activities");
+            let mut other = new_heatmap(args, video, reference_map, args.fixed_max, None);
+            let start = Instant::now();
+            let mut points = 0;
+            for act in builder.project(&others, &*other) {
+                for (tp, weight) in act.track_points.iter().zip(steps(&act)) {
+                    add_point(&mut other, tp, weight, &act.date);
+                    points += 1;
+                }
+            }
+            timings.record("Accumulation", start, points, "points");
+            heat::compare_heat(&*map, &*other).to_rgba8()
+        }
+        None => map.as_image().to_rgba8(),
+    };
+    let bounds = finish
+        .crop
+        .and_then(|padding| content_bounds(&heat_pixmap, padding.to_pixels(&reference_map)));
+    let crop_heat = |heat: image::RgbaImage| match bounds {
+        Some((x, y, width, height)) => {
+            image::imageops::crop_imm(&heat, x, y, width, height).to_image()
+        }
+        None => heat,
+    };
+    let basemap = match (rendered_basemap, bounds) {
+        (Some(pixmap), _) => pixmap,
+        (None, Some((x, y, width, height))) => {
+            let view = reference_map.crop(x, y, width, height);
+            draw_basemap(builder.basemap(view)?.as_ref(), &view, tint, &mut timings)?
+        }
+        (None, None) => draw_basemap(basemap.as_ref(), &reference_map, tint, &mut timings)?,
+    };
+    let metadata = builder.metadata(&*map, normalization);
+
+    if let Some((file, depth)) = finish.alpha {
+        let mut mask = map.as_mask();
+        if let Some((x, y, width, height)) = bounds {
+            mask = image::imageops::crop_imm(&mask, x, y, width, height).to_image();
+        }
+        let mask = match depth {
+            16 => image::DynamicImage::ImageLuma16(mask),
+            _ => image::DynamicImage::ImageLuma8(image::ImageBuffer::from_fn(
+                mask.width(),
+                mask.height(),
+                |x, y| image::Luma([(mask.get_pixel(x, y)[0] / 257) as u8]),
+            )),
+        };
+        let _hold = interrupt::hold();
+        mask.save(file)?;
+    }
+
+    if let Some(frames) = finish.cycle {
+        let start = Instant::now();
+        for frame in 0..frames {
+            let heat = map
+                .as_image_at_phase(frame as f64 / frames as f64)
+                .to_rgba8();
+            let pixmap = builder.overlay(&basemap, &crop_heat(heat));
+            sink.write(pixmap, &metadata)?;
+            let written = frame as u64 + 1;
+            progress::emit(&Progress::new("frames", written, frames as u64).with_frames(written));
+        }
+        sink.finish()?;
+        timings.record("Frames", start, frames as u64, "frames");
+    }
+
+    let heat_pixmap = crop_heat(heat_pixmap);
+    let pixmap = builder.overlay(&basemap, &heat_pixmap);
+    if let Some(file) = finish.layers {
+        let (width, height) = heat_pixmap.dimensions();
+        let mut overlay = image::DynamicImage::new_rgba8(width, height);
+        builder.credit(&mut overlay);
+        let layers = [
+            ("Basemap", basemap.to_rgba8()),
+            ("Heat", heat_pixmap),
+            ("Attribution", overlay.to_rgba8()),
+        ];
+        metadata::save_openraster(&layers, &pixmap, &path::PathBuf::from(file), &metadata)?;
+    }
+    if let (Some((file, template)), Some(summary)) = (finish.report, summary) {
+        let template = match template {
+            Some(template) => std::fs::read_to_string(template)?,
+            None => report::TEMPLATE.to_string(),
+        };
+        let mut days = std::collections::BTreeMap::new();
+        for act in world_activities.iter() {
+            *days.entry(act.date.date_naive()).or_default() += act.distance / 1000.0;
+        }
+        let mut map = Vec::new();
+        metadata::write_png(&pixmap, &mut map, &metadata)?;
+        let report = Report {
+            title: format!("Heatmap of {}", directory),
+            map,
+            summary: &summary,
+            days,
+            top: &Contribution::ranked(&world_activities, &reference_map),
+        };
+        let _hold = interrupt::hold();
+        std::fs::write(file, report.to_html(&template))?;
+    }
+    if args.timings {
+        info!("{}", timings.to_string().trim_end());
+    }
+    if video.is_some() {
+        let shows = last_activity
+            .as_ref()
+            .map_or(("", chrono::DateTime::UNIX_EPOCH), |(name, date)| {
+                (name.as_str(), *date)
+            });
+        let logs = (frame_log.as_mut(), manifest.as_mut());
+        write_video_frame(sink, logs, &mut written, pixmap, &metadata, shows)?;
+        sink.finish()?;
+        if let Some(log) = frame_log {
+            log.finish()?;
+        }
+        if let Some(manifest) = manifest {
+            manifest.save()?;
+        }
+    } else if finish.cycle.is_none() {
+        sink.write(pixmap, &metadata)?;
+        sink.finish()?;
+    }
+    Ok(metadata)
+}
+
+/// Puts the terminal into raw mode on an alternate screen until dropped, also when leaving by
+/// an error
+struct Screen;
+
+impl Screen {
+    fn enter() -> std::io::Result<Self> {
+        terminal::enable_raw_mode()?;
+        stdout()
+            .execute(EnterAlternateScreen)?
+            .execute(cursor::Hide)?;
+        Ok(Screen)
+    }
+}
+
+impl Drop for Screen {
+    fn drop(&mut self) {
+        let _ = stdout()
+            .execute(cursor::Show)
+            .and_then(|out| out.execute(LeaveAlternateScreen));
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+/// An activity with its type, e.g., `Ride`, if known
+type TypedActivity = (Option<String>, WorldActivity);
+
+/// Activities of all types with their type, as the preview toggles types itself
+fn typed_activities(
+    filter: &mut FilterArgs,
+    directory: &str,
+) -> Result<Vec<TypedActivity>, Box<dyn Error>> {
+    let types = std::mem::take(&mut filter.types);
+    let export = filter.export(directory);
+    filter.types = types;
+    Ok(export?
+        .activities()
+        .into_iter()
+        .map(|act| (act.kind().map(str::to_string), act.to_world()))
+        .collect())
+}
+
+/// Colorings the preview cycles through, those of the kind of heatmap
+fn palettes(args: &HeatmapArgs) -> Vec<ColorBy> {
+    match args.heatmap {
+        HeatmapKind::Pixel if args.filter.fit_field.is_some() => vec![
+            ColorBy::Heat,
+            ColorBy::Elevation,
+            ColorBy::Temperature,
+            ColorBy::FitField,
+        ],
+        HeatmapKind::Pixel => vec![ColorBy::Heat, ColorBy::Elevation, ColorBy::Temperature],
+        _ => vec![ColorBy::Heat, ColorBy::Months, ColorBy::Weeks],
+    }
+}
+
+/// Move and zoom the view port, toggle activity types, commutes and colors in a preview in the
+/// terminal, then render with the options chosen
+fn tui(mut args: RenderArgs) -> Result<(), Box<dyn Error>> {
+    let heatmap = &mut args.heatmap;
+    let directory = heatmap
+        .directory
+        .clone()
+        .ok_or_else(|| missing("directory"))?;
+    let mut activities = typed_activities(&mut heatmap.filter, &directory)?;
+    diagnostics::print_summary();
+    let view = &mut heatmap.view;
+    let center = match (view.place.is_some(), view.lat, view.lon) {
+        (true, _, _) => view.map()?.center(),
+        (false, Some(lat), Some(lon)) => geo_types::Point::new(lon, lat),
+        // Without a center given, start at the newest activity
+        (false, _, _) => activities
+            .iter()
+            .max_by_key(|(_, act)| act.date)
+            .and_then(|(_, act)| act.track_points.first())
+            .map(|tp| slippy::from_world(tp.point))
+            .ok_or_else(|| missing("--lat, --lon or --place"))?,
+    };
+    (view.place, view.lat, view.lon) = (None, Some(center.y()), Some(center.x()));
+    let mut kinds: Vec<String> = activities.iter().filter_map(|(k, _)| k.clone()).collect();
+    kinds.sort();
+    kinds.dedup();
+
+    let max_zoom = args.heatmap.max_zoom();
+    let screen = Screen::enter()?;
+    let mut out = stdout();
+    loop {
+        let heatmap = &args.heatmap;
+        let view = &heatmap.view;
+        let map = view.map()?;
+        let selected = |kind: &str| {
+            heatmap
+                .filter
+                .types
+                .iter()
+                .any(|t| t.eq_ignore_ascii_case(kind))
+        };
+        let shown: Vec<&WorldActivity> = activities
+            .iter()
+            .filter(|(kind, _)| {
+                heatmap.filter.types.is_empty() || kind.as_deref().is_some_and(selected)
+            })
+            .map(|(_, act)| act)
+            .collect();
+
+        // Fit the view into the terminal above three lines of status, two pixels per character
+        let (columns, rows) = terminal::size()?;
+        let (available_width, available_height) = (columns as u32, 2 * rows.max(4) as u32 - 6);
+        let (width, height) = (view.width.max(1), view.height.max(1));
+        let preview_width = available_width
+            .min(available_height * width / height)
+            .max(1);
+        let preview_height = (preview_width * height / width).clamp(2, available_height) & !1;
+        let measurement = match heatmap.color_by {
+            ColorBy::Elevation => Some(Measurement::Elevation),
+            ColorBy::Temperature => Some(Measurement::Temperature),
+            ColorBy::FitField => Some(Measurement::FitField),
+            ColorBy::Heat | ColorBy::Months | ColorBy::Weeks => None,
+        };
+        let image = preview::preview(&shown, &map, preview_width, preview_height, measurement);
+
+        out.queue(Clear(ClearType::All))?;
+        preview::draw_preview(&mut out, &image)?;
+        let check = |on: bool| if on { "[x]" } else { "[ ]" };
+        let types: Vec<String> = kinds
+            .iter()
+            .take(9)
+            .enumerate()
+            .map(|(i, kind)| format!("{} {} {}", i + 1, check(selected(kind)), kind))
+            .collect();
+        let color_by = heatmap.color_by.to_possible_value().unwrap();
+        let status = [
+            format!(
+                "{:.5}, {:.5}  zoom {}  {}x{}  {} of {} activities  colored by {}",
+                view.lat.unwrap_or_default(),
+                view.lon.unwrap_or_default(),
+                view.zoom,
+                view.width,
+                view.height,
+                shown.len(),
+                activities.len(),
+                color_by.get_name()
+            ),
+            format!(
+                "{}  c {} skip commutes",
+                types.join("  "),
+                check(heatmap.filter.skip_commutes)
+            ),
+            "hjkl/arrows move  +- zoom  1-9 types  c commutes  p colors  enter render  q quit"
+                .to_string(),
+        ];
+        for (i, line) in status.iter().enumerate() {
+            let line: String = line.chars().take(columns as usize).collect();
+            out.queue(cursor::MoveTo(0, (preview_height / 2 + i as u32) as u16))?
+                .queue(crossterm::style::Print(line))?;
+        }
+        out.flush()?;
+
+        let key = match event::read()? {
+            Event::Key(key) if key.kind == KeyEventKind::Press => key.code,
+            _ => continue,
+        };
+        let heatmap = &mut args.heatmap;
+        let view = &mut heatmap.view;
+        let (dx, dy) = match key {
+            KeyCode::Left | KeyCode::Char('h') => (-0.25, 0.0),
+            KeyCode::Right | KeyCode::Char('l') => (0.25, 0.0),
+            KeyCode::Up | KeyCode::Char('k') => (0.0, -0.25),
+            KeyCode::Down | KeyCode::Char('j') => (0.0, 0.25),
+            _ => (0.0, 0.0),
+        };
+        match key {
+            KeyCode::Enter => break,
+            KeyCode::Esc | KeyCode::Char('q') => return Ok(()),
+            KeyCode::Char('+') | KeyCode::Char('=') => view.zoom = (view.zoom + 1).min(max_zoom),
+            KeyCode::Char('-') => view.zoom = view.zoom.saturating_sub(1),
+            KeyCode::Char('c') => {
+                heatmap.filter.skip_commutes = !heatmap.filter.skip_commutes;
+                activities = typed_activities(&mut heatmap.filter, &directory)?;
+            }
+            KeyCode::Char('p') => {
+                let palettes = palettes(heatmap);
+                let current = palettes.iter().position(|p| *p == heatmap.color_by);
+                heatmap.color_by =
+                    palettes[current.map_or(0, |i| (i + 1) % palettes.len())].clone();
+            }
+            KeyCode::Char(digit @ '1'..='9') => {
+                let index = digit as usize - '1' as usize;
+                if let Some(kind) = kinds.get(index) {
+                    let types = &mut heatmap.filter.types;
+                    match types.iter().position(|t| t.eq_ignore_ascii_case(kind)) {
+                        Some(i) => {
+                            types.remove(i);
+                        }
+                        None => types.push(kind.clone()),
+                    }
+                }
+            }
+            _ if (dx, dy) != (0.0, 0.0) => {
+                let moved = map.shifted(dx, dy).center();
+                (view.lat, view.lon) = (Some(moved.y()), Some(moved.x()));
+            }
+            _ => {}
+        }
+    }
+    drop(screen);
+
+    let view = &args.heatmap.view;
+    info!(
+        "Rendering {} at --lat={:.5} --lon={:.5} --zoom={}, see `derivers history` for all \
+         options",
+        args.output,
+        view.lat.unwrap_or_default(),
+        view.lon.unwrap_or_default(),
+        view.zoom
+    );
+    run(Command::Render(args))
+}
+
+/// Exit unless stdout is redirected, as frames are written there
+fn refuse_tty() {
+    #[cfg(unix)]
+    {
+        let is_tty = unsafe { libc::isatty(libc::STDOUT_FILENO) } != 0;
+        if is_tty {
+            error!(
+                "Refusing to write frame data to TTY.\n
+    Please pipe output to a file or program."
+            );
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Run a subcommand, remembering those producing images in the history
+fn run(command: Command) -> Result<(), Box<dyn Error>> {
+    command.validate()?;
+    // Nothing is written in a dry run, neither the output nor the history
+    let heatmap = match command {
+        Command::Render(ref args) => Some(&args.heatmap),
+        Command::Video(ref args) => Some(&args.heatmap),
+        Command::Cycle(ref args) => Some(&args.heatmap),
+        _ => None,
+    };
+    if let Some(args) = heatmap.filter(|args| args.dry_run) {
+        return dry_run(args);
+    }
+    let config = command.to_toml()?;
+    let name = command.name();
+    let (output, metadata) = match command {
+        Command::Render(args) => {
+            if let Some(ref file) = args.plan_image {
+                return plan(&args.heatmap, file);
+            }
+            let finish = Finish {
+                crop: args.crop_to_content,
+                alpha: args
+                    .export_alpha
+                    .as_deref()
+                    .map(|file| (file, args.alpha_depth)),
+                layers: args.layers.as_deref(),
+                compare: args.compare.as_deref(),
+                report: args
+                    .report_html
+                    .as_deref()
+                    .map(|file| (file, args.report_template.as_deref())),
+                strips: args.strip_height.map(|rows| (args.output.as_str(), rows)),
+                ..Default::default()
+            };
+            let mut sink = ImageFile::new(path::Path::new(&args.output));
+            let metadata = render(&args.heatmap, None, finish, &mut sink)?;
+            (Some(args.output), metadata)
+        }
+        Command::Video(args) => {
+            let frames_dir = args.frames_dir.as_deref().map(path::Path::new);
+            match frames_dir {
+                Some(dir) => std::fs::create_dir_all(dir)?,
+                None => refuse_tty(),
+            }
+            let mut sink = FrameWriter::new(stdout(), args.stream_format)
+                .with_duplicates_skipped(args.skip_duplicate_frames)
+                .with_directory(frames_dir)
+                .with_memory_limit(Resources::detect().frame_memory());
+            let metadata = render(&args.heatmap, Some(&args), Finish::default(), &mut sink)?;
+            (args.frames_dir, metadata)
+        }
+        Command::Cycle(args) => {
+            refuse_tty();
+            let finish = Finish {
+                crop: args.crop_to_content,
+                cycle: Some(args.frames),
+                ..Default::default()
+            };
+            let mut sink = FrameWriter::new(stdout(), args.stream_format)
+                .with_memory_limit(Resources::detect().frame_memory());
+            (None, render(&args.heatmap, None, finish, &mut sink)?)
+        }
+        Command::Tui(args) => return tui(args),
+        Command::Stats(args) => return stats(args),
+        Command::Demo(args) => return demo(args),
+        Command::Tiles(args) => return tiles(args),
+        Command::Import(args) => return import(args),
+        Command::Cache(args) => return cache(args),
+        Command::Assets(args) => return assets(args),
+        Command::History(args) => return history(args),
+        Command::DiffImages(args) => return diff_images(args),
+        Command::Diff(args) => return diff(args),
+    };
+    let recorded = History::open(&history::history_file())
+        .and_then(|history| history.record(name, &config, output.as_deref(), &metadata));
+    if let Err(e) = recorded {
+        warn!("Cannot record the run in the history: {}", e);
+    }
+    Ok(())
+}
+
+/// Write the options to the config file requested, or run the subcommand
+fn start(args: Args) -> Result<(), Box<dyn Error>> {
+    if let Some(ref file) = args.write_config {
+        args.command.validate()?;
+        std::fs::write(file, args.command.to_toml()?)?;
+        info!("Wrote options to {}", file);
+        return Ok(());
+    }
+
+    let threads = match args.threads {
+        Some(threads) => threads.max(1),
+        // Zero leaves the choice to rayon, which reads this variable
+        None if std::env::var_os("RAYON_NUM_THREADS").is_some() => 0,
+        None => {
+            let resources = Resources::detect();
+            let threads = resources.threads();
+            if let (true, Some(memory)) = (threads < resources.cpus, resources.memory) {
+                info!(
+                    "Using {} of {} CPUs as only {} MB of memory are available",
+                    threads,
+                    resources.cpus,
+                    memory >> 20
+                );
+            }
+            threads
+        }
+    };
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build_global()?;
+
+    if let Some(ref target) = args.progress_json {
+        progress::open(target)
+            .map_err(|e| format!("cannot write progress to {}: {}", target, e))?;
+    }
+    run(args.command)?;
+    progress::emit(&Progress::new("done", 1, 1));
+    Ok(())
+}
+
+/// Prints log messages as lines, prefixed with their level and module when more verbose than
+/// informational
+struct Logger;
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let mut out = std::io::stderr().lock();
+        let _ = match record.level() {
+            log::Level::Error | log::Level::Warn | log::Level::Info => {
+                writeln!(out, "{}", record.args())
+            }
+            level => writeln!(out, "[{} {}] {}", level, record.target(), record.args()),
+        };
+    }
+
+    fn flush(&self) {
+        let _ = std::io::stderr().flush();
+    }
+}
+
+/// Logs errors only with `--quiet`, notes by default and details with each `--verbose`
+fn init_logging(args: &Args) -> Result<(), Box<dyn Error>> {
+    let level = match (args.quiet, args.verbose) {
+        (true, _) => log::LevelFilter::Error,
+        (false, 0) => log::LevelFilter::Info,
+        (false, 1) => log::LevelFilter::Debug,
+        (false, _) => log::LevelFilter::Trace,
+    };
+    log::set_boxed_logger(Box::new(Logger))?;
+    log::set_max_level(level);
+    Ok(())
+}
+
+fn main() {
+    let result = parse_args().and_then(|args| {
+        init_logging(&args)?;
+        start(args)
+    });
+    // Print errors as written, they may span several lines
+    if let Err(e) = result {
+        eprintln!("{}", tr!("Error: {}", e));
+        std::process::exit(1);
+    }
+    if interrupt::interrupted() {
+        std::process::exit(interrupt::EXIT_CODE);
     }
-    Ok(())
 }
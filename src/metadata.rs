@@ -0,0 +1,213 @@
+use image::{DynamicImage, GenericImageView, RgbaImage};
+use zip::write::FileOptions;
+use zip::CompressionMethod;
+
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use super::heat::Heatmap;
+use super::interrupt;
+
+/// Describes how an image was rendered as key/value pairs, stored as PNG text chunks
+pub type Metadata = Vec<(String, String)>;
+
+/// Writes an image as PNG, including the metadata
+pub fn write_png<W: Write>(
+    image: &DynamicImage,
+    writer: W,
+    metadata: &Metadata,
+) -> Result<(), Box<dyn Error>> {
+    let rgba = image.to_rgba8();
+    let mut encoder = png::Encoder::new(writer, rgba.width(), rgba.height());
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    for (key, value) in metadata.iter() {
+        encoder.add_text_chunk(key.clone(), value.clone())?;
+    }
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&rgba)?;
+    writer.finish()?;
+    Ok(())
+}
+
+/// Escapes text for an XML attribute
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('"', "&quot;")
+}
+
+/// Saves named layers, bottom first, as an OpenRaster file that image editors open with the
+/// layers intact, along with the merged image they make up, which keeps the metadata
+pub fn save_openraster(
+    layers: &[(&str, RgbaImage)],
+    merged: &DynamicImage,
+    path: &Path,
+    metadata: &Metadata,
+) -> Result<(), Box<dyn Error>> {
+    let (width, height) = merged.dimensions();
+    // The stack lists the topmost layer first
+    let mut stack = String::new();
+    for (index, (name, _)) in layers.iter().enumerate().rev() {
+        stack.push_str(&format!(
+            "    <layer name=\"{}\" src=\"data/layer{}.png\" x=\"0\" y=\"0\"/>\n",
+            xml_escape(name),
+            index
+        ));
+    }
+
+    let _hold = interrupt::hold();
+    let mut zip = zip::ZipWriter::new(BufWriter::new(File::create(path)?));
+    // The MIME type comes first and uncompressed, to be recognized by its magic bytes
+    let stored = FileOptions::default().compression_method(CompressionMethod::Stored);
+    let deflated = FileOptions::default().compression_method(CompressionMethod::Deflated);
+    zip.start_file("mimetype", stored)?;
+    zip.write_all(b"image/openraster")?;
+    zip.start_file("stack.xml", deflated)?;
+    write!(
+        zip,
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <image version=\"0.0.5\" w=\"{}\" h=\"{}\">\n  <stack>\n{}  </stack>\n</image>\n",
+        width, height, stack
+    )?;
+    // PNGs are compressed already
+    for (index, (_, layer)) in layers.iter().enumerate() {
+        zip.start_file(format!("data/layer{}.png", index), stored)?;
+        write_png(
+            &DynamicImage::ImageRgba8(layer.clone()),
+            &mut zip,
+            &Metadata::new(),
+        )?;
+    }
+    zip.start_file("Thumbnails/thumbnail.png", stored)?;
+    write_png(&merged.thumbnail(256, 256), &mut zip, &Metadata::new())?;
+    zip.start_file("mergedimage.png", stored)?;
+    write_png(merged, &mut zip, metadata)?;
+    zip.finish()?;
+    Ok(())
+}
+
+/// A PNG written a band of rows at a time, e.g., the strips of a poster too large to hold in
+/// memory at once
+pub struct PngStrips {
+    writer: png::StreamWriter<'static, BufWriter<File>>,
+}
+
+impl PngStrips {
+    /// Starts a PNG of the given size with the metadata
+    pub fn create(
+        path: &Path,
+        width: u32,
+        height: u32,
+        metadata: &Metadata,
+    ) -> Result<Self, Box<dyn Error>> {
+        let mut encoder = png::Encoder::new(BufWriter::new(File::create(path)?), width, height);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        for (key, value) in metadata.iter() {
+            encoder.add_text_chunk(key.clone(), value.clone())?;
+        }
+        let writer = encoder.write_header()?.into_stream_writer()?;
+        Ok(PngStrips { writer })
+    }
+
+    /// Appends the rows of a strip as wide as the PNG
+    pub fn write(&mut self, strip: &RgbaImage) -> Result<(), Box<dyn Error>> {
+        self.writer.write_all(strip.as_raw())?;
+        Ok(())
+    }
+
+    /// Completes the PNG, failing if fewer rows were written than it is high
+    pub fn finish(self) -> Result<(), Box<dyn Error>> {
+        self.writer.finish()?;
+        Ok(())
+    }
+}
+
+/// Saves an image in the format given by the file extension; metadata is only kept for PNGs
+pub fn save(image: &DynamicImage, path: &Path, metadata: &Metadata) -> Result<(), Box<dyn Error>> {
+    let is_png = path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("png"));
+    let _hold = interrupt::hold();
+    if is_png {
+        write_png(image, BufWriter::new(File::create(path)?), metadata)
+    } else {
+        Ok(image.save(path)?)
+    }
+}
+
+/// Describe how heat was normalized, to be stored with the image
+pub fn heat_metadata(
+    map: &dyn Heatmap,
+    normalization: &str,
+    attribution: Option<&str>,
+) -> Metadata {
+    let mut metadata = vec![
+        ("Software".to_string(), "derive.rs".to_string()),
+        ("Heat Maximum".to_string(), map.max_value().to_string()),
+        ("Heat Unit".to_string(), map.unit().to_string()),
+        ("Heat Normalization".to_string(), normalization.to_string()),
+    ];
+    if let Some(attribution) = attribution {
+        metadata.push(("Copyright".to_string(), attribution.to_string()));
+    }
+    metadata
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::activity::{self, Order, Sensors, TrackPoint, WorldActivity};
+    use super::super::heat::PixelHeatmap;
+    use super::super::slippy;
+    use super::*;
+    use geo_types::Point;
+
+    #[test]
+    fn strips_stitch_into_the_single_image() {
+        let map = slippy::Map::from(6.1432, 46.2044, 300, 200, 14);
+        // Zigzags over the edges of the strips, leaving and coming back to the same pixels, and
+        // drifts across the middle edge
+        let origin = map.tile_origin() * slippy::TILE_SIZE as f64;
+        let scale = 2f64.powi(14) * slippy::TILE_SIZE as f64;
+        let track_points = (0..280)
+            .flat_map(|i| {
+                [49.5, 50.5, 49.5, 99.5 + i as f64 / 10.0, 150.5, 149.5].map(move |y| (i, y))
+            })
+            .map(|(i, y)| TrackPoint {
+                point: (origin + Point::new(10.5 + i as f64, y)) / scale,
+                time: None,
+                elevation: None,
+                sensors: Sensors::default(),
+            })
+            .collect();
+        let activities = [WorldActivity {
+            name: "Zigzag".to_string(),
+            date: chrono::DateTime::default(),
+            path: Default::default(),
+            distance: 0.0,
+            track_points,
+        }];
+        let render = |view: slippy::Map| {
+            let mut heatmap = PixelHeatmap::from(view, false, false).with_fixed_max(Some(4.0));
+            for act in activity::project_to_screen(&activities, &heatmap, Order::Date, false) {
+                for tp in act.track_points.iter() {
+                    heatmap.add_point(&tp.point);
+                }
+            }
+            heatmap.as_image().to_rgba8()
+        };
+
+        let path = std::env::temp_dir().join(format!("derivers-strips-{}.png", std::process::id()));
+        let mut png = PngStrips::create(&path, 300, 200, &vec![]).unwrap();
+        for top in (0..200).step_by(50) {
+            png.write(&render(map.crop(0, top, 300, 50))).unwrap();
+        }
+        png.finish().unwrap();
+        let stitched = image::open(&path).unwrap().to_rgba8();
+        std::fs::remove_file(&path).unwrap();
+        assert!(stitched == render(map));
+    }
+}
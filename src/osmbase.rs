@@ -1,35 +1,324 @@
-use http_req::{request::Request, uri::Uri};
+use http_req::request::{copy_exact, HttpVersion, RequestBuilder};
+use http_req::{response::Response, tls, uri::Uri};
+use image::GenericImageView;
+use imageproc::drawing::{draw_filled_rect_mut, draw_hollow_rect_mut};
+use imageproc::rect::Rect;
+use rusqlite::{params, Connection, OptionalExtension};
 use sha2::{Digest, Sha256};
 
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::error::Error;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
 use std::path::{Path, PathBuf};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+use super::heat;
+use super::provider::Provider;
 use super::slippy;
+use super::style::{BasemapStyle, Resampling, Tint};
+
+/// Directory where downloaded tiles are cached
+pub fn cache_dir() -> PathBuf {
+    directories::BaseDirs::new()
+        .unwrap()
+        .cache_dir()
+        .join("derive.rs")
+        .join("tiles")
+}
+
+/// A failed download, and whether trying again may help
+struct FetchError {
+    message: String,
+    transient: bool,
+}
+
+/// What a request got: the body, or where the resource moved to
+enum Fetched {
+    Body(Vec<u8>),
+    Moved(String),
+}
+
+/// Inactivity after which a tile request is given up
+const READ_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Redirects followed for one resource, e.g., from HTTP to HTTPS or to a CDN
+const MAX_REDIRECTS: usize = 5;
+
+/// The absolute URL a `Location` header points to from the URL requested, which it may be
+/// relative to
+fn resolve(url: &str, location: &str) -> String {
+    let scheme_end = url.find("://").map_or(0, |i| i + 3);
+    let host_end = url[scheme_end..]
+        .find(['/', '?', '#'])
+        .map_or(url.len(), |i| scheme_end + i);
+    if location.contains("://") {
+        location.to_string()
+    } else if location.starts_with("//") {
+        format!("{}{}", &url[..scheme_end - 2], location)
+    } else if location.starts_with('/') {
+        format!("{}{}", &url[..host_end], location)
+    } else {
+        let path = &url[host_end..];
+        let path = &path[..path.find(['?', '#']).unwrap_or(path.len())];
+        let dir = &path[..path.rfind('/').unwrap_or(0)];
+        format!("{}{}/{}", &url[..host_end], dir, location)
+    }
+}
+
+/// A connection to a tile server, kept open across tiles
+enum HttpConnection {
+    Plain(TcpStream),
+    Tls(tls::Conn<TcpStream>),
+}
+
+impl HttpConnection {
+    fn open(uri: &Uri) -> Result<Self, Box<dyn Error>> {
+        let host = uri.host().unwrap_or("");
+        let stream = TcpStream::connect((host, uri.corr_port()))?;
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        if uri.scheme() == "https" {
+            Ok(HttpConnection::Tls(
+                tls::Config::default().connect(host, stream)?,
+            ))
+        } else {
+            Ok(HttpConnection::Plain(stream))
+        }
+    }
+
+    /// Request a resource, returning the response, its body and whether the connection can
+    /// serve further requests
+    ///
+    /// Requests are made as HTTP/1.0 asking to keep the connection alive, so that servers send
+    /// bodies of known length or close the connection after them, but never chunked ones.
+    fn get(&mut self, uri: &Uri) -> Result<(Response, Vec<u8>, bool), http_req::error::Error> {
+        let mut request = RequestBuilder::new(uri);
+        request
+            .version(HttpVersion::Http10)
+            .header("User-Agent", "derive.rs 0.1 contact maps@sushinara.net")
+            .header("Connection", "keep-alive");
+        request.write_msg(self, &request.parse_msg())?;
+        let (response, mut body) = request.read_head(self, Instant::now() + READ_TIMEOUT)?;
+
+        let connection = response
+            .headers()
+            .get("Connection")
+            .map(|v| v.to_ascii_lowercase());
+        let kept = match connection.as_deref() {
+            Some("close") => false,
+            Some("keep-alive") => true,
+            _ => response.version() != "HTTP/1.0",
+        };
+        match response.content_len() {
+            Some(length) => {
+                let missing = length.saturating_sub(body.len());
+                if missing > 0 {
+                    copy_exact(self, &mut body, missing)?;
+                }
+                Ok((response, body, kept))
+            }
+            None => {
+                self.read_to_end(&mut body)?;
+                Ok((response, body, false))
+            }
+        }
+    }
+}
+
+impl Read for HttpConnection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            HttpConnection::Plain(stream) => stream.read(buf),
+            HttpConnection::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for HttpConnection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            HttpConnection::Plain(stream) => stream.write(buf),
+            HttpConnection::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            HttpConnection::Plain(stream) => stream.flush(),
+            HttpConnection::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+/// Bing style key of a tile, one digit per zoom level
+fn quadkey(zoom: u8, x: u32, y: u32) -> String {
+    (1..=zoom)
+        .rev()
+        .map(|level| {
+            let mask = 1 << (level - 1);
+            let digit = (x & mask != 0) as u8 + 2 * (y & mask != 0) as u8;
+            char::from(b'0' + digit)
+        })
+        .collect()
+}
+
+/// Expands the placeholders of a tile URL pattern: `{z}`, `{x}`, `{y}`, `{-y}` counting rows
+/// from the south as in TMS, `{quadkey}`, and `{s}` for one of the subdomains
+fn tile_url(pattern: &str, subdomains: &[String], zoom: u8, x: u32, y: u32) -> String {
+    let mut url = pattern.to_string();
+    if !subdomains.is_empty() {
+        // Neighboring tiles alternate between subdomains, spreading the load
+        let subdomain = &subdomains[(x + y) as usize % subdomains.len()];
+        url = url.replace("{s}", subdomain);
+    }
+    url.replace("{z}", &zoom.to_string())
+        .replace("{x}", &x.to_string())
+        .replace("{-y}", &((1u32 << zoom) - 1 - y).to_string())
+        .replace("{y}", &y.to_string())
+        .replace("{quadkey}", &quadkey(zoom, x, y))
+}
 
 struct Downloader {
     cache_dir: PathBuf,
     url_pattern: String,
+    /// Substituted for `{s}` in the URL pattern
+    subdomains: Vec<String>,
+    /// Number of attempts per tile
+    attempts: u32,
+    /// Delay before the first retry, doubling with every further one
+    retry_delay: Duration,
+    /// Shortest time between the starts of two requests, if rate limited
+    min_interval: Option<Duration>,
+    last_request: Cell<Option<Instant>>,
+    /// Idle connections by `scheme://host:port`
+    connections: RefCell<HashMap<String, HttpConnection>>,
 }
 
 impl Downloader {
     fn new(url_pattern: &str) -> Result<Self, Box<dyn Error>> {
         Ok(Downloader {
-            cache_dir: directories::BaseDirs::new()
-                .unwrap()
-                .cache_dir()
-                .join("derive.rs")
-                .join("tiles"),
+            cache_dir: cache_dir(),
             url_pattern: url_pattern.to_string(),
+            subdomains: ["a", "b", "c"].iter().map(|s| s.to_string()).collect(),
+            attempts: 1,
+            retry_delay: Duration::from_millis(500),
+            min_interval: None,
+            last_request: Cell::new(None),
+            connections: RefCell::new(HashMap::new()),
         })
     }
 
-    fn get(&self, zoom: u8, x: u32, y: u32) -> Result<PathBuf, Box<dyn Error>> {
-        let url = self
-            .url_pattern
-            .replace("{z}", &zoom.to_string())
-            .replace("{x}", &x.to_string())
-            .replace("{y}", &y.to_string());
+    /// A downloader of the same tiles with connections of its own, e.g., for another thread
+    fn fork(&self) -> Self {
+        Downloader {
+            cache_dir: self.cache_dir.clone(),
+            url_pattern: self.url_pattern.clone(),
+            subdomains: self.subdomains.clone(),
+            attempts: self.attempts,
+            retry_delay: self.retry_delay,
+            min_interval: self.min_interval,
+            last_request: Cell::new(None),
+            connections: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Wait until the rate limit allows another request
+    fn throttle(&self) {
+        if let (Some(interval), Some(last)) = (self.min_interval, self.last_request.get()) {
+            let elapsed = last.elapsed();
+            if elapsed < interval {
+                std::thread::sleep(interval - elapsed);
+            }
+        }
+        self.last_request.set(Some(Instant::now()));
+    }
+
+    /// Exponential backoff with up to one base delay of jitter, so that parallel or repeated
+    /// runs do not retry in lockstep
+    fn backoff(&self, retry: u32) -> Duration {
+        let base = self.retry_delay.as_millis() as u64;
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.subsec_nanos() as u64);
+        let jitter = if base > 0 { nanos % base } else { 0 };
+        Duration::from_millis(base.saturating_mul(1 << retry.min(16)) + jitter)
+    }
+
+    /// Fetch a URL, following redirects
+    fn fetch(&self, url: &str) -> Result<Vec<u8>, FetchError> {
+        let mut location = url.to_string();
+        for _ in 0..=MAX_REDIRECTS {
+            match self.request(&location)? {
+                Fetched::Body(body) => return Ok(body),
+                Fetched::Moved(to) => location = resolve(&location, &to),
+            }
+        }
+        Err(FetchError {
+            message: format!(
+                "failed to get {}: redirected more than {} times",
+                url, MAX_REDIRECTS
+            ),
+            transient: false,
+        })
+    }
+
+    fn request(&self, url: &str) -> Result<Fetched, FetchError> {
+        let uri = Uri::try_from(url).map_err(|e| FetchError {
+            message: e.to_string(),
+            transient: false,
+        })?;
+        let failed = |e: &dyn Error| FetchError {
+            message: format!("failed to get {}: {}", url, e),
+            transient: true,
+        };
+        let key = format!(
+            "{}://{}:{}",
+            uri.scheme(),
+            uri.host().unwrap_or(""),
+            uri.corr_port()
+        );
+        let mut idle = self.connections.borrow_mut().remove(&key);
+
+        self.throttle();
+        let (res, writer) = loop {
+            let reused = idle.is_some();
+            let mut connection = match idle.take() {
+                Some(connection) => connection,
+                None => HttpConnection::open(&uri).map_err(|e| failed(&*e))?,
+            };
+            match connection.get(&uri) {
+                Ok((res, writer, reusable)) => {
+                    if reusable {
+                        self.connections.borrow_mut().insert(key, connection);
+                    }
+                    break (res, writer);
+                }
+                // The server may have closed the idle connection in the meantime
+                Err(_) if reused => continue,
+                Err(e) => return Err(failed(&e)),
+            }
+        };
+        let status = res.status_code();
+        let location = res.headers().get("Location");
+        if status.is_success() {
+            Ok(Fetched::Body(writer))
+        } else if let (true, Some(location)) = (status.is_redirect(), location) {
+            Ok(Fetched::Moved(location.clone()))
+        } else {
+            Err(FetchError {
+                message: format!("failed to get {}: {}", url, res.reason()),
+                // Server errors and rate limiting may pass, missing tiles will not
+                transient: status.is_server_err() || u16::from(status) == 429,
+            })
+        }
+    }
+
+    /// URL of a tile and where it is cached
+    fn locate(&self, zoom: u8, x: u32, y: u32) -> (String, PathBuf) {
+        let url = tile_url(&self.url_pattern, &self.subdomains, zoom, x, y);
         let hash = format!("{:X}", {
             let mut s = Sha256::new();
             s.update(&url);
@@ -41,28 +330,204 @@ impl Downloader {
         } else {
             cached = cached.join(Path::new(".png"));
         }
+        (url, cached)
+    }
+
+    fn get(&self, zoom: u8, x: u32, y: u32) -> Result<PathBuf, Box<dyn Error>> {
+        let (url, cached) = self.locate(zoom, x, y);
         if cached.exists() {
             return Ok(cached);
         }
         if let Some(p) = cached.parent() {
             std::fs::create_dir_all(p)?;
         }
-        let mut writer = Vec::new();
-        let uri = Uri::try_from(&url[..])?;
-        match Request::new(&uri)
-            .header("user-agent", "derive.rs 0.1 contact maps@sushinara.net")
-            .send(&mut writer)
-        {
-            Ok(res) => {
-                if !res.status_code().is_success() {
-                    let msg = format!("failed to get {}: {}", url, res.reason());
-                    Err(msg.into())
-                } else {
-                    std::fs::write(&cached, writer)?;
-                    Ok(cached)
+        std::fs::write(&cached, self.fetch_with_retries(&url)?)?;
+        Ok(cached)
+    }
+
+    /// Fetch a URL, trying again after transient failures
+    fn fetch_with_retries(&self, url: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut retry = 0;
+        loop {
+            match self.fetch(url) {
+                Ok(data) => return Ok(data),
+                Err(e) if e.transient && retry + 1 < self.attempts => {
+                    std::thread::sleep(self.backoff(retry));
+                    retry += 1;
                 }
+                Err(e) => return Err(e.message.into()),
+            }
+        }
+    }
+}
+
+/// Download any resource like tiles are downloaded, identifying as derive.rs and trying again
+/// after server or network errors
+pub fn download(url: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut downloader = Downloader::new(url)?;
+    downloader.attempts = 3;
+    downloader.fetch_with_retries(url)
+}
+
+/// A source of basemap tiles, e.g., to render from other sources than the built-in ones
+pub trait TileProvider {
+    /// The image of a tile, which is cropped to `slippy::TILE_SIZE` if larger
+    fn tile(&self, zoom: u8, x: u32, y: u32) -> Result<image::DynamicImage, Box<dyn Error>>;
+}
+
+impl TileProvider for Downloader {
+    fn tile(&self, zoom: u8, x: u32, y: u32) -> Result<image::DynamicImage, Box<dyn Error>> {
+        Ok(image::open(self.get(zoom, x, y)?)?)
+    }
+}
+
+/// Tiles of a local `z/x/y` directory tree, as PNG or JPEG
+pub struct TileDirectory {
+    root: PathBuf,
+}
+
+impl TileDirectory {
+    pub fn from(root: &Path) -> Self {
+        Self {
+            root: root.to_path_buf(),
+        }
+    }
+}
+
+impl TileProvider for TileDirectory {
+    fn tile(&self, zoom: u8, x: u32, y: u32) -> Result<image::DynamicImage, Box<dyn Error>> {
+        let base = self
+            .root
+            .join(zoom.to_string())
+            .join(x.to_string())
+            .join(y.to_string());
+        for ext in ["png", "jpg", "jpeg"].iter() {
+            let filename = base.with_extension(ext);
+            if filename.exists() {
+                return Ok(image::open(filename)?);
             }
-            Err(e) => Err(e.into()),
+        }
+        Err(format!(
+            "tile {}/{}/{} not found in {}",
+            zoom,
+            x,
+            y,
+            self.root.display()
+        )
+        .into())
+    }
+}
+
+/// Tiles of a local MBTiles file
+pub struct MBTiles {
+    db: Connection,
+}
+
+impl MBTiles {
+    pub fn open(path: &Path) -> Result<Self, Box<dyn Error>> {
+        Ok(Self {
+            db: Connection::open(path)?,
+        })
+    }
+}
+
+impl TileProvider for MBTiles {
+    fn tile(&self, zoom: u8, x: u32, y: u32) -> Result<image::DynamicImage, Box<dyn Error>> {
+        // MBTiles uses the TMS scheme, counting rows from the south
+        let row = (1u32 << zoom) - 1 - y;
+        let data: Option<Vec<u8>> = self
+            .db
+            .query_row(
+                "SELECT tile_data FROM tiles
+                 WHERE zoom_level = ?1 AND tile_column = ?2 AND tile_row = ?3",
+                params![zoom, x, row],
+                |r| r.get(0),
+            )
+            .optional()?;
+        match data {
+            Some(data) => Ok(image::load_from_memory(&data)?),
+            None => Err(format!("tile {}/{}/{} not found in MBTiles", zoom, x, y).into()),
+        }
+    }
+}
+
+/// Tiles held in memory, e.g., generated ones or fixtures, with a solid fill for the others
+#[derive(Default)]
+pub struct MemoryTiles {
+    tiles: HashMap<(u8, u32, u32), image::DynamicImage>,
+    fill: Option<image::Rgba<u8>>,
+}
+
+impl MemoryTiles {
+    /// Add or replace a tile
+    pub fn insert(&mut self, zoom: u8, x: u32, y: u32, tile: image::DynamicImage) {
+        self.tiles.insert((zoom, x, y), tile);
+    }
+
+    /// Fill tiles that were not added with a color instead of failing on them
+    pub fn with_fill(mut self, color: image::Rgba<u8>) -> Self {
+        self.fill = Some(color);
+        self
+    }
+}
+
+impl TileProvider for MemoryTiles {
+    fn tile(&self, zoom: u8, x: u32, y: u32) -> Result<image::DynamicImage, Box<dyn Error>> {
+        match (self.tiles.get(&(zoom, x, y)), self.fill) {
+            (Some(tile), _) => Ok(tile.clone()),
+            (None, Some(color)) => Ok(image::DynamicImage::ImageRgba8(
+                image::RgbaImage::from_pixel(slippy::TILE_SIZE, slippy::TILE_SIZE, color),
+            )),
+            (None, None) => Err(format!("tile {}/{}/{} not in memory", zoom, x, y).into()),
+        }
+    }
+}
+
+/// Where basemap tiles come from
+enum TileSource {
+    /// Downloaded via HTTP and cached locally, with settings for the downloads
+    Http(Downloader),
+    /// Any other provider
+    Other(Box<dyn TileProvider>),
+}
+
+impl TileSource {
+    fn get(&self, zoom: u8, x: u32, y: u32) -> Result<image::DynamicImage, Box<dyn Error>> {
+        match self {
+            TileSource::Http(downloader) => downloader.tile(zoom, x, y),
+            TileSource::Other(provider) => provider.tile(zoom, x, y),
+        }
+    }
+
+    fn state(&self, zoom: u8, x: u32, y: u32) -> TileState {
+        match self {
+            TileSource::Http(downloader) if downloader.locate(zoom, x, y).1.exists() => {
+                TileState::Cached
+            }
+            TileSource::Http(_) => TileState::Download,
+            TileSource::Other(_) => TileState::Local,
+        }
+    }
+}
+
+/// Whether a basemap tile is at hand or needs to be downloaded
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TileState {
+    /// Downloaded before
+    Cached,
+    /// To be downloaded
+    Download,
+    /// Read from a local provider, e.g., an MBTiles file
+    Local,
+}
+
+impl TileState {
+    /// Color of the tile in the plan image
+    fn color(self) -> image::Rgba<u8> {
+        match self {
+            TileState::Cached => image::Rgba([40, 160, 60, 255]),
+            TileState::Download => image::Rgba([230, 120, 20, 255]),
+            TileState::Local => image::Rgba([50, 110, 200, 255]),
         }
     }
 }
@@ -70,7 +535,11 @@ impl Downloader {
 /// A basemap displaying OSM tiles
 pub struct Basemap {
     map: slippy::Map,
-    getter: Downloader,
+    getter: TileSource,
+    /// Recoloring of the tiles before the tint
+    style: BasemapStyle,
+    /// Filter scaling tiles of other sizes to the grid
+    resampling: Resampling,
 }
 
 impl Basemap {
@@ -78,73 +547,405 @@ impl Basemap {
     pub fn from(map: slippy::Map, url_pattern: &str) -> Result<Self, Box<dyn Error>> {
         Ok(Self {
             map,
-            getter: Downloader::new(url_pattern)?,
+            getter: TileSource::Http(Downloader::new(url_pattern)?),
+            style: BasemapStyle::Normal,
+            resampling: Resampling::default(),
+        })
+    }
+
+    /// Create a basemap with specified map settings downloading tiles from a preset provider
+    pub fn from_provider(map: slippy::Map, provider: &Provider) -> Result<Self, Box<dyn Error>> {
+        if map.zoom() > provider.max_zoom {
+            return Err(format!(
+                "{} has no tiles beyond zoom level {}",
+                provider.name, provider.max_zoom
+            )
+            .into());
+        }
+        let mut downloader = Downloader::new(provider.url)?;
+        downloader.subdomains = provider.subdomains.iter().map(|s| s.to_string()).collect();
+        Ok(Self {
+            map,
+            getter: TileSource::Http(downloader),
+            style: BasemapStyle::Normal,
+            resampling: Resampling::default(),
         })
     }
 
-    /// Download tile images and construct the basemap, tinting it with 1.0 being a fully black
-    /// map.
-    pub fn as_image(&self, tint: f32) -> Result<image::DynamicImage, Box<dyn Error>> {
+    /// Try downloading each tile up to the given number of times, waiting exponentially longer
+    /// between attempts starting from the given delay; local tiles are not affected
+    pub fn with_retries(mut self, attempts: u32, delay: Duration) -> Self {
+        if let TileSource::Http(ref mut downloader) = self.getter {
+            downloader.attempts = attempts.max(1);
+            downloader.retry_delay = delay;
+        }
+        self
+    }
+
+    /// Substitute these subdomains for `{s}` in the URL pattern instead of `a`, `b` and `c`
+    pub fn with_subdomains(mut self, subdomains: &[String]) -> Self {
+        if let TileSource::Http(ref mut downloader) = self.getter {
+            if !subdomains.is_empty() {
+                downloader.subdomains = subdomains.to_vec();
+            }
+        }
+        self
+    }
+
+    /// Recolor the tiles, e.g., to gray, so that colorful map styles do not compete with the
+    /// heat
+    pub fn with_style(mut self, style: BasemapStyle) -> Self {
+        self.style = style;
+        self
+    }
+
+    /// Scale tiles that are not 256 pixels wide, e.g., 512 pixel retina tiles, with the filter
+    pub fn with_resampling(mut self, resampling: Resampling) -> Self {
+        self.resampling = resampling;
+        self
+    }
+
+    /// Download at most the given number of tiles per second, if any; local tiles are not
+    /// affected
+    pub fn with_rate_limit(mut self, per_second: Option<f64>) -> Self {
+        if let TileSource::Http(ref mut downloader) = self.getter {
+            downloader.min_interval = per_second.map(|r| Duration::from_secs_f64(1.0 / r));
+        }
+        self
+    }
+
+    /// Create a basemap with specified map settings reading tiles from a local MBTiles file or
+    /// `z/x/y` directory
+    pub fn from_file(map: slippy::Map, path: &Path) -> Result<Self, Box<dyn Error>> {
+        let provider: Box<dyn TileProvider> = if path.is_dir() {
+            Box::new(TileDirectory::from(path))
+        } else if path.is_file() {
+            Box::new(MBTiles::open(path)?)
+        } else {
+            return Err(format!("basemap file {} does not exist", path.display()).into());
+        };
+        Ok(Self::from_tiles(map, provider))
+    }
+
+    /// Create a basemap with specified map settings taking tiles from any provider
+    pub fn from_tiles(map: slippy::Map, provider: Box<dyn TileProvider>) -> Self {
+        Self {
+            map,
+            getter: TileSource::Other(provider),
+            style: BasemapStyle::Normal,
+            resampling: Resampling::default(),
+        }
+    }
+
+    /// Number of tiles covering the map
+    pub fn tile_count(&self) -> u64 {
+        self.map.tile_xs().count() as u64 * self.map.tile_ys().count() as u64
+    }
+
+    /// Which tiles covering the map are at hand, by column and row, without fetching any
+    pub fn plan(&self) -> Vec<(u32, u32, TileState)> {
+        let zoom = self.map.zoom();
+        self.map
+            .tile_xs()
+            .flat_map(|x| self.map.tile_ys().map(move |y| (x, y)))
+            .map(|(x, y)| (x, y, self.getter.state(zoom, x, y)))
+            .collect()
+    }
+
+    /// Start downloading the tiles covering the map that are not cached yet on another thread,
+    /// e.g., while activities are parsed; joining gives the number of tiles downloaded. Nothing
+    /// is started for local tiles or if all are cached
+    pub fn prefetch(&self) -> Option<JoinHandle<u64>> {
+        let downloader = match self.getter {
+            TileSource::Http(ref downloader) => downloader.fork(),
+            TileSource::Other(_) => return None,
+        };
+        let zoom = self.map.zoom();
+        let missing: Vec<(u32, u32)> = self
+            .plan()
+            .into_iter()
+            .filter(|&(_, _, state)| state == TileState::Download)
+            .map(|(x, y, _)| (x, y))
+            .collect();
+        if missing.is_empty() {
+            return None;
+        }
+        Some(std::thread::spawn(move || {
+            // Failures are left to drawing the basemap, which tries again and lists them
+            missing
+                .into_iter()
+                .filter(|&(x, y)| downloader.get(zoom, x, y).is_ok())
+                .count() as u64
+        }))
+    }
+
+    /// Draw the tile grid over the map, in green where tiles are cached, orange where they are
+    /// to be downloaded and blue where they are read locally, with the counts in the corner
+    pub fn plan_image(&self) -> image::DynamicImage {
+        let (width, height) = self.map.pixel_size();
+        let mut image = image::RgbaImage::from_pixel(width, height, image::Rgba([0, 0, 0, 255]));
+        let (offset_x, offset_y) = self.map.pixel_offsets();
+        let (tile_min_x, tile_min_y) = self.map.tile_offsets();
+        let plan = self.plan();
+        let size = slippy::TILE_SIZE;
+        for &(x, y, state) in plan.iter() {
+            let left = ((x - tile_min_x) * size) as i32 - offset_x as i32;
+            let top = ((y - tile_min_y) * size) as i32 - offset_y as i32;
+            draw_filled_rect_mut(
+                &mut image,
+                Rect::at(left, top).of_size(size, size),
+                state.color(),
+            );
+            draw_hollow_rect_mut(
+                &mut image,
+                Rect::at(left, top).of_size(size, size),
+                image::Rgba([0, 0, 0, 255]),
+            );
+        }
+        let mut image = image::DynamicImage::ImageRgba8(image);
+        heat::draw_label(&mut image, &self.plan_summary());
+        image
+    }
+
+    /// Number of tiles covering the map by whether they are at hand, e.g., `12 tiles at zoom
+    /// 13, 8 cached, 4 to download`
+    pub fn plan_summary(&self) -> String {
+        let plan = self.plan();
+        let count = |state| plan.iter().filter(|&&(_, _, s)| s == state).count();
+        let mut summary = format!("{} tiles at zoom {}", plan.len(), self.map.zoom());
+        for (state, name) in [
+            (TileState::Cached, "cached"),
+            (TileState::Download, "to download"),
+            (TileState::Local, "local"),
+        ] {
+            if count(state) > 0 {
+                summary.push_str(&format!(", {} {}", count(state), name));
+            }
+        }
+        summary
+    }
+
+    /// Download tile images and construct the basemap, darkened by the tint; tiles that cannot
+    /// be fetched are left blank and listed
+    pub fn as_image(&self, tint: &Tint) -> Result<image::DynamicImage, Box<dyn Error>> {
         let (width, height) = self.map.pixel_size();
         let mut pixmap = image::DynamicImage::new_rgba8(width, height);
 
         let (offset_x, offset_y) = self.map.pixel_offsets();
         let (tile_min_x, tile_min_y) = self.map.tile_offsets();
 
+        let mut skipped = Vec::new();
         for i in self.map.tile_xs() {
             for j in self.map.tile_ys() {
-                let filename = self.getter.get(self.map.zoom(), i, j)?;
-                let raw_tile = image::open(filename)?;
-                let mut tile = image::imageops::crop_imm(
+                let raw_tile = match self.getter.get(self.map.zoom(), i, j) {
+                    Ok(tile) if tile.dimensions() != (slippy::TILE_SIZE, slippy::TILE_SIZE) => {
+                        let size = slippy::TILE_SIZE;
+                        tile.resize_exact(size, size, self.resampling.filter())
+                    }
+                    Ok(tile) => tile,
+                    Err(e) => {
+                        skipped.push(format!("{}/{}/{}: {}", self.map.zoom(), i, j, e));
+                        continue;
+                    }
+                };
+                // The first row and column of tiles start above and left of the map, by the
+                // offsets in whole pixels
+                let (i, j) = (i - tile_min_x, j - tile_min_y);
+                let (left, x) = match i {
+                    0 => (offset_x, 0),
+                    _ => (0, i * slippy::TILE_SIZE - offset_x),
+                };
+                let (top, y) = match j {
+                    0 => (offset_y, 0),
+                    _ => (0, j * slippy::TILE_SIZE - offset_y),
+                };
+                let tile = image::imageops::crop_imm(
                     &raw_tile,
-                    0,
-                    0,
-                    slippy::TILE_SIZE,
-                    slippy::TILE_SIZE,
+                    left,
+                    top,
+                    slippy::TILE_SIZE - left,
+                    slippy::TILE_SIZE - top,
                 );
+                image::imageops::overlay(&mut pixmap, &tile, x, y);
+            }
+        }
+        if !skipped.is_empty() {
+            warn!("Skipped {} basemap tiles", skipped.len());
+            for tile in skipped.iter() {
+                debug!("    {}", tile);
+            }
+        }
+        if let Some(buffer) = pixmap.as_mut_rgba8() {
+            self.style.apply(buffer);
+        }
+        image::imageops::overlay(&mut pixmap, &tint.layer(width, height), 0, 0);
+        Ok(pixmap)
+    }
+}
 
-                let i = i - tile_min_x;
-                let j = j - tile_min_y;
-                let mut x = i * slippy::TILE_SIZE - offset_x;
-                let mut y = j * slippy::TILE_SIZE - offset_y;
-
-                if i == 0 && j == 0 {
-                    x = 0;
-                    y = 0;
-                    tile = image::imageops::crop_imm(
-                        &raw_tile,
-                        offset_x,
-                        offset_y,
-                        slippy::TILE_SIZE - offset_x,
-                        slippy::TILE_SIZE - offset_y,
-                    );
-                } else if i == 0 {
-                    x = 0;
-                    tile = image::imageops::crop_imm(
-                        &raw_tile,
-                        offset_x,
-                        0,
-                        slippy::TILE_SIZE - offset_x,
-                        slippy::TILE_SIZE,
-                    );
-                } else if j == 0 {
-                    y = 0;
-                    tile = image::imageops::crop_imm(
-                        &raw_tile,
-                        0,
-                        offset_y,
-                        slippy::TILE_SIZE,
-                        slippy::TILE_SIZE - offset_y,
+#[cfg(test)]
+mod tests {
+    use super::super::heat::{Heatmap, PixelHeatmap};
+    use super::*;
+    use geo_types::Point;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    const GRAY: image::Rgba<u8> = image::Rgba([200, 200, 200, 255]);
+    const BLACK: image::Rgba<u8> = image::Rgba([0, 0, 0, 255]);
+
+    /// A gray tile with black lines through the middle of pixel column and row 128
+    fn crosshair() -> image::DynamicImage {
+        let size = slippy::TILE_SIZE;
+        let color = |x, y| if x == 128 || y == 128 { BLACK } else { GRAY };
+        let tile = image::RgbaImage::from_fn(size, size, color);
+        image::DynamicImage::ImageRgba8(tile)
+    }
+
+    /// Tiles colored by their column and row, failing on one of them, which records what was
+    /// asked for
+    struct FakeTiles {
+        failing: (u32, u32),
+        requested: Rc<RefCell<Vec<(u8, u32, u32)>>>,
+    }
+
+    fn tile_color(x: u32, y: u32) -> image::Rgba<u8> {
+        image::Rgba([(x % 256) as u8, (y % 256) as u8, 100, 255])
+    }
+
+    impl TileProvider for FakeTiles {
+        fn tile(&self, zoom: u8, x: u32, y: u32) -> Result<image::DynamicImage, Box<dyn Error>> {
+            self.requested.borrow_mut().push((zoom, x, y));
+            if (x, y) == self.failing {
+                return Err("unavailable".into());
+            }
+            // Half the size, to be scaled up like large tiles are cropped
+            let half = slippy::TILE_SIZE / 2;
+            let tile = image::RgbaImage::from_pixel(half, half, tile_color(x, y));
+            Ok(image::DynamicImage::ImageRgba8(tile))
+        }
+    }
+
+    #[test]
+    fn basemap_composes_the_tiles_of_a_provider() {
+        let zoom = 13;
+        let view = slippy::Map::from(6.1432, 46.2044, 600, 400, zoom);
+        let failing = (
+            view.tile_xs().next().unwrap(),
+            view.tile_ys().next().unwrap(),
+        );
+        let requested = Rc::new(RefCell::new(Vec::new()));
+        let tiles = FakeTiles {
+            failing,
+            requested: requested.clone(),
+        };
+        let basemap = Basemap::from_tiles(view, Box::new(tiles));
+        assert!(basemap.prefetch().is_none());
+        assert!(basemap
+            .plan()
+            .iter()
+            .all(|&(_, _, state)| state == TileState::Local));
+        let image = basemap.as_image(&Tint::from(0.0)).unwrap().to_rgba8();
+
+        // Every tile of the view is asked for once
+        let mut expected: Vec<_> = view
+            .tile_xs()
+            .flat_map(|x| view.tile_ys().map(move |y| (zoom, x, y)))
+            .collect();
+        let mut requested = requested.borrow().clone();
+        expected.sort();
+        requested.sort();
+        assert_eq!(requested, expected);
+
+        // Each pixel shows the tile it lies on, or nothing where the tile failed
+        let (offset_x, offset_y) = view.pixel_offsets();
+        let (tile_min_x, tile_min_y) = view.tile_offsets();
+        let size = slippy::TILE_SIZE;
+        for (px, py, pixel) in image.enumerate_pixels() {
+            let x = tile_min_x + (px + offset_x) / size;
+            let y = tile_min_y + (py + offset_y) / size;
+            if (x, y) == failing {
+                assert_eq!(pixel[3], 0);
+            } else {
+                assert_eq!(*pixel, tile_color(x, y), "pixel {}, {}", px, py);
+            }
+        }
+    }
+
+    #[test]
+    fn redirects_resolve_against_the_url_requested() {
+        let url = "http://tiles.example.com/osm/12/2134/1445.png?key=abc";
+        let cases = [
+            (
+                "https://cdn.example.org/a.png",
+                "https://cdn.example.org/a.png",
+            ),
+            ("//cdn.example.org/a.png", "http://cdn.example.org/a.png"),
+            (
+                "/v2/12/2134/1445.png",
+                "http://tiles.example.com/v2/12/2134/1445.png",
+            ),
+            (
+                "1445.png?v=2",
+                "http://tiles.example.com/osm/12/2134/1445.png?v=2",
+            ),
+        ];
+        for (location, expected) in cases.iter() {
+            assert_eq!(resolve(url, location), *expected);
+        }
+        assert_eq!(
+            resolve("http://a.example.com", "b.png"),
+            "http://a.example.com/b.png"
+        );
+    }
+
+    #[test]
+    fn heat_lands_on_the_crosshairs_of_the_basemap() {
+        let zoom = 17;
+        let scale = 2f64.powi(zoom as i32) * slippy::TILE_SIZE as f64;
+        // Centers shifted by fractions of a pixel, which views start on whole pixels of
+        for shift in [0.0, 0.25, 0.5, 0.75] {
+            let center = slippy::to_tile(Point::new(6.1432, 46.2044), zoom);
+            let center = slippy::from_tile(center + Point::new(shift, shift) / 256.0, zoom);
+            let view = slippy::Map::from(center.x(), center.y(), 600, 400, zoom);
+            let mut tiles = MemoryTiles::default();
+            for x in view.tile_xs() {
+                for y in view.tile_ys() {
+                    tiles.insert(zoom, x, y, crosshair());
+                }
+            }
+            let basemap = Basemap::from_tiles(view, Box::new(tiles))
+                .as_image(&Tint::from(0.0))
+                .unwrap()
+                .to_rgba8();
+            let mut checked = 0;
+            for x in view.tile_xs() {
+                for y in view.tile_ys() {
+                    // Middle of the pixel the crosshair goes through
+                    let world = Point::new(
+                        (x as f64 * slippy::TILE_SIZE as f64 + 128.5) / scale,
+                        (y as f64 * slippy::TILE_SIZE as f64 + 128.5) / scale,
                     );
+                    let mut heatmap = PixelHeatmap::from(view, false, false);
+                    let pixel = match heatmap.project_world_to_screen(&world) {
+                        Some(pixel) => pixel,
+                        None => continue,
+                    };
+                    heatmap.add_point(&pixel);
+                    let heat = heatmap.as_image().to_rgba8();
+                    assert!(heat.get_pixel(pixel.x, pixel.y)[3] > 0);
+                    assert_eq!(*basemap.get_pixel(pixel.x, pixel.y), BLACK);
+                    // Only the crossing itself is black in both directions
+                    for (dx, dy) in [(1, 1), (-1, -1)] {
+                        let (x, y) = (pixel.x as i64 + dx, pixel.y as i64 + dy);
+                        if x >= 0 && y >= 0 && x < 600 && y < 400 {
+                            assert_eq!(*basemap.get_pixel(x as u32, y as u32), GRAY);
+                        }
+                    }
+                    checked += 1;
                 }
-                image::imageops::overlay(&mut pixmap, &tile, x, y);
             }
+            assert!(checked > 0, "no crosshair in view at shift {}", shift);
         }
-        let mut tint_layer = image::DynamicImage::new_rgba8(width, height);
-        let color = image::Rgba([0u8, 0, 0, (tint.clamp(0.0, 1.0) * 255.0) as u8]);
-        let fullscreen = imageproc::rect::Rect::at(0, 0).of_size(width, height);
-        imageproc::drawing::draw_filled_rect_mut(&mut tint_layer, fullscreen, color);
-        image::imageops::overlay(&mut pixmap, &tint_layer, 0, 0);
-        Ok(pixmap)
     }
 }
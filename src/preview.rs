@@ -0,0 +1,103 @@
+use crossterm::style::{Color, Print, ResetColor, SetBackgroundColor, SetForegroundColor};
+use crossterm::{cursor, QueueableCommand};
+use image::{Rgb, RgbImage};
+
+use std::io::{self, Write};
+
+use super::activity::WorldActivity;
+use super::heat::{self, Measurement};
+use super::slippy;
+
+/// Color of places without heat
+const EMPTY: [u8; 3] = [24, 24, 24];
+
+/// Adds up the heat of activities in a view with only a few pixels, e.g., one per half of a
+/// terminal character, colored by heat or by the mean of a measurement
+pub fn preview(
+    activities: &[&WorldActivity],
+    view: &slippy::Map,
+    width: u32,
+    height: u32,
+    measurement: Option<Measurement>,
+) -> RgbImage {
+    let (view_width, view_height) = view.pixel_size();
+    let cells = (width * height) as usize;
+    let mut heat = vec![0.0; cells];
+    let mut measured = vec![(0.0, 0u32); cells];
+    for act in activities {
+        for tp in &act.track_points {
+            let pixel = match view.world_to_pixels(&tp.point) {
+                Some(pixel) => pixel,
+                None => continue,
+            };
+            let x = (pixel.x as u64 * width as u64 / view_width as u64) as u32;
+            let y = (pixel.y as u64 * height as u64 / view_height as u64) as u32;
+            let cell = (y * width + x) as usize;
+            heat[cell] += 1.0;
+            let value = match measurement {
+                Some(Measurement::Elevation) => tp.elevation,
+                Some(Measurement::Temperature) => tp.sensors.temperature,
+                Some(Measurement::FitField) => tp.sensors.field,
+                None => None,
+            };
+            if let Some(value) = value {
+                measured[cell].0 += value;
+                measured[cell].1 += 1;
+            }
+        }
+    }
+
+    let max_value = heat.iter().cloned().fold(0.0, f64::max);
+    let means: Vec<Option<f64>> = measured
+        .iter()
+        .map(|&(sum, count)| (count > 0).then(|| sum / count as f64))
+        .collect();
+    let low = means
+        .iter()
+        .flatten()
+        .cloned()
+        .fold(f64::INFINITY, f64::min);
+    let high = means
+        .iter()
+        .flatten()
+        .cloned()
+        .fold(f64::NEG_INFINITY, f64::max);
+    RgbImage::from_fn(width, height, |x, y| {
+        let cell = (y * width + x) as usize;
+        let level = heat::heat_color(heat[cell], max_value)[3] as f64 / 255.0;
+        let color = match (measurement, means[cell]) {
+            (Some(measurement), Some(mean)) => {
+                let fraction = if high > low {
+                    (mean - low) / (high - low)
+                } else {
+                    0.5
+                };
+                measurement.color(fraction)
+            }
+            _ => [255, 0, 0],
+        };
+        // Heat decides the opacity over the empty background, as in full renders
+        let blend = |i: usize| (EMPTY[i] as f64 * (1.0 - level) + color[i] as f64 * level) as u8;
+        Rgb([blend(0), blend(1), blend(2)])
+    })
+}
+
+/// Queues a preview to be drawn from the top left of the terminal, each character showing two
+/// pixels on top of each other with the upper half block
+pub fn draw_preview<W: Write>(out: &mut W, image: &RgbImage) -> io::Result<()> {
+    let color = |pixel: &Rgb<u8>| Color::Rgb {
+        r: pixel[0],
+        g: pixel[1],
+        b: pixel[2],
+    };
+    for row in 0..image.height() / 2 {
+        out.queue(cursor::MoveTo(0, row as u16))?;
+        for x in 0..image.width() {
+            out.queue(SetForegroundColor(color(image.get_pixel(x, 2 * row))))?
+                .queue(SetBackgroundColor(color(image.get_pixel(x, 2 * row + 1))))?
+                .queue(Print('▀'))?;
+        }
+    }
+    out.queue(ResetColor)?;
+    Ok(())
+}
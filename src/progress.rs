@@ -0,0 +1,82 @@
+use serde::Serialize;
+
+use std::fs::File;
+use std::io::{self, Write};
+use std::sync::Mutex;
+
+/// A step of a run, written as one line of JSON for programs wrapping derivers, e.g., GUIs
+#[derive(Debug, Default, Serialize)]
+pub struct Event<'a> {
+    /// What is being done, e.g., `parsing`, `accumulation` or `done`
+    pub phase: &'a str,
+    /// Share of the phase done, between 0 and 100
+    pub percent: f64,
+    /// Name of the activity being added
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub activity: Option<&'a str>,
+    /// Number of frames written so far
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frames: Option<u64>,
+}
+
+impl<'a> Event<'a> {
+    /// Progress through a phase with the given number of items done out of all
+    pub fn new(phase: &'a str, done: u64, total: u64) -> Self {
+        Event {
+            phase,
+            percent: match total {
+                0 => 100.0,
+                _ => done as f64 * 100.0 / total as f64,
+            },
+            ..Default::default()
+        }
+    }
+
+    pub fn with_activity(mut self, name: &'a str) -> Self {
+        self.activity = Some(name);
+        self
+    }
+
+    pub fn with_frames(mut self, frames: u64) -> Self {
+        self.frames = Some(frames);
+        self
+    }
+}
+
+lazy_static! {
+    static ref SINK: Mutex<Option<Box<dyn Write + Send>>> = Mutex::new(None);
+}
+
+/// Writes events to an open file descriptor if given a number, e.g., `3`, and to a file
+/// otherwise
+pub fn open(target: &str) -> io::Result<()> {
+    let file = match target.parse::<i32>() {
+        #[cfg(unix)]
+        Ok(fd) => {
+            use std::os::unix::io::FromRawFd;
+            if unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            // The descriptor was handed over by the caller, no one else writes to it
+            unsafe { File::from_raw_fd(fd) }
+        }
+        _ => File::create(target)?,
+    };
+    *SINK.lock().unwrap() = Some(Box::new(file));
+    Ok(())
+}
+
+/// Writes an event if requested; safe to call from parallel workers. A reader going away does
+/// not stop the run, further events are dropped
+pub fn emit(event: &Event) {
+    let mut sink = SINK.lock().unwrap();
+    if let Some(writer) = sink.as_mut() {
+        let line = serde_json::to_string(event).expect("events serialize");
+        if writeln!(writer, "{}", line)
+            .and_then(|_| writer.flush())
+            .is_err()
+        {
+            *sink = None;
+        }
+    }
+}
@@ -0,0 +1,131 @@
+use clap::builder::PossibleValue;
+use clap::ValueEnum;
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// A public tile server and the terms of using it
+#[derive(Clone, Copy, Debug)]
+pub struct Provider {
+    /// Short name selecting it, e.g., in `--provider=osm-de` or a config file
+    pub key: &'static str,
+    /// Name shown in messages
+    pub name: &'static str,
+    /// URL pattern, with `{s}` standing for one of the subdomains
+    pub url: &'static str,
+    /// Subdomains tiles are spread over
+    pub subdomains: &'static [&'static str],
+    /// Attribution required by the usage policy
+    pub attribution: &'static str,
+    /// Highest zoom level with tiles
+    pub max_zoom: u8,
+}
+
+const OSM_ATTRIBUTION: &str = "© OpenStreetMap contributors";
+const CARTO_ATTRIBUTION: &str = "© OpenStreetMap contributors © CARTO";
+
+/// Standard OpenStreetMap style
+pub const OSM: Provider = Provider {
+    key: "osm",
+    name: "OpenStreetMap",
+    url: "https://tile.openstreetmap.org/{z}/{x}/{y}.png",
+    subdomains: &[],
+    attribution: OSM_ATTRIBUTION,
+    max_zoom: 19,
+};
+
+/// German OpenStreetMap style
+pub const OSM_DE: Provider = Provider {
+    key: "osm-de",
+    name: "OpenStreetMap Germany",
+    url: "https://{s}.tile.openstreetmap.de/{z}/{x}/{y}.png",
+    subdomains: &["a", "b", "c"],
+    attribution: OSM_ATTRIBUTION,
+    max_zoom: 18,
+};
+
+/// Humanitarian OpenStreetMap style
+pub const OSM_HOT: Provider = Provider {
+    key: "osm-hot",
+    name: "Humanitarian OpenStreetMap",
+    url: "https://{s}.tile.openstreetmap.fr/hot/{z}/{x}/{y}.png",
+    subdomains: &["a", "b"],
+    attribution: "© OpenStreetMap contributors, style by Humanitarian OpenStreetMap Team \
+                  hosted by OpenStreetMap France",
+    max_zoom: 19,
+};
+
+/// Topographic map with contour lines and hill shading
+pub const OPENTOPOMAP: Provider = Provider {
+    key: "opentopomap",
+    name: "OpenTopoMap",
+    url: "https://{s}.tile.opentopomap.org/{z}/{x}/{y}.png",
+    subdomains: &["a", "b", "c"],
+    attribution: "© OpenStreetMap contributors, SRTM, style © OpenTopoMap (CC-BY-SA)",
+    max_zoom: 17,
+};
+
+/// Cycling map highlighting bike infrastructure
+pub const CYCLOSM: Provider = Provider {
+    key: "cyclosm",
+    name: "CyclOSM",
+    url: "https://{s}.tile-cyclosm.openstreetmap.fr/cyclosm/{z}/{x}/{y}.png",
+    subdomains: &["a", "b", "c"],
+    attribution: "© OpenStreetMap contributors, CyclOSM hosted by OpenStreetMap France",
+    max_zoom: 20,
+};
+
+/// Dark, muted CARTO style
+pub const CARTO_DARK: Provider = Provider {
+    key: "carto-dark",
+    name: "CARTO Dark Matter",
+    url: "https://{s}.basemaps.cartocdn.com/dark_all/{z}/{x}/{y}.png",
+    subdomains: &["a", "b", "c", "d"],
+    attribution: CARTO_ATTRIBUTION,
+    max_zoom: 20,
+};
+
+/// Light, muted CARTO style
+pub const CARTO_LIGHT: Provider = Provider {
+    key: "carto-light",
+    name: "CARTO Positron",
+    url: "https://{s}.basemaps.cartocdn.com/light_all/{z}/{x}/{y}.png",
+    subdomains: &["a", "b", "c", "d"],
+    attribution: CARTO_ATTRIBUTION,
+    max_zoom: 20,
+};
+
+/// All presets, in the order they are listed in the help
+pub const PRESETS: &[Provider] = &[
+    OSM,
+    OSM_DE,
+    OSM_HOT,
+    OPENTOPOMAP,
+    CYCLOSM,
+    CARTO_DARK,
+    CARTO_LIGHT,
+];
+
+impl ValueEnum for Provider {
+    fn value_variants<'a>() -> &'a [Self] {
+        PRESETS
+    }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        Some(PossibleValue::new(self.key).help(self.name))
+    }
+}
+
+/// Stored by key, e.g., in config files
+impl Serialize for Provider {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.key)
+    }
+}
+
+/// Only presets can be read back, by key
+impl<'de> Deserialize<'de> for Provider {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let key = String::deserialize(deserializer)?;
+        Provider::from_str(&key, false).map_err(D::Error::custom)
+    }
+}
@@ -0,0 +1,305 @@
+use geo::algorithm::contains::Contains;
+use geo_types::{Point, Rect};
+use image::{ImageBuffer, RgbaImage};
+use rayon::prelude::*;
+use rusqlite::{params, Connection};
+
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::io::Write;
+use std::path::Path;
+
+use super::heat::heat_color;
+use super::slippy;
+
+type Tile = Vec<u32>;
+
+/// Lowest opacity `heat_color` gives visited pixels
+const MIN_HEAT: f64 = 6.0;
+
+/// Thresholds of a 4×4 Bayer matrix for ordered dithering, in sixteenths
+const BAYER: [[u8; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+
+/// Heat accumulated on the OSM tile grid for a range of zoom levels
+pub struct Pyramid {
+    extends: Rect<f64>,
+    min_zoom: u8,
+    max_zoom: u8,
+    /// Tiles with any heat, indexed by zoom level above `min_zoom`; points are only added to
+    /// the highest level, `downsample` derives the others from it
+    levels: Vec<HashMap<(u32, u32), Tile>>,
+    /// Highest pixel count per zoom level
+    max_values: Vec<u32>,
+    /// Write tiles as indexed PNG with this many colors, including the transparent one
+    palette_colors: Option<u16>,
+    /// Dither between palette colors instead of rounding to the nearest
+    dither: bool,
+}
+
+impl Pyramid {
+    /// Create an empty pyramid covering the lon/lat extends between both zoom levels, inclusive
+    pub fn from(extends: Rect<f64>, min_zoom: u8, max_zoom: u8) -> Self {
+        let n = (max_zoom.max(min_zoom) - min_zoom) as usize + 1;
+        Self {
+            extends,
+            min_zoom,
+            max_zoom: max_zoom.max(min_zoom),
+            levels: vec![HashMap::new(); n],
+            max_values: vec![0; n],
+            palette_colors: None,
+            dither: false,
+        }
+    }
+
+    /// Write tiles as indexed PNG with the given number of colors, between 2 and 256, which
+    /// makes them several times smaller, optionally dithered to hide the coarser steps
+    pub fn with_palette(mut self, colors: Option<u16>, dither: bool) -> Self {
+        self.palette_colors = colors.map(|c| c.clamp(2, 256));
+        self.dither = dither;
+        self
+    }
+
+    /// Adds a lon/lat point to the highest zoom level; points outside the extends are ignored
+    pub fn add_point(&mut self, point: &Point<f64>) {
+        if !self.extends.contains(point) {
+            return;
+        }
+        let level = self.levels.len() - 1;
+        let mapped = slippy::to_tile(*point, self.max_zoom);
+        let x = mapped.x().floor();
+        let y = mapped.y().floor();
+        let px = ((mapped.x() - x) * slippy::TILE_SIZE as f64) as u32;
+        let py = ((mapped.y() - y) * slippy::TILE_SIZE as f64) as u32;
+
+        let tile = self.levels[level]
+            .entry((x as u32, y as u32))
+            .or_insert_with(|| vec![0; (slippy::TILE_SIZE * slippy::TILE_SIZE) as usize]);
+        let count = &mut tile[(px + py * slippy::TILE_SIZE) as usize];
+        *count += 1;
+        self.max_values[level] = self.max_values[level].max(*count);
+    }
+
+    /// Fills the lower zoom levels from the highest one once all points are added, each pixel
+    /// summing the four it covers one level up, which gives the same counts as adding the
+    /// points to every level; the tiles of a level are built in parallel
+    pub fn downsample(&mut self) {
+        let size = slippy::TILE_SIZE;
+        let half = size / 2;
+        for level in (0..self.levels.len() - 1).rev() {
+            let children = &self.levels[level + 1];
+            let parents: HashSet<(u32, u32)> =
+                children.keys().map(|&(x, y)| (x / 2, y / 2)).collect();
+            let tiles: HashMap<(u32, u32), Tile> = parents
+                .into_par_iter()
+                .map(|(x, y)| {
+                    let mut tile = vec![0; (size * size) as usize];
+                    for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                        let Some(child) = children.get(&(2 * x + dx, 2 * y + dy)) else {
+                            continue;
+                        };
+                        for (index, &count) in child.iter().enumerate() {
+                            let (cx, cy) = (index as u32 % size, index as u32 / size);
+                            let px = dx * half + cx / 2;
+                            let py = dy * half + cy / 2;
+                            tile[(px + py * size) as usize] += count;
+                        }
+                    }
+                    ((x, y), tile)
+                })
+                .collect();
+            self.max_values[level] = tiles
+                .par_iter()
+                .map(|(_, tile)| tile.iter().cloned().max().unwrap_or(0))
+                .max()
+                .unwrap_or(0);
+            self.levels[level] = tiles;
+        }
+    }
+
+    /// All tiles with heat as `(zoom, x, y)`
+    pub fn tile_indices(&self) -> Vec<(u8, u32, u32)> {
+        (self.min_zoom..=self.max_zoom)
+            .zip(self.levels.iter())
+            .flat_map(|(zoom, tiles)| tiles.keys().map(move |&(x, y)| (zoom, x, y)))
+            .collect()
+    }
+
+    /// Renders a single tile, or None if it holds no heat
+    pub fn render_tile(&self, zoom: u8, x: u32, y: u32) -> Option<RgbaImage> {
+        if zoom < self.min_zoom || zoom > self.max_zoom {
+            return None;
+        }
+        let level = (zoom - self.min_zoom) as usize;
+        let tile = self.levels[level].get(&(x, y))?;
+        let max_value = self.max_values[level];
+
+        let pixels = tile
+            .iter()
+            .flat_map(|&count| heat_color(count as f64, max_value as f64))
+            .collect();
+        ImageBuffer::from_raw(slippy::TILE_SIZE, slippy::TILE_SIZE, pixels)
+    }
+
+    /// Encodes a tile with heat as PNG, indexed if a palette was requested
+    fn encode_tile(&self, zoom: u8, x: u32, y: u32) -> Result<Vec<u8>, String> {
+        let tile = self.render_tile(zoom, x, y).unwrap();
+        let mut data = Vec::new();
+        match self.palette_colors {
+            Some(colors) => {
+                encode_indexed(&tile, colors, self.dither, &mut data).map_err(|e| e.to_string())?
+            }
+            None => image::DynamicImage::ImageRgba8(tile)
+                .write_to(&mut data, image::ImageFormat::Png)
+                .map_err(|e| e.to_string())?,
+        }
+        Ok(data)
+    }
+
+    /// Writes all tiles with heat as `z/x/y.png` below `directory`, returning the tile count
+    pub fn write_dir(&self, directory: &Path) -> Result<usize, Box<dyn Error>> {
+        let indices = self.tile_indices();
+        indices
+            .par_iter()
+            .map(|&(zoom, x, y)| -> Result<(), String> {
+                let data = self.encode_tile(zoom, x, y)?;
+                let dir = directory.join(zoom.to_string()).join(x.to_string());
+                std::fs::create_dir_all(&dir).map_err(|e| e.to_string())?;
+                std::fs::write(dir.join(format!("{}.png", y)), data).map_err(|e| e.to_string())
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(indices.len())
+    }
+
+    /// Writes all tiles with heat into a single MBTiles file, replacing any existing one, and
+    /// returns the tile count
+    pub fn write_mbtiles(&self, path: &Path, name: &str) -> Result<usize, Box<dyn Error>> {
+        let encoded = self
+            .tile_indices()
+            .into_par_iter()
+            .map(|(zoom, x, y)| -> Result<_, String> {
+                Ok((zoom, x, y, self.encode_tile(zoom, x, y)?))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if path.exists() {
+            std::fs::remove_file(path)?;
+        }
+        let mut db = Connection::open(path)?;
+        let tx = db.transaction()?;
+        tx.execute_batch(
+            "CREATE TABLE metadata (name TEXT, value TEXT);
+             CREATE TABLE tiles (zoom_level INTEGER, tile_column INTEGER, tile_row INTEGER,
+                                 tile_data BLOB);
+             CREATE UNIQUE INDEX tile_index ON tiles (zoom_level, tile_column, tile_row);",
+        )?;
+
+        let (min, max) = (self.extends.min(), self.extends.max());
+        let center = self.extends.center();
+        let metadata = [
+            ("name", name.to_string()),
+            ("format", "png".to_string()),
+            ("type", "overlay".to_string()),
+            ("version", "1.0".to_string()),
+            (
+                "description",
+                "Activity heatmap rendered by derive.rs".to_string(),
+            ),
+            ("minzoom", self.min_zoom.to_string()),
+            ("maxzoom", self.max_zoom.to_string()),
+            ("bounds", format!("{},{},{},{}", min.x, min.y, max.x, max.y)),
+            (
+                "center",
+                format!("{},{},{}", center.x, center.y, self.min_zoom),
+            ),
+        ];
+        for (key, value) in metadata.iter() {
+            tx.execute(
+                "INSERT INTO metadata (name, value) VALUES (?1, ?2)",
+                params![key, value],
+            )?;
+        }
+
+        for (zoom, x, y, data) in encoded.iter() {
+            // MBTiles uses the TMS scheme, counting rows from the south
+            let row = (1u32 << zoom) - 1 - y;
+            tx.execute(
+                "INSERT INTO tiles (zoom_level, tile_column, tile_row, tile_data)
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![zoom, x, row, data],
+            )?;
+        }
+        tx.commit()?;
+        Ok(encoded.len())
+    }
+}
+
+/// Writes a tile colored by `heat_color` as indexed PNG: index 0 is transparent and the others
+/// step evenly through the heat levels, with as few bits per pixel as the palette allows
+fn encode_indexed<W: Write>(
+    tile: &RgbaImage,
+    colors: u16,
+    dither: bool,
+    writer: W,
+) -> Result<(), png::EncodingError> {
+    let steps = (colors - 1) as f64;
+    let mut palette = vec![0; 3];
+    let mut alphas = vec![0];
+    for index in 1..colors {
+        let fraction = if colors > 2 {
+            (index - 1) as f64 / (steps - 1.0)
+        } else {
+            1.0
+        };
+        let heat = (MIN_HEAT + fraction * (255.0 - MIN_HEAT)).round() as u8;
+        palette.extend([heat, 0, 0]);
+        alphas.push(heat);
+    }
+
+    let depth: u8 = match colors {
+        2 => 1,
+        3..=4 => 2,
+        5..=16 => 4,
+        _ => 8,
+    };
+    let (width, height) = tile.dimensions();
+    let row_bytes = (width as usize * depth as usize).div_ceil(8);
+    let mut data = vec![0u8; row_bytes * height as usize];
+    for (x, y, pixel) in tile.enumerate_pixels() {
+        let heat = pixel[3];
+        if heat == 0 {
+            continue;
+        }
+        // Position between the first and last opaque palette entries
+        let position = if colors > 2 {
+            (heat as f64 - MIN_HEAT).max(0.0) / (255.0 - MIN_HEAT) * (steps - 1.0)
+        } else {
+            0.0
+        };
+        let offset = if dither {
+            (BAYER[y as usize % 4][x as usize % 4] as f64 + 0.5) / 16.0
+        } else {
+            0.5
+        };
+        let index = 1 + ((position + offset).floor() as u16).min(colors - 2);
+        let bit = x as usize * depth as usize;
+        let shift = 8 - depth as usize - bit % 8;
+        data[y as usize * row_bytes + bit / 8] |= (index as u8) << shift;
+    }
+
+    let mut encoder = png::Encoder::new(writer, width, height);
+    encoder.set_color(png::ColorType::Indexed);
+    encoder.set_depth(match depth {
+        1 => png::BitDepth::One,
+        2 => png::BitDepth::Two,
+        4 => png::BitDepth::Four,
+        _ => png::BitDepth::Eight,
+    });
+    encoder.set_palette(palette);
+    encoder.set_trns(alphas);
+    // Filters rarely help indices, which are not gradual like colors
+    encoder.set_filter(png::FilterType::NoFilter);
+    encoder.set_compression(png::Compression::Best);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&data)?;
+    writer.finish()
+}
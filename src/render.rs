@@ -0,0 +1,495 @@
+use image::{DynamicImage, GenericImageView, RgbaImage};
+
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use super::activity::{self, Order, ScreenActivity, WorldActivity};
+use super::diagnostics;
+use super::heat::{self, Heatmap, PixelHeatmap, TileHeatmap};
+use super::metadata::{self, Metadata};
+use super::osmbase::Basemap;
+use super::provider::Provider;
+use super::slippy;
+use super::strava::{DataExport, Filter};
+use super::style::{BasemapStyle, Resampling, Tint};
+
+/// What heat is added up per
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum HeatKind {
+    /// Pixels of the image
+    #[default]
+    Pixels,
+    /// Tiles of the given zoom level, e.g., `stats::SQUADRAT_ZOOM`
+    Tiles(u8),
+}
+
+/// What heat is drawn over
+#[derive(Clone, Debug, Default)]
+pub enum Background {
+    /// Nothing, leaving the image transparent
+    #[default]
+    Transparent,
+    /// A flat color
+    Color(image::Rgba<u8>),
+    /// Tiles of a public tile server, downloaded and cached
+    Provider(Provider),
+    /// Tiles downloaded from a URL pattern with `{z}`, `{x}` and `{y}`, and `{s}` for the
+    /// subdomains `a`, `b` and `c`
+    Url(String),
+    /// Tiles read from an MBTiles file or a `z/x/y` directory
+    File(PathBuf),
+}
+
+/// Renders heatmaps as the `render` subcommand does, which draws with it too: configure the
+/// view and looks, then `render_export` an export, or `render` activities read before, and save
+/// the image with `metadata::save`
+#[derive(Clone, Debug)]
+pub struct RenderBuilder {
+    view: slippy::Map,
+    background: Background,
+    /// Substituted for `{s}` in tile URLs instead of `a`, `b` and `c`
+    subdomains: Vec<String>,
+    /// Attempts to download each tile and the delay before the first retry
+    retries: Option<(u32, Duration)>,
+    /// Tiles downloaded per second at most
+    rate_limit: Option<f64>,
+    style: BasemapStyle,
+    tint: Tint,
+    resampling: Resampling,
+    heat: HeatKind,
+    /// Standard deviation of the Gaussian spreading heat around tracks, in pixels
+    blur: f64,
+    fixed_max: Option<f64>,
+    filter: Filter,
+    order: Order,
+    reverse: bool,
+    /// Credit the tile server in the corner, as its terms require
+    attribution: bool,
+    /// Credit given instead of the one of the tile server, e.g., for custom tiles
+    attribution_text: Option<String>,
+}
+
+impl RenderBuilder {
+    /// Renders the view around a longitude and latitude at the zoom level, 1920x1080 pixels
+    /// large, with heat per pixel over a transparent background
+    pub fn new(lon: f64, lat: f64, zoom: u8) -> Self {
+        Self::from_view(slippy::Map::from(lon, lat, 1920, 1080, zoom))
+    }
+
+    /// Renders the view, e.g., one fitted to the activities
+    pub fn from_view(view: slippy::Map) -> Self {
+        RenderBuilder {
+            view,
+            background: Background::default(),
+            subdomains: Vec::new(),
+            retries: None,
+            rate_limit: None,
+            style: BasemapStyle::Normal,
+            tint: Tint::from(0.8),
+            resampling: Resampling::default(),
+            heat: HeatKind::default(),
+            blur: 0.0,
+            fixed_max: None,
+            filter: Filter::default(),
+            order: Order::Date,
+            reverse: false,
+            attribution: true,
+            attribution_text: None,
+        }
+    }
+
+    /// Render this many pixels around the same center
+    pub fn with_size(mut self, width: u32, height: u32) -> Self {
+        let center = self.view.center();
+        self.view = slippy::Map::from(center.x(), center.y(), width, height, self.view.zoom());
+        self
+    }
+
+    pub fn with_background(mut self, background: Background) -> Self {
+        self.background = background;
+        self
+    }
+
+    /// Substitute these subdomains for `{s}` in the URL of `Background::Url`
+    pub fn with_subdomains(mut self, subdomains: &[String]) -> Self {
+        self.subdomains = subdomains.to_vec();
+        self
+    }
+
+    /// Try downloading each tile up to the given number of times, waiting exponentially longer
+    /// between attempts starting from the given delay
+    pub fn with_retries(mut self, attempts: u32, delay: Duration) -> Self {
+        self.retries = Some((attempts, delay));
+        self
+    }
+
+    /// Download at most the given number of tiles per second, if any
+    pub fn with_rate_limit(mut self, per_second: Option<f64>) -> Self {
+        self.rate_limit = per_second;
+        self
+    }
+
+    /// Recolor basemap tiles, e.g., to gray, before the tint
+    pub fn with_style(mut self, style: BasemapStyle) -> Self {
+        self.style = style;
+        self
+    }
+
+    /// Darken basemap tiles with the tint, e.g., `Tint::from(0.8)` as by default
+    pub fn with_tint(mut self, tint: Tint) -> Self {
+        self.tint = tint;
+        self
+    }
+
+    /// Scale basemap tiles of other sizes than 256 pixels with the filter, Lanczos by default
+    pub fn with_resampling(mut self, resampling: Resampling) -> Self {
+        self.resampling = resampling;
+        self
+    }
+
+    pub fn with_heat(mut self, kind: HeatKind) -> Self {
+        self.heat = kind;
+        self
+    }
+
+    /// Spread heat per pixel smoothly around tracks with a Gaussian of the given standard
+    /// deviation, in pixels
+    pub fn with_blur(mut self, sigma: f64) -> Self {
+        self.blur = sigma;
+        self
+    }
+
+    /// Normalize colors to the given heat value instead of the highest one, so that renders
+    /// can be compared
+    pub fn with_fixed_max(mut self, max: Option<f64>) -> Self {
+        self.fixed_max = max;
+        self
+    }
+
+    /// Render only the activities of an export the filter accepts
+    pub fn with_filter(mut self, filter: Filter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Add activities in the order, reversed if asked to, instead of by date
+    pub fn with_order(mut self, order: Order, reverse: bool) -> Self {
+        self.order = order;
+        self.reverse = reverse;
+        self
+    }
+
+    /// Whether to credit the tile server in the corner; its terms usually require it
+    pub fn with_attribution(mut self, attribution: bool) -> Self {
+        self.attribution = attribution;
+        self
+    }
+
+    /// Credit the text instead of the preset tile server, e.g., the source of custom tiles
+    pub fn with_attribution_text(mut self, text: Option<String>) -> Self {
+        self.attribution_text = text;
+        self
+    }
+
+    /// The view rendered
+    pub fn view(&self) -> slippy::Map {
+        self.view
+    }
+
+    /// Credit drawn in the corner and written to the metadata; custom tiles come with their own
+    /// terms, so only preset tile servers are credited without a text
+    pub fn attribution(&self) -> Option<&str> {
+        match (&self.attribution_text, &self.background) {
+            (Some(text), _) => Some(text),
+            (None, Background::Provider(provider)) => Some(provider.attribution),
+            _ => None,
+        }
+    }
+
+    /// An empty heatmap of the view, to add activities to
+    pub fn heatmap(&self) -> Box<dyn Heatmap + Send> {
+        match self.heat {
+            HeatKind::Pixels => Box::new(
+                PixelHeatmap::from(self.view(), false, false)
+                    .with_blur(self.blur)
+                    .with_fixed_max(self.fixed_max),
+            ),
+            HeatKind::Tiles(zoom) => {
+                Box::new(TileHeatmap::from(self.view(), zoom).with_fixed_max(self.fixed_max))
+            }
+        }
+    }
+
+    /// Reads the activities of an export, a directory or zip archive, that the filter accepts;
+    /// `diagnostics` then holds the warnings of this export only
+    pub fn activities(&self, export: &Path) -> Result<Vec<WorldActivity>, Box<dyn Error>> {
+        diagnostics::clear();
+        Ok(DataExport::new(export, &self.filter)?.world_activities())
+    }
+
+    /// Tiles of a view, e.g., a part of the one rendered, as the background asks for; None for
+    /// backgrounds without tiles
+    pub fn basemap(&self, view: slippy::Map) -> Result<Option<Basemap>, Box<dyn Error>> {
+        let basemap = match self.background {
+            Background::Transparent | Background::Color(_) => return Ok(None),
+            Background::Provider(ref provider) => Basemap::from_provider(view, provider)?,
+            Background::Url(ref url) => Basemap::from(view, url)?.with_subdomains(&self.subdomains),
+            Background::File(ref path) => Basemap::from_file(view, path)?,
+        };
+        let basemap = match self.retries {
+            Some((attempts, delay)) => basemap.with_retries(attempts, delay),
+            None => basemap,
+        };
+        Ok(Some(
+            basemap
+                .with_rate_limit(self.rate_limit)
+                .with_style(self.style)
+                .with_resampling(self.resampling),
+        ))
+    }
+
+    /// Draws what the heat goes over a view: the tiles of the basemap, given by `basemap`,
+    /// tinted, or else the flat color or transparency of the background
+    pub fn draw_background(
+        &self,
+        basemap: Option<&Basemap>,
+        view: &slippy::Map,
+        tint: &Tint,
+    ) -> Result<DynamicImage, Box<dyn Error>> {
+        let fill = match (basemap, &self.background) {
+            (Some(basemap), _) => return basemap.as_image(tint),
+            (None, Background::Color(color)) => *color,
+            (None, _) => image::Rgba([0; 4]),
+        };
+        let (width, height) = view.pixel_size();
+        Ok(DynamicImage::ImageRgba8(RgbaImage::from_pixel(
+            width, height, fill,
+        )))
+    }
+
+    /// Draws what the heat goes over, tinted if made of tiles
+    pub fn background(&self) -> Result<DynamicImage, Box<dyn Error>> {
+        let basemap = self.basemap(self.view)?;
+        self.draw_background(basemap.as_ref(), &self.view, &self.tint)
+    }
+
+    /// Activities on the screen of a heatmap, in the order they are added
+    pub fn project(
+        &self,
+        activities: &[WorldActivity],
+        heatmap: &dyn Heatmap,
+    ) -> Vec<ScreenActivity> {
+        activity::project_to_screen(activities, heatmap, self.order, self.reverse)
+    }
+
+    /// Draws heat over the background and credits the tile server
+    pub fn overlay(&self, background: &DynamicImage, heat: &RgbaImage) -> DynamicImage {
+        let mut image = background.clone();
+        image::imageops::overlay(&mut image, heat, 0, 0);
+        self.credit(&mut image);
+        image
+    }
+
+    /// Draws the heat of a heatmap over the background, with the metadata describing it
+    pub fn draw(
+        &self,
+        heatmap: &dyn Heatmap,
+        background: &DynamicImage,
+    ) -> (DynamicImage, Metadata) {
+        let image = self.overlay(background, &heatmap.as_image().to_rgba8());
+        (image, self.metadata(heatmap, self.normalization()))
+    }
+
+    /// Credits the tile server in the corner of an image, if asked to
+    pub fn credit(&self, image: &mut DynamicImage) {
+        let height = image.height();
+        self.credit_sized(image, height);
+    }
+
+    /// Credits the tile server in the bottom strip of a picture rendered in strips, sized for
+    /// the height of the whole picture; the strip needs to be `credit_height` rows high
+    pub fn credit_sized(&self, image: &mut DynamicImage, picture_height: u32) {
+        if let (Some(text), true) = (self.attribution(), self.attribution) {
+            heat::draw_attribution_sized(image, text, picture_height);
+        }
+    }
+
+    /// Rows at the bottom of a picture of the given height that the credit covers, if any
+    pub fn credit_height(&self, picture_height: u32) -> u32 {
+        match (self.attribution(), self.attribution) {
+            (Some(_), true) => heat::label_height(picture_height),
+            _ => 0,
+        }
+    }
+
+    /// Describes an image of the heatmap, with heat normalized as named, e.g., `own`
+    pub fn metadata(&self, heatmap: &dyn Heatmap, normalization: &str) -> Metadata {
+        metadata::heat_metadata(heatmap, normalization, self.attribution())
+    }
+
+    /// How heat is normalized by the heatmaps of the builder
+    fn normalization(&self) -> &'static str {
+        match self.fixed_max {
+            Some(_) => "fixed",
+            None => "own",
+        }
+    }
+
+    /// Renders activities, adding them in order
+    pub fn render(
+        &self,
+        activities: &[WorldActivity],
+    ) -> Result<(DynamicImage, Metadata), Box<dyn Error>> {
+        let mut heatmap = self.heatmap();
+        for act in self.project(activities, &*heatmap) {
+            for tp in act.track_points.iter() {
+                heatmap.add_point_at(&tp.point, &tp.time.unwrap_or(act.date));
+            }
+        }
+        Ok(self.draw(&*heatmap, &self.background()?))
+    }
+
+    /// Reads the activities of an export, a directory or zip archive, and renders them
+    pub fn render_export(&self, export: &Path) -> Result<(DynamicImage, Metadata), Box<dyn Error>> {
+        self.render(&self.activities(export)?)
+    }
+
+    /// Renders activities as an animation, adding them in order with a frame every `rate` track
+    /// points and one showing all of them last, unless that was the last of the rate. Frames are
+    /// only drawn as the iterator advances, so that callers encode and pace them as they like
+    pub fn frames(
+        &self,
+        activities: &[WorldActivity],
+        rate: u32,
+    ) -> Result<Frames<'_>, Box<dyn Error>> {
+        if rate == 0 {
+            return Err("frames need a rate of at least one track point".into());
+        }
+        let heatmap = self.heatmap();
+        let activities = self.project(activities, &*heatmap);
+        Ok(Frames {
+            builder: self,
+            heatmap,
+            activities: activities.into_iter(),
+            current: None,
+            canvas: Canvas::new(self.background()?),
+            rate,
+            added: 0,
+        })
+    }
+}
+
+/// Heat drawn over a background for one frame after the other, only redrawn where the heat
+/// changed
+pub struct Canvas {
+    background: DynamicImage,
+    /// Background with the heat of the last frame
+    canvas: DynamicImage,
+    heat: RgbaImage,
+}
+
+impl Canvas {
+    pub fn new(background: DynamicImage) -> Self {
+        Canvas {
+            canvas: background.clone(),
+            background,
+            heat: RgbaImage::new(0, 0),
+        }
+    }
+
+    /// Draws the heat added to the heatmap since the last frame, recoloring all of it if its
+    /// normalization changed
+    pub fn update(&mut self, heatmap: &mut dyn Heatmap) -> &DynamicImage {
+        if let Some((x, y, width, height)) = heatmap.update_image(&mut self.heat) {
+            let base = image::imageops::crop_imm(&self.background, x, y, width, height);
+            image::imageops::replace(&mut self.canvas, &base, x, y);
+            let changed = image::imageops::crop_imm(&self.heat, x, y, width, height);
+            image::imageops::overlay(&mut self.canvas, &changed, x, y);
+        }
+        &self.canvas
+    }
+}
+
+/// A frame of an animation with what it shows
+pub struct Frame {
+    pub image: DynamicImage,
+    pub metadata: Metadata,
+    /// Name of the activity being added
+    pub activity: String,
+    pub date: chrono::DateTime<chrono::Utc>,
+}
+
+/// Frames of an animation rendered one at a time, see `RenderBuilder::frames`
+pub struct Frames<'a> {
+    builder: &'a RenderBuilder,
+    heatmap: Box<dyn Heatmap + Send>,
+    activities: std::vec::IntoIter<ScreenActivity>,
+    /// Activity being added and the number of its track points added so far
+    current: Option<(ScreenActivity, usize)>,
+    canvas: Canvas,
+    rate: u32,
+    /// Number of track points added since the last frame
+    added: u32,
+}
+
+impl<'a> Frames<'a> {
+    /// Heat of the last frame, e.g., to read its maximum or save it as tiles
+    pub fn heatmap(&self) -> &dyn Heatmap {
+        &*self.heatmap
+    }
+
+    /// Draws the heat added so far over the background
+    fn draw(&mut self, activity: String, date: chrono::DateTime<chrono::Utc>) -> Frame {
+        let mut image = self.canvas.update(&mut *self.heatmap).clone();
+        self.builder.credit(&mut image);
+        let metadata = self
+            .builder
+            .metadata(&*self.heatmap, self.builder.normalization());
+        Frame {
+            image,
+            metadata,
+            activity,
+            date,
+        }
+    }
+}
+
+impl<'a> Iterator for Frames<'a> {
+    type Item = Frame;
+
+    fn next(&mut self) -> Option<Frame> {
+        // Name and date of the activity added last, shown by the final frame
+        let mut last = None;
+        loop {
+            let (act, mut index) = match self.current.take() {
+                Some(current) => current,
+                None => match self.activities.next() {
+                    Some(act) => (act, 0),
+                    None => break,
+                },
+            };
+            last = Some((act.name.clone(), act.date));
+            while index < act.track_points.len() {
+                let tp = &act.track_points[index];
+                self.heatmap
+                    .add_point_at(&tp.point, &tp.time.unwrap_or(act.date));
+                index += 1;
+                self.added += 1;
+                if self.added == self.rate {
+                    self.added = 0;
+                    let frame = self.draw(act.name.clone(), act.date);
+                    self.current = Some((act, index));
+                    return Some(frame);
+                }
+            }
+        }
+        match last {
+            Some((activity, date)) if self.added > 0 => {
+                self.added = 0;
+                Some(self.draw(activity, date))
+            }
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,284 @@
+use chrono::{Datelike, Duration, NaiveDate};
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use super::stats::{Contribution, Summary};
+
+/// Number of activities listed as defining the map
+const TOP_ACTIVITIES: usize = 10;
+
+/// Number of weeks shown in the calendar
+const CALENDAR_WEEKS: i64 = 53;
+
+/// Layout of the report, with `{{title}}`, `{{map}}`, `{{summary}}`, `{{calendar}}` and
+/// `{{top}}` replaced by the sections
+pub const TEMPLATE: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>{{title}}</title>
+<style>
+  body { font-family: sans-serif; max-width: 60em; margin: 2em auto; color: #222; }
+  img { max-width: 100%; }
+  table { border-collapse: collapse; margin-bottom: 1em; }
+  td, th { padding: 0.2em 0.8em; text-align: left; }
+  td.number { text-align: right; }
+  tr:nth-child(even) { background: #f4f4f4; }
+</style>
+</head>
+<body>
+<h1>{{title}}</h1>
+<img src="{{map}}" alt="Heatmap">
+<h2>Summary</h2>
+{{summary}}
+<h2>Calendar</h2>
+{{calendar}}
+<h2>Top Activities</h2>
+{{top}}
+</body>
+</html>
+"#;
+
+/// Everything shown in an HTML report of a render
+pub struct Report<'a> {
+    pub title: String,
+    /// The rendered image, encoded as PNG
+    pub map: Vec<u8>,
+    pub summary: &'a Summary,
+    /// Distance per day, in kilometers
+    pub days: BTreeMap<NaiveDate, f64>,
+    /// Contributions of the activities in view, largest first
+    pub top: &'a [Contribution],
+}
+
+/// Escapes text for HTML
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Encodes data as base64, for embedding images
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let bits = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(bits >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// Replaces each `{{key}}` of the template by its value in a single pass, so that values are
+/// never taken for placeholders; unknown placeholders are kept
+fn fill(template: &str, values: &[(&str, String)]) -> String {
+    let mut filled = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        filled.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let value = after.find("}}").and_then(|end| {
+            let key = after[..end].trim();
+            let (_, value) = values.iter().find(|(k, _)| *k == key)?;
+            Some((value, end))
+        });
+        match value {
+            Some((value, end)) => {
+                filled.push_str(value);
+                rest = &after[end + 2..];
+            }
+            None => {
+                filled.push_str("{{");
+                rest = after;
+            }
+        }
+    }
+    filled.push_str(rest);
+    filled
+}
+
+/// A table of labels and values
+fn table(rows: impl IntoIterator<Item = (String, String)>) -> String {
+    let mut html = String::from("<table>\n");
+    for (label, value) in rows {
+        writeln!(
+            html,
+            "<tr><th>{}</th><td class=\"number\">{}</td></tr>",
+            escape(&label),
+            escape(&value)
+        )
+        .unwrap();
+    }
+    html.push_str("</table>\n");
+    html
+}
+
+impl Report<'_> {
+    /// Totals, then activities per type and per year
+    fn summary_html(&self) -> String {
+        let summary = self.summary;
+        let mut totals = vec![
+            ("Activities".to_string(), summary.activities.to_string()),
+            (
+                "Total distance".to_string(),
+                format!("{:.1} km", summary.distance_km),
+            ),
+            (
+                "Eddington number".to_string(),
+                summary.eddington.to_string(),
+            ),
+            ("Squadrats".to_string(), summary.squadrats.to_string()),
+            (
+                "Squadratinhos".to_string(),
+                summary.squadratinhos.to_string(),
+            ),
+        ];
+        if let Some(hr) = summary.mean_heart_rate {
+            totals.push(("Mean heart rate".to_string(), format!("{:.0} bpm", hr)));
+        }
+        let per_type = summary
+            .per_type
+            .iter()
+            .map(|(kind, count)| (kind.clone(), count.to_string()));
+        let per_year = summary
+            .per_year
+            .iter()
+            .map(|(year, count)| (year.to_string(), count.to_string()));
+        format!(
+            "{}<h3>Per Type</h3>\n{}<h3>Per Year</h3>\n{}",
+            table(totals),
+            table(per_type),
+            table(per_year)
+        )
+    }
+
+    /// The distance per day over the last year of activities as a grid of weeks, in columns
+    /// from Monday to Sunday, the more opaque the longer
+    fn calendar_html(&self) -> String {
+        let Some(&last) = self.days.keys().next_back() else {
+            return "<p>No activities</p>\n".to_string();
+        };
+        let max_km = self.days.values().cloned().fold(0.0, f64::max);
+        let last_monday = last - Duration::days(last.weekday().num_days_from_monday() as i64);
+        let first = last_monday - Duration::weeks(CALENDAR_WEEKS - 1);
+        let (cell, gap) = (11, 2);
+        let mut svg = format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\">\n",
+            CALENDAR_WEEKS * (cell + gap),
+            7 * (cell + gap)
+        );
+        for day in first.iter_days().take_while(|&day| day <= last) {
+            let week = (day - first).num_weeks();
+            let weekday = day.weekday().num_days_from_monday() as i64;
+            let km = self.days.get(&day).cloned().unwrap_or(0.0);
+            let (fill, opacity) = if km > 0.0 && max_km > 0.0 {
+                ("#c00000", 0.2 + 0.8 * km / max_km)
+            } else {
+                ("#e8e8e8", 1.0)
+            };
+            writeln!(
+                svg,
+                "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"{}\" \
+                 fill-opacity=\"{:.2}\"><title>{}: {:.1} km</title></rect>",
+                week * (cell + gap),
+                weekday * (cell + gap),
+                cell,
+                cell,
+                fill,
+                opacity,
+                day,
+                km
+            )
+            .unwrap();
+        }
+        svg.push_str("</svg>\n");
+        svg
+    }
+
+    /// The activities covering the most distance in view
+    fn top_html(&self) -> String {
+        let mut html = String::from(
+            "<table>\n<tr><th>Activity</th><th>Date</th><th>Distance in view</th>\
+             <th>Points in view</th></tr>\n",
+        );
+        for contribution in self.top.iter().take(TOP_ACTIVITIES) {
+            writeln!(
+                html,
+                "<tr><td>{}</td><td>{}</td><td class=\"number\">{:.1} km</td>\
+                 <td class=\"number\">{}</td></tr>",
+                escape(&contribution.name),
+                contribution.date.date_naive(),
+                contribution.distance_km,
+                contribution.points
+            )
+            .unwrap();
+        }
+        html.push_str("</table>\n");
+        html
+    }
+
+    /// A single HTML file laid out by the template, with the map embedded
+    pub fn to_html(&self, template: &str) -> String {
+        let values = [
+            ("title", escape(&self.title)),
+            (
+                "map",
+                format!("data:image/png;base64,{}", base64(&self.map)),
+            ),
+            ("summary", self.summary_html()),
+            ("calendar", self.calendar_html()),
+            ("top", self.top_html()),
+        ];
+        fill(template, &values)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base64_matches_the_rfc_vectors() {
+        // RFC 4648, section 10
+        let vectors = [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foob", "Zm9vYg=="),
+            ("fooba", "Zm9vYmE="),
+            ("foobar", "Zm9vYmFy"),
+        ];
+        for (data, encoded) in vectors {
+            assert_eq!(base64(data.as_bytes()), encoded);
+        }
+        assert_eq!(base64(&[0, 255, 62, 63]), "AP8+Pw==");
+    }
+
+    #[test]
+    fn fill_replaces_placeholders_once() {
+        let values = [
+            ("title", "{{map}} & more".to_string()),
+            ("map", "data".to_string()),
+        ];
+        assert_eq!(
+            fill("<h1>{{ title }}</h1><img src=\"{{map}}\">", &values),
+            "<h1>{{map}} & more</h1><img src=\"data\">"
+        );
+        // Unknown and unclosed placeholders are kept as they are
+        assert_eq!(fill("{{top}} {{map", &values), "{{top}} {{map");
+    }
+}
@@ -0,0 +1,95 @@
+use std::path::Path;
+
+/// Memory set aside per worker thread, e.g., for a tile of heat or a frame being encoded
+const MEMORY_PER_THREAD: u64 = 32 << 20;
+
+/// Memory set aside per file parsed at the same time, enough for a large activity file
+const MEMORY_PER_PARSE: u64 = 128 << 20;
+
+/// Memory left for everything but the workers, e.g., the heatmap and basemap
+const BASE_MEMORY: u64 = 128 << 20;
+
+/// CPUs and memory available to this process, honoring the limits of containers
+#[derive(Clone, Copy, Debug)]
+pub struct Resources {
+    /// CPUs usable at the same time, taking cgroup CPU quotas into account
+    pub cpus: usize,
+    /// Memory available in bytes, if known, as the lower of the free system memory and the
+    /// room left below a cgroup memory limit
+    pub memory: Option<u64>,
+}
+
+/// Reads the first line of a file as a number of bytes, None for `max` or unreadable files
+fn read_bytes(path: &Path) -> Option<u64> {
+    std::fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+/// Memory that can still be allocated below the cgroup memory limit, for cgroup v2 and v1
+fn cgroup_memory() -> Option<u64> {
+    let v2 = Path::new("/sys/fs/cgroup");
+    if let Some(limit) = read_bytes(&v2.join("memory.max")) {
+        let used = read_bytes(&v2.join("memory.current")).unwrap_or(0);
+        return Some(limit.saturating_sub(used));
+    }
+    let v1 = Path::new("/sys/fs/cgroup/memory");
+    let limit = read_bytes(&v1.join("memory.limit_in_bytes"))?;
+    // Without a limit, v1 reports a number close to the largest 64 bit one
+    if limit >= 1 << 60 {
+        return None;
+    }
+    let used = read_bytes(&v1.join("memory.usage_in_bytes")).unwrap_or(0);
+    Some(limit.saturating_sub(used))
+}
+
+/// Memory the system can provide without swapping, from `/proc/meminfo`
+fn system_memory() -> Option<u64> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    let line = meminfo
+        .lines()
+        .find(|line| line.starts_with("MemAvailable:"))?;
+    let kilobytes: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kilobytes * 1024)
+}
+
+impl Resources {
+    /// Detect the resources of the machine or container
+    pub fn detect() -> Self {
+        let cpus = std::thread::available_parallelism().map_or(1, |n| n.get());
+        let memory = match (cgroup_memory(), system_memory()) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        Resources { cpus, memory }
+    }
+
+    /// Number of worker threads to use: one per CPU, but fewer if there is not enough memory
+    /// for each
+    pub fn threads(&self) -> usize {
+        self.affordable(MEMORY_PER_THREAD)
+    }
+
+    /// Number of activity files to parse at the same time: one per CPU, but fewer if there is
+    /// not enough memory for each to be a large one
+    pub fn parsers(&self) -> usize {
+        self.affordable(MEMORY_PER_PARSE)
+    }
+
+    /// Memory left for frames waiting to be encoded or written once every worker thread has
+    /// its share, if known
+    pub fn frame_memory(&self) -> Option<u64> {
+        let workers = BASE_MEMORY + self.threads() as u64 * MEMORY_PER_THREAD;
+        self.memory.map(|memory| memory.saturating_sub(workers))
+    }
+
+    /// Number of CPUs, but at least one and no more than there is memory for at the given
+    /// amount each
+    fn affordable(&self, each: u64) -> usize {
+        match self.memory {
+            Some(memory) => {
+                let affordable = memory.saturating_sub(BASE_MEMORY) / each;
+                self.cpus.min(affordable as usize).max(1)
+            }
+            None => self.cpus,
+        }
+    }
+}
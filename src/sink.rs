@@ -0,0 +1,71 @@
+use image::DynamicImage;
+
+use std::error::Error;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use super::frames::FrameWriter;
+use super::metadata::{self, Metadata};
+
+/// Where rendered images go, e.g., a single image file, or frames streamed to stdout or
+/// written to a directory
+pub trait OutputSink {
+    /// Writes an image, e.g., the next frame; returns whether it was written rather than
+    /// skipped as a duplicate of the one before
+    fn write(&mut self, image: DynamicImage, metadata: &Metadata) -> Result<bool, Box<dyn Error>>;
+
+    /// Name of the file the given frame, counting from 1, was written to, for sinks writing a
+    /// file per frame
+    fn frame_file(&self, _frame: u64) -> Option<String> {
+        None
+    }
+
+    /// Number of frames written out completely so far, for sinks writing them in the
+    /// background
+    fn stored(&self) -> u64 {
+        0
+    }
+
+    /// Waits for everything still being written
+    fn finish(&mut self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+}
+
+/// Saves the image to a file, in the format of its extension, with the metadata in PNGs
+pub struct ImageFile {
+    path: PathBuf,
+}
+
+impl ImageFile {
+    pub fn new(path: &Path) -> Self {
+        ImageFile {
+            path: path.to_path_buf(),
+        }
+    }
+}
+
+impl OutputSink for ImageFile {
+    fn write(&mut self, image: DynamicImage, metadata: &Metadata) -> Result<bool, Box<dyn Error>> {
+        metadata::save(&image, &self.path, metadata)?;
+        Ok(true)
+    }
+}
+
+impl<W: Write> OutputSink for FrameWriter<W> {
+    fn write(&mut self, image: DynamicImage, metadata: &Metadata) -> Result<bool, Box<dyn Error>> {
+        FrameWriter::write(self, image, metadata)
+    }
+
+    fn frame_file(&self, frame: u64) -> Option<String> {
+        FrameWriter::frame_file(self, frame)
+    }
+
+    fn stored(&self) -> u64 {
+        FrameWriter::stored(self)
+    }
+
+    fn finish(&mut self) -> Result<(), Box<dyn Error>> {
+        FrameWriter::finish(self)
+    }
+}
@@ -3,6 +3,9 @@ use geo_types::{Coord, Point, Rect};
 
 pub const TILE_SIZE: u32 = 256;
 
+/// Equatorial circumference of the earth as used by the web mercator projection, in meters
+pub const EARTH_CIRCUMFERENCE: f64 = 40_075_016.686;
+
 /// Convert lon/lat coordinates to OSM tile coordinates of the given zoom level
 pub fn to_tile(p: Point<f64>, zoom: u8) -> Point<f64> {
     let n = 2u32.pow(zoom as u32) as f64;
@@ -11,6 +14,49 @@ pub fn to_tile(p: Point<f64>, zoom: u8) -> Point<f64> {
     (x, y).into()
 }
 
+/// Convert lon/lat coordinates to world coordinates, the OSM tile coordinates of zoom level 0
+pub fn to_world(p: Point<f64>) -> Point<f64> {
+    to_tile(p, 0)
+}
+
+/// Convert world coordinates back to lon/lat
+pub fn from_world(p: Point<f64>) -> Point<f64> {
+    from_tile(p, 0)
+}
+
+/// Clips a segment to the rectangle between two corners, returning the part inside if any
+pub fn clip_segment(
+    a: Point<f64>,
+    b: Point<f64>,
+    min: Point<f64>,
+    max: Point<f64>,
+) -> Option<(Point<f64>, Point<f64>)> {
+    // Liang-Barsky: narrow the range of the segment's parameter by each edge
+    let delta = b - a;
+    let (mut enter, mut exit) = (0.0f64, 1.0f64);
+    let edges = [
+        (-delta.x(), a.x() - min.x()),
+        (delta.x(), max.x() - a.x()),
+        (-delta.y(), a.y() - min.y()),
+        (delta.y(), max.y() - a.y()),
+    ];
+    for (p, q) in edges.iter() {
+        if *p == 0.0 {
+            if *q < 0.0 {
+                return None;
+            }
+        } else if *p < 0.0 {
+            enter = enter.max(q / p);
+        } else {
+            exit = exit.min(q / p);
+        }
+    }
+    if enter > exit {
+        return None;
+    }
+    Some((a + delta * enter, a + delta * exit))
+}
+
 /// Converts a coordinate in the OSM tile reference at the given zoom level to lon/lat
 pub fn from_tile(p: Point<f64>, zoom: u8) -> Point<f64> {
     let n = 2u32.pow(zoom as u32) as f64;
@@ -22,7 +68,7 @@ pub fn from_tile(p: Point<f64>, zoom: u8) -> Point<f64> {
 }
 
 /// A reference map with display size and lon/lat as well as OSM extends
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug)]
 pub struct Map {
     /// Extends in tile coordinates
     extends_tiled: Rect<f64>,
@@ -46,7 +92,12 @@ impl Map {
 
         let center = Point::new(center_x, center_y);
         let center = to_tile(center, zoom);
-        let extends_tiled = Rect::new(center + tile_extends * 0.5, center - tile_extends * 0.5);
+        // Start on a whole pixel of the zoom level, so that basemap tiles and heat share one
+        // pixel grid instead of being up to a pixel apart
+        let snap = |v: f64| (v * TILE_SIZE as f64).round() / TILE_SIZE as f64;
+        let min = center - tile_extends * 0.5;
+        let min = Point::new(snap(min.x()), snap(min.y()));
+        let extends_tiled = Rect::new(min, min + tile_extends);
         let extends_coord = Rect::new(
             from_tile(extends_tiled.min().into(), zoom),
             from_tile(extends_tiled.max().into(), zoom),
@@ -60,42 +111,150 @@ impl Map {
         }
     }
 
+    /// The same view port center and size in pixels at another zoom level
+    pub fn at_zoom(&self, zoom: u8) -> Self {
+        let center = self.center();
+        Self::from(center.x(), center.y(), self.size.x(), self.size.y(), zoom)
+    }
+
+    /// Center of the view port in lon/lat
+    pub fn center(&self) -> Point<f64> {
+        from_tile(self.extends_tiled.center().into(), self.zoom)
+    }
+
+    /// The same view port moved by the given fractions of its width and height, to the east
+    /// and south
+    pub fn shifted(&self, dx: f64, dy: f64) -> Self {
+        let shift = Coord::from((
+            dx * self.size.x() as f64 / TILE_SIZE as f64,
+            dy * self.size.y() as f64 / TILE_SIZE as f64,
+        ));
+        let center = from_tile((self.extends_tiled.center() + shift).into(), self.zoom);
+        Self::from(
+            center.x(),
+            center.y(),
+            self.size.x(),
+            self.size.y(),
+            self.zoom,
+        )
+    }
+
+    /// The same area at another zoom level, its size in pixels doubling with each level
+    pub fn scaled_to(&self, zoom: u8) -> Self {
+        let factor = 2f64.powi(zoom as i32 - self.zoom as i32);
+        let scale = |pixels: u32| ((pixels as f64 * factor).round() as u32).max(1);
+        let center = self.center();
+        let (width, height) = (scale(self.size.x()), scale(self.size.y()));
+        Self::from(center.x(), center.y(), width, height, zoom)
+    }
+
+    /// The part of the map with the given pixel offset and size
+    pub fn crop(&self, x: u32, y: u32, width: u32, height: u32) -> Self {
+        let min = self.extends_tiled.min() + Coord::from((x as f64, y as f64)) / TILE_SIZE as f64;
+        let max = min + Coord::from((width as f64, height as f64)) / TILE_SIZE as f64;
+        let extends_tiled = Rect::new(min, max);
+        let extends_coord = Rect::new(
+            from_tile(min.into(), self.zoom),
+            from_tile(max.into(), self.zoom),
+        );
+
+        Self {
+            extends_tiled,
+            extends_coord,
+            size: Point::new(width, height),
+            zoom: self.zoom,
+        }
+    }
+
     pub fn pixel_size(&self) -> (u32, u32) {
         (self.size.x(), self.size.y())
     }
 
+    /// Top left corner in whole pixels of the zoom level, counted from the top left of the
+    /// world; rounded rather than truncated, as tile coordinates carry rounding errors
+    fn pixel_origin(&self) -> (u64, u64) {
+        let min = self.extends_tiled.min() * TILE_SIZE as f64;
+        (min.x.round() as u64, min.y.round() as u64)
+    }
+
+    /// Position of the top left corner within its tile, in pixels
     pub fn pixel_offsets(&self) -> (u32, u32) {
-        let tile_min_x = self.extends_tiled.min().x;
-        let tile_min_y = self.extends_tiled.min().y;
-        let offset_x = ((tile_min_x - tile_min_x.trunc()) * TILE_SIZE as f64) as u32;
-        let offset_y = ((tile_min_y - tile_min_y.trunc()) * TILE_SIZE as f64) as u32;
-        (offset_x, offset_y)
+        let (x, y) = self.pixel_origin();
+        let size = TILE_SIZE as u64;
+        ((x % size) as u32, (y % size) as u32)
+    }
+
+    /// Top left corner in fractional tile coordinates
+    pub fn tile_origin(&self) -> Point<f64> {
+        self.extends_tiled.min().into()
     }
 
+    /// Column and row of the tile containing the top left corner
     pub fn tile_offsets(&self) -> (u32, u32) {
-        (
-            self.extends_tiled.min().x as u32,
-            self.extends_tiled.min().y as u32,
-        )
+        let (x, y) = self.pixel_origin();
+        let size = TILE_SIZE as u64;
+        ((x / size) as u32, (y / size) as u32)
     }
 
+    /// Columns of the tiles covering the map
     pub fn tile_xs(&self) -> std::ops::RangeInclusive<u32> {
-        self.extends_tiled.min().x as u32..=self.extends_tiled.max().x as u32
+        let first = self.pixel_origin().0;
+        let last = first + self.size.x().max(1) as u64 - 1;
+        (first / TILE_SIZE as u64) as u32..=(last / TILE_SIZE as u64) as u32
     }
 
+    /// Rows of the tiles covering the map
     pub fn tile_ys(&self) -> std::ops::RangeInclusive<u32> {
-        self.extends_tiled.min().y as u32..=self.extends_tiled.max().y as u32
+        let first = self.pixel_origin().1;
+        let last = first + self.size.y().max(1) as u64 - 1;
+        (first / TILE_SIZE as u64) as u32..=(last / TILE_SIZE as u64) as u32
+    }
+
+    /// Top left corner in pixels of the zoom level, as subtracted when projecting
+    fn origin(&self) -> Point<f64> {
+        let (x, y) = self.pixel_origin();
+        Point::new(x as f64, y as f64)
+    }
+
+    /// Projects a lon/lat coordinate to fractional pixels, even if outside of the map
+    pub fn project(&self, coord: &Point<f64>) -> Point<f64> {
+        to_tile(*coord, self.zoom) * TILE_SIZE as f64 - self.origin()
     }
 
     pub fn to_pixels(&self, coord: &Point<f64>) -> Option<Coord<u32>> {
         if !self.extends_coord.contains(coord) {
             return None;
         }
-        let float_coord =
-            (to_tile(*coord, self.zoom) - self.extends_tiled.min().into()) * TILE_SIZE.into();
+        let float_coord = self.project(coord);
         Some((float_coord.x() as u32, float_coord.y() as u32).into())
     }
 
+    /// Projects world coordinates to fractional pixels, even if outside of the map
+    pub fn project_world(&self, world: &Point<f64>) -> Point<f64> {
+        let scale = 2u32.pow(self.zoom as u32) as f64 * TILE_SIZE as f64;
+        *world * scale - self.origin()
+    }
+
+    /// Projects world coordinates to pixels, or None if outside of the map
+    pub fn world_to_pixels(&self, world: &Point<f64>) -> Option<Coord<u32>> {
+        let float_coord = self.project_world(world);
+        let (width, height) = self.pixel_size();
+        if float_coord.x() < 0.0
+            || float_coord.y() < 0.0
+            || float_coord.x() >= width as f64
+            || float_coord.y() >= height as f64
+        {
+            return None;
+        }
+        Some((float_coord.x() as u32, float_coord.y() as u32).into())
+    }
+
+    /// Ground resolution at the center of the map, in meters per pixel
+    pub fn meters_per_pixel(&self) -> f64 {
+        let latitude = self.extends_coord.center().y.to_radians();
+        EARTH_CIRCUMFERENCE * latitude.cos() / (TILE_SIZE as f64 * 2f64.powi(self.zoom as i32))
+    }
+
     pub fn zoom(&self) -> u8 {
         self.zoom
     }
@@ -0,0 +1,260 @@
+use chrono::Datelike;
+use geo::algorithm::haversine_distance::HaversineDistance;
+use geo::Point;
+use serde::Serialize;
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::path::PathBuf;
+
+use super::activity::{Activity, WorldActivity};
+use super::diagnostics::Category;
+use super::i18n;
+use super::slippy;
+
+/// Zoom level of squadrat tiles
+pub const SQUADRAT_ZOOM: u8 = 14;
+/// Zoom level of squadratinho tiles
+pub const SQUADRATINHO_ZOOM: u8 = 17;
+
+/// Summary statistics over a set of activities
+#[derive(Debug, Default, Serialize)]
+pub struct Summary {
+    /// Number of activities
+    pub activities: usize,
+    /// Total distance, in kilometers
+    pub distance_km: f64,
+    /// Number of activities per activity type
+    pub per_type: BTreeMap<String, usize>,
+    /// Number of activities per year
+    pub per_year: BTreeMap<i32, usize>,
+    /// Largest number E such that E days have at least E kilometers
+    pub eddington: usize,
+    /// Number of distinct squadrat tiles visited
+    pub squadrats: usize,
+    /// Number of distinct squadratinho tiles visited
+    pub squadratinhos: usize,
+    /// Mean heart rate over all points recording it, in beats per minute
+    pub mean_heart_rate: Option<f64>,
+    /// Mean cadence over all points recording it, in revolutions or steps per minute
+    pub mean_cadence: Option<f64>,
+    /// Mean temperature over all points recording it, in degrees Celsius
+    pub mean_temperature: Option<f64>,
+    /// Number of warnings per category encountered while reading the activities
+    pub warnings: BTreeMap<Category, usize>,
+}
+
+/// Mean of the values present, if any
+fn mean(values: impl Iterator<Item = Option<f64>>) -> Option<f64> {
+    let (sum, count) = values
+        .flatten()
+        .fold((0.0, 0), |(sum, count), v| (sum + v, count + 1));
+    if count > 0 {
+        Some(sum / count as f64)
+    } else {
+        None
+    }
+}
+
+/// Largest number E such that at least E of the given values are at least E
+fn eddington_number(mut values: Vec<f64>) -> usize {
+    values.sort_by(|a, b| b.total_cmp(a));
+    values
+        .iter()
+        .enumerate()
+        .take_while(|&(i, &v)| v >= (i + 1) as f64)
+        .count()
+}
+
+impl Summary {
+    pub fn from(activities: &[Activity]) -> Self {
+        let mut summary = Summary {
+            activities: activities.len(),
+            ..Default::default()
+        };
+        let mut per_day: HashMap<chrono::NaiveDate, f64> = HashMap::new();
+        let mut squadrats = HashSet::new();
+        let mut squadratinhos = HashSet::new();
+
+        for activity in activities {
+            let km = activity.distance() / 1000.0;
+            summary.distance_km += km;
+            *per_day.entry(activity.date().date_naive()).or_default() += km;
+
+            let kind = activity
+                .kind()
+                .filter(|k| !k.is_empty())
+                .unwrap_or("Unknown");
+            *summary.per_type.entry(kind.to_string()).or_default() += 1;
+            *summary.per_year.entry(activity.date().year()).or_default() += 1;
+
+            for point in activity.track_points() {
+                for (zoom, tiles) in [
+                    (SQUADRAT_ZOOM, &mut squadrats),
+                    (SQUADRATINHO_ZOOM, &mut squadratinhos),
+                ] {
+                    let tile = slippy::to_tile(*point, zoom);
+                    tiles.insert((tile.x().floor() as u32, tile.y().floor() as u32));
+                }
+            }
+        }
+
+        summary.eddington = eddington_number(per_day.into_values().collect());
+        summary.squadrats = squadrats.len();
+        summary.squadratinhos = squadratinhos.len();
+
+        let sensors = || {
+            activities
+                .iter()
+                .flat_map(|a| a.recorded_track_points())
+                .map(|tp| tp.sensors)
+        };
+        summary.mean_heart_rate = mean(sensors().map(|s| s.heart_rate));
+        summary.mean_cadence = mean(sensors().map(|s| s.cadence));
+        summary.mean_temperature = mean(sensors().map(|s| s.temperature));
+        summary
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut rows = vec![
+            ("Activities", self.activities.to_string()),
+            ("Total distance", format!("{:.1} km", self.distance_km)),
+            ("Eddington number", self.eddington.to_string()),
+            ("Squadrats", self.squadrats.to_string()),
+            ("Squadratinhos", self.squadratinhos.to_string()),
+        ];
+        if let Some(hr) = self.mean_heart_rate {
+            rows.push(("Mean heart rate", format!("{:.0} bpm", hr)));
+        }
+        if let Some(cadence) = self.mean_cadence {
+            rows.push(("Mean cadence", format!("{:.0} rpm", cadence)));
+        }
+        if let Some(temperature) = self.mean_temperature {
+            rows.push(("Mean temperature", format!("{:.1} °C", temperature)));
+        }
+        let rows: Vec<(String, String)> = rows
+            .into_iter()
+            .map(|(label, value)| (format!("{}:", i18n::tr(label)), value))
+            .collect();
+        // Align values after the longest label, as translated
+        let width = rows
+            .iter()
+            .map(|(label, _)| label.chars().count() + 1)
+            .max()
+            .unwrap_or(0)
+            .max(18);
+        for (label, value) in rows.iter() {
+            writeln!(f, "{:<width$}{}", label, value, width = width)?;
+        }
+        writeln!(f, "{}", i18n::tr("Per type:"))?;
+        for (kind, count) in self.per_type.iter() {
+            writeln!(f, "  {:<width$}{}", kind, count, width = width - 2)?;
+        }
+        writeln!(f, "{}", i18n::tr("Per year:"))?;
+        for (year, count) in self.per_year.iter() {
+            writeln!(f, "  {:<width$}{}", year, count, width = width - 2)?;
+        }
+        Ok(())
+    }
+}
+
+/// Median distance between consecutive track points within the view port, in pixels, to judge
+/// whether the zoom level suits the recording interval; None without any visible steps
+pub fn point_spacing(activities: &[WorldActivity], map: &slippy::Map) -> Option<f64> {
+    let (width, height) = map.pixel_size();
+    let visible = |p: &Point<f64>| {
+        p.x() >= 0.0 && p.y() >= 0.0 && p.x() < width as f64 && p.y() < height as f64
+    };
+    let mut steps: Vec<f64> = activities
+        .iter()
+        .flat_map(|a| a.track_points.windows(2))
+        .filter_map(|w| {
+            let (p, q) = (
+                map.project_world(&w[0].point),
+                map.project_world(&w[1].point),
+            );
+            let step = (p.x() - q.x()).hypot(p.y() - q.y());
+            // Points recorded while standing still say nothing about the resolution
+            (visible(&p) && visible(&q) && step > 0.0).then_some(step)
+        })
+        .collect();
+    if steps.is_empty() {
+        return None;
+    }
+    let middle = steps.len() / 2;
+    let (_, median, _) = steps.select_nth_unstable_by(middle, f64::total_cmp);
+    Some(*median)
+}
+
+/// How much an activity contributes to a view port
+#[derive(Debug, Serialize)]
+pub struct Contribution {
+    pub name: String,
+    pub date: chrono::DateTime<chrono::Utc>,
+    pub path: PathBuf,
+    /// Number of track points within the view port
+    pub points: usize,
+    /// Distance covered within the view port, in kilometers
+    pub distance_km: f64,
+}
+
+impl Contribution {
+    /// Contribution of an activity to the map, or None if it is not visible at all
+    pub fn from(activity: &WorldActivity, map: &slippy::Map) -> Option<Self> {
+        let visible: Vec<bool> = activity
+            .track_points
+            .iter()
+            .map(|tp| map.world_to_pixels(&tp.point).is_some())
+            .collect();
+        let points = visible.iter().filter(|&&v| v).count();
+        if points == 0 {
+            return None;
+        }
+        // Count the part of each segment within the view
+        let (width, height) = map.pixel_size();
+        let corner = Point::new(width as f64, height as f64);
+        let distance: f64 = activity
+            .track_points
+            .windows(2)
+            .filter_map(|w| {
+                let (p, q) = (
+                    map.project_world(&w[0].point),
+                    map.project_world(&w[1].point),
+                );
+                let (a, b) = slippy::clip_segment(p, q, Point::new(0.0, 0.0), corner)?;
+                let length = (p.x() - q.x()).hypot(p.y() - q.y());
+                let fraction = if length > 0.0 {
+                    (a.x() - b.x()).hypot(a.y() - b.y()) / length
+                } else {
+                    1.0
+                };
+                let meters = slippy::from_world(w[0].point)
+                    .haversine_distance(&slippy::from_world(w[1].point));
+                Some(meters * fraction)
+            })
+            .sum();
+        Some(Contribution {
+            name: activity.name.clone(),
+            date: activity.date,
+            path: activity.path.clone(),
+            points,
+            distance_km: distance / 1000.0,
+        })
+    }
+
+    /// Contributions of all visible activities, largest distance in view first
+    pub fn ranked(activities: &[WorldActivity], map: &slippy::Map) -> Vec<Self> {
+        let mut ranked: Vec<Self> = activities
+            .iter()
+            .filter_map(|a| Contribution::from(a, map))
+            .collect();
+        ranked.sort_by(|a, b| {
+            b.distance_km
+                .total_cmp(&a.distance_km)
+                .then(b.points.cmp(&a.points))
+        });
+        ranked
+    }
+}
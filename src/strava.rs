@@ -1,87 +1,584 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
-use std::path::Path;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 
+use chardetng::EncodingDetector;
 use chrono::prelude::*;
-use indicatif::ParallelProgressIterator;
+use encoding_rs::Encoding;
+use indicatif::{ParallelProgressIterator, ProgressBar, ProgressDrawTarget};
 use rayon::prelude::*;
 use regex::Regex;
+use zip::ZipArchive;
 
-use super::activity::{RawActivity, ScreenActivity};
+use super::activity::{Activity, FitField, Order, RawActivity, ScreenActivity, WorldActivity};
+use super::diagnostics::{self, Category};
 use super::heat::Heatmap;
+use super::progress::{self, Event};
+use super::takeout;
 
 pub struct DataExport {
     activities: Vec<RawActivity>,
+    /// Files listed in `activities.csv` that do not exist, e.g., of deleted activities
+    missing: Vec<PathBuf>,
+    /// Simplify tracks to deviate at most this many meters from the recorded ones
+    simplify: Option<f64>,
+    /// Zip archive the activity files are read from, if not from a directory
+    archive: Option<PathBuf>,
+    /// Files of a Google Takeout location history, each holding many activities
+    location_history: Vec<PathBuf>,
+    /// Keep what was read of damaged FIT files
+    lenient_fit: bool,
+    /// Additional field to read from the records of FIT files
+    fit_field: Option<FitField>,
+    /// Number of files parsed at the same time, if fewer than threads
+    parsers: Option<usize>,
+    /// Photos listed in `activities.csv`, by activity file
+    media: HashMap<PathBuf, Vec<PathBuf>>,
+}
+
+/// Photos of the activities of an export, e.g., to show when an activity starts in a video
+#[derive(Debug, Default)]
+pub struct Photos {
+    /// Photo files by activity file
+    files: HashMap<PathBuf, Vec<PathBuf>>,
+    /// Zip archive the photos are read from, if not from a directory
+    archive: Option<PathBuf>,
+}
+
+impl Photos {
+    /// Whether no activity has photos
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    /// Reads the first photo of the activity with the given file, if it has any
+    pub fn first(&self, activity: &Path) -> Option<Result<image::DynamicImage, Box<dyn Error>>> {
+        let file = self.files.get(activity)?.first()?;
+        Some(self.read(file))
+    }
+
+    fn read(&self, file: &Path) -> Result<image::DynamicImage, Box<dyn Error>> {
+        let bytes = match self.archive {
+            Some(ref path) => {
+                let mut archive = Archive::open(path)?;
+                let name = file.strip_prefix(path)?.to_string_lossy();
+                let mut entry = archive.zip.by_name(&name.replace('\\', "/"))?;
+                let mut bytes = Vec::new();
+                entry.read_to_end(&mut bytes)?;
+                bytes
+            }
+            None => std::fs::read(file)?,
+        };
+        Ok(image::load_from_memory(&bytes)?)
+    }
+}
+
+/// A zip archive of an export, with entries addressed by their path below the archive's path
+struct Archive {
+    path: PathBuf,
+    zip: ZipArchive<File>,
+}
+
+impl Archive {
+    fn open(path: &Path) -> Result<Self, Box<dyn Error>> {
+        Ok(Self {
+            path: path.to_path_buf(),
+            zip: ZipArchive::new(File::open(path)?)?,
+        })
+    }
+
+    /// Paths of all files in the archive, below the archive's path
+    fn files(&self) -> HashSet<PathBuf> {
+        self.zip
+            .file_names()
+            .filter(|name| !name.ends_with('/'))
+            .map(|name| self.path.join(name))
+            .collect()
+    }
+
+    /// Streams an activity out of the archive and parses it
+    fn parse(&mut self, activity: RawActivity) -> Result<Vec<Activity>, Box<dyn Error>> {
+        let name = activity.path().strip_prefix(&self.path)?.to_string_lossy();
+        let entry = self.zip.by_name(&name.replace('\\', "/"))?;
+        activity.parse_from(entry)
+    }
 }
 
 type Record = HashMap<String, String>;
 
+/// Selects which activities of an export get rendered
+#[derive(Clone, Debug, Default)]
+pub struct Filter {
+    /// Activity types to keep, matched case-insensitively; empty keeps all types
+    pub types: Vec<String>,
+    /// Drop activities flagged as commutes
+    pub skip_commutes: bool,
+}
+
+impl Filter {
+    fn accepts(&self, record: &Record) -> bool {
+        if self.skip_commutes && record.get("Commute").is_some_and(|c| is_flag_set(c)) {
+            return false;
+        }
+        if self.types.is_empty() {
+            return true;
+        }
+        let kind = record.get("Activity Type").map_or("", |t| t.as_str());
+        self.types.iter().any(|t| t.eq_ignore_ascii_case(kind))
+    }
+}
+
+/// Strava exports boolean columns either as "true"/"false" or "1.0"/"0.0"
+fn is_flag_set(value: &str) -> bool {
+    value.eq_ignore_ascii_case("true") || value.parse::<f64>().is_ok_and(|v| v != 0.0)
+}
+
+/// Reads a CSV file as UTF-8, converting from other encodings as detected
+fn read_csv(path: &Path) -> Result<String, Box<dyn Error>> {
+    Ok(decode_csv(&std::fs::read(path)?, path))
+}
+
+/// Decodes the contents of a CSV file to UTF-8, from other encodings as detected
+fn decode_csv(bytes: &[u8], path: &Path) -> String {
+    let encoding = match Encoding::for_bom(bytes) {
+        Some((encoding, _)) => encoding,
+        None if std::str::from_utf8(bytes).is_ok() => encoding_rs::UTF_8,
+        None => {
+            let mut detector = EncodingDetector::new();
+            detector.feed(bytes, true);
+            detector.guess(None, true)
+        }
+    };
+    let (text, actual, malformed) = encoding.decode(bytes);
+    if actual != encoding_rs::UTF_8 {
+        info!("Reading {} as {}", path.display(), actual.name());
+    }
+    if malformed {
+        warn!("Replaced malformed characters in {}", path.display());
+    }
+    text.into_owned()
+}
+
+/// Bar counting the files parsed, drawn only if stderr is a terminal and notes are logged, so
+/// that silencing the logger, e.g., with `--quiet`, hides it too
+fn parsing_bar(files: u64) -> ProgressBar {
+    let bar = ProgressBar::new(files);
+    if !log_enabled!(log::Level::Info) {
+        bar.set_draw_target(ProgressDrawTarget::hidden());
+    }
+    bar
+}
+
+/// Counts a parsed file, reporting the progress if requested
+fn count_parsed(parsed: &AtomicU64, total: usize) {
+    let done = parsed.fetch_add(1, Ordering::Relaxed) + 1;
+    progress::emit(&Event::new("parsing", done, total as u64));
+}
+
+/// Parses the activities of a file, out of the archive if given and simplified to the given
+/// deviation in meters if any, recording a warning on failure
+fn parse_activity(
+    activity: RawActivity,
+    archive: &mut Option<Archive>,
+    simplify: Option<f64>,
+) -> Vec<Activity> {
+    let path = activity.path().to_path_buf();
+    let parsed = match archive {
+        Some(archive) => archive.parse(activity),
+        None => activity.parse(),
+    };
+    match parsed {
+        Ok(activities) => match simplify {
+            Some(epsilon) => activities
+                .into_iter()
+                .map(|a| a.simplified(epsilon))
+                .collect(),
+            None => activities,
+        },
+        Err(e) => {
+            diagnostics::warn(Category::InvalidFile, format!("{}: {}", path.display(), e));
+            Vec::new()
+        }
+    }
+}
+
+/// Whether a file looks like a (possibly gzipped) activity the parsers understand
+pub fn is_activity_file(path: &Path) -> bool {
+    let gzipped = path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("gz"));
+    let path = if gzipped {
+        path.with_extension("")
+    } else {
+        path.to_path_buf()
+    };
+    matches!(
+        path.extension().and_then(OsStr::to_str),
+        Some("gpx") | Some("fit") | Some("kml") | Some("kmz")
+    )
+}
+
+/// Filters need the metadata of `activities.csv`
+fn warn_unfiltered(filter: &Filter) {
+    if !filter.types.is_empty() || filter.skip_commutes {
+        warn!("Activity filters require an activities.csv, ignoring them");
+    }
+}
+
+/// Recursively collects all activity and location history files below a directory
+fn find_activity_files(
+    directory: &Path,
+    files: &mut Vec<PathBuf>,
+    location_history: &mut Vec<PathBuf>,
+) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(directory)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            find_activity_files(&path, files, location_history)?;
+        } else if is_activity_file(&path) {
+            files.push(path);
+        } else if takeout::is_location_history(&path) {
+            location_history.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Parses the files of a location history, simplified to the given deviation in meters if any,
+/// recording a warning for each file that fails
+fn parse_location_history(files: &[PathBuf], simplify: Option<f64>) -> Vec<Activity> {
+    if !files.is_empty() {
+        info!("Reading {} location history files", files.len());
+    }
+    files
+        .par_iter()
+        .flat_map_iter(|path| match takeout::parse(path) {
+            Ok(activities) => activities,
+            Err(e) => {
+                diagnostics::warn(Category::InvalidFile, format!("{}: {}", path.display(), e));
+                Vec::new()
+            }
+        })
+        .map(|activity| match simplify {
+            Some(epsilon) => activity.simplified(epsilon),
+            None => activity,
+        })
+        .collect()
+}
+
 impl DataExport {
-    pub fn new(path: &Path) -> Result<Self, Box<dyn Error>> {
+    /// Reads the activities of an export, either a directory or the zip archive as downloaded,
+    /// or of a plain directory of activity files when no `activities.csv` is present; Google
+    /// Takeout location history is read from such directories or given as a single file
+    pub fn new(path: &Path, filter: &Filter) -> Result<Self, Box<dyn Error>> {
+        if path.extension() == Some(OsStr::new("zip")) {
+            return Self::from_archive(path, filter);
+        }
+        if path.is_file() && takeout::is_location_history(path) {
+            warn_unfiltered(filter);
+            let mut export = Self::from_files(Vec::new());
+            export.location_history.push(path.to_path_buf());
+            return Ok(export);
+        }
+        if !path.join("activities.csv").exists() {
+            return Self::from_directory(path, filter);
+        }
+        let csv = read_csv(&path.join("activities.csv"))?;
+        Self::from_csv(&csv, path, filter, |file| file.exists())
+    }
+
+    /// Reads the activities of an export archive without extracting it
+    fn from_archive(path: &Path, filter: &Filter) -> Result<Self, Box<dyn Error>> {
+        let mut archive = Archive::open(path)?;
+        let files = archive.files();
+        let csv = match archive.zip.by_name("activities.csv") {
+            Ok(mut entry) => {
+                let mut bytes = Vec::new();
+                entry.read_to_end(&mut bytes)?;
+                Some(decode_csv(&bytes, &path.join("activities.csv")))
+            }
+            Err(_) => None,
+        };
+        let mut export = match csv {
+            Some(csv) => Self::from_csv(&csv, path, filter, |file| files.contains(file))?,
+            None => {
+                warn_unfiltered(filter);
+                let mut activity_files: Vec<PathBuf> =
+                    files.into_iter().filter(|f| is_activity_file(f)).collect();
+                activity_files.sort();
+                Self::from_files(activity_files)
+            }
+        };
+        export.archive = Some(path.to_path_buf());
+        Ok(export)
+    }
+
+    /// Reads the activities listed in `activities.csv` of an export at the given path, using
+    /// the check for activity files that exist
+    fn from_csv(
+        csv: &str,
+        path: &Path,
+        filter: &Filter,
+        exists: impl Fn(&Path) -> bool,
+    ) -> Result<Self, Box<dyn Error>> {
         let time_padding_re = Regex::new(r"(, )(\d:)")?;
         let date_padding_re = Regex::new(r"( )(\d,)")?;
 
         let mut no_files = 0;
-        let mut read_errors = 0;
-        let mut parse_errors = 0;
+        let mut filtered = 0;
+        let mut missing = Vec::new();
+        let mut media = HashMap::new();
 
-        let mut rdr = csv::Reader::from_path(path.join("activities.csv"))?;
+        let mut rdr = csv::Reader::from_reader(csv.as_bytes());
         let activities: Vec<RawActivity> = rdr
             .deserialize()
             .filter_map(|result| {
-                if result.is_err() {
-                    read_errors += 1;
-                    return None;
-                }
-                let record: Record = result.unwrap();
+                let record: Record = match result {
+                    Ok(record) => record,
+                    Err(e) => {
+                        diagnostics::warn(Category::UnreadableRecord, e.to_string());
+                        return None;
+                    }
+                };
                 let filename = &record["Filename"];
                 if filename.is_empty() {
                     no_files += 1;
                     return None;
                 }
+                if !filter.accepts(&record) {
+                    filtered += 1;
+                    return None;
+                }
+                let file = path.join(filename);
+                if !exists(&file) {
+                    diagnostics::warn(Category::MissingFile, file.display().to_string());
+                    missing.push(file);
+                    return None;
+                }
                 let raw_datetime = date_padding_re.replace(&record["Activity Date"], "${1} ${2}");
                 let raw_datetime = time_padding_re.replace(&raw_datetime, "${1} ${2}");
                 let parsed_datetime =
                     NaiveDateTime::parse_from_str(&raw_datetime, "%b %e, %Y, %l:%M:%S %p");
                 let datetime = match parsed_datetime {
                     Err(e) => {
-                        parse_errors += 1;
-                        eprintln!("Failed to parse {:?}: {}", raw_datetime, e);
+                        diagnostics::warn(
+                            Category::InvalidDate,
+                            format!("{:?}: {}", raw_datetime, e),
+                        );
                         DateTime::from_timestamp(0, 0).unwrap()
                     }
                     Ok(t) => t.and_utc(),
                 };
+                // Several photos are separated by pipes
+                let photos: Vec<PathBuf> = record
+                    .get("Media")
+                    .map(|m| m.split('|').filter(|p| !p.is_empty()).map(|p| path.join(p)))
+                    .into_iter()
+                    .flatten()
+                    .collect();
+                if !photos.is_empty() {
+                    media.insert(file.clone(), photos);
+                }
                 Some(RawActivity::new(
                     record["Activity Name"].clone(),
+                    record.get("Activity Type").cloned().unwrap_or_default(),
                     datetime,
-                    path.join(filename),
+                    file,
                 ))
             })
             .collect();
         if no_files > 0 {
-            eprintln!("Found {} activities without files", no_files);
+            info!("Found {} activities without files", no_files);
         }
-        if read_errors > 0 {
-            eprintln!("Could not read {} activity records", read_errors);
+        if filtered > 0 {
+            info!("Skipped {} activities not matching the filter", filtered);
         }
-        if parse_errors > 0 {
-            eprintln!("Could not parse {} timestamps", parse_errors);
+        Ok(DataExport {
+            activities,
+            missing,
+            simplify: None,
+            archive: None,
+            location_history: Vec::new(),
+            lenient_fit: false,
+            fit_field: None,
+            parsers: None,
+            media,
+        })
+    }
+
+    /// Reads all activity files below a directory, taking names and dates from the files
+    fn from_directory(path: &Path, filter: &Filter) -> Result<Self, Box<dyn Error>> {
+        warn_unfiltered(filter);
+        let mut files = Vec::new();
+        let mut location_history = Vec::new();
+        find_activity_files(path, &mut files, &mut location_history)?;
+        files.sort();
+        location_history.sort();
+        let mut export = Self::from_files(files);
+        export.location_history = location_history;
+        Ok(export)
+    }
+
+    /// Reads the given activity files, taking names and dates from the files
+    fn from_files(files: Vec<PathBuf>) -> Self {
+        DataExport {
+            activities: files.into_iter().map(RawActivity::from_path).collect(),
+            missing: Vec::new(),
+            simplify: None,
+            archive: None,
+            location_history: Vec::new(),
+            lenient_fit: false,
+            fit_field: None,
+            parsers: None,
+            media: HashMap::new(),
         }
-        Ok(DataExport { activities })
     }
 
-    pub fn parse(self, map: &dyn Heatmap) -> Vec<ScreenActivity> {
+    /// Files listed in `activities.csv` that do not exist
+    pub fn missing_files(&self) -> &[PathBuf] {
+        &self.missing
+    }
+
+    /// Simplify tracks right after parsing, allowing them to deviate by the given distance in
+    /// meters, to save memory and time on large exports
+    pub fn with_simplification(mut self, epsilon: Option<f64>) -> Self {
+        self.simplify = epsilon;
+        self
+    }
+
+    /// Salvage the records of truncated or corrupt FIT files, e.g., from crashed head units,
+    /// instead of skipping them
+    pub fn with_lenient_fit(mut self, lenient: bool) -> Self {
+        self.lenient_fit = lenient;
+        self
+    }
+
+    /// Read an additional field of the records of FIT files into the sensors of each point,
+    /// e.g., of a muscle oxygen sensor, to color heatmaps by
+    pub fn with_fit_field(mut self, field: Option<FitField>) -> Self {
+        self.fit_field = field;
+        self
+    }
+
+    /// Parse at most this many files at the same time, e.g., to bound memory, instead of one
+    /// per thread
+    pub fn with_parsers(mut self, parsers: usize) -> Self {
+        self.parsers = Some(parsers.max(1));
+        self
+    }
+
+    /// Photos of the activities, as listed in `activities.csv`
+    pub fn photos(&self) -> Photos {
+        Photos {
+            files: self.media.clone(),
+            archive: self.archive.clone(),
+        }
+    }
+
+    /// Runs the parsing on a smaller thread pool if fewer parsers than threads are requested
+    fn in_parse_pool<T: Send>(self, parse: impl FnOnce(Self) -> T + Send) -> T {
+        let pool = self
+            .parsers
+            .filter(|&parsers| parsers < rayon::current_num_threads())
+            .and_then(|parsers| {
+                rayon::ThreadPoolBuilder::new()
+                    .num_threads(parsers)
+                    .build()
+                    .ok()
+            });
+        match pool {
+            Some(pool) => pool.install(move || parse(self)),
+            None => parse(self),
+        }
+    }
+
+    /// Parses all activity files, keeping track points in lon/lat
+    pub fn activities(self) -> Vec<Activity> {
+        self.in_parse_pool(DataExport::parse_activities)
+    }
+
+    fn parse_activities(self) -> Vec<Activity> {
+        let n = self.activities.len();
+        let simplify = self.simplify;
+        let lenient = self.lenient_fit;
+        let fit_field = self.fit_field;
+        let archive = self.archive;
+        info!("Parsing {} files", n);
+        let parsed = AtomicU64::new(0);
+
+        let mut activities: Vec<Activity> = self
+            .activities
+            .into_par_iter()
+            .progress_with(parsing_bar(n as u64))
+            .map_init(
+                || archive.as_deref().and_then(|path| Archive::open(path).ok()),
+                |zip, a| {
+                    let a = a
+                        .with_lenient_fit(lenient)
+                        .with_fit_field(fit_field.clone());
+                    let activities = parse_activity(a, zip, simplify);
+                    count_parsed(&parsed, n);
+                    activities
+                },
+            )
+            .flatten()
+            .collect();
+        activities.extend(parse_location_history(&self.location_history, simplify));
+        activities.sort_by_key(|a| a.date());
+        activities
+    }
+
+    /// Parses all activity files, keeping track points in world coordinates so that they can be
+    /// projected onto any number of heatmaps without parsing again
+    pub fn world_activities(self) -> Vec<WorldActivity> {
+        self.activities()
+            .into_par_iter()
+            .map(Activity::to_world)
+            .collect()
+    }
+
+    /// Parses all activity files and projects them onto the heatmap, sorted by the given order
+    pub fn parse(self, map: &dyn Heatmap, order: Order, reverse: bool) -> Vec<ScreenActivity> {
+        self.in_parse_pool(move |export| export.parse_onto(map, order, reverse))
+    }
+
+    fn parse_onto(self, map: &dyn Heatmap, order: Order, reverse: bool) -> Vec<ScreenActivity> {
         let n = self.activities.len();
-        eprint!("Parsing {:?} files", n);
+        let simplify = self.simplify;
+        let lenient = self.lenient_fit;
+        let fit_field = self.fit_field;
+        let archive = self.archive;
+        info!("Parsing {} files", n);
+        let parsed = AtomicU64::new(0);
 
         let mut activities: Vec<ScreenActivity> = self
             .activities
             .into_par_iter()
-            .progress_count(n as u64)
-            .filter_map(|a| a.parse().ok())
+            .progress_with(parsing_bar(n as u64))
+            .map_init(
+                || archive.as_deref().and_then(|path| Archive::open(path).ok()),
+                |zip, a| {
+                    let a = a
+                        .with_lenient_fit(lenient)
+                        .with_fit_field(fit_field.clone());
+                    let activities = parse_activity(a, zip, simplify);
+                    count_parsed(&parsed, n);
+                    activities
+                },
+            )
+            .flatten()
             .filter_map(|a| a.project_to_screen(map).ok())
             .collect();
-        activities.sort_by_key(|a| a.date);
+        activities.extend(
+            parse_location_history(&self.location_history, simplify)
+                .into_iter()
+                .filter_map(|a| a.project_to_screen(map).ok()),
+        );
+        order.sort(&mut activities, reverse);
         activities
     }
 }
@@ -0,0 +1,142 @@
+use clap::ValueEnum;
+use image::{ImageBuffer, Rgba, RgbaImage};
+use serde::{Deserialize, Serialize};
+
+/// Recoloring of the basemap, applied before the tint
+#[derive(Clone, Copy, Debug, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BasemapStyle {
+    /// The colors of the tiles
+    Normal,
+    /// Luminance only
+    Grayscale,
+    /// Brownish tones of old photographs
+    Sepia,
+    /// Inverted colors, turning light styles dark
+    Invert,
+}
+
+impl BasemapStyle {
+    /// Recolors an image in place, keeping its opacity
+    pub fn apply(self, image: &mut RgbaImage) {
+        if let BasemapStyle::Normal = self {
+            return;
+        }
+        let recolor = |[r, g, b]: [f32; 3]| -> [f32; 3] {
+            match self {
+                BasemapStyle::Normal => [r, g, b],
+                BasemapStyle::Grayscale => {
+                    let luma = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+                    [luma; 3]
+                }
+                BasemapStyle::Sepia => [
+                    0.393 * r + 0.769 * g + 0.189 * b,
+                    0.349 * r + 0.686 * g + 0.168 * b,
+                    0.272 * r + 0.534 * g + 0.131 * b,
+                ],
+                BasemapStyle::Invert => [255.0 - r, 255.0 - g, 255.0 - b],
+            }
+        };
+        for pixel in image.pixels_mut() {
+            let [r, g, b, a] = pixel.0;
+            let [r, g, b] = recolor([r as f32, g as f32, b as f32]);
+            let channel = |c: f32| c.round().clamp(0.0, 255.0) as u8;
+            *pixel = Rgba([channel(r), channel(g), channel(b), a]);
+        }
+    }
+}
+
+/// Filter scaling basemap tiles that are not of the usual size, e.g., retina tiles
+#[derive(Clone, Copy, Debug, Default, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Resampling {
+    /// Nearest pixel, crisp but jagged
+    Nearest,
+    /// Linear interpolation, smooth but blurring labels
+    Bilinear,
+    /// Lanczos windowed sinc, keeping labels sharp
+    #[default]
+    Lanczos,
+}
+
+impl Resampling {
+    pub fn filter(self) -> image::imageops::FilterType {
+        match self {
+            Resampling::Nearest => image::imageops::FilterType::Nearest,
+            Resampling::Bilinear => image::imageops::FilterType::Triangle,
+            Resampling::Lanczos => image::imageops::FilterType::Lanczos3,
+        }
+    }
+}
+
+/// How the darkening of the tint varies across the image
+#[derive(Clone, Copy, Debug)]
+pub enum Vignette {
+    /// The same darkening everywhere
+    None,
+    /// Fading from the tint in the center to the given darkening in the corners
+    Radial(f32),
+    /// Fading from the tint at the top to the given darkening at the bottom
+    Linear(f32),
+}
+
+/// A black layer darkening the basemap, with 1.0 being fully black
+#[derive(Clone, Copy, Debug)]
+pub struct Tint {
+    amount: f32,
+    vignette: Vignette,
+    /// Offset and size of the whole picture the tinted image is part of, if it is rendered in
+    /// parts, so that the vignette spans the picture
+    picture: Option<(u32, u32, u32, u32)>,
+}
+
+impl Tint {
+    /// Darken the whole basemap by the same amount
+    pub fn from(amount: f32) -> Self {
+        Self {
+            amount,
+            vignette: Vignette::None,
+            picture: None,
+        }
+    }
+
+    /// Vary the darkening across the image
+    pub fn with_vignette(mut self, vignette: Vignette) -> Self {
+        self.vignette = vignette;
+        self
+    }
+
+    /// Tint an image at the given offset within a picture of the given size, e.g., a strip of
+    /// a poster
+    pub fn within(mut self, x: u32, y: u32, width: u32, height: u32) -> Self {
+        self.picture = Some((x, y, width, height));
+        self
+    }
+
+    /// Darkening at a pixel
+    fn amount_at(&self, x: u32, y: u32, width: u32, height: u32) -> f32 {
+        let (edge, fraction) = match self.vignette {
+            Vignette::None => return self.amount,
+            Vignette::Radial(edge) => {
+                let dx = (x as f32 + 0.5) / width as f32 - 0.5;
+                let dy = (y as f32 + 0.5) / height as f32 - 0.5;
+                // Squared distance from the center, reaching one in the corners
+                (edge, (dx * dx + dy * dy) * 2.0)
+            }
+            Vignette::Linear(edge) => (edge, (y as f32 + 0.5) / height as f32),
+        };
+        self.amount + (edge - self.amount) * fraction
+    }
+
+    /// Renders the tint as a layer to be put over the basemap
+    pub fn layer(&self, width: u32, height: u32) -> RgbaImage {
+        let (left, top, picture_width, picture_height) =
+            self.picture.unwrap_or((0, 0, width, height));
+        ImageBuffer::from_fn(width, height, |x, y| {
+            let amount = self
+                .amount_at(left + x, top + y, picture_width, picture_height)
+                .clamp(0.0, 1.0);
+            Rgba([0, 0, 0, (amount * 255.0) as u8])
+        })
+    }
+}
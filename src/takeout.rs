@@ -0,0 +1,286 @@
+use chrono::{DateTime, NaiveDate, Utc};
+use geo::Point;
+use serde::de::{DeserializeSeed, IgnoredAny, MapAccess, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer};
+
+use std::error::Error;
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use super::activity::{Activity, Sensors, TrackPoint};
+
+/// Locations estimated less precisely than this many meters, e.g., from cell towers, are skipped
+const MAX_ACCURACY: f64 = 200.0;
+
+/// Whether a file is part of a Google Takeout location history: `Records.json` with all raw
+/// locations, or a monthly file of the semantic location history
+pub fn is_location_history(path: &Path) -> bool {
+    path.file_name() == Some(OsStr::new("Records.json"))
+        || (path.extension() == Some(OsStr::new("json"))
+            && path
+                .ancestors()
+                .any(|dir| dir.file_name() == Some(OsStr::new("Semantic Location History"))))
+}
+
+/// Parses a location history file, the raw locations as one activity per day and the semantic
+/// history as one activity per segment of movement
+pub fn parse(path: &Path) -> Result<Vec<Activity>, Box<dyn Error>> {
+    let reader = BufReader::new(File::open(path)?);
+    if path.file_name() == Some(OsStr::new("Records.json")) {
+        parse_records(reader, path)
+    } else {
+        parse_semantic(reader, path)
+    }
+}
+
+/// Time of a location, given as RFC 3339 by recent exports and as milliseconds by older ones
+fn timestamp(rfc3339: Option<&str>, milliseconds: Option<&str>) -> Option<DateTime<Utc>> {
+    match (rfc3339, milliseconds) {
+        (Some(text), _) => DateTime::parse_from_rfc3339(text)
+            .ok()
+            .map(|t| t.with_timezone(&Utc)),
+        (None, Some(ms)) => DateTime::from_timestamp_millis(ms.parse().ok()?),
+        (None, None) => None,
+    }
+}
+
+/// A track point at coordinates in units of 10⁻⁷ degrees
+fn track_point(lat_e7: i64, lon_e7: i64, time: Option<DateTime<Utc>>) -> TrackPoint<Point<f64>> {
+    TrackPoint {
+        point: Point::new(lon_e7 as f64 * 1e-7, lat_e7 as f64 * 1e-7),
+        time,
+        elevation: None,
+        sensors: Sensors::default(),
+    }
+}
+
+/// An entry of `Records.json`, skipping everything but the position and time
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Location {
+    latitude_e7: Option<i64>,
+    longitude_e7: Option<i64>,
+    timestamp: Option<String>,
+    timestamp_ms: Option<String>,
+    altitude: Option<f64>,
+    accuracy: Option<f64>,
+}
+
+/// Streams the `locations` array of `Records.json` to a callback, as the file can take
+/// hundreds of megabytes
+struct Locations<F>(F);
+
+impl<'de, F: FnMut(Location)> Visitor<'de> for Locations<F> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("an object with a locations array")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(mut self, mut map: A) -> Result<(), A::Error> {
+        while let Some(key) = map.next_key::<String>()? {
+            if key == "locations" {
+                map.next_value_seed(&mut self)?;
+            } else {
+                map.next_value::<IgnoredAny>()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(mut self, mut seq: A) -> Result<(), A::Error> {
+        while let Some(location) = seq.next_element()? {
+            (self.0)(location);
+        }
+        Ok(())
+    }
+}
+
+impl<'de, F: FnMut(Location)> DeserializeSeed<'de> for &mut Locations<F> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_seq(Locations(&mut self.0))
+    }
+}
+
+/// Splits the raw locations into one activity per day
+fn parse_records<R: std::io::Read>(
+    reader: R,
+    path: &Path,
+) -> Result<Vec<Activity>, Box<dyn Error>> {
+    let mut activities = Vec::new();
+    let mut day: Option<NaiveDate> = None;
+    let mut points = Vec::new();
+    let mut finish_day = |day: NaiveDate, points: &mut Vec<_>| {
+        activities.push(Activity::new(
+            format!("Location history {}", day),
+            None,
+            path.to_path_buf(),
+            std::mem::take(points),
+        ))
+    };
+    let mut deserializer = serde_json::Deserializer::from_reader(reader);
+    deserializer.deserialize_map(Locations(|location: Location| {
+        let (Some(lat), Some(lon)) = (location.latitude_e7, location.longitude_e7) else {
+            return;
+        };
+        if location.accuracy.is_some_and(|a| a > MAX_ACCURACY) {
+            return;
+        }
+        let time = timestamp(
+            location.timestamp.as_deref(),
+            location.timestamp_ms.as_deref(),
+        );
+        let date = time.map(|t| t.date_naive());
+        if date != day {
+            if let Some(previous) = day.filter(|_| !points.is_empty()) {
+                finish_day(previous, &mut points);
+            }
+            day = date;
+        }
+        let mut point = track_point(lat, lon, time);
+        point.elevation = location.altitude;
+        points.push(point);
+    }))?;
+    if let Some(day) = day.filter(|_| !points.is_empty()) {
+        finish_day(day, &mut points);
+    }
+    if activities.is_empty() {
+        return Err(Box::from("no dated locations"));
+    }
+    Ok(activities)
+}
+
+/// A monthly file of the semantic location history
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Timeline {
+    #[serde(default)]
+    timeline_objects: Vec<TimelineObject>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TimelineObject {
+    activity_segment: Option<Segment>,
+}
+
+/// A stretch of movement, with the recorded path simplified by Google
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Segment {
+    activity_type: Option<String>,
+    start_location: Option<Place>,
+    end_location: Option<Place>,
+    duration: Option<Duration>,
+    simplified_raw_path: Option<RawPath>,
+    waypoint_path: Option<WaypointPath>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Place {
+    latitude_e7: Option<i64>,
+    longitude_e7: Option<i64>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Duration {
+    start_timestamp: Option<String>,
+    start_timestamp_ms: Option<String>,
+    end_timestamp: Option<String>,
+    end_timestamp_ms: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RawPath {
+    #[serde(default)]
+    points: Vec<PathPoint>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PathPoint {
+    lat_e7: i64,
+    lng_e7: i64,
+    timestamp: Option<String>,
+    timestamp_ms: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct WaypointPath {
+    #[serde(default)]
+    waypoints: Vec<Waypoint>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Waypoint {
+    lat_e7: i64,
+    lng_e7: i64,
+}
+
+/// Takes each segment of movement as an activity of its type, from its start location along
+/// the recorded path, or the waypoints if there is none, to its end location
+fn parse_semantic<R: std::io::Read>(
+    reader: R,
+    path: &Path,
+) -> Result<Vec<Activity>, Box<dyn Error>> {
+    let timeline: Timeline = serde_json::from_reader(reader)?;
+    let activities = timeline
+        .timeline_objects
+        .into_iter()
+        .filter_map(|object| object.activity_segment)
+        .filter_map(|segment| {
+            let (start, end) = match segment.duration {
+                Some(d) => (
+                    timestamp(
+                        d.start_timestamp.as_deref(),
+                        d.start_timestamp_ms.as_deref(),
+                    ),
+                    timestamp(d.end_timestamp.as_deref(), d.end_timestamp_ms.as_deref()),
+                ),
+                None => (None, None),
+            };
+            let place = |place: Option<Place>, time| {
+                let place = place?;
+                Some(track_point(place.latitude_e7?, place.longitude_e7?, time))
+            };
+            let mut points: Vec<_> = place(segment.start_location, start).into_iter().collect();
+            match (segment.simplified_raw_path, segment.waypoint_path) {
+                (Some(raw), _) if !raw.points.is_empty() => {
+                    points.extend(raw.points.into_iter().map(|p| {
+                        let time = timestamp(p.timestamp.as_deref(), p.timestamp_ms.as_deref());
+                        track_point(p.lat_e7, p.lng_e7, time)
+                    }))
+                }
+                (_, Some(waypoints)) => points.extend(
+                    waypoints
+                        .waypoints
+                        .into_iter()
+                        .map(|w| track_point(w.lat_e7, w.lng_e7, None)),
+                ),
+                _ => {}
+            }
+            points.extend(place(segment.end_location, end));
+            if points.len() < 2 {
+                return None;
+            }
+            let kind = segment.activity_type;
+            let name = match (&kind, start) {
+                (Some(kind), Some(start)) => format!("{} {}", kind, start.date_naive()),
+                (Some(kind), None) => kind.clone(),
+                (None, _) => String::from("Location history"),
+            };
+            Some(Activity::new(name, kind, path.to_path_buf(), points))
+        })
+        .collect();
+    Ok(activities)
+}
@@ -0,0 +1,86 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// A phase of rendering and how much it got through
+struct Phase {
+    name: &'static str,
+    duration: Duration,
+    count: u64,
+    unit: &'static str,
+}
+
+/// Wall time and throughput of the phases of a run
+#[derive(Default)]
+pub struct Timings {
+    phases: Vec<Phase>,
+}
+
+impl Timings {
+    /// Add the time since the start to the named phase, counting the items it processed
+    pub fn record(&mut self, name: &'static str, start: Instant, count: u64, unit: &'static str) {
+        self.add(name, start.elapsed(), count, unit);
+    }
+
+    /// Add a duration to the named phase, counting the items it processed
+    pub fn add(&mut self, name: &'static str, duration: Duration, count: u64, unit: &'static str) {
+        match self.phases.iter_mut().find(|p| p.name == name) {
+            Some(phase) => {
+                phase.duration += duration;
+                phase.count += count;
+            }
+            None => self.phases.push(Phase {
+                name,
+                duration,
+                count,
+                unit,
+            }),
+        }
+    }
+}
+
+/// Largest resident set size of this process so far, in bytes
+pub fn peak_rss() -> Option<u64> {
+    #[cfg(unix)]
+    {
+        let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+        if unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) } != 0 {
+            return None;
+        }
+        let max = usage.ru_maxrss as u64;
+        // Linux reports kilobytes, macOS bytes
+        if cfg!(target_os = "macos") {
+            Some(max)
+        } else {
+            Some(max * 1024)
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        None
+    }
+}
+
+impl fmt::Display for Timings {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for phase in &self.phases {
+            let seconds = phase.duration.as_secs_f64();
+            write!(f, "{:<14} {:>8.2} s", phase.name, seconds)?;
+            if phase.count > 0 {
+                write!(f, "  {:>10} {}", phase.count, phase.unit)?;
+                if seconds > 0.0 {
+                    write!(f, " ({:.1} {}/s)", phase.count as f64 / seconds, phase.unit)?;
+                }
+            }
+            writeln!(f)?;
+        }
+        if let Some(bytes) = peak_rss() {
+            writeln!(
+                f,
+                "{:<14} {:>8.1} MiB",
+                "Peak memory",
+                bytes as f64 / 1048576.0
+            )?;
+        }
+        Ok(())
+    }
+}